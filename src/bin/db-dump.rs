@@ -0,0 +1,177 @@
+extern crate electrs;
+#[macro_use]
+extern crate error_chain;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use error_chain::ChainedError;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+use std::process;
+
+use electrs::{
+    errors::*,
+    new_index::{DBFlush, DBRow, Store, DB},
+};
+
+const DB_NAMES: [&str; 3] = ["txstore", "history", "cache"];
+const RESTORE_BATCH_SIZE: usize = 10_000;
+
+// Dumps are plain text, one "<db> <hex key> <hex value>" line per row. Rows of every spec are
+// written in key order (by column family for a whole database), so that dumping the same database
+// state always yields the same output.
+const DUMP_HEADER: &str = "# electrs db rows v1";
+
+fn db<'a>(store: &'a Store, name: &str) -> Result<&'a DB> {
+    Ok(match name {
+        "txstore" => store.txstore_db(),
+        "history" => store.history_db(),
+        "cache" => store.cache_db(),
+        _ => bail!(
+            "unknown db {:?} (expected one of: {})",
+            name,
+            DB_NAMES.join(", ")
+        ),
+    })
+}
+
+// Parse a rows spec, formatted as "<db>[:<key prefix>]" (e.g. "txstore:B" or "history:J")
+fn parse_rows_spec(spec: &str) -> Result<(&str, &[u8])> {
+    let mut parts = spec.splitn(2, ':');
+    let name = parts.next().unwrap();
+    if !DB_NAMES.contains(&name) {
+        bail!("invalid rows {:?}, expected <db>[:<key prefix>]", spec);
+    }
+    Ok((name, parts.next().unwrap_or("").as_bytes()))
+}
+
+fn dump(store: &Store, m: &ArgMatches) -> Result<()> {
+    let specs = m
+        .values_of("rows")
+        .unwrap()
+        .map(parse_rows_spec)
+        .collect::<Result<Vec<_>>>()?;
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    writeln!(out, "{}", DUMP_HEADER).chain_err(|| "failed to write dump")?;
+    for (name, prefix) in specs {
+        let mut count = 0;
+        for row in db(store, name)?.iter_scan(prefix) {
+            writeln!(
+                out,
+                "{} {} {}",
+                name,
+                hex::encode(&row.key),
+                hex::encode(&row.value)
+            )
+            .chain_err(|| "failed to write dump")?;
+            count += 1;
+        }
+        eprintln!(
+            "dumped {} rows from {}:{}",
+            count,
+            name,
+            String::from_utf8_lossy(prefix)
+        );
+    }
+    out.flush().chain_err(|| "failed to write dump")
+}
+
+fn parse_row(line: &str) -> Result<(&str, DBRow)> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    if parts.len() != 3 || !DB_NAMES.contains(&parts[0]) {
+        bail!("invalid row {:?}", line);
+    }
+    let key = hex::decode(parts[1]).chain_err(|| format!("invalid row key {:?}", parts[1]))?;
+    let value = hex::decode(parts[2]).chain_err(|| format!("invalid row value {:?}", parts[2]))?;
+    Ok((parts[0], DBRow { key, value }))
+}
+
+fn restore(store: &Store) -> Result<()> {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    match lines.next() {
+        Some(Ok(ref header)) if header == DUMP_HEADER => (),
+        _ => bail!("missing dump header, expected {:?}", DUMP_HEADER),
+    }
+
+    let mut batch: Vec<(String, DBRow)> = vec![];
+    let mut count = 0;
+    for line in lines {
+        let line = line.chain_err(|| "failed to read dump")?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, row) = parse_row(&line)?;
+        batch.push((name.to_string(), row));
+        if batch.len() >= RESTORE_BATCH_SIZE {
+            count += write_rows(store, &mut batch)?;
+        }
+    }
+    count += write_rows(store, &mut batch)?;
+    eprintln!("restored {} rows", count);
+    Ok(())
+}
+
+fn write_rows(store: &Store, batch: &mut Vec<(String, DBRow)>) -> Result<usize> {
+    let count = batch.len();
+    let mut rows_by_db: HashMap<String, Vec<DBRow>> = HashMap::new();
+    for (name, row) in batch.drain(..) {
+        rows_by_db.entry(name).or_insert_with(Vec::new).push(row);
+    }
+    for (name, rows) in rows_by_db {
+        db(store, &name)?.write(rows, DBFlush::Enable);
+    }
+    Ok(count)
+}
+
+fn main() {
+    let m = App::new("electrs db-dump")
+        .about("Export and import database rows in a portable, deterministic text format")
+        .setting(AppSettings::SubcommandRequired)
+        .arg(
+            Arg::with_name("db_dir")
+                .long("db-dir")
+                .help("Directory to store index database (default: ./db/)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("network")
+                .long("network")
+                .help("Select network type (mainnet, testnet, regtest)")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Write the selected rows to stdout, in key order")
+                .arg(
+                    Arg::with_name("rows")
+                        .long("rows")
+                        .help("Rows to dump, as <db>[:<key prefix>] (e.g. txstore:B or history:J)")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Write the rows read from stdin, overwriting rows with the same keys"),
+        )
+        .get_matches();
+
+    let network_name = m.value_of("network").unwrap_or("mainnet");
+    let db_dir = Path::new(m.value_of("db_dir").unwrap_or("./db"));
+    let store = Store::open(&db_dir.join(network_name).join("newindex"));
+
+    let res = match m.subcommand() {
+        ("dump", Some(sub_m)) => dump(&store, sub_m),
+        ("restore", _) => restore(&store),
+        _ => unreachable!(),
+    };
+    if let Err(e) = res {
+        eprintln!("{}", e.display_chain());
+        process::exit(1);
+    }
+}
@@ -8,7 +8,7 @@ extern crate electrs;
 use error_chain::ChainedError;
 use std::process;
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use electrs::{
     config::Config,
@@ -19,16 +19,36 @@ use electrs::{
     new_index::{precache, ChainQuery, FetchFrom, Indexer, Mempool, Query, Store},
     rest,
     signal::Waiter,
+    ws::WsServer,
 };
 
+#[cfg(not(feature = "liquid"))]
+use electrs::p2p::P2PServer;
+#[cfg(feature = "zmq")]
+use electrs::zmq_listener::ZmqListener;
+
+// How often to poll the daemon for new blocks and sync the mempool
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
 fn fetch_from(config: &Config, store: &Store) -> FetchFrom {
     let mut jsonrpc_import = config.jsonrpc_import;
     if !jsonrpc_import {
-        jsonrpc_import = !store.is_empty();
+        // until the end of the initial sync, even when resuming an interrupted one
+        jsonrpc_import = store.initial_sync_done();
+    }
+    #[cfg(not(feature = "liquid"))]
+    {
+        // only the blocks after the snapshot's are indexed, not worth reading every blk*.dat file
+        jsonrpc_import |= config.utxo_snapshot.is_some();
     }
     match jsonrpc_import {
         true => FetchFrom::Bitcoind, // slower, uses JSONRPC (good for incremental updates)
-        false => FetchFrom::BlkFiles, // faster, uses blk*.dat files (good for initial indexing)
+        // faster, uses blk*.dat files (good for initial indexing)
+        false => FetchFrom::BlkFiles {
+            readers: config.blk_file_readers,
+            parsers: config.bulk_index_threads,
+            magic: config.network_magic,
+        },
     }
 }
 
@@ -47,7 +67,12 @@ fn finish_verification(daemon: &Daemon, signal: &Waiter) -> Result<()> {
 }
 
 fn run_server(config: Arc<Config>) -> Result<()> {
-    let signal = Waiter::new();
+    // SIGHUP reloads the TLS certificate
+    let signal = if config.http_tls_cert.is_some() {
+        Waiter::new_with_reloads()
+    } else {
+        Waiter::new()
+    };
     let metrics = Metrics::new(config.monitoring_addr);
     metrics.start();
 
@@ -61,59 +86,157 @@ fn run_server(config: Arc<Config>) -> Result<()> {
     )?);
     finish_verification(&daemon, &signal)?;
     let store = Arc::new(Store::open(&config.db_path.join("newindex")));
-    let mut indexer = Indexer::open(Arc::clone(&store), fetch_from(&config, &store), &metrics);
+    Store::start_stats_exporter(&store, &metrics);
+    if config.persistent_cache {
+        Store::start_cache_evictor(&store, config.persistent_cache_max_txs);
+    }
+    let mut indexer = Indexer::open(
+        Arc::clone(&store),
+        fetch_from(&config, &store),
+        &config,
+        &metrics,
+        &signal,
+    )?;
     let mut tip = indexer.update(&daemon)?;
 
-    let chain = Arc::new(ChainQuery::new(Arc::clone(&store), &metrics));
+    // in light mode, the raw transactions are fetched from the daemon
+    let light_daemon = if config.light_mode {
+        Some(Arc::clone(&daemon))
+    } else {
+        None
+    };
+    let chain = Arc::new(ChainQuery::new(Arc::clone(&store), light_daemon, &metrics));
 
     if let Some(ref precache_file) = config.precache_scripts {
-        let precache_scripthashes = precache::scripthashes_from_file(precache_file.to_string())
-            .expect("cannot load scripts to precache");
-        precache::precache(&chain, precache_scripthashes);
+        let precache_scripthashes =
+            precache::scripthashes_from_file(precache_file.to_string(), config.address_params)
+                .expect("cannot load scripts to precache");
+        precache::precache(&chain, precache_scripthashes, &signal)?;
     }
 
     let mempool = Arc::new(RwLock::new(Mempool::new(Arc::clone(&chain), &metrics)));
+    mempool.write().unwrap().load_snapshot(&store);
     mempool.write().unwrap().update(&daemon)?;
 
     let query = Arc::new(Query::new(
         Arc::clone(&chain),
         Arc::clone(&mempool),
         Arc::clone(&daemon),
+        &config,
     ));
+    Query::start_fee_estimator(&query, config.fee_estimates_interval);
+    #[cfg(not(feature = "liquid"))]
+    let webhooks = Query::start_webhooks(&query);
 
     // TODO: configuration for which servers to start
-    let rest_server = rest::run_server(Arc::clone(&config), Arc::clone(&query));
-    let electrum_server =
-        ElectrumRPC::start(config.electrum_rpc_addr, Arc::clone(&query), &metrics);
+    let rest_server = rest::run_server(Arc::clone(&config), Arc::clone(&query), &metrics, &signal);
+    let electrum_server = ElectrumRPC::start(&config, Arc::clone(&query), &metrics);
+    let ws_server = config
+        .ws_addr
+        .map(|addr| WsServer::start(addr, &config, Arc::clone(&query), &metrics));
+    #[cfg(not(feature = "liquid"))]
+    let p2p_server = config
+        .p2p_addr
+        .map(|addr| P2PServer::start(addr, &config, Arc::clone(&query), &metrics));
+
+    #[cfg(feature = "zmq")]
+    let zmq = match (&config.zmq_rawtx, &config.zmq_rawblock) {
+        (None, None) => None,
+        (rawtx, rawblock) => Some(ZmqListener::start(
+            rawtx.as_ref().map(String::as_str),
+            rawblock.as_ref().map(String::as_str),
+        )?),
+    };
+    let mut last_sync = Instant::now();
 
     loop {
-        if let Err(err) = signal.wait(Duration::from_secs(5)) {
+        #[cfg(feature = "zmq")]
+        let res = match zmq {
+            Some(ref zmq) => signal.wait_or_wakeup(SYNC_INTERVAL, zmq.wakeup()),
+            None => signal.wait(SYNC_INTERVAL),
+        };
+        #[cfg(not(feature = "zmq"))]
+        let res = signal.wait(SYNC_INTERVAL);
+        if let Err(err) = res {
             info!("stopping server: {}", err);
-            rest_server.stop();
             break;
         }
 
-        // Index new blocks
-        let current_tip = daemon.getbestblockhash()?;
-        if current_tip != tip {
-            indexer.update(&daemon)?;
-            tip = current_tip;
-        };
-
-        // Update mempool
-        mempool.write().unwrap().update(&daemon)?;
+        // Add the transactions announced over ZMQ without a full mempool sync, unless a new
+        // block calls for one anyway (these include the transactions it confirms)
+        #[cfg(feature = "zmq")]
+        let new_block = zmq.as_ref().map_or(false, |zmq| {
+            let updates = zmq.take_updates();
+            if !updates.new_block {
+                mempool.write().unwrap().add_announced(updates.txs);
+            }
+            updates.new_block
+        });
+        #[cfg(not(feature = "zmq"))]
+        let new_block = false;
+
+        if new_block || last_sync.elapsed() >= SYNC_INTERVAL {
+            last_sync = Instant::now();
+
+            // Index new blocks and update mempool, stopping the servers as well when interrupted
+            let synced = daemon
+                .getbestblockhash()
+                .and_then(|current_tip| {
+                    if current_tip != tip {
+                        indexer.update(&daemon)?;
+                        tip = current_tip;
+                    }
+                    Ok(())
+                })
+                .and_then(|()| mempool.write().unwrap().update(&daemon));
+            match synced {
+                Err(ref err) if is_interrupt(err) => {
+                    info!("stopping server: {}", err);
+                    break;
+                }
+                // keep serving the index as of the last valid headers, with the alarm raised
+                Err(ref err) if is_invalid_headers(err) => error!("{}", err),
+                synced => synced?,
+            }
+        }
 
         // Update subscribed clients
         electrum_server.notify();
+        if let Some(ref ws_server) = ws_server {
+            ws_server.notify();
+        }
+        #[cfg(not(feature = "liquid"))]
+        {
+            if let Some(ref webhooks) = webhooks {
+                webhooks.notify();
+            }
+        }
     }
+    // the servers are stopped (and their connections closed) before saving the mempool
+    rest_server.stop();
+    drop(electrum_server);
+    drop(ws_server);
+    #[cfg(not(feature = "liquid"))]
+    drop(p2p_server);
+    #[cfg(not(feature = "liquid"))]
+    drop(webhooks);
+    #[cfg(feature = "zmq")]
+    drop(zmq);
+    mempool.read().unwrap().save_snapshot(&store);
+    store.flush_cache();
     info!("server stopped");
     Ok(())
 }
 
 fn main() {
     let config = Arc::new(Config::from_args());
-    if let Err(e) = run_server(config) {
-        error!("server failed: {}", e.display_chain());
-        process::exit(1);
+    match run_server(config) {
+        // e.g. during the initial sync, with the index flushed
+        Err(ref e) if is_interrupt(e) => info!("server stopped: {}", e),
+        Err(e) => {
+            error!("server failed: {}", e.display_chain());
+            process::exit(1);
+        }
+        Ok(()) => (),
     }
 }
@@ -0,0 +1,125 @@
+extern crate electrs;
+#[macro_use]
+extern crate log;
+
+use bincode;
+use std::process;
+use std::sync::Arc;
+
+use electrs::{
+    chain::{Network, OutPoint},
+    config::Config,
+    metrics::Metrics,
+    new_index::{compute_script_hash, ChainQuery, Store, TxHistoryInfo, TxHistoryKey},
+    util::is_spendable,
+};
+
+// Heights of the historic duplicate coinbase transactions (BIP30), as (first, duplicate)
+const MAINNET_DUPLICATE_COINBASES: [(usize, usize); 2] = [(91812, 91842), (91722, 91880)];
+
+// Verify that the chain quirks at the given heights were indexed correctly, i.e. that every
+// transaction resolves to its first best-chain confirmation and that every spendable output
+// has exactly one funding history row.
+fn audit_height(chain: &ChainQuery, store: &Store, height: usize, first_height: usize) -> bool {
+    let blockhash = match chain.hash_by_height(height) {
+        Some(blockhash) => blockhash,
+        None => {
+            warn!("height {} is not indexed yet, skipping", height);
+            return true;
+        }
+    };
+    let txids = chain
+        .get_block_txids(&blockhash)
+        .expect("missing block txids");
+    let txs = chain
+        .lookup_txns(&txids)
+        .expect("missing block transactions");
+    if chain.get_stored_block_summary(&blockhash).is_none() {
+        error!("block {} at height {} has no summary", blockhash, height);
+        return false;
+    }
+
+    let mut ok = true;
+    for (tx_index, tx) in txs.iter().enumerate() {
+        let txid = tx.txid();
+        // only the coinbase of a duplicate block is expected to resolve to an earlier block
+        let expected_height = if tx_index == 0 { first_height } else { height };
+        match chain.tx_confirming_block(&txid) {
+            Some(ref b) if b.height == expected_height => (),
+            confirmed => {
+                error!(
+                    "{} at height {} resolves to {:?}, expected height {}",
+                    txid, height, confirmed, expected_height
+                );
+                ok = false;
+            }
+        }
+
+        for (vout, txo) in tx.output.iter().enumerate() {
+            if !is_spendable(txo) {
+                continue;
+            }
+            let outpoint = OutPoint {
+                txid,
+                vout: vout as u32,
+            };
+            let scripthash = compute_script_hash(&txo.script_pubkey);
+            let funding_heights: Vec<u32> = store
+                .history_db()
+                .iter_scan(&[&b"H"[..], &scripthash[..]].concat())
+                .map(|row| {
+                    bincode::config()
+                        .big_endian()
+                        .deserialize::<TxHistoryKey>(&row.key)
+                        .expect("failed to deserialize TxHistoryKey")
+                })
+                .filter_map(|key| match key.txinfo {
+                    TxHistoryInfo::Funding(ref info)
+                        if info.txid[..] == txid[..] && info.vout as u32 == outpoint.vout =>
+                    {
+                        Some(key.confirmed_height)
+                    }
+                    _ => None,
+                })
+                .collect();
+            if funding_heights != vec![expected_height as u32] {
+                error!(
+                    "{} has funding rows at heights {:?}, expected {}",
+                    outpoint, funding_heights, expected_height
+                );
+                ok = false;
+            }
+        }
+    }
+    info!(
+        "audited height {}: {}",
+        height,
+        if ok { "ok" } else { "FAILED" }
+    );
+    ok
+}
+
+fn main() {
+    let config = Config::from_args();
+    let store = Arc::new(Store::open(&config.db_path.join("newindex")));
+
+    let metrics = Metrics::new(config.monitoring_addr);
+    let chain = ChainQuery::new(Arc::clone(&store), None, &metrics);
+
+    // (height, expected first confirmation height of its coinbase)
+    let mut heights = vec![(0, 0)];
+    if config.network_type == Network::Bitcoin {
+        for &(first, duplicate) in MAINNET_DUPLICATE_COINBASES.iter() {
+            heights.push((first, first));
+            heights.push((duplicate, first));
+        }
+    }
+
+    let mut ok = true;
+    for (height, first_height) in heights {
+        ok &= audit_height(&chain, &store, height, first_height);
+    }
+    if !ok {
+        process::exit(1);
+    }
+}
@@ -10,7 +10,7 @@ fn main() {
     let config = Config::from_args();
     let store = Store::open(&config.db_path.join("newindex"));
 
-    let mut iter = store.history_db().raw_iterator();
+    let mut iter = store.history_db().raw_iterator(b"H");
     iter.seek(b"H");
 
     let mut curr_scripthash = [0u8; 32];
@@ -26,7 +26,7 @@ fn main() {
     let metrics = Metrics::new(config.monitoring_addr);
     metrics.start();
 
-    let chain = ChainQuery::new(Arc::clone(&store), &metrics);
+    let chain = ChainQuery::new(Arc::clone(&store), None, &metrics);
 
     let daemon = Arc::new(
         Daemon::new(
@@ -40,10 +40,17 @@ fn main() {
         .unwrap(),
     );
 
-    let mut indexer = Indexer::open(Arc::clone(&store), FetchFrom::Bitcoind, &metrics);
+    let mut indexer = Indexer::open(
+        Arc::clone(&store),
+        FetchFrom::Bitcoind,
+        &config,
+        &metrics,
+        &signal,
+    )
+    .unwrap();
     indexer.update(&daemon).unwrap();
 
-    let mut iter = store.txstore_db().raw_iterator();
+    let mut iter = store.txstore_db().raw_iterator(b"T");
     iter.seek(b"T");
 
     let mut total = 0;
@@ -51,7 +58,7 @@ fn main() {
 
     while iter.valid() {
         let key = iter.key().unwrap();
-        let value = iter.value().unwrap();
+        let value = iter.value().unwrap().to_vec();
 
         if !key.starts_with(b"T") {
             break;
@@ -6,8 +6,13 @@ pub use bitcoin::{Block, BlockHeader, OutPoint, Transaction, TxIn, TxOut};
 #[cfg(feature = "liquid")]
 pub use elements::address;
 #[cfg(feature = "liquid")]
+pub use elements::address::AddressParams;
+#[cfg(feature = "liquid")]
 pub use elements::{confidential, Address, Block, BlockHeader, OutPoint, Transaction, TxIn, TxOut};
 
+#[cfg(not(feature = "liquid"))]
+use std::str::FromStr;
+
 use bitcoin::blockdata::constants::genesis_block;
 use bitcoin::network::constants::Network as BNetwork;
 use bitcoin::util::hash::BitcoinHash;
@@ -19,6 +24,60 @@ pub type Value = u64;
 #[cfg(feature = "liquid")]
 pub use confidential::Value;
 
+// The encoding of the addresses (Bitcoin only). Defaults to the network's, and can be set with
+// --address-params to run on Bitcoin-derived chains that don't have a Network of their own.
+#[cfg(not(feature = "liquid"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressParams {
+    pub p2pkh_prefix: u8,
+    pub p2sh_prefix: u8,
+    pub bech32_hrp: String,
+}
+
+// Parsed from 'p2pkh=<prefix>,p2sh=<prefix>,hrp=<bech32 hrp>', e.g. 'p2pkh=48,p2sh=50,hrp=ltc'
+#[cfg(not(feature = "liquid"))]
+impl FromStr for AddressParams {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut p2pkh_prefix, mut p2sh_prefix, mut bech32_hrp) = (None, None, None);
+        for param in s.split(',') {
+            let mut parts = param.splitn(2, '=');
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim()),
+                _ => return Err(format!("invalid address param {:?}", param)),
+            };
+            let prefix = || {
+                value
+                    .parse::<u8>()
+                    .map_err(|_| format!("invalid {} prefix {:?}", key, value))
+            };
+            match key {
+                "p2pkh" => p2pkh_prefix = Some(prefix()?),
+                "p2sh" => p2sh_prefix = Some(prefix()?),
+                "hrp" => {
+                    // 1 to 83 characters in the 33-126 range
+                    if !(1..=83).contains(&value.len())
+                        || value.bytes().any(|c| !(33..=126).contains(&c))
+                    {
+                        return Err(format!("invalid bech32 hrp {:?}", value));
+                    }
+                    bech32_hrp = Some(value.to_lowercase())
+                }
+                _ => return Err(format!("unknown address param {:?}", key)),
+            }
+        }
+        match (p2pkh_prefix, p2sh_prefix, bech32_hrp) {
+            (Some(p2pkh_prefix), Some(p2sh_prefix), Some(bech32_hrp)) => Ok(AddressParams {
+                p2pkh_prefix,
+                p2sh_prefix,
+                bech32_hrp,
+            }),
+            _ => Err("the p2pkh, p2sh and hrp address params are all required".to_string()),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Hash, Serialize, Ord, PartialOrd, Eq)]
 pub enum Network {
     Bitcoin,
@@ -37,6 +96,7 @@ impl Network {
         block.bitcoin_hash()
     }
 
+    // The start of the P2P messages and of the blocks in the blk*.dat files
     pub fn magic(&self) -> u32 {
         match self {
             Network::Bitcoin => 0xD9B4BEF9,
@@ -60,6 +120,47 @@ impl Network {
         }
     }
 
+    #[cfg(not(feature = "liquid"))]
+    pub fn address_params(&self) -> AddressParams {
+        let (p2pkh_prefix, p2sh_prefix, bech32_hrp) = match self {
+            Network::Bitcoin => (0, 5, "bc"),
+            Network::Testnet => (111, 196, "tb"),
+            Network::Regtest => (111, 196, "bcrt"),
+        };
+        AddressParams {
+            p2pkh_prefix,
+            p2sh_prefix,
+            bech32_hrp: bech32_hrp.to_string(),
+        }
+    }
+
+    // The name used for this network by the --network option
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Bitcoin => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Regtest => "regtest",
+
+            #[cfg(feature = "liquid")]
+            Network::Liquid => "liquid",
+            #[cfg(feature = "liquid")]
+            Network::LiquidRegtest => "liquidregtest",
+        }
+    }
+
+    // The block subsidy at `height`, halving every 210000 blocks (150 on regtest)
+    #[cfg(not(feature = "liquid"))]
+    pub fn block_subsidy(&self, height: usize) -> u64 {
+        let halving_interval = match self {
+            Network::Regtest => 150,
+            _ => 210_000,
+        };
+        match height / halving_interval {
+            halvings if halvings >= 64 => 0,
+            halvings => (50 * 100_000_000) >> halvings,
+        }
+    }
+
     pub fn names() -> Vec<String> {
         #[cfg(not(feature = "liquid"))]
         return vec![
@@ -141,3 +242,59 @@ impl From<&BNetwork> for Network {
         }
     }
 }
+
+// Parsed from the message start bytes as hex, in the order they're sent (e.g. 'f9beb4d9' for
+// mainnet), to the value of Network::magic()
+pub fn parse_network_magic(s: &str) -> Result<u32, String> {
+    match hex::decode(s) {
+        Ok(ref bytes) if bytes.len() == 4 => Ok(u32::from_le_bytes(*array_ref![bytes, 0, 4])),
+        _ => Err(format!(
+            "invalid network magic {:?}, expected 4 hex bytes",
+            s
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_parse_network_magic() {
+        use super::{parse_network_magic, Network};
+
+        assert_eq!(
+            parse_network_magic("f9beb4d9"),
+            Ok(Network::Bitcoin.magic())
+        );
+        assert_eq!(
+            parse_network_magic("0b110907"),
+            Ok(Network::Testnet.magic())
+        );
+        assert_eq!(
+            parse_network_magic("FABFB5DA"),
+            Ok(Network::Regtest.magic())
+        );
+        assert!(parse_network_magic("f9beb4").is_err());
+        assert!(parse_network_magic("f9beb4d9d9").is_err());
+        assert!(parse_network_magic("network").is_err());
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    #[test]
+    fn test_block_subsidy() {
+        use super::Network;
+
+        let btc = 100_000_000;
+        assert_eq!(Network::Bitcoin.block_subsidy(0), 50 * btc);
+        assert_eq!(Network::Bitcoin.block_subsidy(209_999), 50 * btc);
+        assert_eq!(Network::Bitcoin.block_subsidy(210_000), 25 * btc);
+        assert_eq!(Network::Bitcoin.block_subsidy(840_000), 3 * btc + btc / 8);
+        assert_eq!(Network::Testnet.block_subsidy(420_000), 12 * btc + btc / 2);
+        assert_eq!(Network::Regtest.block_subsidy(149), 50 * btc);
+        assert_eq!(Network::Regtest.block_subsidy(150), 25 * btc);
+        // down to 1 sat after 32 halvings, then nothing (as the shift would overflow)
+        assert_eq!(Network::Bitcoin.block_subsidy(32 * 210_000), 1);
+        assert_eq!(Network::Bitcoin.block_subsidy(33 * 210_000), 0);
+        assert_eq!(Network::Bitcoin.block_subsidy(64 * 210_000), 0);
+        assert_eq!(Network::Bitcoin.block_subsidy(usize::max_value()), 0);
+    }
+}
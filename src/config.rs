@@ -1,44 +1,101 @@
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, Error as ClapError, ErrorKind as ClapErrorKind};
 use dirs::home_dir;
+use error_chain::ChainedError;
 use num_cpus;
+use std::collections::BTreeMap;
+use std::env;
 use std::fs;
+use std::iter;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::Arc;
+use std::time::Duration;
 use stderrlog;
+use toml;
 
+use bitcoin_hashes::hex::FromHex;
 #[cfg(feature = "liquid")]
 use bitcoin_hashes::hex::ToHex;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 
-use crate::chain::Network;
+use crate::chain::{parse_network_magic, AddressParams, Network};
 use crate::daemon::CookieGetter;
+use crate::util::{
+    parse_cache_ttl, parse_client_limit, parse_disabled_route, parse_route_limit, parse_tenant,
+    AddressStyle, CacheClass, CachePolicy, ClientLimit, LogFilter, LogLevels, RouteLimit, Tenant,
+};
 
 use crate::errors::*;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     // See below for the documentation of each field:
-    pub log: stderrlog::StdErrLog,
+    pub log: &'static LogFilter,
     pub network_type: Network,
     pub db_path: PathBuf,
     pub daemon_dir: PathBuf,
     pub daemon_rpc_addr: SocketAddr,
     pub cookie: Option<String>,
     pub electrum_rpc_addr: SocketAddr,
-    pub http_addr: SocketAddr,
+    pub http_addrs: Vec<SocketAddr>,
+    pub http_socket_file: Option<PathBuf>,
+    pub http_tls_cert: Option<PathBuf>,
+    pub http_tls_key: Option<PathBuf>,
+    pub ws_addr: Option<SocketAddr>,
     pub monitoring_addr: SocketAddr,
     pub jsonrpc_import: bool,
     pub index_batch_size: usize,
     pub bulk_index_threads: usize,
+    pub blk_file_readers: usize,
     pub tx_cache_size: usize,
     pub prevout_enabled: bool,
     pub cors: Option<String>,
+    pub cache_policy: CachePolicy,
+    pub persistent_cache: bool,
+    pub persistent_cache_max_txs: u64,
+    pub http_compression_min_size: Option<usize>,
+    pub route_limits: Vec<RouteLimit>,
+    pub client_limits: Vec<ClientLimit>,
+    pub trust_forwarded_for: bool,
+    pub tenants: Vec<Tenant>,
     pub precache_scripts: Option<String>,
+    pub utxo_commitment_interval: Option<usize>,
+    pub utxo_index: bool,
+    pub light_mode: bool,
+    pub history_start_height: usize,
+    pub index_profile: Option<PathBuf>,
+    pub notify_debounce: Duration,
+    pub fee_estimates_interval: Duration,
+    pub mempool_history_limit: usize,
+    pub xpub_gap_limit: usize,
+    pub address_consistent_reads: bool,
+    pub address_scan_budget: Duration,
+    pub address_style: AddressStyle,
+    pub address_params: &'static AddressParams,
+    pub genesis_hash: Option<Sha256dHash>,
+    pub network_magic: u32,
+    pub admin_api: bool,
+    #[cfg(not(feature = "liquid"))]
+    pub block_filters: bool,
+    #[cfg(not(feature = "liquid"))]
+    pub utxo_snapshot: Option<PathBuf>,
+    #[cfg(not(feature = "liquid"))]
+    pub p2p_addr: Option<SocketAddr>,
+    #[cfg(not(feature = "liquid"))]
+    pub webhooks: bool,
+
+    #[cfg(feature = "zmq")]
+    pub zmq_rawtx: Option<String>,
+    #[cfg(feature = "zmq")]
+    pub zmq_rawblock: Option<String>,
 
     #[cfg(feature = "liquid")]
     pub parent_network: Network,
     #[cfg(feature = "liquid")]
     pub parent_genesis_hash: String,
+    #[cfg(feature = "liquid")]
+    pub asset_db_path: Option<PathBuf>,
 }
 
 impl Config {
@@ -47,9 +104,26 @@ impl Config {
             "Select Bitcoin network type ({})",
             Network::names().join(", ")
         );
+        let cache_ttl_help = format!(
+            "Override the Cache-Control max-age of a class of REST endpoints, as '<class>=<seconds>' ({})",
+            CacheClass::names().join(", ")
+        );
 
         let args = App::new("Electrum Rust Server")
             .version(crate_version!())
+            .arg(
+                Arg::with_name("conf")
+                    .long("conf")
+                    .help("TOML config file, keyed by the option names with underscores instead of dashes (default: $ELECTRS_CONF). Options can also be set as ELECTRS_<NAME> environment variables, overriding the config file, and are overridden by the command line")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("profile")
+                    .long("profile")
+                    .help("Preset of options for a deployment role (explorer: all endpoints with the indexes and caches for heavy public use, wallet: the indexes for wallet backends without the explorer-only endpoints, minimal: the least resources with the wallet endpoints only). Individual options override the preset")
+                    .possible_values(PROFILES)
+                    .takes_value(true),
+            )
             .arg(
                 Arg::with_name("verbosity")
                     .short("v")
@@ -94,7 +168,35 @@ impl Config {
             .arg(
                 Arg::with_name("http_addr")
                     .long("http-addr")
-                    .help("HTTP server 'addr:port' to listen on (default: '127.0.0.1:3000' for mainnet, '127.0.0.1:3001' for testnet and '127.0.0.1:3002' for regtest)")
+                    .help("HTTP server 'addr:port' to listen on, can be repeated (default: '127.0.0.1:3000' for mainnet, '127.0.0.1:3001' for testnet and '127.0.0.1:3002' for regtest, unless --http-socket-file is set)")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("http_socket_file")
+                    .long("http-socket-file")
+                    .help("Unix socket file for the HTTP server to listen on, e.g. for a reverse proxy (only listening on it unless --http-addr is set too)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("http_tls_cert")
+                    .long("http-tls-cert")
+                    .help("PEM certificate chain to serve HTTPS with on the --http-addr addresses, reloaded on SIGHUP (requires --http-tls-key)")
+                    .takes_value(true)
+                    .requires("http_tls_key"),
+            )
+            .arg(
+                Arg::with_name("http_tls_key")
+                    .long("http-tls-key")
+                    .help("PEM private key (PKCS#8 or RSA) of the --http-tls-cert certificate, reloaded on SIGHUP")
+                    .takes_value(true)
+                    .requires("http_tls_cert"),
+            )
+            .arg(
+                Arg::with_name("ws_addr")
+                    .long("ws-addr")
+                    .help("WebSocket server 'addr:port' to listen on for subscriptions (disabled by default)")
                     .takes_value(true),
             )
             .arg(
@@ -126,10 +228,16 @@ impl Config {
                     .help("Number of threads used for bulk indexing (default: use the # of CPUs)")
                     .default_value("0")
             )
+            .arg(
+                Arg::with_name("blk_file_readers")
+                    .long("blk-file-readers")
+                    .help("Number of blk*.dat files read concurrently during the initial sync, each held in memory until parsed by the bulk indexing threads")
+                    .default_value("4")  // enough to saturate fast disks
+            )
             .arg(
                 Arg::with_name("tx_cache_size")
                     .long("tx-cache-size")
-                    .help("Number of transactions to keep in for query LRU cache")
+                    .help("Number of transactions to keep in the LRU cache of previous outputs fetched from the daemon")
                     .default_value("10000")  // should be enough for a small wallet.
             )
             .arg(
@@ -143,11 +251,231 @@ impl Config {
                     .help("Origins allowed to make cross-site requests")
                     .takes_value(true)
             )
+            .arg(
+                Arg::with_name("cache_ttl")
+                    .long("cache-ttl")
+                    .help(&cache_ttl_help)
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("cache_stale_while_revalidate")
+                    .long("cache-stale-while-revalidate")
+                    .help("Number of seconds cached REST responses may be served stale while revalidating them (disabled by default)")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("cache_etag")
+                    .long("cache-etag")
+                    .help("Add an ETag derived from the chain tip hash to REST responses that only change with new blocks")
+            )
+            .arg(
+                Arg::with_name("persistent_cache")
+                    .long("persistent-cache")
+                    .help("Cache the REST JSON of deeply confirmed transactions (and the recomputed block stats) on disk, so that it's kept across restarts")
+            )
+            .arg(
+                Arg::with_name("persistent_cache_max_txs")
+                    .long("persistent-cache-max-txs")
+                    .help("Maximum number of transactions JSON kept in the persistent cache, the ones over it are evicted every minute")
+                    .default_value("1000000")
+            )
+            .arg(
+                Arg::with_name("http_compression_min_size")
+                    .long("http-compression-min-size")
+                    .help("Minimum size in bytes of the REST responses to compress with gzip or brotli, when accepted by the client")
+                    .default_value("1024")
+            )
+            .arg(
+                Arg::with_name("disable_http_compression")
+                    .long("disable-http-compression")
+                    .help("Don't compress REST responses (e.g. when done by a reverse proxy)")
+            )
+            .arg(
+                Arg::with_name("route_limit")
+                    .long("route-limit")
+                    .help("Limit the REST requests to the paths matching a pattern (e.g. '/address/:address/txs/chain/*'), as '<pattern>?<param>=<max>' to cap a parameter or '<pattern>@<count>/<seconds>' to cap the rate of requests (per value of the :named segments)")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("disable_route")
+                    .long("disable-route")
+                    .help("Don't serve the REST requests to the paths matching a pattern (e.g. '/blocks/stats/*'), with the same syntax as --route-limit")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("client_limit")
+                    .long("client-limit")
+                    .help("Limit the rate of REST requests of each client IP to the paths matching a pattern (e.g. '/address/*'), as '<pattern>@<count>/<seconds>' for bursts of up to <count> requests refilled over <seconds>")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("trust_forwarded_for")
+                    .long("trust-forwarded-for")
+                    .help("Identify the clients by the last address of the X-Forwarded-For header, as set by a reverse proxy (for --client-limit)")
+            )
+            .arg(
+                Arg::with_name("tenant")
+                    .long("tenant")
+                    .help("Serve a client application with its own webhooks and WebSocket script quota, identified by the API key of its requests, as '<name>:<api key>[@<max webhooks>/<max watched scripts>]' (once set, these endpoints require a key)")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
             .arg(
                 Arg::with_name("precache_scripts")
                     .long("precache-scripts")
                     .help("Path to file with list of scripts to pre-cache")
                     .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("utxo_commitments")
+                    .long("utxo-commitments")
+                    .help("Maintain MuHash commitments to the UTXO set (compatible with bitcoind's `gettxoutsetinfo muhash`)")
+            )
+            .arg(
+                Arg::with_name("utxo_commitment_interval")
+                    .long("utxo-commitment-interval")
+                    .help("Number of blocks between stored cumulative UTXO commitment checkpoints")
+                    .default_value("1000")
+            )
+            .arg(
+                Arg::with_name("utxo_index")
+                    .long("utxo-index")
+                    .help("Maintain an index of the UTXO set, for fast unspent outputs lookups of scripthashes with a long history")
+            )
+            .arg(
+                Arg::with_name("light_mode")
+                    .long("light-mode")
+                    .help("Don't store the raw transactions, fetch them from bitcoind instead (requires txindex=1)")
+            )
+            .arg(
+                Arg::with_name("history_start_height")
+                    .long("history-start-height")
+                    .help("Don't index the address history of the blocks below this height (requires --utxo-index)")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("index_profile")
+                    .long("index-profile")
+                    .help("Record the time spent per indexing stage during the initial sync, and write a report to this path once it's done")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("notify_debounce")
+                    .long("notify-debounce")
+                    .help("Number of seconds the Electrum and WebSocket subscription changes are buffered for, before being sent as one notification per subscription")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("fee_estimates_interval")
+                    .long("fee-estimates-interval")
+                    .help("Number of seconds between refreshes of the cached fee estimates")
+                    .default_value("60")
+            )
+            .arg(
+                Arg::with_name("mempool_history_limit")
+                    .long("mempool-history-limit")
+                    .help("Maximum number of unconfirmed transactions returned per address/scripthash, or per page of /txs/mempool")
+                    .default_value("50")
+            )
+            .arg(
+                Arg::with_name("xpub_gap_limit")
+                    .long("xpub-gap-limit")
+                    .help("Number of consecutive unused addresses to derive before stopping the /xpub scan")
+                    .default_value("20")
+            )
+            .arg(
+                Arg::with_name("address_consistent_reads")
+                    .long("address-consistent-reads")
+                    .help("Evaluate the confirmed address stats and history against the applied height of the index, exposed with the responses")
+            )
+            .arg(
+                Arg::with_name("address_scan_budget")
+                    .long("address-scan-budget")
+                    .help("Number of milliseconds after which the address history scans requested with ?partial=1 return the transactions found so far, with a continuation token")
+                    .default_value("5000")
+            )
+            .arg(
+                Arg::with_name("address_bech32_uppercase")
+                    .long("address-bech32-uppercase")
+                    .help("Render bech32 addresses in uppercase")
+            )
+            .arg(
+                Arg::with_name("address_p2pk_as_p2pkh")
+                    .long("address-p2pk-as-p2pkh")
+                    .help("Render p2pk outputs using the p2pkh address of their public key")
+            )
+            .arg(
+                Arg::with_name("genesis_hash")
+                    .long("genesis-hash")
+                    .help("Hash of the genesis block of the chain, checked against bitcoind's on startup (for Bitcoin-derived chains run with the --network they are closest to)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("network_magic")
+                    .long("network-magic")
+                    .help("Message start bytes of the chain as hex, expected in the blk*.dat files, UTXO snapshots and P2P messages (default: the --network's)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("admin_api")
+                    .long("admin-api")
+                    .help("Enable the /admin/* REST endpoints (expensive, should not be exposed publicly)")
+            );
+
+        #[cfg(not(feature = "liquid"))]
+        let args = args
+            .arg(
+                Arg::with_name("block_filters")
+                    .long("block-filters")
+                    .help("Index BIP158 compact block filters"),
+            )
+            .arg(
+                Arg::with_name("p2p_addr")
+                    .long("p2p-addr")
+                    .help("P2P server 'addr:port' to listen on for BIP157 light clients, serving the headers and block filters (disabled by default)")
+                    .takes_value(true)
+                    .requires("block_filters"),
+            )
+            .arg(
+                Arg::with_name("address_params")
+                    .long("address-params")
+                    .help("Address encoding of the chain, as 'p2pkh=<prefix>,p2sh=<prefix>,hrp=<bech32 hrp>' (default: the --network's)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("utxo_snapshot")
+                    .long("utxo-snapshot")
+                    .help("Bootstrap a new index from this UTXO set snapshot (written by bitcoind's `dumptxoutset`), indexing the following blocks only (requires --utxo-index)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("webhooks")
+                    .long("webhooks")
+                    .help("Enable the /webhooks REST endpoints, registering callback URLs notified once an address or outpoint received an amount with enough confirmations (should not be exposed publicly)"),
+            );
+
+        #[cfg(feature = "zmq")]
+        let args = args
+            .arg(
+                Arg::with_name("zmq_rawtx")
+                    .long("zmq-rawtx")
+                    .help("bitcoind's ZMQ endpoint for rawtx notifications (-zmqpubrawtx), to add new mempool transactions as soon as they are announced")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("zmq_rawblock")
+                    .long("zmq-rawblock")
+                    .help("bitcoind's ZMQ endpoint for rawblock notifications (-zmqpubrawblock), to index new blocks as soon as they are announced")
+                    .takes_value(true),
             );
 
         #[cfg(feature = "liquid")]
@@ -157,8 +485,18 @@ impl Config {
                 .help("Select parent network type (mainnet, testnet, regtest)")
                 .takes_value(true),
         );
+        #[cfg(feature = "liquid")]
+        let args = args.arg(
+            Arg::with_name("asset_db_path")
+                .long("asset-db-path")
+                .help("Directory of the asset registry, with the metadata of each asset as <asset-id>.json (in any subdirectory)")
+                .takes_value(true),
+        );
 
-        let m = args.get_matches();
+        let m = layered_matches(args).unwrap_or_else(|e| {
+            eprintln!("{}", e.display_chain());
+            process::exit(1);
+        });
 
         let network_name = m.value_of("network").unwrap_or("mainnet");
         let network_type = Network::from(network_name);
@@ -221,11 +559,27 @@ impl Config {
             .unwrap_or(&format!("127.0.0.1:{}", default_electrum_port))
             .parse()
             .expect("invalid Electrum RPC address");
-        let http_addr: SocketAddr = m
-            .value_of("http_addr")
-            .unwrap_or(&format!("127.0.0.1:{}", default_http_port))
-            .parse()
-            .expect("invalid HTTP server address");
+        let http_socket_file = m.value_of("http_socket_file").map(PathBuf::from);
+        #[cfg(not(unix))]
+        {
+            if http_socket_file.is_some() {
+                eprintln!("--http-socket-file is only supported on unix");
+                process::exit(1);
+            }
+        }
+        let http_addrs: Vec<SocketAddr> = match m.values_of("http_addr") {
+            Some(addrs) => addrs
+                .map(|addr| addr.parse().expect("invalid HTTP server address"))
+                .collect(),
+            // no TCP port is exposed when only listening on the socket file
+            None if http_socket_file.is_some() => vec![],
+            None => vec![format!("127.0.0.1:{}", default_http_port)
+                .parse()
+                .expect("invalid HTTP server address")],
+        };
+        let ws_addr: Option<SocketAddr> = m
+            .value_of("ws_addr")
+            .map(|addr| addr.parse().expect("invalid WebSocket server address"));
         let monitoring_addr: SocketAddr = m
             .value_of("monitoring_addr")
             .unwrap_or(&format!("127.0.0.1:{}", default_monitoring_port))
@@ -253,17 +607,108 @@ impl Config {
         let cookie = m.value_of("cookie").map(|s| s.to_owned());
 
         let mut log = stderrlog::new();
-        log.verbosity(m.occurrences_of("verbosity") as usize);
+        log.verbosity(4); // filtered by LogFilter, which can be adjusted at runtime
         log.timestamp(if m.is_present("timestamp") {
             stderrlog::Timestamp::Millisecond
         } else {
             stderrlog::Timestamp::Off
         });
-        log.init().expect("logging initialization failed");
+        let log = LogFilter::init(
+            log,
+            LogLevels::from_verbosity(m.occurrences_of("verbosity")),
+        );
         let mut bulk_index_threads = value_t_or_exit!(m, "bulk_index_threads", usize);
         if bulk_index_threads == 0 {
             bulk_index_threads = num_cpus::get();
         }
+        let blk_file_readers = value_t_or_exit!(m, "blk_file_readers", usize);
+        if blk_file_readers == 0 {
+            panic!("--blk-file-readers must be positive");
+        }
+        let utxo_commitment_interval = if m.is_present("utxo_commitments") {
+            let interval = value_t_or_exit!(m, "utxo_commitment_interval", usize);
+            if interval == 0 {
+                panic!("--utxo-commitment-interval must be positive");
+            }
+            Some(interval)
+        } else {
+            None
+        };
+        let history_start_height = value_t_or_exit!(m, "history_start_height", usize);
+        if history_start_height > 0 && !m.is_present("utxo_index") {
+            // the unspent outputs couldn't be found from the history
+            usage_error(
+                "--history-start-height requires --utxo-index",
+                ClapErrorKind::MissingRequiredArgument,
+            );
+        }
+        #[cfg(not(feature = "liquid"))]
+        let utxo_snapshot = m.value_of("utxo_snapshot").map(PathBuf::from);
+        #[cfg(not(feature = "liquid"))]
+        {
+            if utxo_snapshot.is_some() {
+                // the unspent outputs are only known from the UTXO set index, and the rows of
+                // these options need the blocks below the snapshot's
+                if !m.is_present("utxo_index") {
+                    usage_error(
+                        "--utxo-snapshot requires --utxo-index",
+                        ClapErrorKind::MissingRequiredArgument,
+                    );
+                }
+                if m.is_present("block_filters") || utxo_commitment_interval.is_some() {
+                    usage_error(
+                        "--utxo-snapshot can't be used with --block-filters or --utxo-commitments",
+                        ClapErrorKind::ArgumentConflict,
+                    );
+                }
+            }
+        }
+        let tenants: Vec<Tenant> = m
+            .values_of("tenant")
+            .map_or(vec![], |values| values.collect())
+            .into_iter()
+            .map(|value| parse_tenant(value).expect("invalid --tenant"))
+            .collect();
+        for (i, tenant) in tenants.iter().enumerate() {
+            if tenants[..i]
+                .iter()
+                .any(|other| other.name == tenant.name || other.api_key == tenant.api_key)
+            {
+                usage_error(
+                    &format!(
+                        "--tenant {} reuses the name or API key of another",
+                        tenant.name
+                    ),
+                    ClapErrorKind::ArgumentConflict,
+                );
+            }
+        }
+        let fee_estimates_interval = value_t_or_exit!(m, "fee_estimates_interval", u64);
+        if fee_estimates_interval == 0 {
+            panic!("--fee-estimates-interval must be positive");
+        }
+        let cache_policy = CachePolicy::new(
+            m.values_of("cache_ttl")
+                .map_or(vec![], |values| values.collect())
+                .into_iter()
+                .map(|value| parse_cache_ttl(value).expect("invalid --cache-ttl"))
+                .collect(),
+            m.value_of("cache_stale_while_revalidate").map(|value| {
+                value
+                    .parse()
+                    .expect("invalid --cache-stale-while-revalidate")
+            }),
+            m.is_present("cache_etag"),
+        );
+        // shared for the lifetime of the process, like the liquid networks' static params
+        #[cfg(not(feature = "liquid"))]
+        let address_params: &'static AddressParams =
+            Box::leak(Box::new(m.value_of("address_params").map_or_else(
+                || network_type.address_params(),
+                |value| value.parse().expect("invalid --address-params"),
+            )));
+        #[cfg(feature = "liquid")]
+        let address_params = network_type.address_params();
         let config = Config {
             log,
             network_type,
@@ -272,19 +717,97 @@ impl Config {
             daemon_rpc_addr,
             cookie,
             electrum_rpc_addr,
-            http_addr,
+            http_addrs,
+            http_socket_file,
+            http_tls_cert: m.value_of("http_tls_cert").map(PathBuf::from),
+            http_tls_key: m.value_of("http_tls_key").map(PathBuf::from),
+            ws_addr,
             monitoring_addr,
             jsonrpc_import: m.is_present("jsonrpc_import"),
             index_batch_size: value_t_or_exit!(m, "index_batch_size", usize),
             bulk_index_threads,
+            blk_file_readers,
             tx_cache_size: value_t_or_exit!(m, "tx_cache_size", usize),
             prevout_enabled: !m.is_present("disable_prevout"),
             cors: m.value_of("cors").map(|s| s.to_string()),
+            cache_policy,
+            persistent_cache: m.is_present("persistent_cache"),
+            persistent_cache_max_txs: value_t_or_exit!(m, "persistent_cache_max_txs", u64),
+            http_compression_min_size: if m.is_present("disable_http_compression") {
+                None
+            } else {
+                Some(value_t_or_exit!(m, "http_compression_min_size", usize))
+            },
+            // disabled routes first, so that their requests aren't counted against the rate limits
+            route_limits: m
+                .values_of("disable_route")
+                .map_or(vec![], |values| values.collect())
+                .into_iter()
+                .map(|value| parse_disabled_route(value).expect("invalid --disable-route"))
+                .chain(
+                    m.values_of("route_limit")
+                        .map_or(vec![], |values| values.collect())
+                        .into_iter()
+                        .map(|value| parse_route_limit(value).expect("invalid --route-limit")),
+                )
+                .collect(),
+            client_limits: m
+                .values_of("client_limit")
+                .map_or(vec![], |values| values.collect())
+                .into_iter()
+                .map(|value| parse_client_limit(value).expect("invalid --client-limit"))
+                .collect(),
+            trust_forwarded_for: m.is_present("trust_forwarded_for"),
+            tenants,
             precache_scripts: m.value_of("precache_scripts").map(|s| s.to_string()),
+            utxo_commitment_interval,
+            utxo_index: m.is_present("utxo_index"),
+            light_mode: m.is_present("light_mode"),
+            history_start_height,
+            index_profile: m.value_of("index_profile").map(PathBuf::from),
+            notify_debounce: Duration::from_secs(value_t_or_exit!(m, "notify_debounce", u64)),
+            fee_estimates_interval: Duration::from_secs(fee_estimates_interval),
+            mempool_history_limit: value_t_or_exit!(m, "mempool_history_limit", usize),
+            xpub_gap_limit: value_t_or_exit!(m, "xpub_gap_limit", usize),
+            address_consistent_reads: m.is_present("address_consistent_reads"),
+            address_scan_budget: Duration::from_millis(value_t_or_exit!(
+                m,
+                "address_scan_budget",
+                u64
+            )),
+            address_style: AddressStyle {
+                bech32_uppercase: m.is_present("address_bech32_uppercase"),
+                p2pk_as_p2pkh: m.is_present("address_p2pk_as_p2pkh"),
+            },
+            address_params,
+            genesis_hash: m
+                .value_of("genesis_hash")
+                .map(|value| Sha256dHash::from_hex(value).expect("invalid --genesis-hash")),
+            network_magic: m.value_of("network_magic").map_or_else(
+                || network_type.magic(),
+                |value| parse_network_magic(value).expect("invalid --network-magic"),
+            ),
+            admin_api: m.is_present("admin_api"),
+            #[cfg(not(feature = "liquid"))]
+            block_filters: m.is_present("block_filters"),
+            #[cfg(not(feature = "liquid"))]
+            utxo_snapshot,
+            #[cfg(not(feature = "liquid"))]
+            p2p_addr: m
+                .value_of("p2p_addr")
+                .map(|addr| addr.parse().expect("invalid P2P server address")),
+            #[cfg(not(feature = "liquid"))]
+            webhooks: m.is_present("webhooks"),
+            #[cfg(feature = "zmq")]
+            zmq_rawtx: m.value_of("zmq_rawtx").map(|s| s.to_string()),
+            #[cfg(feature = "zmq")]
+            zmq_rawblock: m.value_of("zmq_rawblock").map(|s| s.to_string()),
             #[cfg(feature = "liquid")]
             parent_network,
             #[cfg(feature = "liquid")]
             parent_genesis_hash,
+            #[cfg(feature = "liquid")]
+            asset_db_path: m.value_of("asset_db_path").map(PathBuf::from),
         };
         eprintln!("{:?}", config);
         config
@@ -303,6 +826,300 @@ impl Config {
     }
 }
 
+const ENV_PREFIX: &str = "ELECTRS_";
+
+// Exit with a usage error, like clap does for the arguments it checks itself
+fn usage_error(message: &str, kind: ClapErrorKind) -> ! {
+    ClapError::with_description(message, kind).exit()
+}
+
+// An option set by the config file or the environment
+struct Setting {
+    name: String,
+    source: String, // where the option was set, for validation errors
+    value: SettingValue,
+}
+
+enum SettingValue {
+    Bool(bool),
+    Str(String),
+    List(Vec<String>),
+}
+
+// Parse the command line arguments, layered over the environment variables and the config file
+fn layered_matches<'a, 'b>(app: App<'a, 'b>) -> Result<ArgMatches<'a>> {
+    layered_matches_from(app, env::args().collect(), env::vars().collect())
+}
+
+fn layered_matches_from<'a, 'b>(
+    app: App<'a, 'b>,
+    cli_args: Vec<String>,
+    env_vars: Vec<(String, String)>,
+) -> Result<ArgMatches<'a>> {
+    // exits on invalid arguments, like get_matches()
+    let cli = app.clone().get_matches_from(&cli_args);
+
+    let conf_var = format!("{}CONF", ENV_PREFIX);
+    let conf = cli.value_of("conf").map(PathBuf::from).or_else(|| {
+        env_vars
+            .iter()
+            .find(|(var, _)| *var == conf_var)
+            .map(|(_, value)| PathBuf::from(value))
+    });
+    let mut settings = BTreeMap::new();
+    if let Some(path) = conf {
+        for setting in file_settings(&path)? {
+            settings.insert(setting.name.clone(), setting);
+        }
+    }
+    for setting in env_settings(&app, env_vars) {
+        settings.insert(setting.name.clone(), setting);
+    }
+    // the profile's presets are the lowest layer
+    let profile = match (cli.value_of("profile"), settings.get("profile")) {
+        (Some(profile), _) => Some(profile.to_string()),
+        (None, Some(setting)) => match setting.value {
+            SettingValue::Str(ref profile) => Some(profile.clone()),
+            _ => bail!("invalid {}: expected a profile name", setting.source),
+        },
+        (None, None) => None,
+    };
+    if let Some(profile) = profile {
+        for setting in profile_settings(&profile)? {
+            settings.entry(setting.name.clone()).or_insert(setting);
+        }
+    }
+
+    let mut args = vec![cli_args[0].clone()];
+    for (name, setting) in settings {
+        if name == "conf" {
+            bail!(
+                "invalid {}: the config file can only be set by --conf",
+                setting.source
+            );
+        }
+        if cli.occurrences_of(&name) == 0 {
+            args.extend(setting_args(&app, setting)?);
+        }
+    }
+    args.extend(cli_args.into_iter().skip(1));
+    Ok(app.get_matches_from(args))
+}
+
+const PROFILES: &[&str] = &["explorer", "wallet", "minimal"];
+
+// Endpoints only needed to browse the chain, not by wallets
+const EXPLORER_ROUTES: &[&str] = &[
+    "/blocks",
+    "/blocks/:start_height",
+    "/blocks/stats/*",
+    "/block/:hash/summary",
+    "/block/:hash/stats",
+    "/block/:hash/raw",
+    "/block/:hash/txids",
+    "/block/:hash/txs/*",
+    "/tx/:txid/ancestry",
+    "/outpoint/:outpoint/trace",
+    "/mempool/recent",
+    "/mempool/congestion",
+    "/mempool/txids",
+    "/graphql",
+];
+
+// Endpoints that are expensive to serve
+const EXPENSIVE_ROUTES: &[&str] = &["/xpub/*"];
+
+fn profile_settings(profile: &str) -> Result<Vec<Setting>> {
+    let source = format!("--profile {}", profile);
+    let flag = |name: &'static str| (name, SettingValue::Bool(true));
+    let value = |name: &'static str, value: &str| (name, SettingValue::Str(value.to_string()));
+    let routes = |groups: &[&[&str]]| {
+        let routes = groups.iter().flat_map(|group| group.iter());
+        (
+            "disable_route",
+            SettingValue::List(routes.map(|r| r.to_string()).collect()),
+        )
+    };
+    let presets = match profile {
+        "explorer" => vec![
+            flag("utxo_index"),
+            flag("cache_etag"),
+            value("cache_stale_while_revalidate", "10"),
+            value("tx_cache_size", "100000"),
+        ],
+        "wallet" => vec![
+            flag("utxo_index"),
+            #[cfg(not(feature = "liquid"))]
+            flag("block_filters"),
+            value("notify_debounce", "1"),
+            routes(&[EXPLORER_ROUTES]),
+        ],
+        "minimal" => vec![
+            flag("disable_prevout"),
+            value("tx_cache_size", "1000"),
+            routes(&[EXPLORER_ROUTES, EXPENSIVE_ROUTES]),
+        ],
+        _ => bail!(
+            "invalid {}: expected one of {}",
+            source,
+            PROFILES.join(", ")
+        ),
+    };
+    Ok(presets
+        .into_iter()
+        .map(|(name, value)| Setting {
+            name: name.to_string(),
+            source: source.clone(),
+            value,
+        })
+        .collect())
+}
+
+fn file_settings(path: &Path) -> Result<Vec<Setting>> {
+    let contents = fs::read_to_string(path)
+        .chain_err(|| format!("failed to read config file {}", path.display()))?;
+    let table = match contents
+        .parse::<toml::Value>()
+        .chain_err(|| format!("failed to parse config file {}", path.display()))?
+    {
+        toml::Value::Table(table) => table,
+        _ => bail!("invalid config file {}", path.display()),
+    };
+    table
+        .into_iter()
+        .map(|(name, value)| {
+            let source = format!("key `{}` in {}", name, path.display());
+            let value = match value {
+                toml::Value::Boolean(value) => SettingValue::Bool(value),
+                toml::Value::Array(values) => SettingValue::List(
+                    values
+                        .into_iter()
+                        .map(toml_scalar)
+                        .collect::<Option<_>>()
+                        .chain_err(|| format!("invalid {}: unsupported list item", source))?,
+                ),
+                value => SettingValue::Str(
+                    toml_scalar(value)
+                        .chain_err(|| format!("invalid {}: unsupported value", source))?,
+                ),
+            };
+            Ok(Setting {
+                name,
+                source,
+                value,
+            })
+        })
+        .collect()
+}
+
+fn toml_scalar(value: toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+// The environment may hold ELECTRS_* variables meant for something else (e.g. a wrapper script),
+// so the ones not naming an option are skipped, unlike the unknown keys of the config file
+fn env_settings(app: &App, env_vars: Vec<(String, String)>) -> Vec<Setting> {
+    let conf_var = format!("{}CONF", ENV_PREFIX);
+    env_vars
+        .into_iter()
+        .filter(|(var, _)| var.starts_with(ENV_PREFIX) && *var != conf_var)
+        .filter_map(|(var, value)| {
+            let name = var[ENV_PREFIX.len()..].to_lowercase();
+            if !is_option(app, &name) {
+                // logging isn't set up yet
+                eprintln!("WARNING: ignoring {}, not an electrs option", var);
+                return None;
+            }
+            Some(Setting {
+                name,
+                source: format!("environment variable {}", var),
+                value: SettingValue::Str(value),
+            })
+        })
+        .collect()
+}
+
+fn is_option(app: &App, name: &str) -> bool {
+    let flag = format!("--{}", name.replace('_', "-"));
+    let parsed = app
+        .clone()
+        .setting(AppSettings::ColorNever)
+        .get_matches_from_safe(vec!["electrs".to_string(), flag]);
+    match parsed {
+        Err(ref e) if e.kind == ClapErrorKind::UnknownArgument => name == "verbosity",
+        _ => true,
+    }
+}
+
+// Translate a setting to the equivalent command line arguments, validated by the parser
+fn setting_args(app: &App, setting: Setting) -> Result<Vec<String>> {
+    let source = setting.source;
+    let flag = format!("--{}", setting.name.replace('_', "-"));
+    // the options this one requires may be set by another setting or on the command line, so
+    // they're only checked once all the arguments are merged
+    let parse = |args: &[String]| match app
+        .clone()
+        .setting(AppSettings::ColorNever)
+        .get_matches_from_safe(iter::once("electrs").chain(args.iter().map(String::as_str)))
+    {
+        Err(ref e) if e.kind == ClapErrorKind::MissingRequiredArgument => Ok(()),
+        parsed => parsed.map(|_| ()),
+    };
+
+    let args = if setting.name == "verbosity" {
+        let count = match setting.value {
+            SettingValue::Str(ref value) => value.parse().ok(),
+            _ => None,
+        }
+        .chain_err(|| format!("invalid {}: expected a number", source))?;
+        vec!["-v".to_string(); count]
+    } else if parse(&[flag.clone()]).is_ok() {
+        // flags that take no value are set by a boolean
+        let enabled = match setting.value {
+            SettingValue::Bool(value) => value,
+            SettingValue::Str(ref value) if value == "true" || value == "1" => true,
+            SettingValue::Str(ref value) if value == "false" || value == "0" => false,
+            _ => bail!("invalid {}: expected a boolean", source),
+        };
+        if enabled {
+            vec![flag]
+        } else {
+            vec![]
+        }
+    } else {
+        let values = match setting.value {
+            SettingValue::Bool(_) => bail!("invalid {}: expected a value", source),
+            SettingValue::Str(value) => vec![value],
+            SettingValue::List(values) => values,
+        };
+        values
+            .into_iter()
+            .flat_map(|value| vec![flag.clone(), value])
+            .collect()
+    };
+
+    if let Err(e) = parse(&args) {
+        match e.kind {
+            ClapErrorKind::UnknownArgument => bail!("unknown option {}", source),
+            _ => bail!(
+                "invalid {}: {}",
+                source,
+                e.message
+                    .lines()
+                    .next()
+                    .unwrap_or("")
+                    .trim_start_matches("error: ")
+            ),
+        }
+    }
+    Ok(args)
+}
+
 struct StaticCookie {
     value: Vec<u8>,
 }
@@ -326,3 +1143,114 @@ impl CookieGetter for CookieFile {
         Ok(contents)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn test_app<'a, 'b>() -> App<'a, 'b> {
+        let option =
+            |name: &'a str, long: &'a str| Arg::with_name(name).long(long).takes_value(true);
+        App::new("test")
+            .arg(option("conf", "conf"))
+            .arg(option("profile", "profile"))
+            .arg(option("db_dir", "db-dir"))
+            .arg(option("tx_cache_size", "tx-cache-size"))
+            .arg(option(
+                "cache_stale_while_revalidate",
+                "cache-stale-while-revalidate",
+            ))
+            .arg(option("http_tls_cert", "http-tls-cert").requires("http_tls_key"))
+            .arg(option("http_tls_key", "http-tls-key").requires("http_tls_cert"))
+            .arg(option("p2p_addr", "p2p-addr").requires("block_filters"))
+            .arg(Arg::with_name("utxo_index").long("utxo-index"))
+            .arg(Arg::with_name("cache_etag").long("cache-etag"))
+            .arg(Arg::with_name("block_filters").long("block-filters"))
+            .arg(Arg::with_name("verbosity").short("v").multiple(true))
+    }
+
+    fn matches<'a>(
+        cli: &[&str],
+        env: &[(&str, &str)],
+        conf: Option<&str>,
+    ) -> Result<ArgMatches<'a>> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let mut cli_args: Vec<String> = vec!["electrs".to_string()];
+        cli_args.extend(cli.iter().map(|arg| arg.to_string()));
+        if let Some(conf) = conf {
+            file.write_all(conf.as_bytes()).unwrap();
+            cli_args.push("--conf".to_string());
+            cli_args.push(file.path().to_str().unwrap().to_string());
+        }
+        let env_vars = env
+            .iter()
+            .map(|(var, value)| (var.to_string(), value.to_string()))
+            .collect();
+        layered_matches_from(test_app(), cli_args, env_vars)
+    }
+
+    #[test]
+    fn test_layered_precedence() {
+        let conf = Some("db_dir = \"file\"\ntx_cache_size = 5\nverbosity = 2\n");
+        let env = [("ELECTRS_DB_DIR", "env"), ("ELECTRS_UTXO_INDEX", "1")];
+
+        // the command line overrides the environment, which overrides the config file
+        let m = matches(&["--db-dir", "cli"], &env, conf).unwrap();
+        assert_eq!(m.value_of("db_dir"), Some("cli"));
+        let m = matches(&[], &env, conf).unwrap();
+        assert_eq!(m.value_of("db_dir"), Some("env"));
+        assert!(m.is_present("utxo_index"));
+        assert_eq!(m.value_of("tx_cache_size"), Some("5"));
+        assert_eq!(m.occurrences_of("verbosity"), 2);
+        let m = matches(&[], &[], conf).unwrap();
+        assert_eq!(m.value_of("db_dir"), Some("file"));
+        assert!(!m.is_present("utxo_index"));
+
+        // the profile's presets are overridden by every other layer
+        let m = matches(&["--profile", "explorer"], &[], conf).unwrap();
+        assert_eq!(m.value_of("tx_cache_size"), Some("5"));
+        assert!(m.is_present("cache_etag"));
+        let env = [
+            ("ELECTRS_PROFILE", "explorer"),
+            ("ELECTRS_TX_CACHE_SIZE", "7"),
+        ];
+        let m = matches(&["--cache-stale-while-revalidate", "3"], &env, None).unwrap();
+        assert_eq!(m.value_of("tx_cache_size"), Some("7"));
+        assert_eq!(m.value_of("cache_stale_while_revalidate"), Some("3"));
+        assert!(m.is_present("utxo_index"));
+    }
+
+    #[test]
+    fn test_layered_unknown_options() {
+        // unknown environment variables are ignored, unlike unknown config keys
+        let env = [("ELECTRS_NOT_AN_OPTION", "1"), ("ELECTRS_DB_DIR", "env")];
+        let m = matches(&[], &env, None).unwrap();
+        assert_eq!(m.value_of("db_dir"), Some("env"));
+        assert!(matches(&[], &[], Some("not_an_option = 1\n")).is_err());
+
+        // known ones are still validated
+        assert!(matches(&[], &[("ELECTRS_UTXO_INDEX", "maybe")], None).is_err());
+        assert!(matches(&[], &[("ELECTRS_CONF", "/nonexistent/electrs.toml")], None).is_err());
+    }
+
+    #[test]
+    fn test_layered_requires() {
+        // options requiring each other can be set by the same or by different layers
+        let conf = Some("http_tls_cert = \"cert.pem\"\nhttp_tls_key = \"key.pem\"\n");
+        let m = matches(&[], &[], conf).unwrap();
+        assert_eq!(m.value_of("http_tls_cert"), Some("cert.pem"));
+        assert_eq!(m.value_of("http_tls_key"), Some("key.pem"));
+        let conf = Some("http_tls_cert = \"cert.pem\"\n");
+        let m = matches(&[], &[("ELECTRS_HTTP_TLS_KEY", "key.pem")], conf).unwrap();
+        assert_eq!(m.value_of("http_tls_key"), Some("key.pem"));
+
+        let conf = Some("p2p_addr = \"127.0.0.1:8333\"\n");
+        let m = matches(&[], &[("ELECTRS_BLOCK_FILTERS", "1")], conf).unwrap();
+        assert_eq!(m.value_of("p2p_addr"), Some("127.0.0.1:8333"));
+        assert!(m.is_present("block_filters"));
+
+        // the values are still validated
+        assert!(matches(&[], &[], Some("p2p_addr = true\n")).is_err());
+    }
+}
@@ -6,17 +6,17 @@ use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 use glob;
 use hex;
 use serde_json::{from_str, from_value, Value};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::{BufRead, BufReader, Lines, Write};
 use std::net::{SocketAddr, TcpStream};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::chain::{Block, BlockHeader, Network, Transaction};
 use crate::metrics::{HistogramOpts, HistogramVec, Metrics};
 use crate::signal::Waiter;
-use crate::util::HeaderList;
+use crate::util::{DebugTiming, HeaderList, Layer};
 
 use crate::errors::*;
 
@@ -279,6 +279,43 @@ impl Counter {
     }
 }
 
+const RPC_TRACE_SIZE: usize = 1000;
+
+// A recent RPC call (or batch of calls), exposed via /admin/rpc-trace
+#[derive(Serialize, Clone, Debug)]
+pub struct RpcCall {
+    pub method: String,
+    pub batch_size: usize,
+    pub started_at: f64, // unix time
+    pub duration_ms: f64,
+    pub error: Option<String>,
+}
+
+// The last RPC calls, shared by the reconnected instances
+struct RpcTrace {
+    calls: Mutex<VecDeque<RpcCall>>,
+}
+
+impl RpcTrace {
+    fn new() -> Self {
+        RpcTrace {
+            calls: Mutex::new(VecDeque::with_capacity(RPC_TRACE_SIZE)),
+        }
+    }
+
+    fn record(&self, call: RpcCall) {
+        let mut calls = self.calls.lock().unwrap();
+        if calls.len() == RPC_TRACE_SIZE {
+            calls.pop_front();
+        }
+        calls.push_back(call);
+    }
+}
+
+fn as_secs_f64(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
 pub struct Daemon {
     daemon_dir: PathBuf,
     network: Network,
@@ -289,6 +326,7 @@ pub struct Daemon {
     // monitoring
     latency: HistogramVec,
     size: HistogramVec,
+    trace: Arc<RpcTrace>,
 }
 
 impl Daemon {
@@ -318,6 +356,7 @@ impl Daemon {
                 HistogramOpts::new("daemon_bytes", "Bitcoind RPC size (in bytes)"),
                 &["method", "dir"],
             ),
+            trace: Arc::new(RpcTrace::new()),
         };
         let network_info = daemon.getnetworkinfo()?;
         info!("{:?}", network_info);
@@ -360,9 +399,16 @@ impl Daemon {
             signal: self.signal.clone(),
             latency: self.latency.clone(),
             size: self.size.clone(),
+            trace: Arc::clone(&self.trace),
         })
     }
 
+    // The last RPC calls, the most recent first
+    pub fn rpc_trace(&self) -> Vec<RpcCall> {
+        let calls = self.trace.calls.lock().unwrap();
+        calls.iter().rev().cloned().collect()
+    }
+
     pub fn list_blk_files(&self) -> Result<Vec<PathBuf>> {
         let mut path = self.daemon_dir.clone();
         path.push("blocks");
@@ -376,10 +422,6 @@ impl Daemon {
         Ok(paths)
     }
 
-    pub fn magic(&self) -> u32 {
-        self.network.magic()
-    }
-
     fn call_jsonrpc(&self, method: &str, request: &Value) -> Result<Value> {
         let mut conn = self.conn.lock().unwrap();
         let timer = self.latency.with_label_values(&[method]).start_timer();
@@ -415,8 +457,19 @@ impl Daemon {
     }
 
     fn retry_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
+        let _timer = DebugTiming::timer(Layer::Daemon);
         loop {
-            match self.handle_request_batch(method, params_list) {
+            let started_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            let started = Instant::now();
+            let result = self.handle_request_batch(method, params_list);
+            self.trace.record(RpcCall {
+                method: method.to_string(),
+                batch_size: params_list.len(),
+                started_at: as_secs_f64(started_at),
+                duration_ms: as_secs_f64(started.elapsed()) * 1000.0,
+                error: result.as_ref().err().map(|e| e.to_string()),
+            });
+            match result {
                 Err(Error(ErrorKind::Connection(msg), _)) => {
                     warn!("reconnecting to bitcoind: {}", msg);
                     self.signal.wait(Duration::from_secs(3))?;
@@ -455,6 +508,12 @@ impl Daemon {
         parse_hash(&self.request("getbestblockhash", json!([]))?).chain_err(|| "invalid blockhash")
     }
 
+    pub fn getblockhash(&self, height: usize) -> Result<Sha256dHash> {
+        let value = self.request("getblockhash", json!([height]))?;
+        let hash = value.as_str().chain_err(|| "non-string blockhash")?;
+        Ok(Sha256dHash::from_hex(hash).chain_err(|| "non-hex blockhash")?)
+    }
+
     pub fn getblockheader(&self, blockhash: &Sha256dHash) -> Result<BlockHeader> {
         header_from_value(self.request(
             "getblockheader",
@@ -516,6 +575,15 @@ impl Daemon {
         Ok(txs)
     }
 
+    pub fn gettransaction_raw(&self, txhash: &Sha256dHash) -> Result<Vec<u8>> {
+        let value = self.request(
+            "getrawtransaction",
+            json!([txhash.to_hex(), /*verbose=*/ false]),
+        )?;
+        let tx_hex = value.as_str().chain_err(|| "non-string tx")?;
+        Ok(hex::decode(tx_hex).chain_err(|| "non-hex tx")?)
+    }
+
     pub fn getmempooltx(&self, txhash: &Sha256dHash) -> Result<Transaction> {
         let value = self.request(
             "getrawtransaction",
@@ -561,6 +629,37 @@ impl Daemon {
         )
     }
 
+    pub fn getnewaddress(&self) -> Result<String> {
+        let address = self.request("getnewaddress", json!([]))?;
+        Ok(address
+            .as_str()
+            .chain_err(|| "non-string address")?
+            .to_string())
+    }
+
+    pub fn generatetoaddress(&self, blocks: u32, address: &str) -> Result<Vec<Sha256dHash>> {
+        let blockhashes = self.request("generatetoaddress", json!([blocks, address]))?;
+        blockhashes
+            .as_array()
+            .chain_err(|| "non-array blockhashes")?
+            .iter()
+            .map(|hash| {
+                Sha256dHash::from_hex(hash.as_str().chain_err(|| "non-string blockhash")?)
+                    .chain_err(|| "failed to parse blockhash")
+            })
+            .collect()
+    }
+
+    pub fn sendtoaddress(&self, address: &str, amount: u64) -> Result<Sha256dHash> {
+        // from sat to BTC
+        let amount = amount as f64 / 100_000_000f64;
+        let txid = self.request("sendtoaddress", json!([address, amount]))?;
+        Ok(
+            Sha256dHash::from_hex(txid.as_str().chain_err(|| "non-string txid")?)
+                .chain_err(|| "failed to parse txid")?,
+        )
+    }
+
     pub fn estimatesmartfee(&self, conf_target: u16) -> Result<f32> {
         let feerate = self
             .request("estimatesmartfee", json!([conf_target]))?
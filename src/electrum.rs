@@ -1,8 +1,6 @@
 use bitcoin::consensus::encode::serialize;
 use bitcoin_hashes::hex::{FromHex, ToHex};
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
-use crypto::digest::Digest;
-use crypto::sha2::Sha256;
 use error_chain::ChainedError;
 use hex;
 use serde_json::{from_str, Value};
@@ -12,13 +10,15 @@ use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::sync::mpsc::{Sender, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::config::Config;
 use crate::errors::*;
 use crate::metrics::{Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
 use crate::new_index::Query;
 use crate::util::{
-    full_hash, get_header_merkle_proof, get_id_from_pos, get_tx_merkle_proof, spawn_thread,
-    BlockId, Channel, FullHash, HeaderEntry, SyncChannel,
+    get_header_merkle_proof, get_id_from_pos, get_tx_merkle_proof, spawn_thread, Channel,
+    HeaderEntry, NotifyBuffer, SyncChannel,
 };
 
 // TODO: Sha256dHash should be a generic hash-container (since script hash is single SHA256)
@@ -55,32 +55,11 @@ fn bool_from_value_or(val: Option<&Value>, name: &str, default: bool) -> Result<
     bool_from_value(val, name)
 }
 
-// FIXME: implement caching and delta updates
-// FIXME: ensure stable ordering
-fn get_status_hash(txs: Vec<(Sha256dHash, Option<BlockId>)>) -> Option<FullHash> {
-    if txs.is_empty() {
-        None
-    } else {
-        let mut hash = FullHash::default();
-        let mut sha2 = Sha256::new();
-        for (txid, blockid) in txs {
-            // TODO: use height of 0 to indicate a mempool tx with confirmed inputs
-            let part = format!(
-                "{}:{}:",
-                txid.to_hex(),
-                blockid.map_or(-1, |b| b.height as isize)
-            );
-            sha2.input(part.as_bytes());
-        }
-        sha2.result(&mut hash);
-        Some(hash)
-    }
-}
-
 struct Connection {
     query: Arc<Query>,
     last_header_entry: Option<HeaderEntry>,
-    status_hashes: HashMap<Sha256dHash, Value>, // ScriptHash -> StatusHash
+    status_hashes: HashMap<Sha256dHash, Value>, // ScriptHash -> notified StatusHash
+    pending: NotifyBuffer<Sha256dHash, Value>,  // ScriptHash -> StatusHash to notify
     stream: TcpStream,
     addr: SocketAddr,
     chan: SyncChannel<Message>,
@@ -92,12 +71,14 @@ impl Connection {
         query: Arc<Query>,
         stream: TcpStream,
         addr: SocketAddr,
+        notify_debounce: Duration,
         stats: Arc<Stats>,
     ) -> Connection {
         Connection {
             query,
             last_header_entry: None, // disable header subscription for now
             status_hashes: HashMap::new(),
+            pending: NotifyBuffer::new(notify_debounce),
             stream,
             addr,
             chan: SyncChannel::new(10),
@@ -216,11 +197,13 @@ impl Connection {
     fn blockchain_scripthash_subscribe(&mut self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
 
-        let history_txids = self.query.history_txids(&script_hash[..]);
-        let status_hash = get_status_hash(history_txids)
-            .map_or(Value::Null, |h| json!(hex::encode(full_hash(&h[..]))));
+        let status_hash = self
+            .query
+            .status_hash(&script_hash[..])
+            .map_or(Value::Null, |h| json!(hex::encode(h)));
 
         self.status_hashes.insert(script_hash, status_hash.clone());
+        self.pending.remove(&script_hash);
         Ok(status_hash)
     }
 
@@ -230,7 +213,7 @@ impl Connection {
         let (chain_stats, mempool_stats) = self.query.stats(&script_hash[..]);
 
         Ok(
-            json!({ "confirmed": chain_stats.funded_txo_sum - chain_stats.spent_txo_sum, "unconfirmed": mempool_stats.funded_txo_sum - mempool_stats.spent_txo_sum}),
+            json!({ "confirmed": chain_stats.funded_txo_sum.signed_sub(chain_stats.spent_txo_sum), "unconfirmed": mempool_stats.funded_txo_sum.signed_sub(mempool_stats.spent_txo_sum)}),
         )
     }
 
@@ -245,6 +228,23 @@ impl Connection {
         )))
     }
 
+    fn blockchain_scripthash_get_mempool(&self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        let mempool = self.query.mempool();
+        Ok(json!(Value::Array(
+            mempool
+                .history_txids(&script_hash[..])
+                .into_iter()
+                .map(|txid| json!({
+                    // a height of -1 indicates a transaction with unconfirmed parents
+                    "height": if mempool.ancestors(&txid).is_empty() { 0 } else { -1 },
+                    "tx_hash": txid.to_hex(),
+                    "fee": mempool.get_fee(&txid),
+                }))
+                .collect()
+        )))
+    }
+
     fn blockchain_scripthash_listunspent(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
         let utxos = self.query.utxo(&script_hash[..]);
@@ -343,6 +343,7 @@ impl Connection {
             #[cfg(not(feature = "liquid"))]
             "blockchain.scripthash.get_balance" => self.blockchain_scripthash_get_balance(&params),
             "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(&params),
+            "blockchain.scripthash.get_mempool" => self.blockchain_scripthash_get_mempool(&params),
             "blockchain.scripthash.listunspent" => self.blockchain_scripthash_listunspent(&params),
             "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(&params),
             "blockchain.transaction.broadcast" => self.blockchain_transaction_broadcast(&params),
@@ -395,18 +396,28 @@ impl Connection {
                     "params": [header]}));
             }
         }
-        for (script_hash, status_hash) in self.status_hashes.iter_mut() {
-            let history_txids = self.query.history_txids(&script_hash[..]);
-            let new_status_hash = get_status_hash(history_txids)
-                .map_or(Value::Null, |h| json!(hex::encode(full_hash(&h[..]))));
+        let now = Instant::now();
+        for (script_hash, status_hash) in self.status_hashes.iter() {
+            let new_status_hash = self
+                .query
+                .status_hash(&script_hash[..])
+                .map_or(Value::Null, |h| json!(hex::encode(h)));
             if new_status_hash == *status_hash {
-                continue;
+                // reverted within the window
+                self.pending.remove(script_hash);
+            } else {
+                self.pending
+                    .push(*script_hash, new_status_hash, now, |pending, new| {
+                        *pending = new
+                    });
             }
+        }
+        for (script_hash, status_hash) in self.pending.flush(now) {
             result.push(json!({
                 "jsonrpc": "2.0",
                 "method": "blockchain.scripthash.subscribe",
-                "params": [script_hash.to_hex(), new_status_hash]}));
-            *status_hash = new_status_hash;
+                "params": [script_hash.to_hex(), status_hash]}));
+            self.status_hashes.insert(script_hash, status_hash);
         }
         timer.observe_duration();
         self.stats
@@ -570,7 +581,9 @@ impl RPC {
         chan
     }
 
-    pub fn start(addr: SocketAddr, query: Arc<Query>, metrics: &Metrics) -> RPC {
+    pub fn start(config: &Config, query: Arc<Query>, metrics: &Metrics) -> RPC {
+        let addr = config.electrum_rpc_addr;
+        let notify_debounce = config.notify_debounce;
         let stats = Arc::new(Stats {
             latency: metrics.histogram_vec(
                 HistogramOpts::new("electrum_rpc", "Electrum RPC latency (seconds)"),
@@ -595,7 +608,7 @@ impl RPC {
                     let stats = stats.clone();
                     children.push(spawn_thread("peer", move || {
                         info!("[{}] connected peer", addr);
-                        let conn = Connection::new(query, stream, addr, stats);
+                        let conn = Connection::new(query, stream, addr, notify_debounce, stats);
                         senders.lock().unwrap().push(conn.chan.sender());
                         conn.run();
                         info!("[{}] disconnected peer", addr);
@@ -15,5 +15,26 @@ error_chain! {
             description("Interruption by external signal")
             display("Iterrupted by SIG{:?}", signal)
         }
+
+        InvalidHeaders(msg: String) {
+            description("Invalid block headers")
+            display("Invalid block headers: {}", msg)
+        }
+    }
+}
+
+// Whether the error is due to a requested shutdown rather than a failure
+pub fn is_interrupt(err: &Error) -> bool {
+    match err.kind() {
+        ErrorKind::Interrupt(_) => true,
+        _ => false,
+    }
+}
+
+// Whether the daemon's headers failed the verification, leaving the index as it was
+pub fn is_invalid_headers(err: &Error) -> bool {
+    match err.kind() {
+        ErrorKind::InvalidHeaders(_) => true,
+        _ => false,
     }
 }
@@ -0,0 +1,714 @@
+use bitcoin::consensus::encode::serialize;
+use bitcoin_hashes::hex::{FromHex, ToHex};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use graphql_parser::query::{
+    parse_query, Definition, Field, OperationDefinition, Selection, SelectionSet,
+    Value as QueryValue,
+};
+use juniper::http::{GraphQLRequest, GraphQLResponse};
+use juniper::{EmptyMutation, FieldError, FieldResult, GraphQLObject, InputValue, RootNode, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use crate::chain::{AddressParams, OutPoint, Transaction, TxIn, TxOut};
+use crate::config::Config;
+use crate::new_index::{compute_script_hash, Query, ScriptStats};
+use crate::util::{
+    address_to_script, get_script_type, has_prevout, is_coinbase, script_to_address, AddressStyle,
+    BlockHeaderMeta, BlockId, FullHash,
+};
+
+const DEFAULT_ADDRESS_TXS: i32 = 25;
+const MAX_ADDRESS_TXS: i32 = 100;
+const DEFAULT_BLOCK_TXS: i32 = 25;
+const MAX_BLOCK_TXS: i32 = 1000;
+
+const MAX_QUERY_DEPTH: usize = 10;
+const MAX_QUERY_ALIASES: usize = 30;
+const MAX_QUERY_COMPLEXITY: usize = 10_000;
+
+// GraphQL layer over Query, served at /graphql. Fields are resolved lazily, so that clients only
+// pay for what they ask for (e.g. the previous outputs are only looked up when requested).
+// Amounts are in satoshis, as floats since GraphQL integers are limited to 32 bits.
+pub struct Context {
+    query: Arc<Query>,
+    address_params: &'static AddressParams,
+    address_style: AddressStyle,
+    mempool_history_limit: usize,
+}
+
+impl juniper::Context for Context {}
+
+pub type Schema = RootNode<'static, QueryRoot, EmptyMutation<Context>>;
+
+thread_local! {
+    static SCHEMA: Schema = Schema::new(QueryRoot, EmptyMutation::new());
+}
+
+#[derive(Deserialize)]
+pub struct Request {
+    query: String,
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+    variables: Option<InputValue>,
+}
+
+// Execute the request, returning the JSON response (including the query errors, if any)
+pub fn execute(request: &Request, query: &Arc<Query>, config: &Config) -> serde_json::Value {
+    if let Err(e) = check_limits(&request.query) {
+        let response: GraphQLResponse = GraphQLResponse::error(FieldError::new(e, Value::null()));
+        return serde_json::to_value(&response).expect("failed to serialize GraphQL response");
+    }
+    let request = GraphQLRequest::new(
+        request.query.clone(),
+        request.operation_name.clone(),
+        request.variables.clone(),
+    );
+    let context = Context {
+        query: Arc::clone(query),
+        address_params: config.address_params,
+        address_style: config.address_style,
+        mempool_history_limit: config.mempool_history_limit,
+    };
+    SCHEMA.with(|schema| {
+        serde_json::to_value(&request.execute(schema, &context))
+            .expect("failed to serialize GraphQL response")
+    })
+}
+
+// Check the operations against the depth, alias and complexity limits before executing them, so
+// that a single request can't make the server look up an unbounded number of transactions. The
+// complexity is the number of fields to resolve, where the fields selected for each transaction of
+// a list are counted once per transaction (up to its limit).
+fn check_limits(source: &str) -> Result<(), String> {
+    let document = parse_query(source).map_err(|e| e.to_string())?;
+    let mut limits = QueryLimits {
+        fragments: document
+            .definitions
+            .iter()
+            .filter_map(|definition| match definition {
+                Definition::Fragment(fragment) => {
+                    Some((fragment.name.as_str(), &fragment.selection_set))
+                }
+                Definition::Operation(_) => None,
+            })
+            .collect(),
+        spreading: vec![],
+        aliases: 0,
+    };
+    for definition in &document.definitions {
+        let selection_set = match definition {
+            Definition::Operation(OperationDefinition::SelectionSet(selection_set)) => {
+                selection_set
+            }
+            Definition::Operation(OperationDefinition::Query(query)) => &query.selection_set,
+            Definition::Operation(OperationDefinition::Mutation(mutation)) => {
+                &mutation.selection_set
+            }
+            Definition::Operation(OperationDefinition::Subscription(subscription)) => {
+                &subscription.selection_set
+            }
+            Definition::Fragment(_) => continue,
+        };
+        limits.complexity(selection_set, 1)?;
+    }
+    Ok(())
+}
+
+struct QueryLimits<'a> {
+    fragments: HashMap<&'a str, &'a SelectionSet>,
+    spreading: Vec<&'a str>, // the fragments being expanded, to detect cycles
+    aliases: usize,
+}
+
+impl<'a> QueryLimits<'a> {
+    // The complexity of the selection set, at the given depth (the operation's being 1)
+    fn complexity(
+        &mut self,
+        selection_set: &'a SelectionSet,
+        depth: usize,
+    ) -> Result<usize, String> {
+        if depth > MAX_QUERY_DEPTH {
+            return Err(format!(
+                "Query is too deep (the limit is {} levels)",
+                MAX_QUERY_DEPTH
+            ));
+        }
+        let mut complexity: usize = 0;
+        for selection in &selection_set.items {
+            let selected = match selection {
+                Selection::Field(field) => {
+                    if field.alias.is_some() {
+                        self.aliases += 1;
+                        if self.aliases > MAX_QUERY_ALIASES {
+                            return Err(format!(
+                                "Query has too many aliases (the limit is {})",
+                                MAX_QUERY_ALIASES
+                            ));
+                        }
+                    }
+                    let children = if field.selection_set.items.is_empty() {
+                        0
+                    } else {
+                        self.complexity(&field.selection_set, depth + 1)?
+                    };
+                    children.saturating_mul(list_size(field)).saturating_add(1)
+                }
+                Selection::InlineFragment(fragment) => {
+                    self.complexity(&fragment.selection_set, depth)?
+                }
+                Selection::FragmentSpread(spread) => {
+                    let name = spread.fragment_name.as_str();
+                    if self.spreading.contains(&name) {
+                        return Err(format!("Fragment {} spreads itself", name));
+                    }
+                    let fragment = *self
+                        .fragments
+                        .get(name)
+                        .ok_or_else(|| format!("Unknown fragment {}", name))?;
+                    self.spreading.push(name);
+                    let selected = self.complexity(fragment, depth)?;
+                    self.spreading.pop();
+                    selected
+                }
+            };
+            complexity = complexity.saturating_add(selected);
+            if complexity > MAX_QUERY_COMPLEXITY {
+                return Err(format!(
+                    "Query is too complex (the limit is {} fields)",
+                    MAX_QUERY_COMPLEXITY
+                ));
+            }
+        }
+        Ok(complexity)
+    }
+}
+
+// The number of items the field resolves to, for the transaction lists. The limit passed as a
+// variable isn't known before the execution, so the maximum is assumed.
+fn list_size(field: &Field) -> usize {
+    if field.name != "transactions" {
+        return 1;
+    }
+    let limit = field
+        .arguments
+        .iter()
+        .find(|(name, _)| name == "limit")
+        .map(|(_, value)| value);
+    let limit = match limit {
+        None | Some(QueryValue::Null) => DEFAULT_BLOCK_TXS as i64,
+        Some(QueryValue::Int(limit)) => limit.as_i64().unwrap_or(0),
+        Some(_) => MAX_BLOCK_TXS as i64,
+    };
+    limit.max(0).min(MAX_BLOCK_TXS as i64) as usize
+}
+
+pub struct QueryRoot;
+
+#[juniper::object(Context = Context)]
+impl QueryRoot {
+    fn tip_height(context: &Context) -> i32 {
+        context.query.chain().best_height() as i32
+    }
+
+    fn block(context: &Context, hash: String) -> FieldResult<Option<Block>> {
+        let hash = Sha256dHash::from_hex(&hash)?;
+        Ok(context.query.chain().get_block_with_meta(&hash).map(Block))
+    }
+
+    fn block_by_height(context: &Context, height: i32) -> Option<Block> {
+        let chain = context.query.chain();
+        chain
+            .hash_by_height(height as usize)
+            .and_then(|hash| chain.get_block_with_meta(&hash))
+            .map(Block)
+    }
+
+    fn transaction(context: &Context, txid: String) -> FieldResult<Option<Tx>> {
+        let txid = Sha256dHash::from_hex(&txid)?;
+        Ok(context.query.lookup_txn(&txid).map(|tx| Tx {
+            tx,
+            blockid: context.query.chain().tx_confirming_block(&txid),
+        }))
+    }
+
+    fn address(context: &Context, address: String) -> FieldResult<Address> {
+        let script = address_to_script(&address, context.address_params)
+            .ok_or("Invalid address or address on invalid network")?;
+        Ok(Address {
+            address,
+            scripthash: compute_script_hash(&script),
+        })
+    }
+}
+
+pub struct Block(BlockHeaderMeta);
+
+#[juniper::object(Context = Context)]
+impl Block {
+    fn hash(&self) -> String {
+        self.0.header_entry.hash().to_hex()
+    }
+
+    fn height(&self) -> i32 {
+        self.0.header_entry.height() as i32
+    }
+
+    fn previous_block_hash(&self) -> Option<String> {
+        match self.0.header_entry.height() {
+            0 => None,
+            _ => Some(self.0.header_entry.header().prev_blockhash.to_hex()),
+        }
+    }
+
+    fn merkle_root(&self) -> String {
+        self.0.header_entry.header().merkle_root.to_hex()
+    }
+
+    fn version(&self) -> i32 {
+        self.0.header_entry.header().version as i32
+    }
+
+    fn timestamp(&self) -> f64 {
+        self.0.header_entry.header().time as f64
+    }
+
+    fn tx_count(&self) -> i32 {
+        self.0.meta.tx_count as i32
+    }
+
+    fn size(&self) -> i32 {
+        self.0.meta.size as i32
+    }
+
+    fn weight(&self) -> i32 {
+        self.0.meta.weight as i32
+    }
+
+    fn txids(&self, context: &Context) -> FieldResult<Vec<String>> {
+        Ok(self
+            .block_txids(context)?
+            .iter()
+            .map(|txid| txid.to_hex())
+            .collect())
+    }
+
+    fn transactions(
+        &self,
+        context: &Context,
+        start: Option<i32>,
+        limit: Option<i32>,
+    ) -> FieldResult<Vec<Tx>> {
+        let start = start.unwrap_or(0).max(0) as usize;
+        let limit = limit.unwrap_or(DEFAULT_BLOCK_TXS).max(0).min(MAX_BLOCK_TXS) as usize;
+        let blockid = BlockId::from(&self.0.header_entry);
+        self.block_txids(context)?
+            .iter()
+            .skip(start)
+            .take(limit)
+            .map(|txid| {
+                let tx = context
+                    .query
+                    .lookup_txn(txid)
+                    .ok_or_else(|| format!("missing transaction {}", txid))?;
+                Ok(Tx {
+                    tx,
+                    blockid: Some(blockid.clone()),
+                })
+            })
+            .collect()
+    }
+}
+
+impl Block {
+    fn block_txids(&self, context: &Context) -> FieldResult<Vec<Sha256dHash>> {
+        let hash = self.0.header_entry.hash();
+        Ok(context
+            .query
+            .chain()
+            .get_block_txids(hash)
+            .ok_or_else(|| format!("missing txids of block {}", hash))?)
+    }
+}
+
+pub struct Tx {
+    tx: Transaction,
+    blockid: Option<BlockId>,
+}
+
+#[derive(GraphQLObject)]
+pub struct TxStatus {
+    confirmed: bool,
+    block_height: Option<i32>,
+    block_hash: Option<String>,
+    block_time: Option<f64>,
+}
+
+impl From<Option<&BlockId>> for TxStatus {
+    fn from(blockid: Option<&BlockId>) -> Self {
+        TxStatus {
+            confirmed: blockid.is_some(),
+            block_height: blockid.map(|b| b.height as i32),
+            block_hash: blockid.map(|b| b.hash.to_hex()),
+            block_time: blockid.map(|b| b.time as f64),
+        }
+    }
+}
+
+#[juniper::object(Context = Context)]
+impl Tx {
+    fn txid(&self) -> String {
+        self.tx.txid().to_hex()
+    }
+
+    fn version(&self) -> i32 {
+        self.tx.version as i32
+    }
+
+    fn locktime(&self) -> f64 {
+        self.tx.lock_time as f64
+    }
+
+    fn size(&self) -> i32 {
+        serialize(&self.tx).len() as i32
+    }
+
+    fn weight(&self) -> i32 {
+        self.tx.get_weight() as i32
+    }
+
+    fn status(&self) -> TxStatus {
+        TxStatus::from(self.blockid.as_ref())
+    }
+
+    // Requires looking up the previous outputs
+    fn fee(&self, context: &Context) -> Option<f64> {
+        // null like the fees that can't be computed, rather than a misleading zero
+        if self.tx.is_coin_base() {
+            return None;
+        }
+        let outpoints: BTreeSet<OutPoint> = self
+            .tx
+            .input
+            .iter()
+            .filter(|txin| has_prevout(txin))
+            .map(|txin| txin.previous_output)
+            .collect();
+        let prevouts = context.query.lookup_txos(&outpoints);
+        if prevouts.len() != outpoints.len() {
+            return None;
+        }
+        let total_in: u64 = prevouts.values().map(|txo| txo.value).sum();
+        let total_out: u64 = self.tx.output.iter().map(|txo| txo.value).sum();
+        total_in.checked_sub(total_out).map(|fee| fee as f64)
+    }
+
+    fn inputs(&self) -> Vec<Input> {
+        self.tx
+            .input
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(vin, txin)| Input {
+                txin,
+                vin: vin as u32,
+            })
+            .collect()
+    }
+
+    fn outputs(&self) -> Vec<Output> {
+        let txid = self.tx.txid();
+        self.tx
+            .output
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(vout, txout)| Output {
+                txout,
+                outpoint: OutPoint {
+                    txid,
+                    vout: vout as u32,
+                },
+            })
+            .collect()
+    }
+}
+
+pub struct Input {
+    txin: TxIn,
+    vin: u32,
+}
+
+#[juniper::object(Context = Context)]
+impl Input {
+    fn vin(&self) -> i32 {
+        self.vin as i32
+    }
+
+    fn is_coinbase(&self) -> bool {
+        is_coinbase(&self.txin)
+    }
+
+    fn txid(&self) -> Option<String> {
+        if has_prevout(&self.txin) {
+            Some(self.txin.previous_output.txid.to_hex())
+        } else {
+            None
+        }
+    }
+
+    fn vout(&self) -> Option<i32> {
+        if has_prevout(&self.txin) {
+            Some(self.txin.previous_output.vout as i32)
+        } else {
+            None
+        }
+    }
+
+    fn scriptsig(&self) -> String {
+        self.txin.script_sig.to_bytes().to_hex()
+    }
+
+    fn witness(&self) -> Vec<String> {
+        self.txin.witness.iter().map(|w| w.to_hex()).collect()
+    }
+
+    fn sequence(&self) -> f64 {
+        self.txin.sequence as f64
+    }
+
+    // Requires looking up the previous output
+    fn prevout(&self, context: &Context) -> Option<Output> {
+        if !has_prevout(&self.txin) {
+            return None;
+        }
+        let outpoint = self.txin.previous_output;
+        let mut outpoints = BTreeSet::new();
+        outpoints.insert(outpoint);
+        context
+            .query
+            .lookup_txos(&outpoints)
+            .remove(&outpoint)
+            .map(|txout| Output { txout, outpoint })
+    }
+}
+
+pub struct Output {
+    txout: TxOut,
+    outpoint: OutPoint,
+}
+
+#[derive(GraphQLObject)]
+pub struct Spend {
+    txid: String,
+    vin: i32,
+    status: TxStatus,
+}
+
+#[juniper::object(Context = Context)]
+impl Output {
+    fn vout(&self) -> i32 {
+        self.outpoint.vout as i32
+    }
+
+    fn value(&self) -> f64 {
+        self.txout.value as f64
+    }
+
+    fn scriptpubkey(&self) -> String {
+        self.txout.script_pubkey.to_bytes().to_hex()
+    }
+
+    fn scriptpubkey_type(&self) -> String {
+        get_script_type(&self.txout.script_pubkey).to_string()
+    }
+
+    fn scriptpubkey_address(&self, context: &Context) -> Option<String> {
+        script_to_address(
+            &self.txout.script_pubkey,
+            context.address_params,
+            &context.address_style,
+        )
+    }
+
+    // Requires looking up the spending input, null while unspent
+    fn spent_by(&self, context: &Context) -> Option<Spend> {
+        context
+            .query
+            .lookup_spend(&self.outpoint)
+            .map(|spend| Spend {
+                txid: spend.txid.to_hex(),
+                vin: spend.vin as i32,
+                status: TxStatus::from(spend.confirmed.as_ref()),
+            })
+    }
+}
+
+pub struct Address {
+    address: String,
+    scripthash: FullHash,
+}
+
+#[derive(GraphQLObject)]
+pub struct Stats {
+    tx_count: i32,
+    funded_txo_count: i32,
+    funded_txo_sum: f64,
+    spent_txo_count: i32,
+    spent_txo_sum: f64,
+}
+
+impl From<ScriptStats> for Stats {
+    fn from(stats: ScriptStats) -> Self {
+        Stats {
+            tx_count: stats.tx_count as i32,
+            funded_txo_count: stats.funded_txo_count as i32,
+            funded_txo_sum: stats.funded_txo_sum.as_sat() as f64,
+            spent_txo_count: stats.spent_txo_count as i32,
+            spent_txo_sum: stats.spent_txo_sum.as_sat() as f64,
+        }
+    }
+}
+
+#[derive(GraphQLObject)]
+pub struct Utxo {
+    txid: String,
+    vout: i32,
+    value: f64,
+    status: TxStatus,
+}
+
+#[juniper::object(Context = Context)]
+impl Address {
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+
+    // In the byte order of the REST API's /scripthash/:hash (not reversed like Electrum's)
+    fn scripthash(&self) -> String {
+        self.scripthash.to_hex()
+    }
+
+    fn chain_stats(&self, context: &Context) -> Stats {
+        Stats::from(context.query.stats(&self.scripthash[..]).0)
+    }
+
+    fn mempool_stats(&self, context: &Context) -> Stats {
+        Stats::from(context.query.stats(&self.scripthash[..]).1)
+    }
+
+    // The unconfirmed transactions (up to --mempool-history-limit), followed by the latest confirmed ones
+    fn transactions(&self, context: &Context, limit: Option<i32>) -> FieldResult<Vec<Tx>> {
+        let limit = limit
+            .unwrap_or(DEFAULT_ADDRESS_TXS)
+            .max(0)
+            .min(MAX_ADDRESS_TXS) as usize;
+        let mut txs: Vec<Tx> = context
+            .query
+            .mempool()
+            .history(&self.scripthash[..], None, context.mempool_history_limit)
+            .into_iter()
+            .map(|tx| Tx { tx, blockid: None })
+            .collect();
+        txs.extend(
+            context
+                .query
+                .chain()
+                .history(&self.scripthash[..], None, limit)?
+                .into_iter()
+                .map(|(tx, blockid)| Tx {
+                    tx,
+                    blockid: Some(blockid),
+                }),
+        );
+        Ok(txs)
+    }
+
+    fn utxos(&self, context: &Context) -> Vec<Utxo> {
+        context
+            .query
+            .utxo(&self.scripthash[..])
+            .into_iter()
+            .map(|utxo| Utxo {
+                txid: utxo.txid.to_hex(),
+                vout: utxo.vout as i32,
+                value: utxo.value as f64,
+                status: TxStatus::from(utxo.confirmed.as_ref()),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested(field: &str, depth: usize) -> String {
+        let mut query = field.to_string();
+        for _ in 1..depth {
+            query = format!("{} {{ {} }}", field, query);
+        }
+        format!("{{ {} }}", query)
+    }
+
+    #[test]
+    fn test_check_limits() {
+        let query = r#"{ transaction(txid: "00") { fee outputs { value spentBy { txid } } } }"#;
+        assert!(check_limits(query).is_ok());
+        assert!(check_limits("{ transaction(").is_err());
+
+        assert!(check_limits(&nested("a", MAX_QUERY_DEPTH)).is_ok());
+        assert!(check_limits(&nested("a", MAX_QUERY_DEPTH + 1)).is_err());
+
+        let aliases = |count: usize| {
+            let fields: Vec<String> = (0..count).map(|i| format!("h{}: tipHeight", i)).collect();
+            format!("{{ {} }}", fields.join(" "))
+        };
+        assert!(check_limits(&aliases(MAX_QUERY_ALIASES)).is_ok());
+        assert!(check_limits(&aliases(MAX_QUERY_ALIASES + 1)).is_err());
+    }
+
+    #[test]
+    fn test_check_limits_complexity() {
+        let txs = |args: &str, selection: &str| {
+            format!(
+                r#"block(hash: "00") {{ transactions{} {{ {} }} }}"#,
+                args, selection
+            )
+        };
+        let selection = "txid inputs { prevout { value } }";
+        // 1 (block) + 1 (transactions) + 1000 * 4 (txid, inputs, prevout and value)
+        let block = txs("(limit: 1000)", selection);
+        assert!(check_limits(&format!("{{ {} }}", block)).is_ok());
+        let query = format!("{{ a: {} b: {} c: {} }}", block, block, block);
+        assert!(check_limits(&query).is_err());
+
+        // the limit passed as a variable is assumed to be the maximum
+        let block = txs("(limit: $limit)", selection);
+        let query = format!(
+            "query($limit: Int) {{ a: {} b: {} c: {} }}",
+            block, block, block
+        );
+        assert!(check_limits(&query).is_err());
+        // and the default one when not passed
+        let block = txs("", selection);
+        let query = format!("{{ a: {} b: {} c: {} }}", block, block, block);
+        assert!(check_limits(&query).is_ok());
+
+        // the fragments are counted once per spread
+        let fragment = format!("fragment A on Tx {{ {} }}", selection);
+        let query = format!("{{ {} }} {}", txs("(limit: 1000)", "...A"), fragment);
+        assert!(check_limits(&query).is_ok());
+        let query = format!(
+            "{{ {} }} {}",
+            txs("(limit: 1000)", "...A ...A ...A"),
+            fragment
+        );
+        assert!(check_limits(&query).is_err());
+
+        let query = "{ ...A } fragment A on QueryRoot { tipHeight ...B } \
+                     fragment B on QueryRoot { ...A }";
+        assert!(check_limits(query).is_err());
+        assert!(check_limits("{ ...A }").is_err());
+    }
+}
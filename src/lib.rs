@@ -1,13 +1,16 @@
 #![recursion_limit = "1024"]
 
 extern crate base64;
+extern crate bech32;
 extern crate bincode;
 extern crate bitcoin;
 extern crate bitcoin_bech32;
 extern crate bitcoin_hashes;
+extern crate brotli;
 extern crate chan_signal;
 extern crate crypto;
 extern crate dirs;
+extern crate flate2;
 extern crate glob;
 extern crate hex;
 extern crate hyper;
@@ -26,10 +29,23 @@ extern crate stderrlog;
 extern crate sysconf;
 extern crate time;
 extern crate tiny_http;
+extern crate tokio_rustls;
+extern crate tokio_timer;
+#[cfg(unix)]
+extern crate tokio_uds;
+extern crate toml;
 extern crate url;
 
 #[cfg(feature = "liquid")]
 extern crate elements;
+#[cfg(feature = "graphql")]
+extern crate graphql_parser;
+#[cfg(feature = "graphql")]
+extern crate juniper;
+#[cfg(feature = "liquid")]
+extern crate secp256k1_zkp;
+#[cfg(feature = "zmq")]
+extern crate zmq;
 
 #[macro_use]
 extern crate chan;
@@ -51,9 +67,18 @@ pub mod config;
 pub mod daemon;
 pub mod electrum;
 pub mod errors;
+#[cfg(all(feature = "graphql", not(feature = "liquid")))]
+pub mod graphql;
 pub mod metrics;
+#[cfg(not(feature = "liquid"))]
+pub mod p2p;
 pub mod rest;
 pub mod signal;
 pub mod util;
+#[cfg(not(feature = "liquid"))]
+pub mod webhooks;
+pub mod ws;
+#[cfg(feature = "zmq")]
+pub mod zmq_listener;
 
 pub mod new_index;
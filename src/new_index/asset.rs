@@ -0,0 +1,276 @@
+use bincode;
+use bitcoin_hashes::hex::ToHex;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
+use crypto::sha2::sha256_digest_block;
+use elements::confidential::Value;
+use glob::glob;
+use rayon::prelude::*;
+use serde_json::Value as JsonValue;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::chain::{OutPoint, TxIn};
+use crate::errors::*;
+use crate::new_index::db::DBRow;
+use crate::new_index::fetch::BlockEntry;
+use crate::new_index::parse_hash;
+use crate::util::{full_hash, Bytes, FullHash, IssuanceValue};
+
+// The initial state of SHA256
+const SHA256_IV: [u32; 8] = [
+    0x6a09_e667,
+    0xbb67_ae85,
+    0x3c6e_f372,
+    0xa54f_f53a,
+    0x510e_527f,
+    0x9b05_688c,
+    0x1f83_d9ab,
+    0x5be0_cd19,
+];
+
+// The root of a two leaves "fast merkle tree" (as used by Elements to derive the asset ids): a
+// single SHA256 compression of the leaves, without padding
+fn fast_merkle_root(left: &FullHash, right: &FullHash) -> FullHash {
+    let mut state = SHA256_IV;
+    let mut block = [0u8; 64];
+    block[..32].copy_from_slice(left);
+    block[32..].copy_from_slice(right);
+    sha256_digest_block(&mut state, &block);
+
+    let mut root = FullHash::default();
+    for (i, word) in state.iter().enumerate() {
+        root[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    root
+}
+
+// The entropy of a new issuance, committing to the outpoint spent by its input and to the
+// contract hash
+pub fn issuance_entropy(prevout: &OutPoint, contract_hash: &FullHash) -> FullHash {
+    let mut outpoint = prevout.txid[..].to_vec();
+    outpoint.extend_from_slice(&prevout.vout.to_le_bytes());
+    let prevout_hash = full_hash(&Sha256dHash::hash(&outpoint)[..]);
+    fast_merkle_root(&prevout_hash, contract_hash)
+}
+
+pub fn asset_id(entropy: &FullHash) -> Sha256dHash {
+    parse_hash(&fast_merkle_root(entropy, &FullHash::default()))
+}
+
+// The token allowing to reissue the asset, which differs for issuances of confidential amounts
+pub fn reissuance_token_id(entropy: &FullHash, confidential: bool) -> Sha256dHash {
+    let mut leaf = FullHash::default();
+    leaf[0] = if confidential { 2 } else { 1 };
+    parse_hash(&fast_merkle_root(entropy, &leaf))
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IssuanceInfo {
+    pub is_reissuance: bool,
+    pub entropy: FullHash,
+    pub contract_hash: Option<FullHash>,    // of new issuances
+    pub reissuance_token: Option<FullHash>, // created by new issuances, spent by reissuances
+    pub amount: Option<u64>,                // unknown when confidential
+    pub tokens: Option<u64>,
+}
+
+impl IssuanceInfo {
+    // The asset issued by the input and the details of its issuance
+    fn new(txin: &TxIn) -> (Sha256dHash, Self) {
+        let issuance = &txin.asset_issuance;
+        let value = IssuanceValue::from(issuance);
+        // the entropy field holds the contract hash of new issuances
+        let (entropy, contract_hash, reissuance_token) = if value.is_reissuance {
+            (issuance.asset_entropy, None, None)
+        } else {
+            let contract_hash = issuance.asset_entropy;
+            let entropy = issuance_entropy(&txin.previous_output, &contract_hash);
+            let confidential = match issuance.amount {
+                Value::Confidential(..) => true,
+                _ => false,
+            };
+            let token = reissuance_token_id(&entropy, confidential);
+            (entropy, Some(contract_hash), Some(full_hash(&token[..])))
+        };
+        let info = IssuanceInfo {
+            is_reissuance: value.is_reissuance,
+            entropy,
+            contract_hash,
+            reissuance_token,
+            amount: value.assetamount,
+            tokens: value.tokenamount,
+        };
+        (asset_id(&entropy), info)
+    }
+}
+
+// A confirmed issuance or reissuance of an asset
+pub struct Issuance {
+    pub txid: Sha256dHash,
+    pub vin: u16,
+    pub height: usize,
+    pub info: IssuanceInfo,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IssuanceKey {
+    code: u8,
+    asset_id: FullHash,
+    height: u32, // MUST be serialized as big-endian (for correct scans).
+    txid: FullHash,
+    vin: u16,
+}
+
+pub struct IssuanceRow {
+    key: IssuanceKey,
+    info: IssuanceInfo,
+}
+
+impl IssuanceRow {
+    pub fn filter(asset_id: &Sha256dHash) -> Bytes {
+        bincode::serialize(&(b'i', full_hash(&asset_id[..]))).unwrap()
+    }
+
+    fn to_row(&self) -> DBRow {
+        DBRow {
+            key: bincode::config().big_endian().serialize(&self.key).unwrap(),
+            value: bincode::serialize(&self.info).unwrap(),
+        }
+    }
+
+    pub fn from_row(row: DBRow) -> Issuance {
+        let key: IssuanceKey = bincode::config()
+            .big_endian()
+            .deserialize(&row.key)
+            .expect("failed to deserialize IssuanceKey");
+        Issuance {
+            txid: parse_hash(&key.txid),
+            vin: key.vin,
+            height: key.height as usize,
+            info: bincode::deserialize(&row.value).expect("failed to deserialize IssuanceInfo"),
+        }
+    }
+}
+
+pub fn asset_rows(block_entries: &[BlockEntry]) -> Vec<DBRow> {
+    // persist the issuances and reissuances of each asset:
+    //      i{asset-id}{height}{txid:vin} → "{issuance}"
+    block_entries
+        .par_iter()
+        .map(|b| {
+            let height = b.entry.height() as u32;
+            let mut rows = vec![];
+            for tx in &b.block.txdata {
+                let txid = full_hash(&tx.txid()[..]);
+                for (vin, txin) in tx.input.iter().enumerate() {
+                    if !txin.has_issuance() {
+                        continue;
+                    }
+                    let (asset_id, info) = IssuanceInfo::new(txin);
+                    let key = IssuanceKey {
+                        code: b'i',
+                        asset_id: full_hash(&asset_id[..]),
+                        height,
+                        txid,
+                        vin: vin as u16,
+                    };
+                    rows.push(IssuanceRow { key, info }.to_row());
+                }
+            }
+            rows
+        })
+        .flatten()
+        .collect()
+}
+
+// Asset metadata (name, ticker, precision, issuer entity...) from an external registry, read from
+// a directory of `<asset-id>.json` files, including from its subdirectories
+pub struct AssetRegistry {
+    assets: BTreeMap<String, JsonValue>, // asset id hex => metadata
+}
+
+impl AssetRegistry {
+    pub fn load(dir: &Path) -> Result<Self> {
+        let pattern = dir.join("**").join("*.json");
+        let paths = glob(&pattern.to_string_lossy()).chain_err(|| "invalid asset registry path")?;
+        let mut assets = BTreeMap::new();
+        for path in paths {
+            let path = path.chain_err(|| "failed to list the asset registry")?;
+            let asset_id = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(stem) if stem.len() == 64 && stem.chars().all(|c| c.is_ascii_hexdigit()) => {
+                    stem.to_lowercase()
+                }
+                _ => continue, // e.g. the registry's index files
+            };
+            let contents =
+                fs::read(&path).chain_err(|| format!("failed to read {}", path.display()))?;
+            match serde_json::from_slice::<JsonValue>(&contents) {
+                Ok(JsonValue::Object(mut metadata)) => {
+                    metadata
+                        .entry("asset_id")
+                        .or_insert_with(|| JsonValue::String(asset_id.clone()));
+                    assets.insert(asset_id, JsonValue::Object(metadata));
+                }
+                _ => warn!("ignoring invalid asset registry entry {}", path.display()),
+            }
+        }
+        info!(
+            "loaded {} assets from the registry at {}",
+            assets.len(),
+            dir.display()
+        );
+        Ok(AssetRegistry { assets })
+    }
+
+    pub fn get(&self, asset_id: &Sha256dHash) -> Option<&JsonValue> {
+        self.assets.get(&asset_id.to_hex())
+    }
+
+    pub fn count(&self) -> usize {
+        self.assets.len()
+    }
+
+    // The metadata of the assets, sorted by asset id
+    pub fn list(&self, start_index: usize, limit: usize) -> Vec<&JsonValue> {
+        self.assets.values().skip(start_index).take(limit).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin_hashes::hex::FromHex;
+
+    #[test]
+    fn test_asset_id() {
+        // the test vectors of Elements' issuance_tests.cpp
+        let prevout = OutPoint {
+            txid: Sha256dHash::from_hex(
+                "05a047c98e82a848dee94efcf32462b065198bebf2404d201ba2e06db30b28f4",
+            )
+            .unwrap(),
+            vout: 0,
+        };
+        let contract_hash = FullHash::default();
+        let entropy = issuance_entropy(&prevout, &contract_hash);
+        assert_eq!(
+            parse_hash(&entropy).to_hex(),
+            "746f447f691323502cad2ef646f932613d37a83aeaa2133185b316648df4b70a"
+        );
+        assert_eq!(
+            asset_id(&entropy).to_hex(),
+            "dcd60818d863b5c026c40b2bc3ba6fdaf5018bcc8606c18adf7db4da0bcd8533"
+        );
+        assert_eq!(
+            reissuance_token_id(&entropy, false).to_hex(),
+            "c1adb114f4f87d33bf9ce90dd4f9ca523dd414d6cd010a7917903e2009689530"
+        );
+        assert_eq!(
+            reissuance_token_id(&entropy, true).to_hex(),
+            "d08425cac1a728360ae7c8aad2b21e9a04d1ab1c09959562661e5f13d9c5f803"
+        );
+    }
+}
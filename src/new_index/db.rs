@@ -1,10 +1,49 @@
 use rocksdb;
 
+use std::fs;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::util::Bytes;
+use crate::util::{Bytes, DebugTiming, Layer};
 
-static DB_VERSION: u32 = 1;
+// the rows are split into column families since version 2, along with the UTXO set, the block
+// filters and the blocks done markers of the history since version 3
+#[cfg(not(feature = "liquid"))]
+static DB_VERSION: u32 = 3;
+// the liquid history rows include the asset since version 2, and are split since version 3 (and
+// 4, as above)
+#[cfg(feature = "liquid")]
+static DB_VERSION: u32 = 4;
+
+// The earliest version migrated to DB_VERSION when opened, by moving its rows to their families
+#[cfg(not(feature = "liquid"))]
+static DB_MIGRATABLE_VERSION: u32 = 1;
+#[cfg(feature = "liquid")]
+static DB_MIGRATABLE_VERSION: u32 = 2;
+
+// The cache is versioned on its own since version 4 (before, it had the version of the index),
+// and rebuilt when opened by another version
+static CACHE_DB_VERSION: u32 = 4;
+
+// The webhook registrations are versioned on their own, as they can't be rebuilt from the chain
+static WEBHOOKS_DB_VERSION: u32 = 2;
+
+// Number of rows moved to their column families per write, when migrating
+const MIGRATION_BATCH_SIZE: usize = 100_000;
+
+// Holds the rows that aren't part of any family, like the version and the indexing progress
+const DEFAULT_FAMILY: &str = "default";
+
+// A column family of a database, holding the rows of the given key prefixes (by the first byte of
+// their keys), with the storage options suiting how they're read
+#[derive(Debug)]
+pub struct Family {
+    pub name: &'static str,
+    pub prefixes: &'static [u8],
+    pub block_size: usize,
+    pub compression: rocksdb::DBCompressionType,
+    pub bloom_filter: bool, // for the rows mostly read by key, rather than scanned
+}
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct DBRow {
@@ -14,26 +53,28 @@ pub struct DBRow {
 
 pub struct ScanIterator<'a> {
     prefix: Vec<u8>,
-    iter: rocksdb::DBIterator<'a>,
-    done: bool,
+    iters: Vec<rocksdb::DBIterator<'a>>, // of the column families left to scan, the next last
 }
 
 impl<'a> Iterator for ScanIterator<'a> {
     type Item = DBRow;
 
     fn next(&mut self) -> Option<DBRow> {
-        if self.done {
-            return None;
-        }
-        let (key, value) = self.iter.next()?;
-        if !key.starts_with(&self.prefix) {
-            self.done = true;
-            return None;
+        let _timer = DebugTiming::timer(Layer::Database);
+        loop {
+            match self.iters.last_mut()?.next() {
+                Some((key, value)) if key.starts_with(&self.prefix) => {
+                    DebugTiming::add_rows(1);
+                    return Some(DBRow {
+                        key: key.to_vec(),
+                        value: value.to_vec(),
+                    });
+                }
+                _ => {
+                    self.iters.pop();
+                }
+            }
         }
-        Some(DBRow {
-            key: key.to_vec(),
-            value: value.to_vec(),
-        })
     }
 }
 
@@ -51,14 +92,16 @@ impl<'a> Iterator for ReverseScanIterator<'a> {
             return None;
         }
 
-        let key = self.iter.key().unwrap();
+        let key = self.iter.key().unwrap().to_vec();
         if !key.starts_with(&self.prefix) {
             self.done = true;
             return None;
         }
-        let value = self.iter.value().unwrap();
+        let _timer = DebugTiming::timer(Layer::Database);
+        let value = self.iter.value().unwrap().to_vec();
 
         self.iter.prev();
+        DebugTiming::add_rows(1);
 
         Some(DBRow { key, value })
     }
@@ -67,6 +110,17 @@ impl<'a> Iterator for ReverseScanIterator<'a> {
 #[derive(Debug)]
 pub struct DB {
     db: rocksdb::DB,
+    families: &'static [Family],
+    stats: DBStats,
+}
+
+// Operation counters, exported as metrics by Store::start_stats_exporter()
+#[derive(Debug, Default)]
+struct DBStats {
+    gets: AtomicUsize,
+    scans: AtomicUsize,
+    written_rows: AtomicUsize,
+    deleted_rows: AtomicUsize,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -76,69 +130,161 @@ pub enum DBFlush {
 }
 
 impl DB {
-    pub fn open(path: &Path) -> DB {
+    pub fn open(path: &Path, families: &'static [Family]) -> DB {
+        let db = DB::open_families(path, families);
+        db.verify_compatibility();
+        db
+    }
+
+    // Nothing is lost by dropping the cache, so it's rebuilt rather than migrated
+    pub fn open_cache(path: &Path, families: &'static [Family]) -> DB {
+        let db = DB::open_families(path, families);
+        match db.version() {
+            None => db.put_version(CACHE_DB_VERSION),
+            Some(version) if version == CACHE_DB_VERSION => (),
+            Some(version) => {
+                warn!(
+                    "rebuilding the cache at {:?}, of version {} (instead of {})",
+                    path, version, CACHE_DB_VERSION
+                );
+                drop(db);
+                fs::remove_dir_all(path).expect("failed to remove the cache");
+                let db = DB::open_families(path, families);
+                db.put_version(CACHE_DB_VERSION);
+                return db;
+            }
+        }
+        db
+    }
+
+    // Unlike the cache, the registrations would be lost by rebuilding it
+    pub fn open_webhooks(path: &Path, families: &'static [Family]) -> DB {
+        let db = DB::open_families(path, families);
+        match db.version() {
+            None => db.put_version(WEBHOOKS_DB_VERSION),
+            Some(version) if version == WEBHOOKS_DB_VERSION => (),
+            Some(version) => panic!(
+                "Incompatible webhooks database found at {:?} (version {} instead of {})",
+                path, version, WEBHOOKS_DB_VERSION
+            ),
+        }
+        db
+    }
+
+    fn open_families(path: &Path, families: &'static [Family]) -> DB {
         debug!("opening DB at {:?}", path);
-        let mut db_opts = rocksdb::Options::default();
+        let mut db_opts = default_options();
         db_opts.create_if_missing(true);
+        db_opts.create_missing_column_families(true);
         db_opts.set_max_open_files(-1); // TODO: make sure to `ulimit -n` this process correctly
-        db_opts.set_compaction_style(rocksdb::DBCompactionStyle::Level);
-        db_opts.set_compression_type(rocksdb::DBCompressionType::Snappy);
-        db_opts.set_target_file_size_base(256 << 20);
-        db_opts.set_write_buffer_size(256 << 20);
-        db_opts.set_disable_auto_compactions(true); // for initial bulk load
-
-        // db_opts.set_advise_random_on_open(???);
-        db_opts.set_compaction_readahead_size(1 << 20);
         db_opts.increase_parallelism(2);
 
-        // let mut block_opts = rocksdb::BlockBasedOptions::default();
-        // block_opts.set_block_size(???);
-
-        let db = DB {
-            db: rocksdb::DB::open(&db_opts, path).expect("failed to open RocksDB"),
-        };
-        db.verify_compatibility();
-        db
+        let descriptors = families
+            .iter()
+            .map(|family| rocksdb::ColumnFamilyDescriptor::new(family.name, family.options()))
+            .chain(std::iter::once(rocksdb::ColumnFamilyDescriptor::new(
+                DEFAULT_FAMILY,
+                default_options(),
+            )));
+        DB {
+            db: rocksdb::DB::open_cf_descriptors(&db_opts, path, descriptors)
+                .expect("failed to open RocksDB"),
+            families,
+            stats: DBStats::default(),
+        }
     }
 
     pub fn full_compaction(&self) {
         // TODO: make sure this doesn't fail silently
         debug!("starting full compaction on {:?}", self.db);
-        self.db.compact_range(None::<&[u8]>, None::<&[u8]>);
+        for cf in self.all_cfs() {
+            self.db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
+        }
         debug!("finished full compaction on {:?}", self.db);
     }
 
     pub fn enable_auto_compaction(&self) {
         let opts = [("disable_auto_compactions", "false")];
-        self.db.set_options(&opts).unwrap();
+        for cf in self.all_cfs() {
+            self.db.set_options_cf(cf, &opts).unwrap();
+        }
+    }
+
+    // The column family holding the rows starting with `key` (by its first byte)
+    fn cf(&self, key: &[u8]) -> &rocksdb::ColumnFamily {
+        self.db
+            .cf_handle(self.family_name(key))
+            .expect("missing column family")
+    }
+
+    fn family_name(&self, key: &[u8]) -> &'static str {
+        key.first()
+            .and_then(|code| {
+                self.families
+                    .iter()
+                    .find(|family| family.prefixes.contains(code))
+            })
+            .map_or(DEFAULT_FAMILY, |family| family.name)
+    }
+
+    fn all_cfs(&self) -> Vec<&rocksdb::ColumnFamily> {
+        std::iter::once(DEFAULT_FAMILY)
+            .chain(self.families.iter().map(|family| family.name))
+            .map(|name| self.db.cf_handle(name).expect("missing column family"))
+            .collect()
+    }
+
+    // The number of operations since the database was opened, by operation
+    pub fn stats(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("get", self.stats.gets.load(Ordering::Relaxed)),
+            ("scan", self.stats.scans.load(Ordering::Relaxed)),
+            ("write", self.stats.written_rows.load(Ordering::Relaxed)),
+            ("delete", self.stats.deleted_rows.load(Ordering::Relaxed)),
+        ]
     }
 
-    pub fn raw_iterator(&self) -> rocksdb::DBRawIterator {
-        self.db.raw_iterator()
+    // Over the column family of the rows starting with `prefix`
+    pub fn raw_iterator(&self, prefix: &[u8]) -> rocksdb::DBRawIterator {
+        self.stats.scans.fetch_add(1, Ordering::Relaxed);
+        self.db.raw_iterator_cf(self.cf(prefix))
     }
 
+    // An empty prefix scans all the rows, one column family after the other
     pub fn iter_scan(&self, prefix: &[u8]) -> ScanIterator {
+        self.stats.scans.fetch_add(1, Ordering::Relaxed);
+        let _timer = DebugTiming::timer(Layer::Database);
+        let cfs = if prefix.is_empty() {
+            self.all_cfs()
+        } else {
+            vec![self.cf(prefix)]
+        };
         ScanIterator {
             prefix: prefix.to_vec(),
-            iter: self.db.prefix_iterator(prefix),
-            done: false,
+            iters: cfs
+                .into_iter()
+                .rev()
+                .map(|cf| self.db.prefix_iterator_cf(cf, prefix))
+                .collect(),
         }
     }
 
     pub fn iter_scan_from(&self, prefix: &[u8], start_at: &[u8]) -> ScanIterator {
-        let iter = self.db.iterator(rocksdb::IteratorMode::From(
-            start_at,
-            rocksdb::Direction::Forward,
-        ));
+        self.stats.scans.fetch_add(1, Ordering::Relaxed);
+        let _timer = DebugTiming::timer(Layer::Database);
+        let iter = self.db.iterator_cf(
+            self.cf(prefix),
+            rocksdb::IteratorMode::From(start_at, rocksdb::Direction::Forward),
+        );
         ScanIterator {
             prefix: prefix.to_vec(),
-            iter,
-            done: false,
+            iters: vec![iter],
         }
     }
 
     pub fn iter_scan_reverse(&self, prefix: &[u8], prefix_max: &[u8]) -> ReverseScanIterator {
-        let mut iter = self.db.raw_iterator();
+        let _timer = DebugTiming::timer(Layer::Database);
+        let mut iter = self.raw_iterator(prefix);
         iter.seek_for_prev(prefix_max);
 
         ReverseScanIterator {
@@ -148,17 +294,39 @@ impl DB {
         }
     }
 
-    pub fn write(&self, mut rows: Vec<DBRow>, flush: DBFlush) {
+    pub fn write(&self, rows: Vec<DBRow>, flush: DBFlush) {
+        self.write_batch(rows, vec![], flush)
+    }
+
+    // Persist the memtables, including the rows written without a WAL (with DBFlush::Disable)
+    pub fn flush(&self) {
+        for cf in self.all_cfs() {
+            self.db.flush_cf(cf).unwrap();
+        }
+    }
+
+    // Atomically delete the given keys and write the rows
+    pub fn write_batch(&self, mut rows: Vec<DBRow>, deletes: Vec<Bytes>, flush: DBFlush) {
         debug!(
-            "writing {} rows to {:?}, flush={:?}",
+            "writing {} rows and deleting {} to {:?}, flush={:?}",
             rows.len(),
+            deletes.len(),
             self.db,
             flush
         );
+        self.stats
+            .written_rows
+            .fetch_add(rows.len(), Ordering::Relaxed);
+        self.stats
+            .deleted_rows
+            .fetch_add(deletes.len(), Ordering::Relaxed);
         rows.sort_unstable_by(|a, b| a.key.cmp(&b.key));
         let mut batch = rocksdb::WriteBatch::default();
+        for key in deletes {
+            batch.delete_cf(self.cf(&key), &key);
+        }
         for row in rows {
-            batch.put(&row.key, &row.value).unwrap();
+            batch.put_cf(self.cf(&row.key), &row.key, &row.value);
         }
         let do_flush = match flush {
             DBFlush::Enable => true,
@@ -170,37 +338,134 @@ impl DB {
         self.db.write_opt(batch, &opts).unwrap();
     }
 
+    pub fn delete(&self, keys: Vec<Bytes>) {
+        self.write_batch(vec![], keys, DBFlush::Enable)
+    }
+
     pub fn put(&self, key: &[u8], value: &[u8]) {
-        self.db.put(key, value).unwrap();
+        self.stats.written_rows.fetch_add(1, Ordering::Relaxed);
+        self.db.put_cf(self.cf(key), key, value).unwrap();
     }
 
     pub fn get(&self, key: &[u8]) -> Option<Bytes> {
-        self.db.get(key).unwrap().map(|v| v.to_vec())
+        self.stats.gets.fetch_add(1, Ordering::Relaxed);
+        let _timer = DebugTiming::timer(Layer::Database);
+        let value = self.db.get_cf(self.cf(key), key).unwrap();
+        DebugTiming::add_rows(value.is_some() as usize);
+        value
     }
 
     fn verify_compatibility(&self) {
-        let compatibility_bytes = bincode::serialize(&DB_VERSION).unwrap();
+        match self.version() {
+            None => self.put_version(DB_VERSION),
+            Some(version) if version == DB_VERSION => (),
+            Some(version) if version >= DB_MIGRATABLE_VERSION && version < DB_VERSION => {
+                info!(
+                    "migrating {:?} from version {} to {}",
+                    self.db, version, DB_VERSION
+                );
+                self.move_to_families();
+                self.put_version(DB_VERSION);
+            }
+            Some(_) => panic!("Incompatible database found. Please reindex."),
+        }
+    }
+
+    fn version(&self) -> Option<u32> {
+        self.get(b"V")
+            .map(|version| bincode::deserialize(&version).expect("failed to parse DB version"))
+    }
+
+    fn put_version(&self, version: u32) {
+        self.put(b"V", &bincode::serialize(&version).unwrap());
+    }
 
-        match self.get(b"V") {
-            None => self.put(b"V", &compatibility_bytes),
-            Some(ref x) if x != &compatibility_bytes => {
-                panic!("Incompatible database found. Please reindex.")
+    // Move the rows of the default column family that belong to another one (all of them, in the
+    // databases written before the column families). Resumed if interrupted, as the version is
+    // only updated once done.
+    fn move_to_families(&self) {
+        let default_cf = self
+            .db
+            .cf_handle(DEFAULT_FAMILY)
+            .expect("missing column family");
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut moved = 0;
+        for (key, value) in self
+            .db
+            .iterator_cf(default_cf, rocksdb::IteratorMode::Start)
+        {
+            if self.family_name(&key) == DEFAULT_FAMILY {
+                continue;
+            }
+            batch.put_cf(self.cf(&key), &key, &value);
+            batch.delete_cf(default_cf, &key);
+            moved += 1;
+            if moved % MIGRATION_BATCH_SIZE == 0 {
+                self.db.write(batch).unwrap();
+                batch = rocksdb::WriteBatch::default();
+                debug!(
+                    "moved {} rows of {:?} to their column families",
+                    moved, self.db
+                );
             }
-            Some(_) => (),
         }
+        self.db.write(batch).unwrap();
+        self.db
+            .compact_range_cf(default_cf, None::<&[u8]>, None::<&[u8]>);
+        info!(
+            "moved {} rows of {:?} to their column families",
+            moved, self.db
+        );
+    }
+}
+
+impl Family {
+    fn options(&self) -> rocksdb::Options {
+        let mut opts = default_options();
+        opts.set_compression_type(self.compression);
+        let mut block_opts = rocksdb::BlockBasedOptions::default();
+        block_opts.set_block_size(self.block_size);
+        if self.bloom_filter {
+            block_opts.set_bloom_filter(10, false);
+        }
+        opts.set_block_based_table_factory(&block_opts);
+        opts
     }
 }
 
+// The options of every column family, the default one included
+fn default_options() -> rocksdb::Options {
+    let mut opts = rocksdb::Options::default();
+    opts.set_compaction_style(rocksdb::DBCompactionStyle::Level);
+    opts.set_compression_type(rocksdb::DBCompressionType::Snappy);
+    opts.set_target_file_size_base(256 << 20);
+    opts.set_write_buffer_size(256 << 20);
+    opts.set_disable_auto_compactions(true); // for initial bulk load
+
+    // opts.set_advise_random_on_open(???);
+    opts.set_compaction_readahead_size(1 << 20);
+    opts
+}
+
 #[cfg(test)]
 mod tests {
 
-    use crate::new_index::db::{DBRow, DB};
+    use crate::new_index::db::{
+        DBFlush, DBRow, Family, CACHE_DB_VERSION, DB, DB_MIGRATABLE_VERSION, DB_VERSION,
+    };
     use tempfile;
 
+    fn row(key: &[u8], value: &[u8]) -> DBRow {
+        DBRow {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        }
+    }
+
     #[test]
     fn test_db_iterator() {
         let dir = tempfile::tempdir().unwrap();
-        let db = DB::open(dir.path());
+        let db = DB::open(dir.path(), &[]);
         let rows = vec![
             DBRow {
                 key: b"X11".to_vec(),
@@ -221,4 +486,124 @@ mod tests {
         let actual: Vec<DBRow> = db.iter_scan(b"X").collect();
         assert_eq!(rows, actual);
     }
+
+    #[test]
+    fn test_db_families() {
+        const FAMILIES: &[Family] = &[
+            Family {
+                name: "first",
+                prefixes: b"AB",
+                block_size: 4 << 10,
+                compression: rocksdb::DBCompressionType::None,
+                bloom_filter: true,
+            },
+            Family {
+                name: "second",
+                prefixes: b"C",
+                block_size: 16 << 10,
+                compression: rocksdb::DBCompressionType::Lz4,
+                bloom_filter: false,
+            },
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path(), FAMILIES);
+        let rows = vec![
+            row(b"A1", b"a"),
+            row(b"B1", b"b"),
+            row(b"B2", b"c"),
+            row(b"C1", b"d"),
+            row(b"D1", b"e"),
+        ];
+        db.write(rows, DBFlush::Enable);
+        db.flush();
+
+        assert_eq!(db.get(b"B2"), Some(b"c".to_vec()));
+        assert_eq!(db.get(b"C2"), None);
+        let keys = |rows: Vec<DBRow>| -> Vec<Vec<u8>> { rows.into_iter().map(|r| r.key).collect() };
+        assert_eq!(keys(db.iter_scan(b"B").collect()), vec![b"B1", b"B2"]);
+        assert_eq!(keys(db.iter_scan_from(b"B", b"B2").collect()), vec![b"B2"]);
+        assert_eq!(
+            keys(db.iter_scan_reverse(b"B", b"B\xff").collect()),
+            vec![b"B2", b"B1"]
+        );
+        // the rows of the default family (including the version) first, then of each family
+        assert_eq!(
+            keys(db.iter_scan(b"").collect()),
+            vec![
+                b"D1".to_vec(),
+                b"V".to_vec(),
+                b"A1".to_vec(),
+                b"B1".to_vec(),
+                b"B2".to_vec(),
+                b"C1".to_vec(),
+            ]
+        );
+
+        db.delete(vec![b"B1".to_vec()]);
+        assert_eq!(keys(db.iter_scan(b"B").collect()), vec![b"B2"]);
+        drop(db);
+        // reopened with the same families
+        let db = DB::open(dir.path(), FAMILIES);
+        assert_eq!(db.get(b"C1"), Some(b"d".to_vec()));
+    }
+
+    #[test]
+    fn test_db_migration() {
+        const FAMILIES: &[Family] = &[Family {
+            name: "first",
+            prefixes: b"AB",
+            block_size: 4 << 10,
+            compression: rocksdb::DBCompressionType::None,
+            bloom_filter: true,
+        }];
+        let dir = tempfile::tempdir().unwrap();
+        // written before the column families
+        let db = DB::open(dir.path(), &[]);
+        let rows = vec![row(b"A1", b"a"), row(b"B1", b"b"), row(b"C1", b"c")];
+        db.write(rows, DBFlush::Enable);
+        db.put_version(DB_MIGRATABLE_VERSION);
+        drop(db);
+
+        let db = DB::open(dir.path(), FAMILIES);
+        assert_eq!(db.version(), Some(DB_VERSION));
+        let keys = |rows: Vec<DBRow>| -> Vec<Vec<u8>> { rows.into_iter().map(|r| r.key).collect() };
+        assert_eq!(keys(db.iter_scan(b"A").collect()), vec![b"A1"]);
+        assert_eq!(db.get(b"B1"), Some(b"b".to_vec()));
+        assert_eq!(
+            keys(db.iter_scan(b"").collect()),
+            vec![
+                b"C1".to_vec(),
+                b"V".to_vec(),
+                b"A1".to_vec(),
+                b"B1".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Incompatible database found")]
+    fn test_db_incompatible() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path(), &[]);
+        db.put_version(DB_VERSION + 1);
+        drop(db);
+        DB::open(dir.path(), &[]);
+    }
+
+    #[test]
+    fn test_cache_rebuild() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open_cache(dir.path(), &[]);
+        db.put(b"A1", b"a");
+        drop(db);
+        // kept by the same version
+        let db = DB::open_cache(dir.path(), &[]);
+        assert_eq!(db.get(b"A1"), Some(b"a".to_vec()));
+        db.put_version(CACHE_DB_VERSION - 1);
+        drop(db);
+
+        let db = DB::open_cache(dir.path(), &[]);
+        assert_eq!(db.get(b"A1"), None);
+        assert_eq!(db.version(), Some(CACHE_DB_VERSION));
+    }
 }
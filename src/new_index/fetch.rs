@@ -5,33 +5,70 @@ use bitcoin::util::hash::BitcoinHash;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 use rayon::prelude::*;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
 use std::io::{Cursor, Seek, SeekFrom};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::Receiver;
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::daemon::Daemon;
 use crate::errors::*;
+use crate::new_index::profile::IndexProfile;
 use crate::util::{spawn_thread, HeaderEntry, SyncChannel};
 
+// The blocks read ahead of the next one to index, waiting in memory for it. bitcoind downloads at
+// most 1024 blocks ahead of the first missing one, so the blocks are normally stored a few files
+// away from their position in the chain. Beyond that, the furthest ones are evicted and fetched
+// from bitcoind once they're next to be indexed.
+const MAX_PENDING_BLOCKS_SIZE: u64 = 2 << 30; // 2 GB
+const EVICTED_BLOCKS_PER_FETCH: usize = 100;
+
 #[derive(Clone, Copy, Debug)]
 pub enum FetchFrom {
     Bitcoind,
-    BlkFiles,
+    // with the number of threads reading the files concurrently (each holding one file in memory),
+    // and of the threads parsing their blocks, and the magic the blocks start with
+    BlkFiles {
+        readers: usize,
+        parsers: usize,
+        magic: u32,
+    },
 }
 
 pub fn start_fetcher(
     from: FetchFrom,
     daemon: &Daemon,
     new_headers: Vec<HeaderEntry>,
-) -> Result<Fetcher<Vec<BlockEntry>>> {
-    let fetcher = match from {
-        FetchFrom::Bitcoind => bitcoind_fetcher,
-        FetchFrom::BlkFiles => blkfiles_fetcher,
-    };
-    fetcher(daemon, new_headers)
+    profile: Option<Arc<IndexProfile>>,
+    resume_file: Option<usize>,
+) -> Result<Fetcher<FetchBatch>> {
+    match from {
+        FetchFrom::Bitcoind => bitcoind_fetcher(daemon, new_headers),
+        FetchFrom::BlkFiles {
+            readers,
+            parsers,
+            magic,
+        } => blkfiles_fetcher(
+            daemon,
+            new_headers,
+            profile,
+            resume_file,
+            readers,
+            parsers,
+            magic,
+        ),
+    }
+}
+
+// The next blocks in chain order, with the index of the first blk*.dat file that may still hold
+// blocks of the following batches (when fetching from blk*.dat files), to resume from it
+pub struct FetchBatch {
+    pub blocks: Vec<BlockEntry>,
+    pub blk_file: Option<usize>,
 }
 
 pub struct BlockEntry {
@@ -61,12 +98,23 @@ impl<T> Fetcher<T> {
         }
         self.thread.join().expect("fetcher thread panicked")
     }
+
+    // Like map(), but stops fetching once `func` returns false (e.g. on shutdown)
+    pub fn map_while<F>(self, mut func: F)
+    where
+        F: FnMut(T) -> bool,
+    {
+        for item in self.receiver {
+            if !func(item) {
+                break;
+            }
+        }
+        // the fetcher stops when failing to send to the dropped receiver
+        self.thread.join().expect("fetcher thread panicked")
+    }
 }
 
-fn bitcoind_fetcher(
-    daemon: &Daemon,
-    new_headers: Vec<HeaderEntry>,
-) -> Result<Fetcher<Vec<BlockEntry>>> {
+fn bitcoind_fetcher(daemon: &Daemon, new_headers: Vec<HeaderEntry>) -> Result<Fetcher<FetchBatch>> {
     new_headers.last().map(|tip| {
         debug!("{:?} ({} new blocks to fetch)", tip, new_headers.len());
     });
@@ -78,9 +126,11 @@ fn bitcoind_fetcher(
         spawn_thread("bitcoind_fetcher", move || -> () {
             for entries in new_headers.chunks(100) {
                 let blockhashes: Vec<Sha256dHash> = entries.iter().map(|e| *e.hash()).collect();
-                let blocks = daemon
-                    .getblocks(&blockhashes)
-                    .expect("failed to get blocks from bitcoind");
+                let blocks = match daemon.getblocks(&blockhashes) {
+                    Ok(blocks) => blocks,
+                    Err(ref e) if is_interrupt(e) => break,
+                    Err(e) => panic!("failed to get blocks from bitcoind: {}", e),
+                };
                 assert_eq!(blocks.len(), entries.len());
                 let block_entries: Vec<BlockEntry> = blocks
                     .into_iter()
@@ -92,9 +142,13 @@ fn bitcoind_fetcher(
                     })
                     .collect();
                 assert_eq!(block_entries.len(), entries.len());
-                sender
-                    .send(block_entries)
-                    .expect("failed to send fetched blocks");
+                let batch = FetchBatch {
+                    blocks: block_entries,
+                    blk_file: None,
+                };
+                if sender.send(batch).is_err() {
+                    break; // stopped by the receiver
+                }
             }
         }),
     ))
@@ -103,86 +157,219 @@ fn bitcoind_fetcher(
 fn blkfiles_fetcher(
     daemon: &Daemon,
     new_headers: Vec<HeaderEntry>,
-) -> Result<Fetcher<Vec<BlockEntry>>> {
-    let magic = daemon.magic();
+    profile: Option<Arc<IndexProfile>>,
+    resume_file: Option<usize>,
+    readers: usize,
+    parsers: usize,
+    magic: u32,
+) -> Result<Fetcher<FetchBatch>> {
     let blk_files = daemon.list_blk_files()?;
+    let last_file = blk_files.len().saturating_sub(1);
+    // the files before the resumed one hold no blocks left to fetch, unless bitcoind rewrote them
+    // since (e.g. with -reindex): they're read last, only while blocks are still missing
+    let first_file = resume_file.map_or(0, |file| file.min(last_file));
+    if first_file > 0 {
+        info!("resuming from blk*.dat file #{}", first_file);
+    }
+    let files: Vec<(usize, PathBuf)> = blk_files.into_iter().enumerate().collect();
+    let files = [&files[first_file..], &files[..first_file]].concat();
 
     let chan = SyncChannel::new(1);
     let sender = chan.sender();
 
-    let mut entry_map: HashMap<Sha256dHash, HeaderEntry> =
-        new_headers.into_iter().map(|h| (*h.hash(), h)).collect();
+    // blocks are stored out of order, so they are applied by their position in the new headers
+    let mut entry_map: HashMap<Sha256dHash, (usize, HeaderEntry)> = new_headers
+        .into_iter()
+        .enumerate()
+        .map(|(index, h)| (*h.hash(), (index, h)))
+        .collect();
 
-    let parser = blkfiles_parser(blkfiles_reader(blk_files), magic);
+    // the blocks evicted from memory are fetched from bitcoind instead, once they're next
+    let daemon = daemon.reconnect()?;
+    let reader = blkfiles_reader(files, magic, readers, parsers);
     Ok(Fetcher::from(
         chan.into_receiver(),
         spawn_thread("blkfiles_fetcher", move || -> () {
-            parser.map(|sizedblocks| {
-                let block_entries: Vec<BlockEntry> = sizedblocks
-                    .into_iter()
-                    .filter_map(|(block, size)| {
-                        let blockhash = block.bitcoin_hash();
-                        entry_map
-                            .remove(&blockhash)
-                            .map(|entry| BlockEntry { block, entry, size })
-                            .or_else(|| {
-                                trace!("skipping block {}", blockhash);
-                                None
-                            })
-                    })
-                    .collect();
-                trace!("fetched {} blocks", block_entries.len());
-                sender
-                    .send(block_entries)
-                    .expect("failed to send blocks entries from blk*.dat files");
+            // blocks waiting for the preceding ones (with their file), from files still being read
+            let mut pending: BTreeMap<usize, (BlockEntry, usize)> = BTreeMap::new();
+            let mut pending_size: u64 = 0;
+            // blocks evicted from `pending` to bound its size
+            let mut evicted: BTreeMap<usize, HeaderEntry> = BTreeMap::new();
+            let mut unread: BTreeSet<usize> = (first_file..=last_file).collect();
+            let mut next_index = 0;
+            let mut stopped = false;
+            reader.map_while(|(file, sizedblocks, parse_duration)| {
+                unread.remove(&file);
+                let mut min_height = None;
+                for (block, size) in sizedblocks {
+                    let blockhash = block.bitcoin_hash();
+                    match entry_map.remove(&blockhash) {
+                        Some((index, entry)) => {
+                            let height = entry.height();
+                            min_height = Some(min_height.map_or(height, |h: usize| h.min(height)));
+                            pending_size += u64::from(size);
+                            pending.insert(index, (BlockEntry { block, entry, size }, file));
+                        }
+                        None => trace!("skipping block {}", blockhash),
+                    }
+                }
+                if let (Some(profile), Some(height)) = (&profile, min_height) {
+                    profile.record("parse", height, parse_duration);
+                }
+                // the following blocks are either pending or in the unread files (or appended to
+                // the last file by bitcoind, for the next runs)
+                let blk_file = unread
+                    .iter()
+                    .cloned()
+                    .chain(pending.values().map(|(_, file)| *file))
+                    .min()
+                    .map_or(last_file, |file| file.min(last_file));
+                // fails once stopped by the receiver, the remaining files are then left unread
+                let send = |blocks: Vec<BlockEntry>| {
+                    let batch = FetchBatch {
+                        blocks,
+                        blk_file: Some(blk_file),
+                    };
+                    sender.send(batch).is_ok()
+                };
+                let mut block_entries = vec![];
+                while !stopped {
+                    if let Some((entry, _)) = pending.remove(&next_index) {
+                        pending_size -= u64::from(entry.size);
+                        block_entries.push(entry);
+                        next_index += 1;
+                        continue;
+                    }
+                    // the blocks read so far are sent first, as fetching the evicted ones is slow
+                    let mut entries = vec![];
+                    while entries.len() < EVICTED_BLOCKS_PER_FETCH {
+                        match evicted.remove(&(next_index + entries.len())) {
+                            Some(entry) => entries.push(entry),
+                            None => break,
+                        }
+                    }
+                    if !block_entries.is_empty() && !send(block_entries.split_off(0)) {
+                        stopped = true;
+                    }
+                    if entries.is_empty() || stopped {
+                        break;
+                    }
+                    match fetch_blocks(&daemon, entries) {
+                        Ok(blocks) => {
+                            next_index += blocks.len();
+                            stopped = !send(blocks);
+                        }
+                        Err(ref e) if is_interrupt(e) => stopped = true,
+                        Err(e) => panic!("failed to get blocks from bitcoind: {}", e),
+                    }
+                }
+                // evict the last blocks, which are the furthest from being indexed, rather than
+                // holding them until their preceding ones are found in the next files
+                if pending_size > MAX_PENDING_BLOCKS_SIZE {
+                    let mut count = 0;
+                    while pending_size > MAX_PENDING_BLOCKS_SIZE / 2 {
+                        let index = *pending.keys().next_back().unwrap();
+                        let (entry, _) = pending.remove(&index).unwrap();
+                        pending_size -= u64::from(entry.size);
+                        evicted.insert(index, entry.entry);
+                        count += 1;
+                    }
+                    debug!(
+                        "evicted {} blocks waiting for earlier ones, to be fetched from bitcoind",
+                        count
+                    );
+                }
+                trace!(
+                    "fetched blocks up to #{} ({} waiting for earlier blocks, {} evicted)",
+                    next_index,
+                    pending.len(),
+                    evicted.len()
+                );
+                // the remaining files are left unread once all the blocks were fetched
+                !stopped && !(entry_map.is_empty() && pending.is_empty() && evicted.is_empty())
             });
-            if !entry_map.is_empty() {
+            if !stopped && (!entry_map.is_empty() || !pending.is_empty() || !evicted.is_empty()) {
                 panic!(
                     "failed to index {} blocks from blk*.dat files",
-                    entry_map.len()
+                    entry_map.len() + pending.len() + evicted.len()
                 )
             }
         }),
     ))
 }
 
-fn blkfiles_reader(blk_files: Vec<PathBuf>) -> Fetcher<Vec<u8>> {
-    let chan = SyncChannel::new(1);
-    let sender = chan.sender();
-
-    Fetcher::from(
-        chan.into_receiver(),
-        spawn_thread("blkfiles_reader", move || -> () {
-            for path in blk_files {
-                trace!("reading {:?}", path);
-                let blob = fs::read(&path).expect(&format!("failed to read {:?}", path));
-                sender
-                    .send(blob)
-                    .expect(&format!("failed to send {:?} contents", path));
-            }
-        }),
-    )
+// The blocks of the given entries, fetched from bitcoind
+fn fetch_blocks(daemon: &Daemon, entries: Vec<HeaderEntry>) -> Result<Vec<BlockEntry>> {
+    let blockhashes: Vec<Sha256dHash> = entries.iter().map(|e| *e.hash()).collect();
+    let blocks = daemon.getblocks(&blockhashes)?;
+    assert_eq!(blocks.len(), entries.len());
+    Ok(blocks
+        .into_iter()
+        .zip(entries)
+        .map(|(block, entry)| BlockEntry {
+            size: serialize(&block).len() as u32,
+            block,
+            entry,
+        })
+        .collect())
 }
 
-fn blkfiles_parser(blobs: Fetcher<Vec<u8>>, magic: u32) -> Fetcher<Vec<SizedBlock>> {
-    let chan = SyncChannel::new(1);
+// Read and parse the blk*.dat files concurrently (by their index, in the given order), yielding
+// the blocks of each file as soon as it was parsed (in no particular order), along with its index
+// and the time it took. The parsed files waiting to be indexed are bounded by the readers.
+fn blkfiles_reader(
+    blk_files: Vec<(usize, PathBuf)>,
+    magic: u32,
+    threads: usize,
+    parsers: usize,
+) -> Fetcher<(usize, Vec<SizedBlock>, Duration)> {
+    let chan = SyncChannel::new(threads);
     let sender = chan.sender();
 
     Fetcher::from(
         chan.into_receiver(),
-        spawn_thread("blkfiles_parser", move || -> () {
-            blobs.map(|blob| {
-                trace!("parsing {} bytes", blob.len());
-                let blocks = parse_blocks(blob, magic).expect("failed to parse blk*.dat file");
-                sender
-                    .send(blocks)
-                    .expect("failed to send blocks from blk*.dat file");
-            });
+        spawn_thread("blkfiles_reader", move || -> () {
+            let pool = Arc::new(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(parsers) // CPU-bound
+                    .thread_name(|i| format!("parse-blocks-{}", i))
+                    .build()
+                    .unwrap(),
+            );
+            let blk_files = Arc::new(blk_files);
+            let next_file = Arc::new(AtomicUsize::new(0));
+            let readers: Vec<thread::JoinHandle<()>> = (0..threads)
+                .map(|i| {
+                    let pool = Arc::clone(&pool);
+                    let blk_files = Arc::clone(&blk_files);
+                    let next_file = Arc::clone(&next_file);
+                    let sender = sender.clone();
+                    spawn_thread(&format!("blkfiles_reader_{}", i), move || loop {
+                        let (file, path) =
+                            match blk_files.get(next_file.fetch_add(1, Ordering::SeqCst)) {
+                                Some((file, path)) => (*file, path),
+                                None => break,
+                            };
+                        trace!("reading {:?}", path);
+                        let started = Instant::now();
+                        let blob = fs::read(path).expect(&format!("failed to read {:?}", path));
+                        trace!("parsing {} bytes", blob.len());
+                        let blocks = parse_blocks(blob, magic, &pool)
+                            .expect("failed to parse blk*.dat file");
+                        if sender.send((file, blocks, started.elapsed())).is_err() {
+                            break; // stopped by the receiver
+                        }
+                    })
+                })
+                .collect();
+            for reader in readers {
+                reader.join().expect("blk*.dat reader thread panicked");
+            }
         }),
     )
 }
 
-fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<SizedBlock>> {
+fn parse_blocks(blob: Vec<u8>, magic: u32, pool: &rayon::ThreadPool) -> Result<Vec<SizedBlock>> {
     let mut cursor = Cursor::new(&blob);
     let mut slices = vec![];
     let max_pos = blob.len() as u64;
@@ -208,11 +395,6 @@ fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<SizedBlock>> {
         slices.push((&blob[start..end], block_size));
     }
 
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(0) // CPU-bound
-        .thread_name(|i| format!("parse-blocks-{}", i))
-        .build()
-        .unwrap();
     Ok(pool.install(|| {
         slices
             .into_par_iter()
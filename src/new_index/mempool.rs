@@ -1,35 +1,61 @@
 use arraydeque::{ArrayDeque, Wrapping};
-use bitcoin::consensus::encode::serialize;
+use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 use itertools::Itertools;
 
-use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::iter::FromIterator;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::chain::{OutPoint, Transaction, TxOut};
 use crate::daemon::Daemon;
 use crate::errors::*;
 use crate::metrics::{GaugeVec, HistogramOpts, HistogramVec, MetricOpts, Metrics};
+use crate::new_index::db::{DBFlush, DBRow};
 use crate::new_index::{
     compute_script_hash, parse_hash, schema::FullHash, ChainQuery, FundingInfo, ScriptStats,
-    SpendingInfo, SpendingInput, TxHistoryInfo, Utxo,
+    SpendingInfo, SpendingInput, Store, TxHistoryInfo, Utxo,
+};
+use crate::util::fees::{
+    congestion_score, make_fee_histogram, make_feerate_histogram, next_block_cutoff, TxFeeInfo,
 };
-use crate::util::fees::{make_fee_histogram, TxFeeInfo};
 use crate::util::{full_hash, has_prevout, is_spendable, Bytes};
 
+#[cfg(not(feature = "liquid"))]
+use crate::util::Amount;
+
 const RECENT_TXS_SIZE: usize = 10;
 const BACKLOG_STATS_TTL: u64 = 10;
+const CONGESTION_HISTORY_SIZE: usize = 60;
+const CONGESTION_SAMPLE_INTERVAL: u64 = 60; // in seconds, for an hour of history
+const REPLACEMENTS_SIZE: usize = 100_000; // number of replaced transactions to remember
+
+// bitcoind's default package limits (-limitancestorcount, -limitancestorsize, etc)
+const ANCESTOR_LIMIT_COUNT: usize = 25;
+const ANCESTOR_LIMIT_VSIZE: u32 = 101_000;
+const DESCENDANT_LIMIT_COUNT: usize = 25;
+const DESCENDANT_LIMIT_VSIZE: u32 = 101_000;
+// like bitcoind's miner, the template stops being filled after this many packages in a row didn't
+// fit in the last few thousands weight units
+const TEMPLATE_MAX_CONSECUTIVE_FAILURES: usize = 1000;
+const TEMPLATE_FULL_WEIGHT_MARGIN: usize = 4000;
 
 pub struct Mempool {
     chain: Arc<ChainQuery>,
     txstore: HashMap<Sha256dHash, Transaction>,
     feeinfo: HashMap<Sha256dHash, TxFeeInfo>,
+    packages: HashMap<Sha256dHash, PackageInfo>,
     history: HashMap<FullHash, Vec<TxHistoryInfo>>, // ScriptHash -> {history_entries}
     edges: HashMap<OutPoint, (Sha256dHash, u32)>,   // OutPoint -> (spending_txid, spending_vin)
     recent: ArrayDeque<[TxOverview; RECENT_TXS_SIZE], Wrapping>, // The N most recent txs to enter the mempool
     backlog_stats: (BacklogStats, Instant),
+    congestion_history: VecDeque<CongestionStats>, // oldest first
+    sequence: u64, // incremented on every change, to identify mempool states
+    synced_tip: Option<Sha256dHash>, // the chain tip as of the last update() from the daemon
+    replaced_by: HashMap<Sha256dHash, (Sha256dHash, u64)>, // txid -> (replacement txid, time)
+    replaces: HashMap<Sha256dHash, Vec<Sha256dHash>>, // txid -> replaced txids
+    replaced_order: VecDeque<Sha256dHash>, // replaced txids, oldest first
 
     // monitoring
     latency: HistogramVec, // mempool requests latency
@@ -53,6 +79,7 @@ impl Mempool {
             chain,
             txstore: HashMap::new(),
             feeinfo: HashMap::new(),
+            packages: HashMap::new(),
             history: HashMap::new(),
             edges: HashMap::new(),
             recent: ArrayDeque::new(),
@@ -60,6 +87,12 @@ impl Mempool {
                 BacklogStats::default(),
                 Instant::now() - Duration::from_secs(BACKLOG_STATS_TTL),
             ),
+            congestion_history: VecDeque::new(),
+            sequence: 0,
+            synced_tip: None,
+            replaced_by: HashMap::new(),
+            replaces: HashMap::new(),
+            replaced_order: VecDeque::new(),
             latency: metrics.histogram_vec(
                 HistogramOpts::new("mempool_latency", "Mempool requests latency (in seconds)"),
                 &["part"],
@@ -91,26 +124,66 @@ impl Mempool {
         })
     }
 
+    pub fn get_fee(&self, txid: &Sha256dHash) -> Option<u64> {
+        self.feeinfo.get(txid).map(|info| info.fee)
+    }
+
+    pub fn get_package(&self, txid: &Sha256dHash) -> Option<&PackageInfo> {
+        self.packages.get(txid)
+    }
+
+    pub fn effective_feerate(&self, txid: &Sha256dHash) -> Option<f32> {
+        self.packages
+            .get(txid)
+            .map(|package| package.effective_feerate)
+    }
+
     pub fn has_spend(&self, outpoint: &OutPoint) -> bool {
         self.edges.contains_key(outpoint)
     }
 
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn synced_tip(&self) -> Option<Sha256dHash> {
+        self.synced_tip
+    }
+
+    #[cfg(test)]
+    pub fn set_synced_tip(&mut self, tip: Sha256dHash) {
+        self.synced_tip = Some(tip);
+    }
+
     // TODO: return as Vec<(Transaction,Option<BlockId>)>?
-    pub fn history(&self, scripthash: &[u8], limit: usize) -> Vec<Transaction> {
+    // The transactions in the order they entered the mempool, resuming after `after_txid` (if any)
+    pub fn history(
+        &self,
+        scripthash: &[u8],
+        after_txid: Option<&Sha256dHash>,
+        limit: usize,
+    ) -> Vec<Transaction> {
         let _timer = self.latency.with_label_values(&["history"]).start_timer();
         match self.history.get(scripthash) {
             None => return vec![],
-            Some(entries) => entries
-                .iter()
-                .map(get_entry_txid)
-                .unique()
-                .take(limit)
-                .map(|txid| self.txstore.get(&txid).expect("missing mempool tx"))
-                .cloned()
-                .collect(),
+            Some(entries) => {
+                let mut txids = entries.iter().map(get_entry_txid).unique();
+                if let Some(after_txid) = after_txid {
+                    txids.by_ref().find(|txid| txid == after_txid);
+                }
+                txids
+                    .take(limit)
+                    .map(|txid| self.txstore.get(&txid).expect("missing mempool tx"))
+                    .cloned()
+                    .collect()
+            }
         }
     }
 
+    pub fn has_history(&self, scripthash: &[u8]) -> bool {
+        self.history.contains_key(scripthash)
+    }
+
     pub fn history_txids(&self, scripthash: &[u8]) -> Vec<Sha256dHash> {
         let _timer = self
             .latency
@@ -144,11 +217,20 @@ impl Mempool {
             .collect()
     }
 
+    // Apply the mempool to the confirmed utxos, removing the spent ones and adding the new ones
+    pub fn apply_to_utxos(&self, mut utxos: Vec<Utxo>, scripthash: &[u8]) -> Vec<Utxo> {
+        utxos.retain(|utxo| !self.has_spend(&OutPoint::from(utxo)));
+        utxos.extend(self.utxo(scripthash));
+        utxos.sort_unstable_by_key(Utxo::sort_key);
+        utxos
+    }
+
     // @XXX avoid code duplication with ChainQuery::stats()?
     pub fn stats(&self, scripthash: &[u8]) -> ScriptStats {
         let _timer = self.latency.with_label_values(&["stats"]).start_timer();
         let mut stats = ScriptStats::default();
         let mut seen_txids = HashSet::new();
+        let mut seen_funding_txids = HashSet::new();
 
         let entries = match self.history.get(scripthash) {
             None => return stats,
@@ -159,27 +241,25 @@ impl Mempool {
             if seen_txids.insert(get_entry_txid(entry)) {
                 stats.tx_count += 1;
             }
+            if let TxHistoryInfo::Funding(_) = entry {
+                if seen_funding_txids.insert(get_entry_txid(entry)) {
+                    stats.funding_tx_count += 1;
+                }
+            }
 
             match entry {
                 #[cfg(not(feature = "liquid"))]
                 TxHistoryInfo::Funding(info) => {
                     stats.funded_txo_count += 1;
-                    stats.funded_txo_sum += info.value;
+                    stats.funded_txo_sum += Amount::from_sat(info.value);
                 }
-                #[cfg(feature = "liquid")]
-                TxHistoryInfo::Funding(_) => {
-                    stats.funded_txo_count += 1;
-                }
-
                 #[cfg(not(feature = "liquid"))]
                 TxHistoryInfo::Spending(info) => {
                     stats.spent_txo_count += 1;
-                    stats.spent_txo_sum += info.value;
+                    stats.spent_txo_sum += Amount::from_sat(info.value);
                 }
                 #[cfg(feature = "liquid")]
-                TxHistoryInfo::Spending(_) => {
-                    stats.spent_txo_count += 1;
-                }
+                info => stats.add_history(info),
             };
         }
 
@@ -200,12 +280,249 @@ impl Mempool {
         self.recent.iter().collect()
     }
 
+    // Get the in-mempool ancestors of the given transaction (excluding itself)
+    pub fn ancestors(&self, txid: &Sha256dHash) -> HashSet<Sha256dHash> {
+        let mut ancestors = HashSet::new();
+        let mut queue = vec![*txid];
+        while let Some(txid) = queue.pop() {
+            let tx = match self.txstore.get(&txid) {
+                Some(tx) => tx,
+                None => continue,
+            };
+            for txin in &tx.input {
+                let parent = txin.previous_output.txid;
+                if self.txstore.contains_key(&parent) && ancestors.insert(parent) {
+                    queue.push(parent);
+                }
+            }
+        }
+        ancestors
+    }
+
+    // Get the in-mempool descendants of the given transaction (excluding itself)
+    pub fn descendants(&self, txid: &Sha256dHash) -> HashSet<Sha256dHash> {
+        let mut descendants = HashSet::new();
+        let mut queue = vec![*txid];
+        while let Some(txid) = queue.pop() {
+            let tx = match self.txstore.get(&txid) {
+                Some(tx) => tx,
+                None => continue,
+            };
+            for vout in 0..tx.output.len() {
+                let outpoint = OutPoint {
+                    txid,
+                    vout: vout as u32,
+                };
+                if let Some((child, _)) = self.edges.get(&outpoint) {
+                    if descendants.insert(*child) {
+                        queue.push(*child);
+                    }
+                }
+            }
+        }
+        descendants
+    }
+
+    // Get the package size of the transaction, compared against bitcoind's policy limits
+    pub fn ancestry(&self, txid: &Sha256dHash) -> Option<AncestryInfo> {
+        let _timer = self.latency.with_label_values(&["ancestry"]).start_timer();
+        if !self.txstore.contains_key(txid) {
+            return None;
+        }
+        let vsize = |txid: &Sha256dHash| self.feeinfo.get(txid).map_or(0, |info| info.vsize);
+
+        let mut ancestors = self.ancestors(txid);
+        let mut descendants = self.descendants(txid);
+        ancestors.insert(*txid);
+        descendants.insert(*txid);
+
+        let ancestor_size = ancestors.iter().map(vsize).sum();
+        let descendant_size = descendants.iter().map(vsize).sum();
+
+        // A new child would be added as a descendant to every ancestor (including this tx),
+        // so the most constrained ancestor determines whether it is accepted
+        let (max_ancestor_descendant_count, max_ancestor_descendant_size) = ancestors
+            .iter()
+            .map(|ancestor| {
+                let descendants = self.descendants(ancestor);
+                (
+                    descendants.len() + 1,
+                    descendants.iter().map(vsize).sum::<u32>() + vsize(ancestor),
+                )
+            })
+            .fold((0, 0), |(count, size), (c, s)| (count.max(c), size.max(s)));
+
+        Some(AncestryInfo::new(
+            (ancestors.len(), ancestor_size),
+            (descendants.len(), descendant_size),
+            (max_ancestor_descendant_count, max_ancestor_descendant_size),
+        ))
+    }
+
+    pub fn replaced_by(&self, txid: &Sha256dHash) -> Option<Sha256dHash> {
+        self.replaced_by
+            .get(txid)
+            .map(|(replacement, _)| *replacement)
+    }
+
+    // The replacements tree the transaction is part of (if any), rooted at the latest replacement.
+    // Replacements are detected by conflicting spends, so only direct conflicts are tracked.
+    pub fn replacements(&self, txid: &Sha256dHash) -> Option<Replacement> {
+        let _timer = self
+            .latency
+            .with_label_values(&["replacements"])
+            .start_timer();
+        if !self.replaced_by.contains_key(txid) && !self.replaces.contains_key(txid) {
+            return None;
+        }
+        // a transaction may be replaced back by an earlier one it conflicts with
+        let mut visited = HashSet::new();
+        let mut latest = *txid;
+        visited.insert(latest);
+        while let Some((replacement, _)) = self.replaced_by.get(&latest) {
+            if !visited.insert(*replacement) {
+                break;
+            }
+            latest = *replacement;
+        }
+        Some(self.replacement_node(&latest, &mut HashSet::new()))
+    }
+
+    fn replacement_node(
+        &self,
+        txid: &Sha256dHash,
+        visited: &mut HashSet<Sha256dHash>,
+    ) -> Replacement {
+        visited.insert(*txid);
+        let mut replaces = vec![];
+        for replaced in self.replaces.get(txid).map_or(&[][..], |txids| &txids[..]) {
+            if !visited.contains(replaced) {
+                replaces.push(self.replacement_node(replaced, visited));
+            }
+        }
+        Replacement {
+            txid: *txid,
+            replaced_at: self.replaced_by.get(txid).map(|(_, time)| *time),
+            in_mempool: self.txstore.contains_key(txid),
+            confirmed: self.chain.tx_confirming_block(txid).is_some(),
+            replaces,
+        }
+    }
+
+    // Get the transactions with the highest effective feerate, i.e. the lower of their own feerate
+    // and the feerate of their package with all in-mempool ancestors (as used by bitcoind's miner)
+    pub fn txids_by_feerate(&self, limit: usize) -> Vec<TxFeerate> {
+        let _timer = self
+            .latency
+            .with_label_values(&["txids_by_feerate"])
+            .start_timer();
+        let mut txs: Vec<TxFeerate> = self
+            .feeinfo
+            .iter()
+            .map(|(txid, info)| TxFeerate {
+                txid: *txid,
+                feerate: self
+                    .packages
+                    .get(txid)
+                    .map_or(info.fee_per_vbyte, |package| {
+                        info.fee_per_vbyte.min(package.ancestor_feerate())
+                    }),
+            })
+            .collect();
+        txs.sort_unstable_by(|a, b| b.feerate.partial_cmp(&a.feerate).unwrap());
+        txs.truncate(limit);
+        txs
+    }
+
+    // The packages a miner would include in the next block, in selection order: each transaction
+    // with its not yet selected in-mempool ancestors (in topological order), by decreasing ancestor
+    // feerate, as long as they fit in `max_weight`. Ancestor feerates aren't updated as ancestors
+    // get selected, so the later packages may have a higher feerate than the earlier ones.
+    pub fn template_hints(&self, max_weight: usize, limit: usize) -> Vec<TemplatePackage> {
+        let _timer = self
+            .latency
+            .with_label_values(&["template_hints"])
+            .start_timer();
+        let mut candidates: Vec<(&Sha256dHash, f32)> = self
+            .feeinfo
+            .iter()
+            .map(|(txid, info)| {
+                let feerate = self
+                    .packages
+                    .get(txid)
+                    .map_or(info.fee_per_vbyte, PackageInfo::ancestor_feerate);
+                (txid, feerate)
+            })
+            .collect();
+        candidates.sort_unstable_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+        let weight = |txid: &Sha256dHash| self.txstore.get(txid).map_or(0, |tx| tx.get_weight());
+        let fee = |txid: &Sha256dHash| self.feeinfo.get(txid).map_or(0, |info| info.fee);
+        let ancestor_count =
+            |txid: &Sha256dHash| self.packages.get(txid).map_or(0, |p| p.ancestor_count);
+
+        let mut selected = HashSet::new();
+        let mut remaining_weight = max_weight;
+        let mut failures = 0;
+        let mut packages = vec![];
+        for (txid, _) in candidates {
+            if packages.len() >= limit {
+                break;
+            }
+            if failures > TEMPLATE_MAX_CONSECUTIVE_FAILURES
+                && remaining_weight < TEMPLATE_FULL_WEIGHT_MARGIN
+            {
+                break;
+            }
+            if selected.contains(txid) {
+                continue;
+            }
+            // the ancestors are only walked for the transactions that may fit
+            if weight(txid) > remaining_weight {
+                failures += 1;
+                continue;
+            }
+            let mut txids: Vec<Sha256dHash> = if ancestor_count(txid) > 1 {
+                self.ancestors(txid)
+                    .into_iter()
+                    .filter(|ancestor| !selected.contains(ancestor))
+                    .collect()
+            } else {
+                vec![]
+            };
+            txids.push(*txid);
+            let total_weight: usize = txids.iter().map(weight).sum();
+            if total_weight > remaining_weight {
+                failures += 1;
+                continue;
+            }
+            failures = 0;
+            // a transaction has more ancestors than each of its parents
+            txids.sort_by_key(ancestor_count);
+            let total_fee: u64 = txids.iter().map(fee).sum();
+            remaining_weight -= total_weight;
+            selected.extend(txids.iter().cloned());
+            packages.push(TemplatePackage {
+                txids,
+                package_feerate: total_fee as f32 / (total_weight as f32 / 4.0),
+                total_weight,
+                total_fee,
+            });
+        }
+        packages
+    }
+
     pub fn backlog_stats(&self) -> &BacklogStats {
         &self.backlog_stats.0
     }
 
+    pub fn congestion_history(&self) -> &VecDeque<CongestionStats> {
+        &self.congestion_history
+    }
+
     pub fn update(&mut self, daemon: &Daemon) -> Result<()> {
         let _timer = self.latency.with_label_values(&["update"]).start_timer();
+        let tip = self.chain.best_hash(); // bitcoind's mempool is fetched after indexing it
         let new_txids = daemon
             .getmempooltxids()
             .chain_err(|| "failed to update mempool from daemon")?;
@@ -225,10 +542,14 @@ impl Mempool {
         self.add(to_add);
         // Remove missing transactions
         self.remove(to_remove);
+        self.synced_tip = Some(tip);
 
         self.count
             .with_label_values(&["txs"])
             .set(self.txstore.len() as f64);
+        self.count
+            .with_label_values(&["vsize"])
+            .set(self.feeinfo.values().map(|info| info.vsize as f64).sum());
 
         // Update cached backlog stats (if expired)
         if self.backlog_stats.1.elapsed() > Duration::from_secs(BACKLOG_STATS_TTL) {
@@ -236,25 +557,157 @@ impl Mempool {
                 .latency
                 .with_label_values(&["update_backlog_stats"])
                 .start_timer();
-            self.backlog_stats = (BacklogStats::new(&self.feeinfo), Instant::now());
+            let earlier = self.congestion_history.front();
+            // the histograms and the congestion are based on the package-aware feerates
+            let entries: Vec<TxFeeInfo> = self
+                .feeinfo
+                .iter()
+                .map(|(txid, info)| TxFeeInfo {
+                    fee: info.fee,
+                    vsize: info.vsize,
+                    fee_per_vbyte: self.effective_feerate(txid).unwrap_or(info.fee_per_vbyte),
+                })
+                .collect();
+            self.backlog_stats = (BacklogStats::new(&entries, earlier), Instant::now());
+
+            let congestion = &self.backlog_stats.0.congestion;
+            let sample_due = self.congestion_history.back().map_or(true, |last| {
+                congestion.time >= last.time + CONGESTION_SAMPLE_INTERVAL
+            });
+            if sample_due {
+                if self.congestion_history.len() >= CONGESTION_HISTORY_SIZE {
+                    self.congestion_history.pop_front();
+                }
+                self.congestion_history.push_back(congestion.clone());
+            }
         }
 
         Ok(())
     }
 
+    // Persist the mempool transactions, so that they don't have to be downloaded again from
+    // the daemon after a restart
+    pub fn save_snapshot(&self, store: &Store) {
+        let _timer = self
+            .latency
+            .with_label_values(&["save_snapshot"])
+            .start_timer();
+        let db = store.cache_db();
+        db.delete(db.iter_scan(b"P").map(|row| row.key).collect());
+        let rows = self
+            .txstore
+            .iter()
+            .map(|(txid, tx)| DBRow {
+                key: [b"P", &txid[..]].concat(),
+                value: serialize(tx),
+            })
+            .collect::<Vec<DBRow>>();
+        info!("saving {} transactions to the mempool snapshot", rows.len());
+        db.write(rows, DBFlush::Enable);
+    }
+
+    // Load the transactions persisted by save_snapshot(). Should be called before the first
+    // update(), which drops the ones that left the daemon's mempool in the meantime.
+    pub fn load_snapshot(&mut self, store: &Store) {
+        let _timer = self
+            .latency
+            .with_label_values(&["load_snapshot"])
+            .start_timer();
+        let mut txs: HashMap<Sha256dHash, Transaction> = store
+            .cache_db()
+            .iter_scan(b"P")
+            .map(|row| {
+                let tx: Transaction =
+                    deserialize(&row.value).expect("failed to parse mempool snapshot tx");
+                (tx.txid(), tx)
+            })
+            .collect();
+        let outpoints = txs
+            .values()
+            .flat_map(|tx| tx.input.iter().filter(|txin| has_prevout(txin)))
+            .map(|txin| txin.previous_output)
+            .collect();
+        let confirmed_txos = self.chain.lookup_avail_txos(&outpoints);
+
+        // drop the transactions spending outputs that can no longer be found, e.g. of
+        // conflicting transactions, along with their descendants
+        loop {
+            let missing_inputs: Vec<Sha256dHash> = txs
+                .iter()
+                .filter(|(_, tx)| {
+                    tx.input
+                        .iter()
+                        .filter(|txin| has_prevout(txin))
+                        .any(|txin| {
+                            let prevout = &txin.previous_output;
+                            !confirmed_txos.contains_key(prevout)
+                                && txs.get(&prevout.txid).map_or(true, |parent| {
+                                    parent.output.len() <= prevout.vout as usize
+                                })
+                        })
+                })
+                .map(|(txid, _)| *txid)
+                .collect();
+            if missing_inputs.is_empty() {
+                break;
+            }
+            for txid in missing_inputs {
+                txs.remove(&txid);
+            }
+        }
+
+        info!(
+            "loaded {} transactions from the mempool snapshot",
+            txs.len()
+        );
+        self.add(txs.into_iter().map(|(_, tx)| tx).collect());
+    }
+
     pub fn add_by_txid(&mut self, daemon: &Daemon, txid: &Sha256dHash) {
         if let Ok(tx) = daemon.getmempooltx(&txid) {
             self.add(vec![tx])
         }
     }
 
+    // Add transactions announced by bitcoind (e.g. over ZMQ), in announcement order. Transactions
+    // that are already known or whose inputs can't be resolved yet are left to the next update().
+    // bitcoind announces the transactions of the connected blocks too, the coinbases and the ones
+    // already confirmed in the index are skipped. Those confirmed by a block that isn't indexed
+    // yet may still be added, these are removed by the update() that follows its indexing.
+    pub fn add_announced(&mut self, txs: Vec<Transaction>) {
+        for tx in txs {
+            let txid = tx.txid();
+            if tx.is_coin_base()
+                || self.txstore.contains_key(&txid)
+                || self.chain.tx_confirming_block(&txid).is_some()
+            {
+                continue;
+            }
+            let prevouts = tx
+                .input
+                .iter()
+                .filter(|txin| has_prevout(txin))
+                .map(|txin| txin.previous_output)
+                .collect();
+            match self.lookup_txos(&prevouts) {
+                Ok(_) => self.add(vec![tx]),
+                Err(err) => debug!("skipping announced tx {}: {}", txid, err),
+            }
+        }
+    }
+
     fn add(&mut self, txs: Vec<Transaction>) {
         self.delta
             .with_label_values(&["add"])
             .observe(txs.len() as f64);
         let _timer = self.latency.with_label_values(&["add"]).start_timer();
+        if !txs.is_empty() {
+            self.sequence += 1;
+        }
 
         let mut txids = vec![];
+        let mut replaced = vec![];
+        let mut changed_packages = HashSet::new();
         // Phase 1: add to txstore
         for tx in txs {
             let txid = tx.txid();
@@ -271,6 +724,7 @@ impl Mempool {
             }
         };
         for txid in txids {
+            changed_packages.insert(txid);
             let tx = self.txstore.get(&txid).expect("missing mempool tx");
             let txid_bytes = full_hash(&txid[..]);
 
@@ -313,6 +767,8 @@ impl Mempool {
                         prev_txid: full_hash(&txi.previous_output.txid[..]),
                         prev_vout: txi.previous_output.vout as u16,
                         value: prevout.value,
+                        #[cfg(feature = "liquid")]
+                        asset: prevout.asset,
                     }),
                 )
             });
@@ -330,6 +786,8 @@ impl Mempool {
                             txid: txid_bytes,
                             vout: index as u16,
                             value: txo.value,
+                            #[cfg(feature = "liquid")]
+                            asset: txo.asset,
                         }),
                     )
                 });
@@ -342,34 +800,161 @@ impl Mempool {
                     .push(entry);
             }
             for (i, txi) in tx.input.iter().enumerate() {
-                self.edges.insert(txi.previous_output, (txid, i as u32));
+                // a conflicting spend replaces the earlier transaction
+                match self.edges.insert(txi.previous_output, (txid, i as u32)) {
+                    Some((spending_txid, _)) if spending_txid != txid => {
+                        replaced.push((spending_txid, txid))
+                    }
+                    _ => (),
+                }
+            }
+        }
+        self.record_replacements(replaced);
+
+        let mut related = HashSet::new();
+        for txid in &changed_packages {
+            related.extend(self.ancestors(txid));
+            related.extend(self.descendants(txid));
+        }
+        changed_packages.extend(related);
+        self.update_packages(changed_packages);
+    }
+
+    // Recompute the package totals of the transactions (removing the ones that left the mempool),
+    // then the effective feerates that depend on them, i.e. of the transactions and their ancestors
+    fn update_packages(&mut self, txids: HashSet<Sha256dHash>) {
+        let _timer = self
+            .latency
+            .with_label_values(&["update_packages"])
+            .start_timer();
+        let mut affected = HashSet::new();
+        for txid in txids {
+            if !self.txstore.contains_key(&txid) {
+                self.packages.remove(&txid);
+                continue;
+            }
+            let package = self.compute_package(&txid);
+            self.packages.insert(txid, package);
+            affected.extend(self.ancestors(&txid));
+            affected.insert(txid);
+        }
+        for txid in affected {
+            let effective_feerate = self.compute_effective_feerate(&txid);
+            if let Some(package) = self.packages.get_mut(&txid) {
+                package.effective_feerate = effective_feerate;
+            }
+        }
+    }
+
+    fn compute_package(&self, txid: &Sha256dHash) -> PackageInfo {
+        let totals = |mut txids: HashSet<Sha256dHash>| {
+            txids.insert(*txid);
+            txids
+                .iter()
+                .filter_map(|txid| self.feeinfo.get(txid))
+                .fold((0, 0, 0), |(count, vsize, fee), info| {
+                    (count + 1, vsize + info.vsize, fee + info.fee)
+                })
+        };
+        let (ancestor_count, ancestor_size, ancestor_fee) = totals(self.ancestors(txid));
+        let (descendant_count, descendant_size, descendant_fee) = totals(self.descendants(txid));
+        PackageInfo {
+            ancestor_count,
+            ancestor_size,
+            ancestor_fee,
+            descendant_count,
+            descendant_size,
+            descendant_fee,
+            effective_feerate: 0.0, // set once all the packages are up to date
+        }
+    }
+
+    // A transaction is mined along with its ancestors, at the lower of its own feerate and theirs,
+    // unless a descendant pays for it (CPFP) and gets it mined at the descendant's package feerate
+    fn compute_effective_feerate(&self, txid: &Sha256dHash) -> f32 {
+        let ancestor_feerate =
+            |txid: &Sha256dHash| self.packages.get(txid).map(PackageInfo::ancestor_feerate);
+        let own_feerate = self
+            .feeinfo
+            .get(txid)
+            .map_or(0.0, |info| info.fee_per_vbyte);
+        let mining_feerate = ancestor_feerate(txid).map_or(own_feerate, |f| f.min(own_feerate));
+        self.descendants(txid)
+            .iter()
+            .filter_map(ancestor_feerate)
+            .fold(mining_feerate, f32::max)
+    }
+
+    fn record_replacements(&mut self, replaced: Vec<(Sha256dHash, Sha256dHash)>) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        for (txid, replacement) in replaced {
+            match self.replaced_by.insert(txid, (replacement, now)) {
+                Some((earlier, _)) if earlier == replacement => continue, // several conflicts
+                Some(_) => (),
+                None => self.replaced_order.push_back(txid),
+            }
+            debug!("{} replaced by {}", txid, replacement);
+            self.replaces
+                .entry(replacement)
+                .or_insert_with(Vec::new)
+                .push(txid);
+        }
+        while self.replaced_order.len() > REPLACEMENTS_SIZE {
+            let txid = self.replaced_order.pop_front().unwrap();
+            if let Some((replacement, _)) = self.replaced_by.remove(&txid) {
+                let replaces = self.replaces.get_mut(&replacement).unwrap();
+                replaces.retain(|replaced| *replaced != txid);
+                if replaces.is_empty() {
+                    self.replaces.remove(&replacement);
+                }
             }
         }
     }
 
     pub fn lookup_txos(&self, outpoints: &BTreeSet<OutPoint>) -> Result<HashMap<OutPoint, TxOut>> {
+        let txos = self.lookup_avail_txos(outpoints);
+        if let Some(outpoint) = outpoints.iter().find(|o| !txos.contains_key(o)) {
+            bail!("missing outpoint {:?}", outpoint);
+        }
+        Ok(txos)
+    }
+
+    // Like lookup_txos(), but skips the outpoints that are neither confirmed nor in the mempool
+    pub fn lookup_avail_txos(&self, outpoints: &BTreeSet<OutPoint>) -> HashMap<OutPoint, TxOut> {
         let _timer = self
             .latency
             .with_label_values(&["lookup_txos"])
             .start_timer();
 
         let confirmed_txos = self.chain.lookup_avail_txos(outpoints);
-
-        let mempool_txos = outpoints
+        let missing: BTreeSet<OutPoint> = outpoints
             .iter()
             .filter(|outpoint| !confirmed_txos.contains_key(outpoint))
-            .map(|outpoint| {
+            .cloned()
+            .collect();
+
+        let mut txos = confirmed_txos;
+        txos.extend(self.lookup_unconfirmed_txos(&missing));
+        txos
+    }
+
+    // The outputs of the mempool transactions only, without any DB reads
+    pub fn lookup_unconfirmed_txos(
+        &self,
+        outpoints: &BTreeSet<OutPoint>,
+    ) -> HashMap<OutPoint, TxOut> {
+        outpoints
+            .iter()
+            .filter_map(|outpoint| {
                 self.txstore
                     .get(&outpoint.txid)
                     .and_then(|tx| tx.output.get(outpoint.vout as usize).cloned())
                     .map(|txout| (*outpoint, txout))
-                    .chain_err(|| format!("missing outpoint {:?}", outpoint))
             })
-            .collect::<Result<HashMap<OutPoint, TxOut>>>()?;
-
-        let mut txos = confirmed_txos;
-        txos.extend(mempool_txos);
-        Ok(txos)
+            .collect()
     }
 
     fn get_prevouts(&self, txids: &[Sha256dHash]) -> BTreeSet<OutPoint> {
@@ -390,6 +975,16 @@ impl Mempool {
             .with_label_values(&["remove"])
             .observe(to_remove.len() as f64);
         let _timer = self.latency.with_label_values(&["remove"]).start_timer();
+        if !to_remove.is_empty() {
+            self.sequence += 1;
+        }
+        // the packages of the remaining ancestors and descendants shrink
+        let mut changed_packages = HashSet::new();
+        for txid in &to_remove {
+            changed_packages.insert(**txid);
+            changed_packages.extend(self.ancestors(txid));
+            changed_packages.extend(self.descendants(txid));
+        }
 
         for txid in &to_remove {
             self.txstore
@@ -410,15 +1005,123 @@ impl Mempool {
 
         self.edges
             .retain(|_outpoint, (txid, _vin)| !to_remove.contains(txid));
+
+        self.update_packages(changed_packages);
+    }
+}
+
+// Package counts include the transaction itself, sizes are in virtual bytes
+#[derive(Serialize)]
+pub struct AncestryInfo {
+    pub ancestor_count: usize,
+    pub ancestor_size: u32,
+    pub descendant_count: usize,
+    pub descendant_size: u32,
+    // whether bitcoind would currently accept another child spending this tx
+    pub accepts_child: bool,
+    pub ancestor_count_remaining: usize,
+    pub ancestor_size_remaining: u32,
+    pub descendant_count_remaining: usize,
+    pub descendant_size_remaining: u32,
+}
+
+impl AncestryInfo {
+    // Each of the (count, vsize) pairs includes the transaction itself. `max_ancestor_descendants`
+    // is the largest descendant package of the transaction's ancestors (or of itself).
+    fn new(
+        ancestors: (usize, u32),
+        descendants: (usize, u32),
+        max_ancestor_descendants: (usize, u32),
+    ) -> Self {
+        let (ancestor_count, ancestor_size) = ancestors;
+        let (descendant_count, descendant_size) = descendants;
+        let (max_descendant_count, max_descendant_size) = max_ancestor_descendants;
+
+        let ancestor_count_remaining = ANCESTOR_LIMIT_COUNT.saturating_sub(ancestor_count);
+        let ancestor_size_remaining = ANCESTOR_LIMIT_VSIZE.saturating_sub(ancestor_size);
+        let descendant_count_remaining =
+            DESCENDANT_LIMIT_COUNT.saturating_sub(max_descendant_count);
+        let descendant_size_remaining = DESCENDANT_LIMIT_VSIZE.saturating_sub(max_descendant_size);
+
+        AncestryInfo {
+            ancestor_count,
+            ancestor_size,
+            descendant_count,
+            descendant_size,
+            // the child itself counts towards every limit, so all of them need some headroom
+            accepts_child: ancestor_count_remaining > 0
+                && ancestor_size_remaining > 0
+                && descendant_count_remaining > 0
+                && descendant_size_remaining > 0,
+            ancestor_count_remaining,
+            ancestor_size_remaining,
+            descendant_count_remaining,
+            descendant_size_remaining,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Replacement {
+    pub txid: Sha256dHash,
+    pub replaced_at: Option<u64>, // unix timestamp, unless it's the latest replacement
+    pub in_mempool: bool,
+    pub confirmed: bool,
+    pub replaces: Vec<Replacement>,
+}
+
+impl Replacement {
+    // The transaction of the tree that made it into the chain, which may be one of the replaced
+    // ones (e.g. if the replacement didn't reach the miners)
+    pub fn confirmed_txid(&self) -> Option<Sha256dHash> {
+        if self.confirmed {
+            return Some(self.txid);
+        }
+        self.replaces.iter().find_map(Replacement::confirmed_txid)
+    }
+}
+
+// Totals of the transaction with its in-mempool ancestors and descendants (including itself),
+// like bitcoind's mempool entries. Sizes are in virtual bytes.
+#[derive(Serialize)]
+pub struct PackageInfo {
+    pub ancestor_count: usize,
+    pub ancestor_size: u32,
+    pub ancestor_fee: u64,
+    pub descendant_count: usize,
+    pub descendant_size: u32,
+    pub descendant_fee: u64,
+    pub effective_feerate: f32, // in sat/vbyte
+}
+
+impl PackageInfo {
+    pub fn ancestor_feerate(&self) -> f32 {
+        self.ancestor_fee as f32 / self.ancestor_size as f32
     }
 }
 
+#[derive(Serialize)]
+pub struct TemplatePackage {
+    pub txids: Vec<Sha256dHash>,
+    pub package_feerate: f32, // in sat/vbyte
+    pub total_weight: usize,
+    pub total_fee: u64,
+}
+
+#[derive(Serialize)]
+pub struct TxFeerate {
+    pub txid: Sha256dHash,
+    pub feerate: f32, // in sat/vbyte
+}
+
 #[derive(Serialize)]
 pub struct BacklogStats {
     pub count: u32,
     pub vsize: u32,     // in virtual bytes (= weight/4)
     pub total_fee: u64, // in satoshis
     pub fee_histogram: Vec<(f32, u32)>,
+    pub feerate_histogram: Vec<(f32, u32)>, // vsize per fixed feerate bucket
+    pub congestion: CongestionStats,
 }
 
 impl BacklogStats {
@@ -428,12 +1131,14 @@ impl BacklogStats {
             vsize: 0,
             total_fee: 0,
             fee_histogram: vec![(0.0, 0)],
+            feerate_histogram: make_feerate_histogram(vec![]),
+            congestion: CongestionStats::new(vec![], None),
         }
     }
 
-    fn new(feeinfo: &HashMap<Sha256dHash, TxFeeInfo>) -> Self {
-        let (count, vsize, total_fee) = feeinfo
-            .values()
+    fn new(entries: &[TxFeeInfo], earlier_congestion: Option<&CongestionStats>) -> Self {
+        let (count, vsize, total_fee) = entries
+            .iter()
             .fold((0, 0, 0), |(count, vsize, fee), feeinfo| {
                 (count + 1, vsize + feeinfo.vsize, fee + feeinfo.fee)
             });
@@ -442,7 +1147,36 @@ impl BacklogStats {
             count,
             vsize,
             total_fee,
-            fee_histogram: make_fee_histogram(feeinfo.values().collect()),
+            fee_histogram: make_fee_histogram(entries.iter().collect()),
+            feerate_histogram: make_feerate_histogram(entries.iter().collect()),
+            congestion: CongestionStats::new(entries.iter().collect(), earlier_congestion),
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct CongestionStats {
+    pub time: u64,               // unix timestamp
+    pub score: u8,               // from 0 (not congested) to 100
+    pub excess_vsize: u32,       // vsize that doesn't fit in the next block
+    pub next_block_feerate: f32, // lowest feerate that makes it into the next block
+    pub fee_trend: f32,          // next_block_feerate relative to the oldest sample
+}
+
+impl CongestionStats {
+    fn new(entries: Vec<&TxFeeInfo>, earlier: Option<&CongestionStats>) -> Self {
+        let (next_block_feerate, excess_vsize) = next_block_cutoff(entries);
+        let fee_trend = earlier.map_or(1.0, |earlier| {
+            next_block_feerate / earlier.next_block_feerate
+        });
+        CongestionStats {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            score: congestion_score(excess_vsize, fee_trend),
+            excess_vsize,
+            next_block_feerate,
+            fee_trend,
         }
     }
 }
@@ -453,3 +1187,31 @@ fn get_entry_txid(entry: &TxHistoryInfo) -> Sha256dHash {
         TxHistoryInfo::Spending(info) => parse_hash(&info.txid),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::AncestryInfo;
+
+    #[test]
+    fn test_ancestry_accepts_child() {
+        let info = AncestryInfo::new((1, 200), (1, 200), (1, 200));
+        assert!(info.accepts_child);
+        assert_eq!(info.ancestor_count_remaining, 24);
+        assert_eq!(info.descendant_size_remaining, 100_800);
+
+        // the count limits
+        assert!(!AncestryInfo::new((25, 5_000), (1, 200), (1, 200)).accepts_child);
+        assert!(!AncestryInfo::new((2, 400), (1, 200), (25, 5_000)).accepts_child);
+
+        // the size limits, with the counts well within theirs
+        let info = AncestryInfo::new((2, 101_000), (1, 200), (2, 101_000));
+        assert!(!info.accepts_child);
+        assert_eq!(info.ancestor_count_remaining, 23);
+        assert_eq!(info.ancestor_size_remaining, 0);
+        assert!(!AncestryInfo::new((2, 400), (1, 200), (3, 120_000)).accepts_child);
+        assert_eq!(
+            AncestryInfo::new((2, 400), (1, 200), (3, 120_000)).descendant_size_remaining,
+            0
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::errors::*;
+
+// The txstore database key of the index metadata
+pub const METADATA_KEY: &[u8] = b"I";
+// The format of the metadata row, stored ahead of it (to be bumped with any change to its fields)
+const METADATA_VERSION: u16 = 1;
+
+// Recorded when the index is created, so that it doesn't get reused with another network or an
+// incompatible build (which would silently mix up their rows)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexMetadata {
+    pub created_at: Option<u64>, // unix time, unknown for indexes created by earlier versions
+    pub version: String,         // of electrs
+    pub network: String,
+    pub features: Vec<String>, // the cargo features changing the rows format
+    pub options: Vec<String>,  // the indexing options that were enabled
+    pub genesis_hash: Option<Sha256dHash>, // recorded once bitcoind is first queried
+}
+
+impl IndexMetadata {
+    // The metadata of an index created with this build and configuration
+    pub fn new(config: &Config, created: bool) -> Self {
+        let created_at = if created {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            Some(now.as_secs())
+        } else {
+            None
+        };
+        IndexMetadata {
+            created_at,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            network: config.network_type.name().to_string(),
+            features: index_features(),
+            options: index_options(config),
+            genesis_hash: None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&(METADATA_VERSION, self)).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let version: u16 =
+            bincode::deserialize(bytes).chain_err(|| "failed to parse index metadata")?;
+        if version != METADATA_VERSION {
+            bail!(
+                "the index metadata has version {}, this build only reads version {} (reindex)",
+                version,
+                METADATA_VERSION
+            );
+        }
+        let (_, metadata): (u16, IndexMetadata) =
+            bincode::deserialize(bytes).chain_err(|| "failed to parse index metadata")?;
+        Ok(metadata)
+    }
+
+    pub fn check_compatible(&self, current: &IndexMetadata) -> Result<()> {
+        if self.network != current.network {
+            bail!(
+                "the index was created for {}, it can't be used for {} (use another --db-dir)",
+                self.network,
+                current.network
+            );
+        }
+        if self.features != current.features {
+            bail!(
+                "the index was created by a build with features [{}], this one has [{}]",
+                self.features.join(", "),
+                current.features.join(", ")
+            );
+        }
+        if self.options != current.options {
+            // the rows of these options are only missing for the blocks indexed without them
+            warn!(
+                "the index was created with [{}], now running with [{}]",
+                self.options.join(", "),
+                current.options.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+fn index_features() -> Vec<String> {
+    let mut features = vec![];
+    if cfg!(feature = "liquid") {
+        features.push("liquid".to_string());
+    }
+    features
+}
+
+fn index_options(config: &Config) -> Vec<String> {
+    let mut options = vec![];
+    if config.utxo_index {
+        options.push("utxo-index");
+    }
+    if config.utxo_commitment_interval.is_some() {
+        options.push("utxo-commitments");
+    }
+    #[cfg(not(feature = "liquid"))]
+    {
+        if config.block_filters {
+            options.push("block-filters");
+        }
+    }
+    if config.light_mode {
+        options.push("light-mode");
+    }
+    options.into_iter().map(String::from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(network: &str, features: Vec<&str>, options: Vec<&str>) -> IndexMetadata {
+        IndexMetadata {
+            created_at: Some(1_600_000_000),
+            version: "0.4.1".to_string(),
+            network: network.to_string(),
+            features: features.into_iter().map(String::from).collect(),
+            options: options.into_iter().map(String::from).collect(),
+            genesis_hash: None,
+        }
+    }
+
+    #[test]
+    fn test_bytes() {
+        let created = metadata("mainnet", vec![], vec!["utxo-index"]);
+        let bytes = created.to_bytes();
+        assert_eq!(IndexMetadata::from_bytes(&bytes).unwrap(), created);
+
+        // another version isn't read as this one
+        let mut other = bytes.clone();
+        other[0] = 2;
+        assert!(IndexMetadata::from_bytes(&other).is_err());
+        assert!(IndexMetadata::from_bytes(&bytes[..1]).is_err());
+    }
+
+    #[test]
+    fn test_check_compatible() {
+        let created = metadata("mainnet", vec![], vec!["utxo-index"]);
+        assert!(created.check_compatible(&created).is_ok());
+        // options can be changed, the rows are only missing for the earlier blocks
+        assert!(created
+            .check_compatible(&metadata("mainnet", vec![], vec![]))
+            .is_ok());
+        assert!(created
+            .check_compatible(&metadata("testnet", vec![], vec!["utxo-index"]))
+            .is_err());
+        assert!(created
+            .check_compatible(&metadata("mainnet", vec!["liquid"], vec!["utxo-index"]))
+            .is_err());
+    }
+}
@@ -1,14 +1,28 @@
+#[cfg(feature = "liquid")]
+mod asset;
 mod db;
 mod fetch;
 mod mempool;
+mod metadata;
 pub mod precache;
+mod profile;
 mod query;
 mod schema;
+#[cfg(not(feature = "liquid"))]
+mod utxo_snapshot;
 
+pub use self::db::{DBFlush, DBRow, DB};
 pub use self::fetch::{BlockEntry, FetchFrom};
 pub use self::mempool::Mempool;
-pub use self::query::Query;
+pub use self::metadata::IndexMetadata;
+pub use self::query::{Query, Snapshot};
 pub use self::schema::{
-    compute_script_hash, parse_hash, ChainQuery, FundingInfo, Indexer, ScriptStats, SpendingInfo,
-    SpendingInput, Store, TxHistoryInfo, TxHistoryKey, Utxo,
+    compute_script_hash, parse_hash, ChainQuery, FundingInfo, HistoryCursor, Indexer, ScriptStats,
+    SpendingInfo, SpendingInput, Store, TxHistoryInfo, TxHistoryKey, Utxo,
 };
+
+#[cfg(not(feature = "liquid"))]
+pub use self::schema::AdoptionPeriod;
+
+#[cfg(feature = "liquid")]
+pub use self::asset::{AssetRegistry, Issuance, IssuanceInfo};
@@ -1,7 +1,8 @@
-use crate::chain::address::Address;
+use crate::chain::AddressParams;
 use crate::errors::*;
 use crate::new_index::ChainQuery;
-use crate::util::{full_hash, FullHash};
+use crate::signal::Waiter;
+use crate::util::{full_hash, spawn_thread, FullHash};
 
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
@@ -11,12 +12,36 @@ use hex;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
-use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-pub fn precache(chain: &ChainQuery, scripthashes: Vec<FullHash>) {
+#[cfg(not(feature = "liquid"))]
+use crate::util::address_to_script;
+#[cfg(feature = "liquid")]
+use {crate::chain::address::Address, std::str::FromStr};
+
+// Stops early on shutdown, skipping the remaining scripthashes
+pub fn precache(chain: &ChainQuery, scripthashes: Vec<FullHash>, signal: &Waiter) -> Result<()> {
     let total = scripthashes.len();
     info!("Pre-caching stats and utxo set for {} scripthashes", total);
 
+    // the signals are watched by a single thread, rather than polled by every worker (each poll
+    // spawning a timer thread)
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let done = Arc::new(AtomicBool::new(false));
+    let watcher = {
+        let (signal, interrupted, done) = (signal.clone(), interrupted.clone(), done.clone());
+        spawn_thread("precache-signal", move || {
+            while !done.load(Ordering::SeqCst) {
+                if signal.wait(Duration::from_millis(100)).is_err() {
+                    interrupted.store(true, Ordering::SeqCst);
+                    break;
+                }
+            }
+        })
+    };
+
     let pool = rayon::ThreadPoolBuilder::new()
         .num_threads(16)
         .thread_name(|i| format!("precache-{}", i))
@@ -27,6 +52,9 @@ pub fn precache(chain: &ChainQuery, scripthashes: Vec<FullHash>) {
             .par_iter()
             .enumerate()
             .for_each(|(i, scripthash)| {
+                if interrupted.load(Ordering::SeqCst) {
+                    return;
+                }
                 if i % 5 == 0 {
                     debug!("Starting pre-cache for scripthash {}/{}", i + 1, total);
                 }
@@ -34,9 +62,12 @@ pub fn precache(chain: &ChainQuery, scripthashes: Vec<FullHash>) {
                 //chain.utxo(&scripthash[..]);
             })
     });
+    done.store(true, Ordering::SeqCst);
+    watcher.join().unwrap();
+    signal.poll()
 }
 
-pub fn scripthashes_from_file(path: String) -> Result<Vec<FullHash>> {
+pub fn scripthashes_from_file(path: String, params: &AddressParams) -> Result<Vec<FullHash>> {
     let reader =
         io::BufReader::new(File::open(path).chain_err(|| "cannot open precache scripthash file")?);
     reader
@@ -44,14 +75,14 @@ pub fn scripthashes_from_file(path: String) -> Result<Vec<FullHash>> {
         .map(|line| {
             let line = line.chain_err(|| "cannot read scripthash line")?;
             let cols: Vec<&str> = line.split(",").collect();
-            to_scripthash(cols[0], cols[1])
+            to_scripthash(cols[0], cols[1], params)
         })
         .collect()
 }
 
-fn to_scripthash(script_type: &str, script_str: &str) -> Result<FullHash> {
+fn to_scripthash(script_type: &str, script_str: &str, params: &AddressParams) -> Result<FullHash> {
     match script_type {
-        "address" => address_to_scripthash(script_str),
+        "address" => address_to_scripthash(script_str, params),
         "scripthash" => Ok(full_hash(
             &hex::decode(script_str).chain_err(|| "invalid hex")?,
         )),
@@ -62,9 +93,15 @@ fn to_scripthash(script_type: &str, script_str: &str) -> Result<FullHash> {
     }
 }
 
-fn address_to_scripthash(addr: &str) -> Result<FullHash> {
-    let addr = Address::from_str(addr).chain_err(|| "invalid address")?;
-    Ok(compute_script_hash(&addr.script_pubkey().as_bytes()))
+#[allow(unused_variables)] // `params` is unused in liquid mode
+fn address_to_scripthash(addr: &str, params: &AddressParams) -> Result<FullHash> {
+    #[cfg(not(feature = "liquid"))]
+    let script = address_to_script(addr, params).chain_err(|| "invalid address")?;
+    #[cfg(feature = "liquid")]
+    let script = Address::from_str(addr)
+        .chain_err(|| "invalid address")?
+        .script_pubkey();
+    Ok(compute_script_hash(&script.as_bytes()))
 }
 
 pub fn compute_script_hash(data: &[u8]) -> FullHash {
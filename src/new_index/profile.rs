@@ -0,0 +1,197 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+use crate::metrics::HistogramTimer;
+
+const PROFILE_WINDOW: usize = 10_000; // blocks per row of the report
+
+// The report columns, in the order blocks go through them
+const STAGES: &[(&str, &str)] = &[
+    (
+        "add_fetch",
+        "waiting for the blocks to add (from bitcoind or blk*.dat files)",
+    ),
+    (
+        "parse",
+        "reading and parsing blk*.dat files (in the reader threads, concurrently)",
+    ),
+    (
+        "add_process",
+        "building the transaction rows (incl. txids hashing)",
+    ),
+    ("add_write", "writing the transaction rows"),
+    ("index_fetch", "waiting for the blocks to index"),
+    ("index_lookup", "looking up the spent outputs"),
+    (
+        "index_process",
+        "building the history rows (incl. scripts hashing)",
+    ),
+    ("index_utxo_changes", "building the utxo index rows"),
+    (
+        "index_utxo_commitments",
+        "building the utxo commitments rows",
+    ),
+    ("index_block_filters", "building the block filter rows"),
+    ("index_write", "writing the history rows"),
+];
+
+// Time spent per indexing stage and per window of block heights during the initial sync, written
+// as a report once it's done (enabled with --index-profile)
+pub struct IndexProfile {
+    windows: Mutex<BTreeMap<usize, BTreeMap<&'static str, Duration>>>, // window start => stages
+    other: Mutex<BTreeMap<&'static str, Duration>>, // stages that aren't per block
+    started: Instant,
+}
+
+impl IndexProfile {
+    pub fn new() -> Self {
+        IndexProfile {
+            windows: Mutex::new(BTreeMap::new()),
+            other: Mutex::new(BTreeMap::new()),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn record(&self, stage: &'static str, height: usize, duration: Duration) {
+        let window = height - height % PROFILE_WINDOW;
+        let mut windows = self.windows.lock().unwrap();
+        *windows
+            .entry(window)
+            .or_insert_with(BTreeMap::new)
+            .entry(stage)
+            .or_insert_with(Duration::default) += duration;
+    }
+
+    pub fn record_other(&self, stage: &'static str, duration: Duration) {
+        *self
+            .other
+            .lock()
+            .unwrap()
+            .entry(stage)
+            .or_insert_with(Duration::default) += duration;
+    }
+
+    pub fn report(&self) -> String {
+        let windows = self.windows.lock().unwrap();
+        let stages: Vec<&(&str, &str)> = STAGES
+            .iter()
+            .filter(|(stage, _)| windows.values().any(|w| w.contains_key(stage)))
+            .collect();
+        let mut totals: BTreeMap<&str, Duration> = BTreeMap::new();
+
+        let mut report = String::new();
+        write!(report, "{:>17}", "blocks").unwrap();
+        for (stage, _) in &stages {
+            write!(report, " {:>w$}", stage, w = column_width(stage)).unwrap();
+        }
+        report.push('\n');
+        for (window, durations) in windows.iter() {
+            let range = format!("{}-{}", window, window + PROFILE_WINDOW - 1);
+            write!(report, "{:>17}", range).unwrap();
+            for (stage, _) in &stages {
+                let duration = durations.get(stage).cloned().unwrap_or_default();
+                *totals.entry(*stage).or_insert_with(Duration::default) += duration;
+                write!(report, " {:>w$}", secs(duration), w = column_width(stage)).unwrap();
+            }
+            report.push('\n');
+        }
+        write!(report, "{:>17}", "total").unwrap();
+        for (stage, _) in &stages {
+            let total = totals.get(stage).cloned().unwrap_or_default();
+            write!(report, " {:>w$}", secs(total), w = column_width(stage)).unwrap();
+        }
+        report.push_str("\n\n");
+
+        for (stage, description) in &stages {
+            writeln!(report, "{}: {}", stage, description).unwrap();
+        }
+        for (stage, duration) in self.other.lock().unwrap().iter() {
+            writeln!(report, "{}: {}s", stage, secs(*duration)).unwrap();
+        }
+        writeln!(report, "elapsed: {}s", secs(self.started.elapsed())).unwrap();
+        report
+    }
+
+    pub fn write_report(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.report())
+            .chain_err(|| format!("failed to write index profile to {}", path.display()))
+    }
+}
+
+// Times a stage of indexing a batch of blocks, for the metrics and the profile (if enabled)
+pub struct StageTimer<'a> {
+    _timer: HistogramTimer,
+    profile: Option<(&'a IndexProfile, &'static str, usize, Instant)>,
+}
+
+impl<'a> StageTimer<'a> {
+    pub fn new(
+        timer: HistogramTimer,
+        profile: Option<&'a IndexProfile>,
+        stage: &'static str,
+        height: usize,
+    ) -> Self {
+        StageTimer {
+            _timer: timer,
+            profile: profile.map(|profile| (profile, stage, height, Instant::now())),
+        }
+    }
+}
+
+impl<'a> Drop for StageTimer<'a> {
+    fn drop(&mut self) {
+        if let Some((profile, stage, height, started)) = self.profile.take() {
+            profile.record(stage, height, started.elapsed());
+        }
+    }
+}
+
+fn column_width(stage: &str) -> usize {
+    stage.len().max(8)
+}
+
+fn secs(duration: Duration) -> String {
+    format!(
+        "{:.1}",
+        duration.as_secs() as f64 + f64::from(duration.subsec_millis()) / 1000.0
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_report() {
+        let profile = IndexProfile::new();
+        profile.record("add_process", 0, Duration::from_millis(1500));
+        profile.record("add_process", 9_999, Duration::from_millis(500));
+        profile.record("add_write", 10_000, Duration::from_secs(3));
+        profile.record_other("compaction", Duration::from_secs(60));
+
+        let report = profile.report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines[0].split_whitespace().collect::<Vec<_>>(),
+            vec!["blocks", "add_process", "add_write"]
+        );
+        assert_eq!(
+            lines[1].split_whitespace().collect::<Vec<_>>(),
+            vec!["0-9999", "2.0", "0.0"]
+        );
+        assert_eq!(
+            lines[2].split_whitespace().collect::<Vec<_>>(),
+            vec!["10000-19999", "0.0", "3.0"]
+        );
+        assert_eq!(
+            lines[3].split_whitespace().collect::<Vec<_>>(),
+            vec!["total", "2.0", "3.0"]
+        );
+        assert!(report.contains("compaction: 60.0s"));
+    }
+}
@@ -1,42 +1,242 @@
+use bitcoin_hashes::hex::ToHex;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use lru::LruCache;
 use rayon::prelude::*;
 
 use std::collections::{BTreeSet, HashMap};
-use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::chain::{OutPoint, Transaction, TxOut};
-use crate::daemon::Daemon;
+use crate::config::Config;
+use crate::daemon::{Daemon, RpcCall};
 use crate::errors::*;
 use crate::new_index::{ChainQuery, Mempool, ScriptStats, SpendingInput, Utxo};
-use crate::util::{is_spendable, BlockId, Bytes, TransactionStatus};
+use crate::util::fees::{estimate_feerate_from_histogram, ConfirmationEta};
+use crate::util::{
+    full_hash, is_spendable, spawn_thread, BlockId, Bytes, FullHash, TransactionStatus,
+};
+
+#[cfg(feature = "liquid")]
+use crate::new_index::AssetRegistry;
+#[cfg(not(feature = "liquid"))]
+use crate::webhooks::{WebhookNotifier, Webhooks};
+
+const MISSING_TXS_CACHE_SIZE: usize = 10_000;
+const UNUSED_SCRIPTS_CACHE_SIZE: usize = 100_000;
+const STATUS_HASH_CACHE_SIZE: usize = 100_000;
+// longer than the interval between the mempool syncs (5 seconds), as a sync may fail right after
+// a block (when the transactions it confirmed can't be fetched anymore) and only the next one
+// catches up with the new tip
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(12);
+const SNAPSHOT_RETRY_DELAY: Duration = Duration::from_millis(200);
 
 const CONF_TARGETS: [u16; 9] = [
     2u16, 3u16, 4u16, 6u16, 10u16, 20u16, 144u16, 504u16, 1008u16,
 ];
 
+// The state of the chain and mempool that a request was evaluated against
+#[derive(Serialize, Debug, Clone)]
+pub struct Snapshot {
+    pub tip_hash: Sha256dHash,
+    pub tip_height: usize,
+    pub mempool_sequence: u64,
+}
+
+// Evaluate `chain_f` then `mempool_f` against the chain and a mempool synced to the same tip.
+// Neither runs with the mempool locked, `mempool_f` locks it for each of its reads instead, so
+// that long scans don't hold up the mempool updates. The evaluation is retried (until `timeout`)
+// if a new block got indexed or the mempool changed meanwhile.
+pub(super) fn snapshot<C, T, F, M>(
+    chain: &ChainQuery,
+    mempool: &RwLock<Mempool>,
+    timeout: Duration,
+    chain_f: F,
+    mempool_f: M,
+) -> Result<(Snapshot, T)>
+where
+    F: Fn(&ChainQuery) -> C,
+    M: Fn(C) -> T,
+{
+    let mempool_state = || {
+        let mempool = mempool.read().unwrap();
+        (mempool.synced_tip(), mempool.sequence())
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        let tip = chain.best_header();
+        let state = mempool_state();
+        // otherwise the mempool wasn't synced since the last block
+        if state.0 == Some(*tip.hash()) {
+            let result = mempool_f(chain_f(chain));
+            if chain.best_hash() == *tip.hash() && mempool_state() == state {
+                let snapshot = Snapshot {
+                    tip_hash: *tip.hash(),
+                    tip_height: tip.height(),
+                    mempool_sequence: state.1,
+                };
+                return Ok((snapshot, result));
+            }
+        }
+        if Instant::now() + SNAPSHOT_RETRY_DELAY > deadline {
+            bail!("the chain and mempool are being updated, retry later")
+        }
+        thread::sleep(SNAPSHOT_RETRY_DELAY);
+    }
+}
+
+// The Electrum status hash (the sha256 of the `{txid}:{height}:` history entries) being computed,
+// kept after hashing the confirmed history so that it can be resumed from the next block
+#[derive(Clone)]
+pub(super) struct StatusHasher {
+    sha2: Sha256,
+    empty: bool,
+    blockhash: Sha256dHash, // the tip the confirmed history was hashed up to
+}
+
+impl StatusHasher {
+    fn new(blockhash: Sha256dHash) -> Self {
+        StatusHasher {
+            sha2: Sha256::new(),
+            empty: true,
+            blockhash,
+        }
+    }
+
+    // unconfirmed transactions have a height of -1
+    fn add(&mut self, txid: &Sha256dHash, height: isize) {
+        // TODO: use height of 0 to indicate a mempool tx with confirmed inputs
+        let part = format!("{}:{}:", txid.to_hex(), height);
+        self.sha2.input(part.as_bytes());
+        self.empty = false;
+    }
+
+    pub(super) fn result(mut self) -> Option<FullHash> {
+        if self.empty {
+            return None;
+        }
+        let mut hash = FullHash::default();
+        self.sha2.result(&mut hash);
+        Some(hash)
+    }
+}
+
+// Hash the confirmed history of `scripthash` up to the tip, resuming from the `cached` hasher of
+// an earlier tip (unless its block was since orphaned)
+pub(super) fn confirmed_status_hasher(
+    chain: &ChainQuery,
+    scripthash: &[u8],
+    cached: Option<StatusHasher>,
+) -> StatusHasher {
+    let tip = chain.best_header();
+    let (mut hasher, start_height) = cached
+        .and_then(|hasher| {
+            let blockid = chain.blockid_by_hash(&hasher.blockhash)?;
+            Some((hasher, blockid.height + 1))
+        })
+        .unwrap_or_else(|| (StatusHasher::new(*tip.hash()), 0));
+
+    for (txid, blockid) in chain.history_txids_since(scripthash, start_height) {
+        if blockid.height > tip.height() {
+            break; // indexed after reading the tip, picked up on the next call
+        }
+        hasher.add(&txid, blockid.height as isize);
+    }
+    hasher.blockhash = *tip.hash();
+    hasher
+}
+
 pub struct Query {
     chain: Arc<ChainQuery>, // TODO: should be used as read-only
     mempool: Arc<RwLock<Mempool>>,
     daemon: Arc<Daemon>,
+    prevout_txs: Mutex<LruCache<Sha256dHash, Transaction>>, // fetched from the daemon
+    missing_txs: Mutex<LruCache<Sha256dHash, Sha256dHash>>, // txid => tip it was missing at
+    unused_scripts: Mutex<LruCache<FullHash, Sha256dHash>>, // scripthash => tip it was unused at
+    status_hashers: Mutex<LruCache<FullHash, StatusHasher>>, // scripthash => confirmed history
+    fee_estimates: RwLock<HashMap<u16, f32>>, // conf target => feerate, for CONF_TARGETS
+    #[cfg(feature = "liquid")]
+    asset_registry: Option<AssetRegistry>,
+    #[cfg(not(feature = "liquid"))]
+    webhooks: Option<Arc<Webhooks>>, // with --webhooks
 }
 
 impl Query {
-    pub fn new(chain: Arc<ChainQuery>, mempool: Arc<RwLock<Mempool>>, daemon: Arc<Daemon>) -> Self {
+    pub fn new(
+        chain: Arc<ChainQuery>,
+        mempool: Arc<RwLock<Mempool>>,
+        daemon: Arc<Daemon>,
+        config: &Config,
+    ) -> Self {
         Query {
             chain,
             mempool,
             daemon,
+            prevout_txs: Mutex::new(LruCache::new(config.tx_cache_size)),
+            missing_txs: Mutex::new(LruCache::new(MISSING_TXS_CACHE_SIZE)),
+            unused_scripts: Mutex::new(LruCache::new(UNUSED_SCRIPTS_CACHE_SIZE)),
+            status_hashers: Mutex::new(LruCache::new(STATUS_HASH_CACHE_SIZE)),
+            fee_estimates: RwLock::new(HashMap::new()),
+            #[cfg(feature = "liquid")]
+            asset_registry: config
+                .asset_db_path
+                .as_ref()
+                .map(|path| AssetRegistry::load(path).expect("failed to load the asset registry")),
+            #[cfg(not(feature = "liquid"))]
+            webhooks: if config.webhooks {
+                Some(Arc::new(Webhooks::open(&config.db_path.join("webhooks"))))
+            } else {
+                None
+            },
         }
     }
 
+    // Refresh the cached fee estimates in the background, after an initial update
+    pub fn start_fee_estimator(query: &Arc<Query>, interval: Duration) {
+        query.update_fee_estimates();
+        let query = Arc::clone(query);
+        spawn_thread("fee-estimator", move || loop {
+            thread::sleep(interval);
+            query.update_fee_estimates();
+        });
+    }
+
+    // Evaluate and deliver the registered webhooks in the background, whenever notified
+    #[cfg(not(feature = "liquid"))]
+    pub fn start_webhooks(query: &Arc<Query>) -> Option<WebhookNotifier> {
+        query
+            .webhooks
+            .as_ref()
+            .map(|webhooks| Webhooks::start(Arc::clone(webhooks), Arc::clone(&query.chain)))
+    }
+
     pub fn chain(&self) -> &ChainQuery {
         &self.chain
     }
 
+    // The guard blocks the mempool updates while held, so it should only be kept for in-memory
+    // lookups (copying out what's needed) and never across DB reads or daemon calls
     pub fn mempool(&self) -> RwLockReadGuard<Mempool> {
         self.mempool.read().unwrap()
     }
 
+    #[cfg(feature = "liquid")]
+    pub fn asset_registry(&self) -> Option<&AssetRegistry> {
+        self.asset_registry.as_ref()
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    pub fn webhooks(&self) -> Option<&Webhooks> {
+        self.webhooks.as_ref().map(Arc::as_ref)
+    }
+
+    pub fn rpc_trace(&self) -> Vec<RpcCall> {
+        self.daemon.rpc_trace()
+    }
+
     pub fn broadcast_raw(&self, txhex: &String) -> Result<Sha256dHash> {
         let txid = self.daemon.broadcast_raw(&txhex)?;
         self.mempool
@@ -46,20 +246,111 @@ impl Query {
         Ok(txid)
     }
 
+    // Mine blocks using the daemon's wallet (for regtest only)
+    pub fn regtest_generate(&self, blocks: u32, address: Option<&str>) -> Result<Vec<Sha256dHash>> {
+        let address = match address {
+            Some(address) => address.to_string(),
+            None => self.daemon.getnewaddress()?,
+        };
+        self.daemon.generatetoaddress(blocks, &address)
+    }
+
+    // Send funds from the daemon's wallet (for regtest only)
+    pub fn regtest_sendtoaddress(&self, address: &str, amount: u64) -> Result<Sha256dHash> {
+        let txid = self.daemon.sendtoaddress(address, amount)?;
+        self.mempool
+            .write()
+            .unwrap()
+            .add_by_txid(&self.daemon, &txid);
+        Ok(txid)
+    }
+
     pub fn utxo(&self, scripthash: &[u8]) -> Vec<Utxo> {
-        let mut utxos = self.chain.utxo(scripthash);
-        let mempool = self.mempool();
-        utxos.retain(|utxo| !mempool.has_spend(&OutPoint::from(utxo)));
-        utxos.extend(mempool.utxo(scripthash));
+        let tip = self.chain.best_hash();
+        let utxos = if self.known_unused(scripthash, &tip) {
+            vec![]
+        } else {
+            self.chain.utxo(scripthash)
+        };
+        self.mempool().apply_to_utxos(utxos, scripthash)
+    }
+
+    // Up to `limit` unspent outputs following the `after` outpoint in the `Utxo::sort_key`
+    // order (which may have been spent since). The order doesn't depend on the confirmation of
+    // the outputs, so that pages don't shift as they get confirmed. The confirmed ones are read
+    // from the UTXO set index a page at a time when it's available, instead of collecting and
+    // sorting them all.
+    pub fn utxo_page(&self, scripthash: &[u8], after: Option<OutPoint>, limit: usize) -> Vec<Utxo> {
+        let mut utxos = vec![];
+        let mut cursor = after;
+        loop {
+            let page = match self.chain.utxo_page(scripthash, cursor.as_ref(), limit) {
+                Some(page) => page,
+                None => return self.utxo_page_unindexed(scripthash, after, limit),
+            };
+            let last_page = page.len() < limit;
+            cursor = page.last().map(OutPoint::from).or(cursor);
+            // the mempool is only locked once the page was read
+            let mempool = self.mempool();
+            utxos.extend(
+                page.into_iter()
+                    .filter(|u| !mempool.has_spend(&OutPoint::from(u))),
+            );
+            if utxos.len() >= limit || last_page {
+                break;
+            }
+        }
+        merge_utxo_page(utxos, self.mempool().utxo(scripthash), after, limit)
+    }
+
+    fn utxo_page_unindexed(
+        &self,
+        scripthash: &[u8],
+        after: Option<OutPoint>,
+        limit: usize,
+    ) -> Vec<Utxo> {
+        self.page_utxos(self.utxo(scripthash), after, limit)
+    }
+
+    // Page through the `utxos`, sorted by `Utxo::sort_key`
+    pub fn page_utxos(
+        &self,
+        mut utxos: Vec<Utxo>,
+        after: Option<OutPoint>,
+        limit: usize,
+    ) -> Vec<Utxo> {
+        utxos.retain(|utxo| is_after(utxo, after));
+        utxos.truncate(limit);
         utxos
     }
 
+    // Evaluate `chain_f` then `mempool_f` (given the result of `chain_f`) against a consistent
+    // snapshot, with a mempool synced to the chain tip `chain_f` was evaluated at. See snapshot().
+    pub fn with_snapshot<C, T, F, M>(&self, chain_f: F, mempool_f: M) -> Result<(Snapshot, T)>
+    where
+        F: Fn(&ChainQuery) -> C,
+        M: Fn(C) -> T,
+    {
+        snapshot(
+            &self.chain,
+            &self.mempool,
+            SNAPSHOT_TIMEOUT,
+            chain_f,
+            mempool_f,
+        )
+    }
+
     pub fn history_txids(&self, scripthash: &[u8]) -> Vec<(Sha256dHash, Option<BlockId>)> {
-        let confirmed_txids = self
-            .chain
-            .history_txids(scripthash)
-            .into_iter()
-            .map(|(tx, b)| (tx, Some(b)));
+        let tip = self.chain.best_hash();
+        let confirmed_txids = if self.known_unused(scripthash, &tip) {
+            vec![]
+        } else {
+            self.chain.history_txids(scripthash)
+        };
+        if confirmed_txids.is_empty() && !self.chain.has_unindexed_coins(scripthash) {
+            self.set_unused(scripthash, tip);
+        }
+        let confirmed_txids = confirmed_txids.into_iter().map(|(tx, b)| (tx, Some(b)));
 
         let mempool_txids = self
             .mempool()
@@ -70,29 +361,169 @@ impl Query {
         confirmed_txids.chain(mempool_txids).collect()
     }
 
+    // Whether the scripthash has any confirmed or unconfirmed history, without reading it
+    pub fn is_used(&self, scripthash: &[u8]) -> bool {
+        if self.mempool().has_history(scripthash) {
+            return true;
+        }
+        let tip = self.chain.best_hash();
+        if self.known_unused(scripthash, &tip) {
+            return false;
+        }
+        let used = self.chain.has_history(scripthash) || self.chain.has_unindexed_coins(scripthash);
+        if !used {
+            self.set_unused(scripthash, tip);
+        }
+        used
+    }
+
     pub fn stats(&self, scripthash: &[u8]) -> (ScriptStats, ScriptStats) {
-        (
-            self.chain.stats(scripthash),
-            self.mempool().stats(scripthash),
-        )
+        self.stats_at(scripthash, std::usize::MAX)
+    }
+
+    // The confirmed stats are evaluated as of `max_height`
+    pub fn stats_at(&self, scripthash: &[u8], max_height: usize) -> (ScriptStats, ScriptStats) {
+        let tip = self.chain.best_header();
+        let chain_stats = if self.known_unused(scripthash, tip.hash()) {
+            ScriptStats::default()
+        } else {
+            let stats = self.chain.stats_at(scripthash, max_height);
+            // only unused as of the tip if none of its blocks were skipped
+            if stats.tx_count == 0
+                && max_height >= tip.height()
+                && !self.chain.has_unindexed_coins(scripthash)
+            {
+                self.set_unused(scripthash, *tip.hash());
+            }
+            stats
+        };
+        (chain_stats, self.mempool().stats(scripthash))
+    }
+
+    // The Electrum status hash of the history (None if it is empty). The confirmed part is hashed
+    // incrementally, resuming from the block it was last computed at.
+    pub fn status_hash(&self, scripthash: &[u8]) -> Option<FullHash> {
+        let cached = self
+            .status_hashers
+            .lock()
+            .unwrap()
+            .get(&full_hash(scripthash))
+            .cloned();
+        let mut hasher = confirmed_status_hasher(&self.chain, scripthash, cached);
+        self.status_hashers
+            .lock()
+            .unwrap()
+            .put(full_hash(scripthash), hasher.clone());
+
+        for txid in self.mempool().history_txids(scripthash) {
+            hasher.add(&txid, -1);
+        }
+        hasher.result()
     }
 
     pub fn lookup_txn(&self, txid: &Sha256dHash) -> Option<Transaction> {
-        self.chain
-            .lookup_txn(txid)
+        self.lookup_confirmed(txid, |txid| self.chain.lookup_txn(txid))
             .or_else(|| self.mempool().lookup_txn(txid))
     }
     pub fn lookup_raw_txn(&self, txid: &Sha256dHash) -> Option<Bytes> {
-        self.chain
-            .lookup_raw_txn(txid)
+        self.lookup_confirmed(txid, |txid| self.chain.lookup_raw_txn(txid))
             .or_else(|| self.mempool().lookup_raw_txn(txid))
     }
 
+    // Look up a confirmed transaction, skipping the DB for txids that were recently missing.
+    // Transactions only get confirmed along with a new tip, which invalidates the misses.
+    fn lookup_confirmed<T, F>(&self, txid: &Sha256dHash, lookup: F) -> Option<T>
+    where
+        F: Fn(&Sha256dHash) -> Option<T>,
+    {
+        // read before the lookup, so that a concurrently indexed block invalidates the miss
+        let tip = self.chain.best_hash();
+        if self.missing_txs.lock().unwrap().get(txid) == Some(&tip) {
+            return None;
+        }
+        let result = lookup(txid);
+        if result.is_none() {
+            self.missing_txs.lock().unwrap().put(*txid, tip);
+        }
+        result
+    }
+
+    // Whether the scripthash was recently found without any confirmed history or coins, as of
+    // the `tip` (read before the lookups, so that a concurrently indexed block invalidates it).
+    // Scanners and wallets probing fresh addresses repeatedly are then answered without reading
+    // the DB.
+    fn known_unused(&self, scripthash: &[u8], tip: &Sha256dHash) -> bool {
+        self.unused_scripts
+            .lock()
+            .unwrap()
+            .get(&full_hash(scripthash))
+            == Some(tip)
+    }
+
+    fn set_unused(&self, scripthash: &[u8], tip: Sha256dHash) {
+        self.unused_scripts
+            .lock()
+            .unwrap()
+            .put(full_hash(scripthash), tip);
+    }
+
     pub fn lookup_txos(&self, outpoints: &BTreeSet<OutPoint>) -> HashMap<OutPoint, TxOut> {
-        // the mempool lookup_txos() internally looks up confirmed txos as well
-        self.mempool()
-            .lookup_txos(outpoints)
-            .expect("failed loading txos")
+        // the confirmed txos are read before locking the mempool, which is only kept for the
+        // in-memory lookup of the remaining ones
+        let mut txos = self.chain.lookup_avail_txos(outpoints);
+        let unconfirmed: BTreeSet<OutPoint> = outpoints
+            .iter()
+            .filter(|outpoint| !txos.contains_key(outpoint))
+            .cloned()
+            .collect();
+        txos.extend(self.mempool().lookup_unconfirmed_txos(&unconfirmed));
+        let missing: BTreeSet<OutPoint> = unconfirmed
+            .into_iter()
+            .filter(|outpoint| !txos.contains_key(outpoint))
+            .collect();
+        if !missing.is_empty() {
+            match self.lookup_txos_from_daemon(&missing) {
+                Ok(daemon_txos) => txos.extend(daemon_txos),
+                Err(e) => warn!("failed loading {} txos from daemon: {}", missing.len(), e),
+            }
+        }
+        txos
+    }
+
+    // Fall back to a batched `getrawtransaction` for the txos missing from the local stores,
+    // caching the transactions for subsequent lookups
+    fn lookup_txos_from_daemon(
+        &self,
+        outpoints: &BTreeSet<OutPoint>,
+    ) -> Result<HashMap<OutPoint, TxOut>> {
+        let txids: BTreeSet<Sha256dHash> = outpoints.iter().map(|o| o.txid).collect();
+        let mut txs: HashMap<Sha256dHash, Transaction> = HashMap::new();
+        {
+            let mut cache = self.prevout_txs.lock().unwrap();
+            for txid in &txids {
+                if let Some(tx) = cache.get(txid) {
+                    txs.insert(*txid, tx.clone());
+                }
+            }
+        }
+        let to_fetch: Vec<&Sha256dHash> = txids.iter().filter(|t| !txs.contains_key(t)).collect();
+        if !to_fetch.is_empty() {
+            let fetched = self.daemon.gettransactions(&to_fetch)?;
+            let mut cache = self.prevout_txs.lock().unwrap();
+            for tx in fetched {
+                let txid = tx.txid();
+                cache.put(txid, tx.clone());
+                txs.insert(txid, tx);
+            }
+        }
+        Ok(outpoints
+            .iter()
+            .filter_map(|outpoint| {
+                txs.get(&outpoint.txid)
+                    .and_then(|tx| tx.output.get(outpoint.vout as usize))
+                    .map(|txo| (*outpoint, txo.clone()))
+            })
+            .collect())
     }
 
     pub fn lookup_spend(&self, outpoint: &OutPoint) -> Option<SpendingInput> {
@@ -121,22 +552,177 @@ impl Query {
     }
 
     pub fn get_tx_status(&self, txid: &Sha256dHash) -> TransactionStatus {
-        TransactionStatus::from(self.chain.tx_confirming_block(txid))
+        match self.chain.tx_confirming_block_pos(txid) {
+            Some((blockid, pos)) => TransactionStatus {
+                block_index: Some(pos),
+                ..TransactionStatus::from(Some(blockid))
+            },
+            None => TransactionStatus::from(None),
+        }
+    }
+
+    // Estimate the blocks until the transaction confirms from its effective feerate and the
+    // projected blocks of the mempool backlog, None if it's neither confirmed nor in the mempool
+    pub fn estimate_confirmation(&self, txid: &Sha256dHash) -> Option<ConfirmationEta> {
+        let unconfirmed = {
+            let mempool = self.mempool();
+            mempool.effective_feerate(txid).map(|feerate| {
+                ConfirmationEta::from_histogram(&mempool.backlog_stats().fee_histogram, feerate)
+            })
+        };
+        unconfirmed.or_else(|| {
+            self.chain
+                .tx_confirming_block(txid)
+                .map(|_| ConfirmationEta::confirmed())
+        })
     }
 
-    // TODO cache, only allow getting estimatess for cached items
+    // Get the cached estimate of the highest conf target up to the requested one (or the lowest)
     pub fn estimate_fee(&self, conf_target: u16) -> Option<f32> {
-        self.daemon.estimatesmartfee(conf_target).ok()
+        let conf_target = CONF_TARGETS
+            .iter()
+            .rev()
+            .find(|target| **target <= conf_target)
+            .unwrap_or(&CONF_TARGETS[0]);
+        self.fee_estimates.read().unwrap().get(conf_target).cloned()
     }
 
-    // TODO cache
     pub fn estimate_fee_targets(&self) -> HashMap<u16, f32> {
-        CONF_TARGETS
+        self.fee_estimates.read().unwrap().clone()
+    }
+
+    // Get fresh estimates from the daemon, falling back to the mempool backlog when it has none
+    // (e.g. right after startup or on regtest)
+    pub fn update_fee_estimates(&self) {
+        let daemon_estimates: Vec<(u16, Option<f32>)> = CONF_TARGETS
             .iter()
-            .filter_map(|conf_target| {
-                self.estimate_fee(*conf_target)
-                    .map(|feerate| (*conf_target, feerate))
+            .map(|conf_target| {
+                let feerate = self.daemon.estimatesmartfee(*conf_target);
+                if let Err(ref e) = feerate {
+                    debug!("no fee estimate for {} blocks: {}", conf_target, e);
+                }
+                (*conf_target, feerate.ok())
             })
-            .collect()
+            .collect();
+
+        let estimates = {
+            let mempool = self.mempool();
+            let histogram = &mempool.backlog_stats().fee_histogram;
+            daemon_estimates
+                .into_iter()
+                .map(|(conf_target, feerate)| {
+                    let feerate = feerate
+                        .unwrap_or_else(|| estimate_feerate_from_histogram(histogram, conf_target));
+                    (conf_target, feerate)
+                })
+                .collect()
+        };
+        *self.fee_estimates.write().unwrap() = estimates;
+    }
+}
+
+fn is_after(utxo: &Utxo, after: Option<OutPoint>) -> bool {
+    after.map_or(true, |outpoint| {
+        utxo.sort_key() > (outpoint.txid, outpoint.vout)
+    })
+}
+
+// Interleave the unconfirmed utxos following `after` with a page of confirmed ones, keeping the
+// confirmed one of a transaction confirmed meanwhile
+fn merge_utxo_page(
+    mut confirmed: Vec<Utxo>,
+    unconfirmed: Vec<Utxo>,
+    after: Option<OutPoint>,
+    limit: usize,
+) -> Vec<Utxo> {
+    confirmed.extend(unconfirmed.into_iter().filter(|utxo| is_after(utxo, after)));
+    // the sort is stable, so that the confirmed ones come first
+    confirmed.sort_by_key(Utxo::sort_key);
+    confirmed.dedup_by_key(|utxo| utxo.sort_key());
+    confirmed.truncate(limit);
+    confirmed
+}
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use super::merge_utxo_page;
+    use crate::chain::OutPoint;
+    use crate::new_index::Utxo;
+    use crate::util::BlockId;
+    use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+    use bitcoin_hashes::Hash;
+
+    #[test]
+    fn test_merge_utxo_page() {
+        let txid = |tag: u8| Sha256dHash::from_slice(&[tag; 32]).unwrap();
+        let utxo = |tag, vout, confirmed: bool| Utxo {
+            txid: txid(tag),
+            vout,
+            confirmed: if confirmed {
+                Some(BlockId {
+                    height: 1,
+                    hash: Sha256dHash::default(),
+                    time: 0,
+                })
+            } else {
+                None
+            },
+            value: 1000,
+        };
+        let outpoints = |utxos: &[Utxo]| {
+            utxos
+                .iter()
+                .map(|utxo| (utxo.txid, utxo.vout))
+                .collect::<Vec<_>>()
+        };
+        let unconfirmed = || vec![utxo(4, 0, false), utxo(2, 1, false), utxo(2, 0, false)];
+        // the confirmed page the UTXO set index would return after a cursor
+        let confirmed_page = |after: Option<OutPoint>, limit| {
+            vec![(1, 0), (1, 1), (3, 0)]
+                .into_iter()
+                .map(|(tag, vout)| utxo(tag, vout, true))
+                .filter(|utxo| after.map_or(true, |o| (utxo.txid, utxo.vout) > (o.txid, o.vout)))
+                .take(limit)
+                .collect::<Vec<_>>()
+        };
+
+        // page through them 2 at a time, resuming after the last outpoint of each page
+        let mut paged = vec![];
+        let mut after = None;
+        loop {
+            let page = merge_utxo_page(confirmed_page(after, 2), unconfirmed(), after, 2);
+            assert!(page.len() <= 2);
+            after = page.last().map(OutPoint::from);
+            paged.extend(page);
+            if after.is_none() {
+                break;
+            }
+        }
+        assert_eq!(
+            outpoints(&paged),
+            vec![
+                (txid(1), 0),
+                (txid(1), 1),
+                (txid(2), 0),
+                (txid(2), 1),
+                (txid(3), 0),
+                (txid(4), 0),
+            ]
+        );
+
+        // the remaining outputs of the cursor's transaction follow it, whether or not it got
+        // confirmed since the previous page
+        let after = Some(OutPoint {
+            txid: txid(2),
+            vout: 0,
+        });
+        let page = merge_utxo_page(confirmed_page(after, 10), unconfirmed(), after, 10);
+        assert_eq!(outpoints(&page)[..2], [(txid(2), 1), (txid(3), 0)]);
+        let mut confirmed_since = confirmed_page(after, 10);
+        confirmed_since.insert(0, utxo(2, 1, true));
+        let page = merge_utxo_page(confirmed_since, unconfirmed(), after, 10);
+        assert_eq!(outpoints(&page)[..2], [(txid(2), 1), (txid(3), 0)]);
+        // listed once, as confirmed
+        assert!(page[0].confirmed.is_some());
     }
 }
@@ -1,51 +1,238 @@
 use bincode;
 use bitcoin::blockdata::script::Script;
-use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::consensus::encode::{deserialize, serialize, VarInt};
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use itertools::Itertools;
 use rayon::prelude::*;
-
-use std::collections::{BTreeSet, HashMap, HashSet};
-use std::path::Path;
-use std::sync::{Arc, RwLock};
-
-use crate::chain::{BlockHeader, OutPoint, Transaction, TxOut, Value};
+use rocksdb::DBCompressionType;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::chain::{Block, BlockHeader, Network, OutPoint, Transaction, TxOut, Value};
+use crate::config::Config;
 use crate::daemon::Daemon;
 use crate::errors::*;
-use crate::metrics::{HistogramOpts, HistogramTimer, HistogramVec, Metrics};
+use crate::metrics::{
+    CounterVec, Gauge, GaugeVec, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics,
+};
+use crate::util::{
+    full_hash, has_prevout, is_coinbase, is_spendable, spawn_thread, BlockHeaderMeta, BlockId,
+    BlockMeta, BlockStatus, BlockSummary, Bytes, HeaderChecker, HeaderEntry, HeaderList, MuHash,
+};
+
+#[cfg(not(feature = "liquid"))]
 use crate::util::{
-    full_hash, has_prevout, is_spendable, BlockHeaderMeta, BlockId, BlockMeta, BlockStatus, Bytes,
-    HeaderEntry, HeaderList,
+    bip158::{block_filter, filter_header},
+    AdoptionStats, Amount, BlockStats,
 };
 
-use crate::new_index::db::{DBFlush, DBRow, ReverseScanIterator, ScanIterator, DB};
-use crate::new_index::fetch::{start_fetcher, BlockEntry, FetchFrom};
+#[cfg(feature = "liquid")]
+use crate::chain::confidential::Asset;
+#[cfg(feature = "liquid")]
+use crate::new_index::asset::{asset_rows, Issuance, IssuanceRow};
+use crate::new_index::db::{DBFlush, DBRow, Family, ReverseScanIterator, ScanIterator, DB};
+use crate::new_index::fetch::{start_fetcher, BlockEntry, FetchBatch, FetchFrom};
+use crate::new_index::metadata::{IndexMetadata, METADATA_KEY};
+use crate::new_index::profile::{IndexProfile, StageTimer};
+#[cfg(not(feature = "liquid"))]
+use crate::new_index::utxo_snapshot::{SnapshotCoin, UtxoSnapshot};
+#[cfg(feature = "liquid")]
+use bitcoin_hashes::hex::ToHex;
 
 const MIN_HISTORY_ITEMS_TO_CACHE: usize = 100;
 
+// Number of coins imported from a UTXO snapshot per write
+#[cfg(not(feature = "liquid"))]
+const UTXO_SNAPSHOT_BATCH_SIZE: usize = 100_000;
+
+// outputs with larger scripts are never added to bitcoind's UTXO set
+const MAX_SCRIPT_SIZE: usize = 10_000;
+
+#[cfg(not(feature = "liquid"))]
+const DIFFICULTY_EPOCH: usize = 2016; // blocks
+#[cfg(not(feature = "liquid"))]
+const SECONDS_PER_DAY: u32 = 86_400;
+
 pub struct Store {
-    // TODO: should be column families
     txstore_db: DB,
     history_db: DB,
     cache_db: DB,
     added_blockhashes: RwLock<HashSet<Sha256dHash>>,
     indexed_blockhashes: RwLock<HashSet<Sha256dHash>>,
     indexed_headers: RwLock<HeaderList>,
+    history_start_height: AtomicUsize, // no history rows are indexed below it
+    tx_json_count: Mutex<u64>,         // of the transactions JSON in the persistent cache
+}
+
+// The column families of the txstore database: the block rows, all loaded on startup (hashes
+// don't compress), and the transaction rows read by txid
+const TXSTORE_FAMILIES: &[Family] = &[
+    Family {
+        name: "headers",
+        prefixes: b"BXMDS",
+        block_size: 16 << 10,
+        compression: DBCompressionType::None,
+        bloom_filter: false,
+    },
+    Family {
+        name: "txs",
+        prefixes: b"TCO",
+        block_size: 32 << 10,
+        compression: DBCompressionType::Lz4,
+        bloom_filter: true,
+    },
+];
+
+// The column families of the history database: the history rows scanned by scripthash (and the
+// spending and issuance rows), the per-block stats and their rollups (and the blocks done
+// markers), the block filters and the UTXO set (with its per-block changes and snapshot coins)
+const HISTORY_FAMILIES: &[Family] = &[
+    Family {
+        name: "history",
+        prefixes: b"HSi",
+        block_size: 16 << 10,
+        compression: DBCompressionType::Snappy,
+        bloom_filter: false,
+    },
+    Family {
+        name: "stats",
+        prefixes: b"IJWALQZKD",
+        block_size: 4 << 10,
+        compression: DBCompressionType::Lz4,
+        bloom_filter: true,
+    },
+    Family {
+        name: "filters",
+        prefixes: b"GF",
+        block_size: 16 << 10,
+        compression: DBCompressionType::None, // hashed, these don't compress
+        bloom_filter: true,
+    },
+    Family {
+        name: "utxos",
+        prefixes: b"OYRTcu",
+        block_size: 16 << 10,
+        compression: DBCompressionType::Snappy,
+        bloom_filter: false,
+    },
+];
+
+// The column families of the cache database: the scripthash caches and the block stats, read by
+// key, the mempool snapshot, scanned, and the transactions JSON, read by txid
+const CACHE_FAMILIES: &[Family] = &[
+    Family {
+        name: "scripthashes",
+        prefixes: b"RUB",
+        block_size: 4 << 10,
+        compression: DBCompressionType::Lz4,
+        bloom_filter: true,
+    },
+    Family {
+        name: "mempool",
+        prefixes: b"P",
+        block_size: 16 << 10,
+        compression: DBCompressionType::Snappy,
+        bloom_filter: false,
+    },
+    Family {
+        name: "txjson",
+        prefixes: b"Jj",
+        block_size: 16 << 10,
+        compression: DBCompressionType::Lz4,
+        bloom_filter: true,
+    },
+];
+
+// The history database key of the height the history is indexed from, when pruned
+const HISTORY_START_KEY: &[u8] = b"P";
+
+// The cache database key of the number of transactions JSON in the persistent cache
+const TX_JSON_COUNT_KEY: &[u8] = b"j";
+
+// The key of each database's marker, written once compacted after its initial sync
+const COMPACTION_DONE_KEY: &[u8] = b"F";
+
+// The key of each database's indexing progress (adding the transactions to the txstore, indexing
+// the history), written along with the rows of every batch of blocks
+const PROGRESS_KEY: &[u8] = b"N";
+
+// All the new blocks up to `height` were processed, the following ones are in the blk*.dat files
+// from `blk_file` (when fetching from them)
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexProgress {
+    pub height: usize,
+    pub blk_file: Option<usize>,
+}
+
+impl IndexProgress {
+    fn new(blocks: &[BlockEntry], blk_file: Option<usize>) -> Self {
+        IndexProgress {
+            height: blocks.last().map_or(0, |b| b.entry.height()),
+            blk_file,
+        }
+    }
+
+    fn to_row(&self) -> DBRow {
+        DBRow {
+            key: PROGRESS_KEY.to_vec(),
+            value: bincode::serialize(self).unwrap(),
+        }
+    }
+
+    fn load(db: &DB) -> Option<Self> {
+        db.get(PROGRESS_KEY)
+            .map(|value| bincode::deserialize(&value).expect("failed to parse index progress"))
+    }
+}
+
+// The history database key of the UTXO snapshot the index was bootstrapped from
+const UTXO_SNAPSHOT_KEY: &[u8] = b"u";
+
+// The index has the coins of the UTXO set at `height` (with --utxo-snapshot), and only the headers
+// of the blocks up to it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UtxoSnapshotInfo {
+    pub blockhash: Sha256dHash,
+    pub height: usize,
+    pub coins: u64,
+}
+
+impl UtxoSnapshotInfo {
+    fn to_row(&self) -> DBRow {
+        DBRow {
+            key: UTXO_SNAPSHOT_KEY.to_vec(),
+            value: bincode::serialize(self).unwrap(),
+        }
+    }
 }
 
 impl Store {
     pub fn open(path: &Path) -> Self {
-        let txstore_db = DB::open(&path.join("txstore"));
+        let txstore_db = DB::open(&path.join("txstore"), TXSTORE_FAMILIES);
         let added_blockhashes = load_blockhashes(&txstore_db, &BlockRow::done_filter());
         debug!("{} blocks were added", added_blockhashes.len());
-        let history_db = DB::open(&path.join("history"));
+        let history_db = DB::open(&path.join("history"), HISTORY_FAMILIES);
         let indexed_blockhashes = load_blockhashes(&history_db, &BlockRow::done_filter());
         debug!("{} blocks were indexed", indexed_blockhashes.len());
         let headers_map = load_blockheaders(&txstore_db);
         debug!("{} headers were loaded", headers_map.len());
-        let cache_db = DB::open(&path.join("cache"));
+        let cache_db = DB::open_cache(&path.join("cache"), CACHE_FAMILIES);
+        let history_start_height = history_db
+            .get(HISTORY_START_KEY)
+            .map_or(0, |height| bincode::deserialize(&height).unwrap());
+        let tx_json_count = cache_db
+            .get(TX_JSON_COUNT_KEY)
+            .map_or(0, |count| bincode::deserialize(&count).unwrap());
         Store {
             txstore_db,
             history_db,
@@ -53,14 +240,83 @@ impl Store {
             added_blockhashes: RwLock::new(added_blockhashes),
             indexed_blockhashes: RwLock::new(indexed_blockhashes),
             indexed_headers: RwLock::new(HeaderList::empty()),
+            history_start_height: AtomicUsize::new(history_start_height),
+            tx_json_count: Mutex::new(tx_json_count),
+        }
+    }
+
+    pub fn history_start_height(&self) -> usize {
+        self.history_start_height.load(Ordering::Relaxed)
+    }
+
+    pub fn index_metadata(&self) -> Result<Option<IndexMetadata>> {
+        match self.txstore_db.get(METADATA_KEY) {
+            Some(value) => Ok(Some(IndexMetadata::from_bytes(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn write_metadata(&self, metadata: &IndexMetadata) {
+        self.txstore_db.put(METADATA_KEY, &metadata.to_bytes());
+    }
+
+    // Record the metadata of a new index, or refuse to open an incompatible one
+    fn init_metadata(&self, config: &Config) -> Result<()> {
+        let current = IndexMetadata::new(config, self.is_empty());
+        match self.index_metadata()? {
+            Some(metadata) => metadata
+                .check_compatible(&current)
+                .chain_err(|| "incompatible index")?,
+            // indexes created by earlier versions have none, it's recorded as of now
+            None => self.write_metadata(&current),
+        }
+        Ok(())
+    }
+
+    // The pruning height is fixed when the history starts being indexed, as the rows below it
+    // can't be indexed later (nor the existing ones dropped) without reindexing
+    fn init_history_start_height(&self, height: usize) -> Result<()> {
+        let current = self.history_start_height();
+        if current == height {
+            return Ok(());
+        }
+        if !self.indexed_blockhashes.read().unwrap().is_empty() {
+            bail!(
+                "the history is indexed from height {}, reindex to index it from height {}",
+                current,
+                height
+            );
         }
+        self.history_db
+            .put(HISTORY_START_KEY, &bincode::serialize(&height).unwrap());
+        self.history_start_height.store(height, Ordering::Relaxed);
+        Ok(())
     }
 
-    /// Used to decide whether to use "blk*.dat" files for faster initial indexing
     pub fn is_empty(&self) -> bool {
         return self.added_blockhashes.read().unwrap().is_empty();
     }
 
+    /// Used to decide whether to use "blk*.dat" files for faster initial indexing, until the
+    /// history was compacted (resuming from where an interrupted initial sync stopped)
+    pub fn initial_sync_done(&self) -> bool {
+        self.history_db.get(COMPACTION_DONE_KEY).is_some()
+    }
+
+    pub fn utxo_snapshot(&self) -> Option<UtxoSnapshotInfo> {
+        self.history_db
+            .get(UTXO_SNAPSHOT_KEY)
+            .map(|value| bincode::deserialize(&value).expect("failed to parse UTXO snapshot info"))
+    }
+
+    pub fn added_progress(&self) -> Option<IndexProgress> {
+        IndexProgress::load(&self.txstore_db)
+    }
+
+    pub fn indexed_progress(&self) -> Option<IndexProgress> {
+        IndexProgress::load(&self.history_db)
+    }
+
     pub fn txstore_db(&self) -> &DB {
         &self.txstore_db
     }
@@ -68,10 +324,93 @@ impl Store {
     pub fn history_db(&self) -> &DB {
         &self.history_db
     }
+
+    pub fn cache_db(&self) -> &DB {
+        &self.cache_db
+    }
+
+    // Persist the rows of the cache database written without the WAL (on shutdown)
+    pub fn flush_cache(&self) {
+        self.cache_db.flush();
+    }
+
+    // Evict cached transactions JSON in key (i.e. txid) order, from `cursor` and wrapping
+    // around, down to 90% of `max_count`. `cursor` is moved past the evicted rows.
+    pub fn evict_tx_json(&self, max_count: u64, cursor: &mut Bytes) -> usize {
+        let count = *self.tx_json_count.lock().unwrap();
+        if count <= max_count {
+            return 0;
+        }
+        let to_evict = (count - max_count + max_count / 10) as usize;
+        let from_cursor = self.cache_db.iter_scan_from(b"J", cursor);
+        let from_start = self.cache_db.iter_scan(b"J");
+        // only the eviction deletes them, these are still cached once the count gets locked
+        let keys: Vec<Bytes> = from_cursor
+            .chain(from_start)
+            .map(|row| row.key)
+            .unique()
+            .take(to_evict)
+            .collect();
+        if let Some(last) = keys.last() {
+            *cursor = [&last[..], &[0]].concat();
+        }
+
+        let mut count = self.tx_json_count.lock().unwrap();
+        *count = count.saturating_sub(keys.len() as u64);
+        let evicted = keys.len();
+        self.cache_db
+            .write_batch(vec![tx_json_count_row(*count)], keys, DBFlush::Disable);
+        evicted
+    }
+
+    // Keep at most `max_count` transactions JSON in the persistent cache, checked every minute
+    pub fn start_cache_evictor(store: &Arc<Store>, max_count: u64) {
+        let store = Arc::clone(store);
+        spawn_thread("cache-evictor", move || {
+            let mut cursor = b"J".to_vec();
+            loop {
+                let evicted = store.evict_tx_json(max_count, &mut cursor);
+                if evicted > 0 {
+                    debug!("evicted {} transactions JSON from the cache", evicted);
+                }
+                thread::sleep(Duration::from_secs(60));
+            }
+        });
+    }
+
+    // Export the number of operations on each database every few seconds
+    pub fn start_stats_exporter(store: &Arc<Store>, metrics: &Metrics) {
+        let ops = metrics.counter_vec(
+            MetricOpts::new("db_operations", "# of database operations"),
+            &["db", "op"],
+        );
+        let store = Arc::clone(store);
+        spawn_thread("db-exporter", move || loop {
+            let dbs = [
+                ("txstore", &store.txstore_db),
+                ("history", &store.history_db),
+                ("cache", &store.cache_db),
+            ];
+            for &(name, db) in &dbs {
+                for (op, count) in db.stats() {
+                    let counter = ops.with_label_values(&[name, op]);
+                    counter.inc_by(count as i64 - counter.get());
+                }
+            }
+            thread::sleep(Duration::from_secs(5));
+        });
+    }
 }
 
 type UtxoMap = HashMap<OutPoint, (BlockId, Value)>;
 
+fn tx_json_count_row(count: u64) -> DBRow {
+    DBRow {
+        key: TX_JSON_COUNT_KEY.to_vec(),
+        value: bincode::serialize(&count).unwrap(),
+    }
+}
+
 #[derive(Debug)]
 pub struct Utxo {
     pub txid: Sha256dHash,
@@ -80,6 +419,14 @@ pub struct Utxo {
     pub value: Value,
 }
 
+impl Utxo {
+    // Stable ordering for paging, by outpoint whether confirmed or not (matching the UTXO set
+    // index order, so that confirmed pages can be read from it directly)
+    pub fn sort_key(&self) -> (Sha256dHash, u32) {
+        (self.txid, self.vout)
+    }
+}
+
 impl From<&Utxo> for OutPoint {
     fn from(utxo: &Utxo) -> Self {
         OutPoint {
@@ -96,27 +443,175 @@ pub struct SpendingInput {
     pub confirmed: Option<BlockId>,
 }
 
+// Where a page of the confirmed history resumes: below `height`, after `last_seen_txid` (when set)
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryCursor {
+    pub height: u32,
+    pub last_seen_txid: Option<Sha256dHash>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ScriptStats {
     pub tx_count: usize,
+    pub funding_tx_count: usize, // distinct transactions funding the script, see reuse_score()
     pub funded_txo_count: usize,
     pub spent_txo_count: usize,
     #[cfg(not(feature = "liquid"))]
-    pub funded_txo_sum: u64,
+    pub funded_txo_sum: Amount,
     #[cfg(not(feature = "liquid"))]
-    pub spent_txo_sum: u64,
+    pub spent_txo_sum: Amount,
+    // the explicit values of the outputs with an explicit asset, by asset id
+    #[cfg(feature = "liquid")]
+    pub funded_asset_sums: BTreeMap<String, u64>,
+    #[cfg(feature = "liquid")]
+    pub spent_asset_sums: BTreeMap<String, u64>,
+    // confirmed activity only, always null for the mempool stats
+    pub first_funding_height: Option<usize>,
+    pub first_funding_time: Option<u32>,
+    pub last_activity_height: Option<usize>,
 }
 
 impl ScriptStats {
     pub fn default() -> Self {
         ScriptStats {
             tx_count: 0,
+            funding_tx_count: 0,
             funded_txo_count: 0,
             spent_txo_count: 0,
             #[cfg(not(feature = "liquid"))]
-            funded_txo_sum: 0,
+            funded_txo_sum: Amount::default(),
             #[cfg(not(feature = "liquid"))]
-            spent_txo_sum: 0,
+            spent_txo_sum: Amount::default(),
+            #[cfg(feature = "liquid")]
+            funded_asset_sums: BTreeMap::new(),
+            #[cfg(feature = "liquid")]
+            spent_asset_sums: BTreeMap::new(),
+            first_funding_height: None,
+            first_funding_time: None,
+            last_activity_height: None,
+        }
+    }
+
+    // Number of times the script was funded again by another transaction after its first funding
+    pub fn reuse_score(chain_stats: &ScriptStats, mempool_stats: &ScriptStats) -> usize {
+        (chain_stats.funding_tx_count + mempool_stats.funding_tx_count).saturating_sub(1)
+    }
+
+    // The balance of each asset (may be negative for the mempool stats)
+    #[cfg(feature = "liquid")]
+    pub fn asset_balances(&self) -> BTreeMap<String, i64> {
+        let mut balances: BTreeMap<String, i64> = self
+            .funded_asset_sums
+            .iter()
+            .map(|(asset_id, sum)| (asset_id.clone(), *sum as i64))
+            .collect();
+        for (asset_id, sum) in &self.spent_asset_sums {
+            *balances.entry(asset_id.clone()).or_insert(0) -= *sum as i64;
+        }
+        balances
+    }
+
+    // Remove the counts and sums of `later`, the stats of the blocks following these ones.
+    // The heights are left to the caller.
+    fn sub(&mut self, later: &ScriptStats) {
+        self.tx_count -= later.tx_count;
+        self.funding_tx_count -= later.funding_tx_count;
+        self.funded_txo_count -= later.funded_txo_count;
+        self.spent_txo_count -= later.spent_txo_count;
+        #[cfg(not(feature = "liquid"))]
+        {
+            self.funded_txo_sum -= later.funded_txo_sum;
+            self.spent_txo_sum -= later.spent_txo_sum;
+        }
+        #[cfg(feature = "liquid")]
+        for (sums, later_sums) in vec![
+            (&mut self.funded_asset_sums, &later.funded_asset_sums),
+            (&mut self.spent_asset_sums, &later.spent_asset_sums),
+        ] {
+            for (asset_id, value) in later_sums {
+                let sum = sums.entry(asset_id.clone()).or_insert(0);
+                *sum -= value;
+                if *sum == 0 {
+                    sums.remove(asset_id);
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "liquid")]
+    pub fn add_history(&mut self, info: &TxHistoryInfo) {
+        match info {
+            TxHistoryInfo::Funding(_) => self.funded_txo_count += 1,
+            TxHistoryInfo::Spending(_) => self.spent_txo_count += 1,
+        }
+        if let Some((asset_id, value)) = info.explicit_asset_value() {
+            let sums = match info {
+                TxHistoryInfo::Funding(_) => &mut self.funded_asset_sums,
+                TxHistoryInfo::Spending(_) => &mut self.spent_asset_sums,
+            };
+            *sums.entry(asset_id).or_insert(0) += value;
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct HistoryHistogram {
+    pub sampled_scripthashes: usize,
+    pub sampled_rows: usize,
+    pub estimated_scripthashes: u64,
+    pub percentiles: BTreeMap<&'static str, usize>,
+    pub buckets: Vec<HistoryHistogramBucket>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HistoryHistogramBucket {
+    pub min_rows: usize,
+    pub max_rows: usize,
+    pub count: usize,
+    pub estimated_count: u64,
+}
+
+impl HistoryHistogram {
+    fn new(mut counts: Vec<usize>, estimated_scripthashes: u64) -> Self {
+        counts.sort_unstable();
+        let sampled = counts.len();
+        let scale = estimated_scripthashes as f64 / sampled.max(1) as f64;
+
+        // nearest-rank percentiles
+        let percentile = |p: f64| match sampled {
+            0 => 0,
+            n => counts[((p * n as f64).ceil() as usize).max(1) - 1],
+        };
+        let mut percentiles = BTreeMap::new();
+        percentiles.insert("p50", percentile(0.5));
+        percentiles.insert("p90", percentile(0.9));
+        percentiles.insert("p99", percentile(0.99));
+        percentiles.insert("p999", percentile(0.999));
+        percentiles.insert("max", counts.last().cloned().unwrap_or(0));
+
+        // power-of-ten buckets (1-9, 10-99, 100-999, ...)
+        let mut buckets = vec![];
+        let mut min_rows = 1;
+        let mut rest = &counts[..];
+        while !rest.is_empty() {
+            let max_rows = min_rows * 10 - 1;
+            let count = rest.iter().take_while(|c| **c <= max_rows).count();
+            buckets.push(HistoryHistogramBucket {
+                min_rows,
+                max_rows,
+                count,
+                estimated_count: (count as f64 * scale).round() as u64,
+            });
+            rest = &rest[count..];
+            min_rows *= 10;
+        }
+
+        HistoryHistogram {
+            sampled_scripthashes: sampled,
+            sampled_rows: counts.iter().sum(),
+            estimated_scripthashes,
+            percentiles,
+            buckets,
         }
     }
 }
@@ -125,32 +620,144 @@ pub struct Indexer {
     store: Arc<Store>,
     flush: DBFlush,
     from: FetchFrom,
+    utxo_commitment_interval: Option<usize>,
+    utxo_index: bool,
+    light_mode: bool,
+    #[cfg(not(feature = "liquid"))]
+    block_filters: bool,
+    #[cfg(not(feature = "liquid"))]
+    utxo_snapshot: Option<PathBuf>, // to bootstrap a new index from
+    #[cfg(not(feature = "liquid"))]
+    network: Network,
+    #[cfg(not(feature = "liquid"))]
+    network_magic: u32, // expected in the UTXO snapshot
+    #[cfg(feature = "liquid")]
+    parent_network: Network,
+    #[cfg(feature = "liquid")]
+    parent_genesis_hash: String,
+    genesis_hash: Option<Sha256dHash>, // expected by --genesis-hash
+    genesis_checked: bool,             // on the first update, bitcoind can't switch chains later
+    header_checker: HeaderChecker,
+    header_check_failures: CounterVec,
+    header_check_alarm: Gauge,
     duration: HistogramVec,
+    height: GaugeVec,
+    lag: Gauge,
+    profile: Option<(Arc<IndexProfile>, PathBuf)>, // until the initial sync is done
+    signal: Waiter,
 }
 
 pub struct ChainQuery {
-    store: Arc<Store>, // TODO: should be used as read-only
+    store: Arc<Store>,           // TODO: should be used as read-only
+    daemon: Option<Arc<Daemon>>, // the raw transactions are fetched from, in light mode
     duration: HistogramVec,
+    tx_json_cache: CounterVec,
 }
 
 // TODO: &[Block] should be an iterator / a queue.
 impl Indexer {
-    pub fn open(store: Arc<Store>, from: FetchFrom, metrics: &Metrics) -> Self {
-        Indexer {
+    pub fn open(
+        store: Arc<Store>,
+        from: FetchFrom,
+        config: &Config,
+        metrics: &Metrics,
+        signal: &Waiter,
+    ) -> Result<Self> {
+        store.init_metadata(config)?;
+        // the history of the blocks of the snapshot the index was bootstrapped from isn't known
+        let history_start_height = match store.utxo_snapshot() {
+            Some(snapshot) => config.history_start_height.max(snapshot.height + 1),
+            None => config.history_start_height,
+        };
+        store.init_history_start_height(history_start_height)?;
+        Ok(Indexer {
             store,
             flush: DBFlush::Disable,
             from,
+            utxo_commitment_interval: config.utxo_commitment_interval,
+            utxo_index: config.utxo_index,
+            light_mode: config.light_mode,
+            #[cfg(not(feature = "liquid"))]
+            block_filters: config.block_filters,
+            #[cfg(not(feature = "liquid"))]
+            utxo_snapshot: config.utxo_snapshot.clone(),
+            #[cfg(not(feature = "liquid"))]
+            network: config.network_type,
+            #[cfg(not(feature = "liquid"))]
+            network_magic: config.network_magic,
+            #[cfg(feature = "liquid")]
+            parent_network: config.parent_network,
+            #[cfg(feature = "liquid")]
+            parent_genesis_hash: config.parent_genesis_hash.clone(),
+            genesis_hash: config.genesis_hash,
+            genesis_checked: false,
+            #[cfg(not(feature = "liquid"))]
+            header_checker: HeaderChecker::new(config.network_type, config.genesis_hash.is_some()),
+            #[cfg(feature = "liquid")]
+            header_checker: HeaderChecker::default(),
+            header_check_failures: metrics.counter_vec(
+                MetricOpts::new(
+                    "index_header_check_failures",
+                    "# of times the daemon's new headers failed the verification, by reason",
+                ),
+                &["reason"],
+            ),
+            header_check_alarm: metrics.gauge(MetricOpts::new(
+                "index_header_check_alarm",
+                "1 while the daemon's new headers fail the verification (and aren't indexed)",
+            )),
             duration: metrics.histogram_vec(
                 HistogramOpts::new("index_duration", "Index update duration (in seconds)"),
                 &["step"],
             ),
-        }
+            height: metrics.gauge_vec(
+                MetricOpts::new("index_height", "Height of the daemon and indexed tips"),
+                &["type"],
+            ),
+            lag: metrics.gauge(MetricOpts::new(
+                "index_lag",
+                "# of blocks the index is behind the daemon",
+            )),
+            profile: config
+                .index_profile
+                .clone()
+                .map(|path| (Arc::new(IndexProfile::new()), path)),
+            signal: signal.clone(),
+        })
     }
 
     fn start_timer(&self, name: &str) -> HistogramTimer {
         self.duration.with_label_values(&[name]).start_timer()
     }
 
+    fn index_profile(&self) -> Option<&IndexProfile> {
+        self.profile.as_ref().map(|(profile, _)| &**profile)
+    }
+
+    // Profiled by the height of the batch's first block
+    fn start_stage_timer(&self, name: &'static str, blocks: &[BlockEntry]) -> StageTimer {
+        let height = blocks.first().map_or(0, |b| b.entry.height());
+        StageTimer::new(self.start_timer(name), self.index_profile(), name, height)
+    }
+
+    fn record_fetch(&self, name: &'static str, blocks: &[BlockEntry], waited: Duration) {
+        if let (Some(profile), Some(first)) = (self.index_profile(), blocks.first()) {
+            profile.record(name, first.entry.height(), waited);
+        }
+    }
+
+    fn update_height_metrics(&self, daemon_height: i64) {
+        // -1 before the genesis block is indexed
+        let indexed_height = self.store.indexed_headers.read().unwrap().len() as i64 - 1;
+        self.height
+            .with_label_values(&["daemon"])
+            .set(daemon_height as f64);
+        self.height
+            .with_label_values(&["indexed"])
+            .set(indexed_height as f64);
+        self.lag.set(daemon_height - indexed_height);
+    }
+
     fn headers_to_add(&self, new_headers: &[HeaderEntry]) -> Vec<HeaderEntry> {
         let added_blockhashes = self.store.added_blockhashes.read().unwrap();
         new_headers
@@ -169,12 +776,16 @@ impl Indexer {
             .collect()
     }
 
-    fn start_auto_compactions(&self, db: &DB) {
-        let key = b"F".to_vec();
-        if db.get(&key).is_none() {
+    fn start_auto_compactions(&self, db: &DB, name: &'static str) {
+        let key = COMPACTION_DONE_KEY;
+        if db.get(key).is_none() {
+            let started = Instant::now();
             db.full_compaction();
-            db.put(&key, b"");
-            assert!(db.get(&key).is_some());
+            db.put(key, b"");
+            assert!(db.get(key).is_some());
+            if let Some(profile) = self.index_profile() {
+                profile.record_other(name, started.elapsed());
+            }
         }
         db.enable_auto_compaction();
     }
@@ -182,6 +793,7 @@ impl Indexer {
     fn get_new_headers(&self, daemon: &Daemon, tip: &Sha256dHash) -> Result<Vec<HeaderEntry>> {
         let headers = self.store.indexed_headers.read().unwrap();
         let new_headers = daemon.get_new_headers(&headers, &tip)?;
+        self.check_headers(&headers, &new_headers)?;
         let result = headers.order(new_headers);
         result.last().map(|tip| {
             info!("{:?} ({} left to process)", tip, result.len());
@@ -189,10 +801,73 @@ impl Indexer {
         Ok(result)
     }
 
+    // Refuses to index headers failing the verification, with the alarm raised until they pass
+    fn check_headers(&self, headers: &HeaderList, new_headers: &[BlockHeader]) -> Result<()> {
+        if let Err(err) = self.header_checker.check(headers, new_headers) {
+            self.header_check_failures
+                .with_label_values(&[err.reason()])
+                .inc();
+            self.header_check_alarm.set(1);
+            bail!(ErrorKind::InvalidHeaders(err.to_string()));
+        }
+        self.header_check_alarm.set(0);
+        Ok(())
+    }
+
+    // bitcoind must be on the configured chain and the one the index was created for
+    fn check_genesis(&self, daemon: &Daemon) -> Result<()> {
+        let genesis_hash = daemon.getblockhash(0)?;
+        if let Some(expected) = self.genesis_hash {
+            if genesis_hash != expected {
+                bail!(
+                    "bitcoind is on the chain with genesis block {}, expected {} by --genesis-hash",
+                    genesis_hash,
+                    expected
+                );
+            }
+        }
+        let mut metadata = match self.store.index_metadata()? {
+            Some(metadata) => metadata,
+            None => return Ok(()),
+        };
+        match metadata.genesis_hash {
+            Some(hash) if hash != genesis_hash => bail!(
+                "the index was created for the chain with genesis block {}, bitcoind is on {}",
+                hash,
+                genesis_hash
+            ),
+            Some(_) => (),
+            None => {
+                metadata.genesis_hash = Some(genesis_hash);
+                self.store.write_metadata(&metadata);
+            }
+        }
+        Ok(())
+    }
+
     pub fn update(&mut self, daemon: &Daemon) -> Result<Sha256dHash> {
         let daemon = daemon.reconnect()?;
+        if !self.genesis_checked {
+            self.check_genesis(&daemon)?;
+            self.genesis_checked = true;
+        }
         let tip = daemon.getbestblockhash()?;
         let new_headers = self.get_new_headers(&daemon, &tip)?;
+        #[cfg(not(feature = "liquid"))]
+        {
+            if let Some(path) = self.utxo_snapshot.take() {
+                if self.store.is_empty() {
+                    self.import_utxo_snapshot(&path, &new_headers)?;
+                } else if self.store.utxo_snapshot().is_none() {
+                    warn!("ignoring --utxo-snapshot, the index was already created");
+                }
+            }
+        }
+        let daemon_height = match new_headers.last() {
+            Some(entry) => entry.height() as i64,
+            None => self.store.indexed_headers.read().unwrap().len() as i64 - 1,
+        };
+        self.update_height_metrics(daemon_height);
 
         let to_add = self.headers_to_add(&new_headers);
         debug!(
@@ -200,8 +875,21 @@ impl Indexer {
             to_add.len(),
             self.from
         );
-        start_fetcher(self.from, &daemon, to_add)?.map(|blocks| self.add(&blocks));
-        self.start_auto_compactions(&self.store.txstore_db);
+        let profile = self.profile.as_ref().map(|p| Arc::clone(&p.0));
+        let resume_file = self.resume_file("adding transactions", self.store.added_progress());
+        let mut fetched = Instant::now();
+        let fetcher = start_fetcher(self.from, &daemon, to_add, profile.clone(), resume_file)?;
+        fetcher.map_while(|FetchBatch { blocks, blk_file }| {
+            if self.signal.poll().is_err() {
+                return false;
+            }
+            self.record_fetch("add_fetch", &blocks, fetched.elapsed());
+            self.add(&blocks, blk_file);
+            fetched = Instant::now();
+            true
+        });
+        self.stop_if_interrupted()?;
+        self.start_auto_compactions(&self.store.txstore_db, "compaction_txstore");
 
         let to_index = self.headers_to_index(&new_headers);
         debug!(
@@ -209,28 +897,188 @@ impl Indexer {
             to_index.len(),
             self.from
         );
-        start_fetcher(self.from, &daemon, to_index)?.map(|blocks| self.index(&blocks));
-        self.start_auto_compactions(&self.store.history_db);
+        // heights of the new blocks, for looking up the confirmation height of spent coins
+        // and of duplicate coinbase transactions
+        let new_heights = NewHeights {
+            heights: new_headers
+                .iter()
+                .map(|h| (*h.hash(), h.height()))
+                .collect(),
+            fork_height: new_headers
+                .first()
+                .map_or(usize::max_value(), |h| h.height()),
+        };
+        let resume_file = self.resume_file("indexing history", self.store.indexed_progress());
+        let mut fetched = Instant::now();
+        let mut indexed = Ok(());
+        let fetcher = start_fetcher(self.from, &daemon, to_index, profile, resume_file)?;
+        fetcher.map_while(|FetchBatch { blocks, blk_file }| {
+            if self.signal.poll().is_err() {
+                return false;
+            }
+            self.record_fetch("index_fetch", &blocks, fetched.elapsed());
+            // the batch isn't marked as indexed, and is indexed again by the next update
+            indexed = self.index(&blocks, &new_heights, blk_file);
+            fetched = Instant::now();
+            indexed.is_ok()
+        });
+        indexed?;
+        self.stop_if_interrupted()?;
+        self.start_auto_compactions(&self.store.history_db, "compaction_history");
+
+        self.apply_headers(new_headers);
+        assert_eq!(tip, *self.store.indexed_headers.read().unwrap().tip());
 
-        let mut headers = self.store.indexed_headers.write().unwrap();
-        headers.apply(new_headers);
-        assert_eq!(tip, *headers.tip());
+        if let Some(interval) = self.utxo_commitment_interval {
+            self.update_utxo_checkpoints(interval);
+        }
+        if self.utxo_index {
+            self.update_utxo_set();
+        }
+        #[cfg(not(feature = "liquid"))]
+        {
+            if self.block_filters {
+                self.update_filter_headers();
+            }
+            self.update_adoption_stats();
+        }
+        #[cfg(feature = "liquid")]
+        self.update_peg_stats();
 
         self.flush = DBFlush::Enable;
         self.store.txstore_db.write(vec![], self.flush);
         self.store.history_db.write(vec![], self.flush);
         self.from = FetchFrom::Bitcoind;
+        self.update_height_metrics(daemon_height);
+
+        if let Some((profile, path)) = self.profile.take() {
+            match profile.write_report(&path) {
+                Ok(()) => info!("wrote the initial sync profile to {}", path.display()),
+                Err(e) => warn!("{}", e.display_chain()),
+            }
+        }
         Ok(tip)
     }
 
-    fn add(&self, blocks: &[BlockEntry]) {
+    // The blk*.dat file to resume an interrupted initial sync from, when fetching from them
+    fn resume_file(&self, phase: &str, progress: Option<IndexProgress>) -> Option<usize> {
+        match (self.from, progress) {
+            (FetchFrom::BlkFiles { .. }, Some(progress)) => {
+                info!("resuming {} after height {}", phase, progress.height);
+                progress.blk_file
+            }
+            _ => None,
+        }
+    }
+
+    // Stop updating on shutdown, after flushing the rows written so far (without a WAL during the
+    // initial sync). Each batch of blocks is written along with their done markers and the
+    // progress of the phase, so the next run resumes after the last fully added and indexed blocks
+    // (and from the blk*.dat files holding the following ones).
+    fn stop_if_interrupted(&self) -> Result<()> {
+        if let Err(e) = self.signal.poll() {
+            info!(
+                "flushing the index before stopping, with {} blocks added and {} indexed",
+                self.store.added_blockhashes.read().unwrap().len(),
+                self.store.indexed_blockhashes.read().unwrap().len()
+            );
+            self.store.txstore_db.flush();
+            self.store.history_db.flush();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Seed a new index with the coins of a UTXO snapshot. The blocks up to the snapshot's are
+    // recorded as added and indexed with their headers only, so that the following ones are
+    // indexed on top of the imported UTXO set (with the history starting after the snapshot).
+    // Marking them as added comes last, an interrupted import is started over on the next run.
+    #[cfg(not(feature = "liquid"))]
+    fn import_utxo_snapshot(&self, path: &Path, new_headers: &[HeaderEntry]) -> Result<()> {
+        let _timer = self.start_timer("utxo_snapshot");
+        let snapshot = UtxoSnapshot::open(path, self.network_magic)?;
+        let blockhash = snapshot.blockhash;
+        let coins_count = snapshot.coins_count;
+        let headers = match new_headers.iter().position(|h| *h.hash() == blockhash) {
+            Some(pos) => &new_headers[..=pos],
+            None => bail!(
+                "the UTXO snapshot's block {} isn't on bitcoind's best chain",
+                blockhash
+            ),
+        };
+        let height = headers.last().unwrap().height();
+        info!(
+            "importing {} coins from the UTXO snapshot of block {} ({})",
+            coins_count, height, blockhash
+        );
+        let history_start_height = self.store.history_start_height().max(height + 1);
+        self.store.init_history_start_height(history_start_height)?;
+
+        let mut imported = 0;
+        for coins in &snapshot.chunks(UTXO_SNAPSHOT_BATCH_SIZE) {
+            self.signal.poll()?;
+            let coins: Vec<SnapshotCoin> = coins.collect::<Result<_>>()?;
+            let (txstore_rows, history_rows) = utxo_snapshot_rows(&coins);
+            self.store.txstore_db.write(txstore_rows, self.flush);
+            self.store.history_db.write(history_rows, self.flush);
+            imported += coins.len();
+            debug!("imported {}/{} coins", imported, coins_count);
+        }
+
+        let hashes: Vec<FullHash> = headers.iter().map(|h| full_hash(&h.hash()[..])).collect();
+        let mut rows: Vec<DBRow> = hashes
+            .iter()
+            .map(|hash| BlockRow::new_done(*hash).to_row())
+            .collect();
+        rows.push(UtxoRow::tip_row(&blockhash));
+        let info = UtxoSnapshotInfo {
+            blockhash,
+            height,
+            coins: coins_count,
+        };
+        rows.push(info.to_row());
+        self.store.history_db.write(rows, self.flush);
+        self.store.history_db.flush();
+        let rows = headers
+            .iter()
+            .zip(hashes)
+            .flat_map(|(entry, hash)| {
+                vec![
+                    BlockRow::new_header(entry).to_row(),
+                    BlockRow::new_done(hash).to_row(),
+                ]
+            })
+            .collect();
+        self.store.txstore_db.write(rows, self.flush);
+        self.store.txstore_db.flush();
+
+        let blockhashes = headers.iter().map(|h| *h.hash());
+        self.store
+            .added_blockhashes
+            .write()
+            .unwrap()
+            .extend(blockhashes.clone());
+        self.store
+            .indexed_blockhashes
+            .write()
+            .unwrap()
+            .extend(blockhashes);
+        info!(
+            "imported the UTXO snapshot, indexing from block {}",
+            height + 1
+        );
+        Ok(())
+    }
+
+    fn add(&self, blocks: &[BlockEntry], blk_file: Option<usize>) {
         // TODO: skip orphaned blocks?
-        let rows = {
-            let _timer = self.start_timer("add_process");
-            add_blocks(blocks)
+        let mut rows = {
+            let _timer = self.start_stage_timer("add_process", blocks);
+            add_blocks(blocks, self.light_mode)
         };
+        rows.push(IndexProgress::new(blocks, blk_file).to_row());
         {
-            let _timer = self.start_timer("add_write");
+            let _timer = self.start_stage_timer("add_write", blocks);
             self.store.txstore_db.write(rows, self.flush);
         }
 
@@ -241,13 +1089,19 @@ impl Indexer {
             .extend(blocks.into_iter().map(|b| b.entry.hash()));
     }
 
-    fn index(&self, blocks: &[BlockEntry]) {
+    fn index(
+        &self,
+        blocks: &[BlockEntry],
+        new_heights: &NewHeights,
+        blk_file: Option<usize>,
+    ) -> Result<()> {
         let previous_txos_map = {
-            let _timer = self.start_timer("index_lookup");
+            let _timer = self.start_stage_timer("index_lookup", blocks);
             lookup_txos(&self.store.txstore_db, &get_previous_txos(blocks), false)
         };
-        let rows = {
-            let _timer = self.start_timer("index_process");
+        let duplicate_coinbases = self.lookup_duplicate_coinbases(blocks, new_heights);
+        let mut rows = {
+            let _timer = self.start_stage_timer("index_process", blocks);
             let added_blockhashes = self.store.added_blockhashes.read().unwrap();
             for b in blocks {
                 let blockhash = b.entry.hash();
@@ -256,41 +1110,638 @@ impl Indexer {
                     panic!("cannot index block {} (missing from store)", blockhash);
                 }
             }
-            index_blocks(blocks, &previous_txos_map)
+            index_blocks(
+                blocks,
+                &previous_txos_map,
+                &duplicate_coinbases,
+                self.store.history_start_height(),
+            )
         };
+        if self.utxo_index {
+            let _timer = self.start_stage_timer("index_utxo_changes", blocks);
+            rows.extend(utxo_changes_rows(
+                blocks,
+                &previous_txos_map,
+                &duplicate_coinbases,
+            )?);
+        }
+        if self.utxo_commitment_interval.is_some() {
+            let _timer = self.start_stage_timer("index_utxo_commitments", blocks);
+            let confirmations = self.lookup_confirmations(blocks, new_heights);
+            rows.extend(utxo_commitment_rows(
+                blocks,
+                &previous_txos_map,
+                &confirmations,
+            )?);
+        }
+        #[cfg(not(feature = "liquid"))]
+        {
+            if self.block_filters {
+                let _timer = self.start_stage_timer("index_block_filters", blocks);
+                rows.extend(block_filter_rows(blocks, &previous_txos_map)?);
+            }
+            rows.extend(block_stats_rows(blocks, &previous_txos_map, self.network));
+            rows.extend(adoption_stats_rows(blocks, &previous_txos_map));
+        }
+        #[cfg(feature = "liquid")]
+        {
+            rows.extend(asset_rows(blocks));
+            rows.extend(peg_stats_rows(
+                blocks,
+                &self.parent_network,
+                &self.parent_genesis_hash,
+            ));
+        }
+        rows.push(IndexProgress::new(blocks, blk_file).to_row());
+        let _timer = self.start_stage_timer("index_write", blocks);
         self.store.history_db.write(rows, self.flush);
+        Ok(())
     }
-}
 
-impl ChainQuery {
-    pub fn new(store: Arc<Store>, metrics: &Metrics) -> Self {
-        ChainQuery {
-            store,
-            duration: metrics.histogram_vec(
-                HistogramOpts::new("query_duration", "Index query duration (in seconds)"),
-                &["name"],
-            ),
-        }
+    // Find the best-chain confirmation heights of the spent coins' transactions (and of the
+    // blocks' coinbase transactions, to detect BIP30 duplicates), and whether they're coinbase.
+    fn lookup_confirmations(
+        &self,
+        blocks: &[BlockEntry],
+        new_heights: &NewHeights,
+    ) -> ConfirmationMap {
+        let txids: HashSet<Sha256dHash> = blocks
+            .iter()
+            .flat_map(|b| b.block.txdata.iter())
+            .flat_map(|tx| {
+                tx.input
+                    .iter()
+                    .filter(|txin| has_prevout(txin))
+                    .map(|txin| txin.previous_output.txid)
+            })
+            .chain(blocks.iter().map(|b| b.block.txdata[0].txid()))
+            .collect();
+
+        let headers = self.store.indexed_headers.read().unwrap();
+        let txstore_db = &self.store.txstore_db;
+        txids
+            .into_par_iter()
+            .map(|txid| {
+                let heights = confirmation_heights(txstore_db, &headers, new_heights, &txid);
+                (txid, (heights, is_stored_coinbase(txstore_db, &txid)))
+            })
+            .collect()
     }
 
-    fn start_timer(&self, name: &str) -> HistogramTimer {
-        self.duration.with_label_values(&[name]).start_timer()
+    // Find the blocks whose coinbase transaction has the same txid as the coinbase of an
+    // earlier best-chain block (BIP30, mainnet heights 91842 and 91880). The duplicate
+    // overwrites the earlier coins, which are indexed once for their first confirmation.
+    fn lookup_duplicate_coinbases(
+        &self,
+        blocks: &[BlockEntry],
+        new_heights: &NewHeights,
+    ) -> HashSet<Sha256dHash> {
+        let headers = self.store.indexed_headers.read().unwrap();
+        let txstore_db = &self.store.txstore_db;
+        blocks
+            .par_iter()
+            .filter(|b| {
+                let txid = b.block.txdata[0].txid();
+                let heights = confirmation_heights(txstore_db, &headers, new_heights, &txid);
+                heights
+                    .first()
+                    .map_or(false, |first| *first < b.entry.height())
+            })
+            .map(|b| *b.entry.hash())
+            .collect()
     }
 
-    pub fn get_block_txids(&self, hash: &Sha256dHash) -> Option<Vec<Sha256dHash>> {
-        let _timer = self.start_timer("get_block_txids");
-        self.store
-            .txstore_db
-            .get(&BlockRow::txids_key(full_hash(&hash[..])))
-            .map(|val| bincode::deserialize(&val).expect("failed to parse block txids"))
+    // Store a cumulative UTXO commitment every `interval` blocks, by combining the per-block
+    // deltas since the previous checkpoint.
+    fn update_utxo_checkpoints(&self, interval: usize) {
+        let _timer = self.start_timer("utxo_checkpoints");
+        let headers = self.store.indexed_headers.read().unwrap();
+        let history_db = &self.store.history_db;
+        let mut rows = vec![];
+        let mut acc = MuHash::new();
+        let mut acc_height = 0;
+        for height in (interval..headers.len()).step_by(interval) {
+            let blockhash = headers.header_by_height(height).unwrap().hash();
+            if let Some(value) = history_db.get(&UtxoCheckpointRow::key(height, blockhash)) {
+                acc = MuHash::from_bytes(&value);
+                acc_height = height;
+                continue;
+            }
+            for h in acc_height + 1..=height {
+                let hash = headers.header_by_height(h).unwrap().hash();
+                match history_db.get(&BlockRow::utxo_delta_key(full_hash(&hash[..]))) {
+                    Some(delta) => acc.combine(&MuHash::from_bytes(&delta)),
+                    None => {
+                        warn!("missing utxo commitment delta for block {} ({})", h, hash);
+                        history_db.write(rows, self.flush);
+                        return;
+                    }
+                }
+            }
+            acc_height = height;
+            rows.push(UtxoCheckpointRow::new(height, blockhash, &acc).to_row());
+        }
+        history_db.write(rows, self.flush);
     }
 
-    pub fn get_block_meta(&self, hash: &Sha256dHash) -> Option<BlockMeta> {
-        let _timer = self.start_timer("get_block_meta");
-        self.store
-            .txstore_db
-            .get(&BlockRow::meta_key(full_hash(&hash[..])))
-            .map(|val| bincode::deserialize(&val).expect("failed to parse BlockMeta"))
+    // Make the indexed blocks the best chain, recording the ones it orphaned as stale (their
+    // headers and transactions are kept in the txstore)
+    fn apply_headers(&self, new_headers: Vec<HeaderEntry>) {
+        let orphaned = self
+            .store
+            .indexed_headers
+            .write()
+            .unwrap()
+            .apply(new_headers);
+        if !orphaned.is_empty() {
+            let hashes: Vec<&Sha256dHash> = orphaned.iter().map(HeaderEntry::hash).collect();
+            warn!("reorg orphaned {} blocks: {:?}", hashes.len(), hashes);
+            let rows = orphaned
+                .iter()
+                .map(|entry| BlockRow::new_orphan(entry).to_row())
+                .collect();
+            self.store.txstore_db.write(rows, self.flush);
+        }
+    }
+
+    // Bring the UTXO set up to date with the best chain, by reverting the blocks orphaned
+    // since the last update and applying the new best-chain blocks in order.
+    fn update_utxo_set(&self) {
+        let _timer = self.start_timer("utxo_set");
+        let headers = self.store.indexed_headers.read().unwrap();
+        let mut tip = self
+            .store
+            .history_db
+            .get(&UtxoRow::tip_key())
+            .map(|hash| parse_hash(&full_hash(&hash)));
+
+        while let Some(hash) = tip {
+            if headers.header_by_blockhash(&hash).is_some() {
+                break;
+            }
+            let header: BlockHeader = self
+                .store
+                .txstore_db
+                .get(&BlockRow::header_key(full_hash(&hash[..])))
+                .map(|header| deserialize(&header).expect("failed to parse BlockHeader"))
+                .expect(&format!("missing header of orphaned block {}", hash));
+            if !self.revert_utxo_changes(&hash, &header.prev_blockhash) {
+                return;
+            }
+            tip = Some(header.prev_blockhash);
+        }
+
+        let start_height = tip.map_or(0, |hash| {
+            headers.header_by_blockhash(&hash).unwrap().height() + 1
+        });
+        for height in start_height..headers.len() {
+            let entry = headers.header_by_height(height).unwrap();
+            if !self.apply_utxo_changes(entry) {
+                return;
+            }
+        }
+    }
+
+    fn apply_utxo_changes(&self, entry: &HeaderEntry) -> bool {
+        let history_db = &self.store.history_db;
+        let hash = full_hash(&entry.hash()[..]);
+        let changes: UtxoChanges = match history_db.get(&BlockRow::utxo_changes_key(hash)) {
+            Some(changes) => bincode::deserialize(&changes).expect("failed to parse UtxoChanges"),
+            None => {
+                warn!(
+                    "missing utxo changes for block {} ({})",
+                    entry.height(),
+                    entry.hash()
+                );
+                return false;
+            }
+        };
+
+        let height = entry.height() as u32;
+        let mut created: HashMap<Bytes, Bytes> = changes
+            .created
+            .iter()
+            .map(|c| {
+                let value = bincode::serialize(&(height, &c.value)).unwrap();
+                (UtxoRow::key(&c.scripthash, &c.txid, c.vout), value)
+            })
+            .collect();
+        let mut deletes = vec![];
+        let mut undo = vec![];
+        for spent in changes.spent {
+            let key = UtxoRow::key(&spent.scripthash, &spent.txid, spent.vout);
+            // coins created and spent within the block never make it to the UTXO set
+            if created.remove(&key).is_some() {
+                continue;
+            }
+            match history_db.get(&key) {
+                Some(value) => {
+                    undo.push((key.clone(), value));
+                    deletes.push(key);
+                }
+                None => warn!(
+                    "missing utxo {}:{} spent in block {}",
+                    parse_hash(&spent.txid),
+                    spent.vout,
+                    entry.hash()
+                ),
+            }
+        }
+
+        let mut rows: Vec<DBRow> = created
+            .into_iter()
+            .map(|(key, value)| DBRow { key, value })
+            .collect();
+        rows.push(BlockRow::new_utxo_undo(hash, &undo).to_row());
+        rows.push(UtxoRow::tip_row(entry.hash()));
+        history_db.write_batch(rows, deletes, self.flush);
+        true
+    }
+
+    fn revert_utxo_changes(&self, blockhash: &Sha256dHash, prev_blockhash: &Sha256dHash) -> bool {
+        let history_db = &self.store.history_db;
+        let hash = full_hash(&blockhash[..]);
+        let (changes, undo): (UtxoChanges, Vec<(Bytes, Bytes)>) = match (
+            history_db.get(&BlockRow::utxo_changes_key(hash)),
+            history_db.get(&BlockRow::utxo_undo_key(hash)),
+        ) {
+            (Some(changes), Some(undo)) => (
+                bincode::deserialize(&changes).expect("failed to parse UtxoChanges"),
+                bincode::deserialize(&undo).expect("failed to parse utxo undo data"),
+            ),
+            _ => {
+                warn!("missing utxo undo data for orphaned block {}", blockhash);
+                return false;
+            }
+        };
+
+        let deletes = changes
+            .created
+            .iter()
+            .map(|c| UtxoRow::key(&c.scripthash, &c.txid, c.vout))
+            .collect();
+        let mut rows: Vec<DBRow> = undo
+            .into_iter()
+            .map(|(key, value)| DBRow { key, value })
+            .collect();
+        rows.push(UtxoRow::tip_row(prev_blockhash));
+        history_db.write_batch(rows, deletes, self.flush);
+        true
+    }
+
+    // Chain the BIP158 filter headers of the best-chain blocks following the highest one
+    // that already has a filter header.
+    #[cfg(not(feature = "liquid"))]
+    fn update_filter_headers(&self) {
+        let _timer = self.start_timer("filter_headers");
+        let headers = self.store.indexed_headers.read().unwrap();
+        let history_db = &self.store.history_db;
+        let mut prev_header = Sha256dHash::default();
+        let mut missing = vec![];
+        for entry in headers.iter().rev() {
+            let hash = full_hash(&entry.hash()[..]);
+            if let Some(header) = history_db.get(&BlockRow::filter_header_key(hash)) {
+                prev_header = parse_hash(&full_hash(&header));
+                break;
+            }
+            missing.push(entry);
+        }
+
+        let mut rows = vec![];
+        for entry in missing.into_iter().rev() {
+            let hash = full_hash(&entry.hash()[..]);
+            let filter = match history_db.get(&BlockRow::filter_key(hash)) {
+                Some(filter) => filter,
+                None => {
+                    warn!(
+                        "missing block filter for block {} ({})",
+                        entry.height(),
+                        entry.hash()
+                    );
+                    break;
+                }
+            };
+            prev_header = filter_header(&filter, &prev_header);
+            rows.push(BlockRow::new_filter_header(hash, &prev_header).to_row());
+        }
+        history_db.write(rows, self.flush);
+    }
+
+    // Apply the adoption stats of the blocks connected to (and disconnected from) the best chain
+    // since the last update to the per-day and per-epoch rollups
+    #[cfg(not(feature = "liquid"))]
+    fn update_adoption_stats(&self) {
+        let _timer = self.start_timer("adoption_stats");
+        self.update_rollups::<AdoptionStats, _>(
+            &AdoptionRow::tip_key(),
+            &AdoptionRow::prefix(),
+            BlockRow::adoption_key,
+            |height, time| {
+                [AdoptionPeriod::Day, AdoptionPeriod::Epoch]
+                    .iter()
+                    .map(|period| AdoptionRow::key(*period, period.index(height, time)))
+                    .collect()
+            },
+        );
+    }
+
+    // Apply the peg stats of the blocks connected to (and disconnected from) the best chain since
+    // the last update to the monthly rollups
+    #[cfg(feature = "liquid")]
+    fn update_peg_stats(&self) {
+        let _timer = self.start_timer("peg_stats");
+        self.update_rollups::<PegStats, _>(
+            &PegRow::tip_key(),
+            &PegRow::filter(),
+            BlockRow::pegs_key,
+            |_, time| vec![PegRow::key(month_index(time))],
+        );
+    }
+
+    // Apply the stats of the blocks connected to (and disconnected from) the best chain since the
+    // last update (whose tip is stored at `tip_key`) to the rollups stored under `prefix`, given
+    // the keys of the rollups a block is part of (by its height and time). The rollups are rebuilt
+    // from the genesis block if the blocks disconnected since can't be walked back.
+    fn update_rollups<S, K>(
+        &self,
+        tip_key: &[u8],
+        prefix: &[u8],
+        block_stats_key: fn(FullHash) -> Bytes,
+        rollup_keys: K,
+    ) where
+        S: Rollup,
+        K: Fn(usize, u32) -> Vec<Bytes>,
+    {
+        let headers = self.store.indexed_headers.read().unwrap();
+        let txstore_db = &self.store.txstore_db;
+        let history_db = &self.store.history_db;
+        let last_tip = history_db
+            .get(tip_key)
+            .map(|hash| parse_hash(&full_hash(&hash)));
+        // blocks indexed by earlier versions have no stats, and are skipped
+        let block_stats = |hash: FullHash| -> Option<S> {
+            history_db
+                .get(&block_stats_key(hash))
+                .map(|val| bincode::deserialize(&val).expect("failed to parse block stats"))
+        };
+
+        // walk back the blocks orphaned since the last update, down to the fork point
+        let mut disconnected = vec![];
+        let mut rebuild = false;
+        let mut tip = last_tip;
+        while let Some(hash) = tip {
+            if headers.header_by_blockhash(&hash).is_some() {
+                break;
+            }
+            let hash_key = full_hash(&hash[..]);
+            let header: Option<BlockHeader> = txstore_db
+                .get(&BlockRow::header_key(hash_key))
+                .map(|header| deserialize(&header).expect("failed to parse BlockHeader"));
+            let height: Option<u32> = txstore_db
+                .get(&BlockRow::orphan_key(hash_key))
+                .map(|height| bincode::deserialize(&height).expect("failed to parse height"));
+            match (header, height) {
+                (Some(header), Some(height)) => {
+                    if let Some(stats) = block_stats(hash_key) {
+                        disconnected.push((height as usize, header.time, stats));
+                    }
+                    tip = Some(header.prev_blockhash);
+                }
+                _ => {
+                    warn!("missing orphaned block {}, rebuilding the rollups", hash);
+                    disconnected.clear();
+                    rebuild = true;
+                    tip = None;
+                }
+            }
+        }
+
+        let start_height = tip.map_or(0, |hash| {
+            headers.header_by_blockhash(&hash).unwrap().height() + 1
+        });
+        if headers.len() == 0 || (start_height == headers.len() && tip == last_tip) {
+            return;
+        }
+
+        let mut rollups: HashMap<Bytes, S> = HashMap::new();
+        if rebuild {
+            // including the periods left without any block, zeroed
+            for row in history_db.iter_scan(prefix) {
+                if row.key != tip_key {
+                    rollups.insert(row.key, S::default());
+                }
+            }
+        }
+        let mut apply = |height: usize, time: u32, stats: S, connected: bool| {
+            for key in rollup_keys(height, time) {
+                let rollup = match rollups.entry(key) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let stored = if rebuild {
+                            None
+                        } else {
+                            history_db.get(entry.key()).map(|val| {
+                                bincode::deserialize(&val).expect("failed to parse rollup")
+                            })
+                        };
+                        entry.insert(stored.unwrap_or_default())
+                    }
+                };
+                if connected {
+                    rollup.add(&stats);
+                } else {
+                    rollup.sub(&stats);
+                }
+            }
+        };
+        for (height, time, stats) in disconnected {
+            apply(height, time, stats, false);
+        }
+        for height in start_height..headers.len() {
+            let entry = headers.header_by_height(height).unwrap();
+            if let Some(stats) = block_stats(full_hash(&entry.hash()[..])) {
+                apply(height, entry.header().time, stats, true);
+            }
+        }
+
+        let mut rows: Vec<DBRow> = rollups
+            .into_iter()
+            .map(|(key, stats)| DBRow {
+                key,
+                value: bincode::serialize(&stats).unwrap(),
+            })
+            .collect();
+        rows.push(DBRow {
+            key: tip_key.to_vec(),
+            value: headers.tip()[..].to_vec(),
+        });
+        history_db.write(rows, self.flush);
+    }
+}
+
+impl ChainQuery {
+    pub fn new(store: Arc<Store>, daemon: Option<Arc<Daemon>>, metrics: &Metrics) -> Self {
+        ChainQuery {
+            store,
+            daemon,
+            duration: metrics.histogram_vec(
+                HistogramOpts::new("query_duration", "Index query duration (in seconds)"),
+                &["name"],
+            ),
+            tx_json_cache: metrics.counter_vec(
+                MetricOpts::new(
+                    "query_tx_json_cache",
+                    "# of lookups of the transactions JSON in the persistent cache, by result",
+                ),
+                &["result"],
+            ),
+        }
+    }
+
+    fn start_timer(&self, name: &str) -> HistogramTimer {
+        self.duration.with_label_values(&[name]).start_timer()
+    }
+
+    pub fn get_block_txids(&self, hash: &Sha256dHash) -> Option<Vec<Sha256dHash>> {
+        let _timer = self.start_timer("get_block_txids");
+        self.store
+            .txstore_db
+            .get(&BlockRow::txids_key(full_hash(&hash[..])))
+            .map(|val| bincode::deserialize(&val).expect("failed to parse block txids"))
+    }
+
+    pub fn get_block_meta(&self, hash: &Sha256dHash) -> Option<BlockMeta> {
+        let _timer = self.start_timer("get_block_meta");
+        self.store
+            .txstore_db
+            .get(&BlockRow::meta_key(full_hash(&hash[..])))
+            .map(|val| bincode::deserialize(&val).expect("failed to parse BlockMeta"))
+    }
+
+    // The summary of the block, computed from its stored transactions if it was indexed before
+    // the summaries were
+    pub fn get_block_summary(&self, hash: &Sha256dHash) -> Option<BlockSummary> {
+        self.get_stored_block_summary(hash)
+            .or_else(|| self.compute_block_summary(hash))
+    }
+
+    pub fn get_stored_block_summary(&self, hash: &Sha256dHash) -> Option<BlockSummary> {
+        let _timer = self.start_timer("get_block_summary");
+        self.store
+            .history_db
+            .get(&BlockRow::summary_key(full_hash(&hash[..])))
+            .map(|val| bincode::deserialize(&val).expect("failed to parse BlockSummary"))
+    }
+
+    fn compute_block_summary(&self, hash: &Sha256dHash) -> Option<BlockSummary> {
+        let _timer = self.start_timer("compute_block_summary");
+        let (block, prevouts) = self.block_with_prevouts(hash)?;
+        Some(BlockSummary::new(&block, &prevouts))
+    }
+
+    // A block of the best chain rebuilt from its stored transactions, with their previous outputs
+    fn block_with_prevouts(
+        &self,
+        hash: &Sha256dHash,
+    ) -> Option<(BlockEntry, HashMap<OutPoint, TxOut>)> {
+        let entry = self.header_by_hash(hash)?;
+        let meta = self.get_block_meta(hash)?;
+        let txdata = self.lookup_txns(&self.get_block_txids(hash)?).ok()?;
+        let block = BlockEntry {
+            block: Block {
+                header: entry.header().clone(),
+                txdata,
+            },
+            entry,
+            size: meta.size,
+        };
+        let outpoints = block
+            .block
+            .txdata
+            .iter()
+            .flat_map(|tx| tx.input.iter())
+            .filter(|txin| has_prevout(txin))
+            .map(|txin| txin.previous_output)
+            .collect();
+        let prevouts = self.lookup_avail_txos(&outpoints);
+        Some((block, prevouts))
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    pub fn get_block_stats(&self, hash: &Sha256dHash) -> Option<BlockStats> {
+        let _timer = self.start_timer("get_block_stats");
+        self.store
+            .history_db
+            .get(&BlockRow::stats_key(full_hash(&hash[..])))
+            .map(|val| bincode::deserialize(&val).expect("failed to parse BlockStats"))
+    }
+
+    // The adoption rollups of the period, latest first (starting before the `before` index)
+    #[cfg(not(feature = "liquid"))]
+    pub fn adoption_stats(
+        &self,
+        period: AdoptionPeriod,
+        before: Option<u32>,
+        limit: usize,
+    ) -> Vec<(u32, AdoptionStats)> {
+        let _timer = self.start_timer("adoption_stats");
+        let last = match before {
+            Some(0) => return vec![],
+            Some(before) => before - 1,
+            None => u32::max_value(),
+        };
+        self.store
+            .history_db
+            .iter_scan_reverse(
+                &AdoptionRow::filter(period),
+                &AdoptionRow::key(period, last),
+            )
+            .map(|row| {
+                let (_, _, index): (u8, u8, u32) = bincode::config()
+                    .big_endian()
+                    .deserialize(&row.key)
+                    .expect("failed to parse AdoptionKey");
+                let stats: AdoptionStats =
+                    bincode::deserialize(&row.value).expect("failed to parse rollup");
+                (index, stats)
+            })
+            // periods whose blocks all got orphaned
+            .filter(|(_, stats)| stats.block_count > 0)
+            .take(limit)
+            .collect()
+    }
+
+    // The monthly peg rollups, oldest first
+    #[cfg(feature = "liquid")]
+    pub fn peg_stats(&self) -> Vec<(u32, PegStats)> {
+        let _timer = self.start_timer("peg_stats");
+        self.store
+            .history_db
+            .iter_scan(&PegRow::filter())
+            .map(|row| {
+                let (_, _, index): (u8, u8, u32) = bincode::config()
+                    .big_endian()
+                    .deserialize(&row.key)
+                    .expect("failed to parse PegKey");
+                let stats: PegStats =
+                    bincode::deserialize(&row.value).expect("failed to parse rollup");
+                (index, stats)
+            })
+            // months whose blocks all got orphaned
+            .filter(|(_, stats)| stats.block_count > 0)
+            .collect()
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    pub fn get_block_filter(&self, hash: &Sha256dHash) -> Option<(Bytes, Sha256dHash)> {
+        let _timer = self.start_timer("get_block_filter");
+        let hash = full_hash(&hash[..]);
+        let filter = self.store.history_db.get(&BlockRow::filter_key(hash))?;
+        let header = self
+            .store
+            .history_db
+            .get(&BlockRow::filter_header_key(hash))?;
+        Some((filter, parse_hash(&full_hash(&header))))
     }
 
     pub fn get_block_with_meta(&self, hash: &Sha256dHash) -> Option<BlockHeaderMeta> {
@@ -301,6 +1752,68 @@ impl ChainQuery {
         })
     }
 
+    // Get a block disconnected from the best chain by a reorg, at the height it used to have
+    pub fn get_orphaned_block_with_meta(&self, hash: &Sha256dHash) -> Option<BlockHeaderMeta> {
+        let _timer = self.start_timer("get_orphaned_block_with_meta");
+        if self.header_by_hash(hash).is_some() {
+            return None; // connected back by a later reorg
+        }
+        let hash = full_hash(&hash[..]);
+        let height: u32 = self
+            .store
+            .txstore_db
+            .get(&BlockRow::orphan_key(hash))
+            .map(|val| bincode::deserialize(&val).expect("failed to parse orphan height"))?;
+        let header = self.store.txstore_db.get(&BlockRow::header_key(hash))?;
+        Some(BlockHeaderMeta {
+            header_entry: HeaderEntry::new(
+                height as usize,
+                deserialize(&header).expect("failed to parse BlockHeader"),
+            ),
+            meta: self.get_block_meta(&parse_hash(&hash))?,
+        })
+    }
+
+    // Get the blocks currently orphaned by reorgs, as (blockhash, former height) by height desc
+    pub fn orphaned_blocks(&self) -> Vec<(Sha256dHash, usize)> {
+        let _timer = self.start_timer("orphaned_blocks");
+        let mut orphans: Vec<(Sha256dHash, usize)> = self
+            .store
+            .txstore_db
+            .iter_scan(&BlockRow::orphan_filter())
+            .map(BlockRow::from_row)
+            .map(|row| {
+                let height: u32 =
+                    bincode::deserialize(&row.value).expect("failed to parse orphan height");
+                (parse_hash(&row.key.hash), height as usize)
+            })
+            .filter(|(hash, _)| self.header_by_hash(hash).is_none())
+            .collect();
+        orphans.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        orphans
+    }
+
+    // Reassemble the consensus-serialized block from its stored header and transactions
+    // None if the block is unknown, an error if one of its transactions can't be read (e.g. if
+    // the daemon fails to provide it in light mode)
+    pub fn get_block_raw(&self, hash: &Sha256dHash) -> Result<Option<Bytes>> {
+        let _timer = self.start_timer("get_block_raw");
+        let (entry, txids) = match (self.header_by_hash(hash), self.get_block_txids(hash)) {
+            (Some(entry), Some(txids)) => (entry, txids),
+            _ => return Ok(None),
+        };
+
+        let mut raw = serialize(entry.header());
+        raw.extend(serialize(&VarInt(txids.len() as u64)));
+        for txid in &txids {
+            let rawtx = self
+                .lookup_raw_txn(txid)
+                .chain_err(|| format!("missing transaction {} of block {}", txid, hash))?;
+            raw.extend(rawtx);
+        }
+        Ok(Some(raw))
+    }
+
     fn history_iter_scan(&self, scripthash: &[u8], start_height: usize) -> ScanIterator {
         self.store.history_db.iter_scan_from(
             &TxHistoryRow::filter(&scripthash[..]),
@@ -319,7 +1832,18 @@ impl ChainQuery {
         scripthash: &[u8],
         last_seen_txid: Option<&Sha256dHash>,
         limit: usize,
-    ) -> Vec<(Transaction, BlockId)> {
+    ) -> Result<Vec<(Transaction, BlockId)>> {
+        self.history_at(scripthash, last_seen_txid, limit, std::usize::MAX)
+    }
+
+    // The history as of `max_height`, ignoring the transactions confirmed after it
+    pub fn history_at(
+        &self,
+        scripthash: &[u8],
+        last_seen_txid: Option<&Sha256dHash>,
+        limit: usize,
+        max_height: usize,
+    ) -> Result<Vec<(Transaction, BlockId)>> {
         let _timer_scan = self.start_timer("history");
         let txs_conf = self
             .history_iter_scan_reverse(scripthash)
@@ -336,30 +1860,268 @@ impl ChainQuery {
                 None => 0,
             })
             .filter_map(|txid| self.tx_confirming_block(&txid).map(|b| (txid, b)))
+            .filter(|(_, blockid)| blockid.height <= max_height)
             .take(limit)
             .collect::<Vec<(Sha256dHash, BlockId)>>();
+        // fresh addresses don't need the (parallel) transaction lookups
+        if txs_conf.is_empty() {
+            return Ok(vec![]);
+        }
 
+        // in light mode, fetching them from the daemon may fail
         let txids = txs_conf.iter().map(|t| t.0.clone()).collect();
-        self.lookup_txns(&txids)
-            .expect("failed looking up txs in history index")
+        Ok(self
+            .lookup_txns(&txids)
+            .chain_err(|| "failed looking up txs in history index")?
             .into_iter()
             .zip(txs_conf)
             .map(|(tx, (_, blockid))| (tx, blockid))
-            .collect()
+            .collect())
+    }
+
+    // Like history_at(), but resumable from a cursor and stopping once `deadline` is reached. The
+    // scan only stops between heights (so that the transactions of a height can be told apart
+    // without keeping them all), returning the cursor of the next page unless it was complete.
+    pub fn history_page(
+        &self,
+        scripthash: &[u8],
+        cursor: Option<&HistoryCursor>,
+        limit: usize,
+        max_height: usize,
+        deadline: Instant,
+    ) -> Result<(Vec<(Transaction, BlockId)>, Option<HistoryCursor>)> {
+        let _timer_scan = self.start_timer("history_page");
+        let rows = match cursor {
+            Some(cursor) => self.store.history_db.iter_scan_reverse(
+                &TxHistoryRow::filter(&scripthash[..]),
+                &TxHistoryRow::prefix_height(&scripthash[..], cursor.height),
+            ),
+            None => self.history_iter_scan_reverse(scripthash),
+        };
+        let mut last_seen_txid = cursor.and_then(|cursor| cursor.last_seen_txid);
+        let mut txs_conf: Vec<(Sha256dHash, BlockId)> = vec![];
+        let mut height_txids = HashSet::new();
+        let mut current_height = None;
+        let mut next = None;
+
+        for row in rows {
+            let row = TxHistoryRow::from_row(row);
+            let height = row.key.confirmed_height;
+            if current_height != Some(height) {
+                if let Some(current_height) = current_height {
+                    if Instant::now() >= deadline {
+                        next = Some(HistoryCursor {
+                            height: current_height,
+                            last_seen_txid,
+                        });
+                        break;
+                    }
+                }
+                current_height = Some(height);
+                height_txids.clear();
+            }
+            let txid = row.get_txid();
+            if !height_txids.insert(txid) {
+                continue;
+            }
+            if let Some(seen) = last_seen_txid {
+                if seen == txid {
+                    last_seen_txid = None;
+                }
+                continue;
+            }
+            // rows left by orphaned blocks are ignored, the transaction is found at its new height
+            let blockid = match self.tx_confirming_block(&txid) {
+                Some(b) if b.height == height as usize && b.height <= max_height => b,
+                _ => continue,
+            };
+            txs_conf.push((txid, blockid));
+            if txs_conf.len() == limit {
+                // the rest of this height is read again by the next page
+                next = Some(HistoryCursor {
+                    height: height + 1,
+                    last_seen_txid: Some(txid),
+                });
+                break;
+            }
+        }
+
+        let txids = txs_conf.iter().map(|t| t.0.clone()).collect();
+        let txs = self
+            .lookup_txns(&txids)
+            .chain_err(|| "failed looking up txs in history index")?
+            .into_iter()
+            .zip(txs_conf)
+            .map(|(tx, (_, blockid))| (tx, blockid))
+            .collect();
+        Ok((txs, next))
     }
 
     pub fn history_txids(&self, scripthash: &[u8]) -> Vec<(Sha256dHash, BlockId)> {
+        self.history_txids_since(scripthash, 0)
+    }
+
+    // The history confirmed from `start_height`, in chain order
+    pub fn history_txids_since(
+        &self,
+        scripthash: &[u8],
+        start_height: usize,
+    ) -> Vec<(Sha256dHash, BlockId)> {
         let _timer = self.start_timer("history_txids");
-        self.history_iter_scan(scripthash, 0)
+        self.history_iter_scan(scripthash, start_height)
             .map(|row| TxHistoryRow::from_row(row).get_txid())
             .unique()
             .filter_map(|txid| self.tx_confirming_block(&txid).map(|b| (txid, b)))
             .collect()
     }
 
+    // Whether any confirmed transaction involves the scripthash. The cached stats counters are
+    // checked first, so that the history index is only seeked for scripthashes without much
+    // history (once for the fresh addresses probed by the gap limit scans of wallets).
+    pub fn has_history(&self, scripthash: &[u8]) -> bool {
+        let cached = self
+            .store
+            .cache_db
+            .get(&StatsCacheRow::key(scripthash))
+            .and_then(|c| bincode::deserialize::<(ScriptStats, Sha256dHash)>(&c).ok())
+            .map_or(false, |(stats, blockhash)| {
+                stats.tx_count > 0 && self.height_by_hash(&blockhash).is_some()
+            });
+        // the rows of orphaned transactions are skipped
+        cached
+            || self
+                .history_iter_scan(scripthash, 0)
+                .map(TxHistoryRow::from_row)
+                .any(|history| self.tx_confirming_block(&history.get_txid()).is_some())
+    }
+
+    // Whether the scripthash has coins confirmed before the history starts, which its history
+    // rows don't show: imported from the UTXO snapshot (even if spent since), or still unspent
+    // in the UTXO set index from the blocks skipped with --history-start-height
+    pub fn has_unindexed_coins(&self, scripthash: &[u8]) -> bool {
+        let start_height = self.history_start_height();
+        if start_height == 0 {
+            return false;
+        }
+        let history_db = &self.store.history_db;
+        history_db
+            .iter_scan(&UtxoRow::snapshot_filter(scripthash))
+            .next()
+            .is_some()
+            || history_db
+                .iter_scan(&UtxoRow::filter(scripthash))
+                .map(UtxoRow::from_row)
+                .any(|row| (row.height as usize) < start_height)
+    }
+
+    // Key of the latest history row of the scripthash, which changes along with its history
+    pub fn last_history_key(&self, scripthash: &[u8]) -> Option<Bytes> {
+        self.history_iter_scan_reverse(scripthash)
+            .next()
+            .map(|row| row.key)
+    }
+
+    // Lazily iterate the confirmed history in chain order, with the net value change of each
+    // transaction for the scripthash. Rows are read one block at a time, so that the full
+    // history never has to be kept in memory.
+    #[cfg(not(feature = "liquid"))]
+    pub fn history_deltas<'a>(
+        &'a self,
+        scripthash: &[u8],
+    ) -> impl Iterator<Item = (Sha256dHash, BlockId, i64)> + 'a {
+        self.history_iter_scan(scripthash, 0)
+            .map(TxHistoryRow::from_row)
+            .peekable()
+            .batching(|rows| {
+                let first = rows.next()?;
+                let height = first.key.confirmed_height;
+                let mut deltas: Vec<(Sha256dHash, i64)> = vec![];
+                let mut add_row = |row: TxHistoryRow| {
+                    let (txid, delta) = match row.key.txinfo {
+                        TxHistoryInfo::Funding(ref info) => (info.txid, info.value as i64),
+                        TxHistoryInfo::Spending(ref info) => (info.txid, -(info.value as i64)),
+                    };
+                    let txid = parse_hash(&txid);
+                    match deltas.iter_mut().find(|(t, _)| *t == txid) {
+                        Some((_, total)) => *total += delta,
+                        None => deltas.push((txid, delta)),
+                    }
+                };
+                add_row(first);
+                while rows
+                    .peek()
+                    .map_or(false, |row| row.key.confirmed_height == height)
+                {
+                    add_row(rows.next().unwrap());
+                }
+                Some((height, deltas))
+            })
+            .flat_map(move |(height, deltas)| {
+                deltas.into_iter().filter_map(move |(txid, delta)| {
+                    // skip rows left behind by orphaned blocks
+                    self.tx_confirming_block(&txid)
+                        .filter(|blockid| blockid.height == height as usize)
+                        .map(|blockid| (txid, blockid, delta))
+                })
+            })
+    }
+
+    // The value received by each of the scripthash's confirmed funding rows, as (height, value)
+    #[cfg(not(feature = "liquid"))]
+    pub fn fundings(&self, scripthash: &[u8]) -> Vec<(usize, Amount)> {
+        let _timer = self.start_timer("fundings");
+        self.history_iter_scan(scripthash, 0)
+            .map(TxHistoryRow::from_row)
+            .filter_map(|row| match row.key.txinfo {
+                TxHistoryInfo::Funding(ref info) => {
+                    let height = row.key.confirmed_height as usize;
+                    // skip rows left behind by orphaned blocks
+                    self.tx_confirming_block(&parse_hash(&info.txid))
+                        .filter(|blockid| blockid.height == height)
+                        .map(|_| (height, Amount::from_sat(info.value)))
+                }
+                TxHistoryInfo::Spending(_) => None,
+            })
+            .collect()
+    }
+
+    // Sample the history index for the distribution of rows per scripthash. Scripthashes are
+    // uniformly distributed, so the first `sample` ones in key order are a fair sample, and
+    // the portion of the keyspace they cover gives an estimate of the total.
+    pub fn history_histogram(&self, sample: usize) -> HistoryHistogram {
+        let _timer = self.start_timer("history_histogram");
+        let mut counts: Vec<usize> = vec![];
+        let mut last_scripthash: Option<FullHash> = None;
+        let mut complete = true;
+        for row in self.store.history_db.iter_scan(&TxHistoryRow::filter(b"")) {
+            let scripthash = full_hash(&row.key[1..33]);
+            if last_scripthash != Some(scripthash) {
+                if counts.len() == sample {
+                    complete = false;
+                    break;
+                }
+                counts.push(0);
+                last_scripthash = Some(scripthash);
+            }
+            *counts.last_mut().unwrap() += 1;
+        }
+
+        let estimated_scripthashes = match (complete, last_scripthash) {
+            (false, Some(scripthash)) => {
+                let position = u64::from_be_bytes(*array_ref![scripthash, 0, 8]) as f64 + 1.0;
+                (counts.len() as f64 * 2f64.powi(64) / position).round() as u64
+            }
+            _ => counts.len() as u64,
+        };
+        HistoryHistogram::new(counts, estimated_scripthashes)
+    }
+
     // TODO: avoid duplication with stats/stats_delta?
     pub fn utxo(&self, scripthash: &[u8]) -> Vec<Utxo> {
         let _timer = self.start_timer("utxo");
+        if let Some(utxos) = self.utxo_from_index(scripthash) {
+            return utxos;
+        }
 
         // get the last known utxo set and the blockhash it was updated for.
         // invalidates the cache if the block was orphaned.
@@ -403,10 +2165,78 @@ impl ChainQuery {
             .collect()
     }
 
-    pub fn utxo_delta(
+    // Read the unspent outputs from the UTXO set index, if it is enabled and up to date with
+    // the best chain
+    fn utxo_from_index(&self, scripthash: &[u8]) -> Option<Vec<Utxo>> {
+        let tip = self.store.history_db.get(&UtxoRow::tip_key())?;
+        let headers = self.store.indexed_headers.read().unwrap();
+        if tip[..] != headers.tip()[..] {
+            return None;
+        }
+        self.store
+            .history_db
+            .iter_scan(&UtxoRow::filter(scripthash))
+            .map(|row| {
+                let row = UtxoRow::from_row(row);
+                let blockid = BlockId::from(headers.header_by_height(row.height as usize)?);
+                Some(Utxo {
+                    txid: parse_hash(&row.key.txid),
+                    vout: row.key.vout,
+                    confirmed: Some(blockid),
+                    value: row.value,
+                })
+            })
+            .collect()
+    }
+
+    // Read up to `limit` unspent outputs following `after` (in outpoint order) from the UTXO set
+    // index, if it is enabled and up to date with the best chain
+    pub fn utxo_page(
         &self,
         scripthash: &[u8],
-        init_utxos: UtxoMap,
+        after: Option<&OutPoint>,
+        limit: usize,
+    ) -> Option<Vec<Utxo>> {
+        let _timer = self.start_timer("utxo_page");
+        let tip = self.store.history_db.get(&UtxoRow::tip_key())?;
+        let headers = self.store.indexed_headers.read().unwrap();
+        if tip[..] != headers.tip()[..] {
+            return None;
+        }
+        let scripthash = full_hash(scripthash);
+        let start_at = match after {
+            Some(outpoint) => {
+                UtxoRow::key(&scripthash, &full_hash(&outpoint.txid[..]), outpoint.vout)
+            }
+            None => UtxoRow::filter(&scripthash),
+        };
+        self.store
+            .history_db
+            .iter_scan_from(&UtxoRow::filter(&scripthash), &start_at)
+            .map(UtxoRow::from_row)
+            // the scan starts at the cursor itself, if it is still unspent
+            .skip_while(|row| {
+                after.map_or(false, |outpoint| {
+                    row.key.txid[..] == outpoint.txid[..] && row.key.vout == outpoint.vout
+                })
+            })
+            .take(limit)
+            .map(|row| {
+                let blockid = BlockId::from(headers.header_by_height(row.height as usize)?);
+                Some(Utxo {
+                    txid: parse_hash(&row.key.txid),
+                    vout: row.key.vout,
+                    confirmed: Some(blockid),
+                    value: row.value,
+                })
+            })
+            .collect()
+    }
+
+    pub fn utxo_delta(
+        &self,
+        scripthash: &[u8],
+        init_utxos: UtxoMap,
         start_height: usize,
     ) -> (UtxoMap, Option<Sha256dHash>, usize) {
         let _timer = self.start_timer("utxo_delta");
@@ -439,6 +2269,11 @@ impl ChainQuery {
     }
 
     pub fn stats(&self, scripthash: &[u8]) -> ScriptStats {
+        self.stats_at(scripthash, std::usize::MAX)
+    }
+
+    // The stats as of `max_height`, ignoring the transactions confirmed after it
+    pub fn stats_at(&self, scripthash: &[u8], max_height: usize) -> ScriptStats {
         let _timer = self.start_timer("stats");
 
         // get the last known stats and the blockhash they are updated for.
@@ -447,17 +2282,41 @@ impl ChainQuery {
             .store
             .cache_db
             .get(&StatsCacheRow::key(scripthash))
-            .map(|c| bincode::deserialize(&c).unwrap())
+            // rows in an outdated stats format are kept under older prefixes, see StatsCacheRow
+            .and_then(|c| bincode::deserialize(&c).ok())
             .and_then(|(stats, blockhash)| {
                 self.height_by_hash(&blockhash)
                     .map(|height| (stats, height))
             });
+        // stats cached past `max_height` are rolled back instead (and aren't replaced by older ones)
+        let cache_ahead = cache
+            .as_ref()
+            .map_or(false, |(_, height)| *height > max_height);
+        // the stats of the coins of the UTXO snapshot, as of its block. Before it, only the
+        // history (starting after the snapshot) is known.
+        let snapshot_height = self.store.utxo_snapshot().map(|snapshot| snapshot.height);
+        let before_snapshot = snapshot_height.map_or(false, |height| height > max_height);
 
         // update stats with new transactions since
-        let (newstats, lastblock) = cache.map_or_else(
-            || self.stats_delta(scripthash, ScriptStats::default(), 0),
-            |(oldstats, blockheight)| self.stats_delta(scripthash, oldstats, blockheight + 1),
-        );
+        let (newstats, lastblock) = match cache {
+            _ if before_snapshot => (ScriptStats::default(), None),
+            Some((oldstats, blockheight)) if cache_ahead => (
+                self.stats_rollback(scripthash, oldstats, blockheight, max_height),
+                None,
+            ),
+            Some((oldstats, blockheight)) => {
+                self.stats_delta(scripthash, oldstats, blockheight + 1, max_height)
+            }
+            None => match snapshot_height {
+                Some(height) => self.stats_delta(
+                    scripthash,
+                    self.snapshot_stats(scripthash),
+                    height + 1,
+                    max_height,
+                ),
+                None => self.stats_delta(scripthash, ScriptStats::default(), 0, max_height),
+            },
+        };
 
         // save updated stats to cache
         if let Some(lastblock) = lastblock {
@@ -472,11 +2331,88 @@ impl ChainQuery {
         newstats
     }
 
+    // The stats of the scripthash's coins in the UTXO snapshot, which were all unspent then
+    fn snapshot_stats(&self, scripthash: &[u8]) -> ScriptStats {
+        let mut stats = ScriptStats::default();
+        let mut txids = HashSet::new();
+        for row in self
+            .store
+            .history_db
+            .iter_scan(&UtxoRow::snapshot_filter(scripthash))
+        {
+            let coin = UtxoRow::from_row(row);
+            let height = coin.height as usize;
+            if txids.insert(coin.key.txid) {
+                stats.tx_count += 1;
+                stats.funding_tx_count += 1;
+            }
+            stats.funded_txo_count += 1;
+            #[cfg(not(feature = "liquid"))]
+            {
+                stats.funded_txo_sum += Amount::from_sat(coin.value);
+            }
+            if stats
+                .first_funding_height
+                .map_or(true, |first| height < first)
+            {
+                stats.first_funding_height = Some(height);
+            }
+            stats.last_activity_height = stats.last_activity_height.max(Some(height));
+        }
+        stats.first_funding_time = stats
+            .first_funding_height
+            .and_then(|height| self.header_by_height(height))
+            .map(|header| header.header().time);
+        stats
+    }
+
+    // Roll the stats as of `cached_height` back to `max_height`, by removing the blocks in between
+    fn stats_rollback(
+        &self,
+        scripthash: &[u8],
+        cached_stats: ScriptStats,
+        cached_height: usize,
+        max_height: usize,
+    ) -> ScriptStats {
+        let (later, _) = self.stats_delta(
+            scripthash,
+            ScriptStats::default(),
+            max_height + 1,
+            cached_height,
+        );
+        let mut stats = cached_stats;
+        stats.sub(&later);
+
+        if stats
+            .first_funding_height
+            .map_or(false, |height| height > max_height)
+        {
+            stats.first_funding_height = None;
+            stats.first_funding_time = None;
+        }
+        if later.last_activity_height.is_some() {
+            // the last confirmed row up to `max_height`, skipping the orphaned ones
+            stats.last_activity_height = self
+                .store
+                .history_db
+                .iter_scan_reverse(
+                    &TxHistoryRow::filter(&scripthash[..]),
+                    &TxHistoryRow::prefix_height(&scripthash[..], (max_height + 1) as u32),
+                )
+                .map(TxHistoryRow::from_row)
+                .filter_map(|history| self.tx_confirming_block(&history.get_txid()))
+                .map(|blockid| blockid.height)
+                .find(|height| *height <= max_height);
+        }
+        stats
+    }
+
     fn stats_delta(
         &self,
         scripthash: &[u8],
         init_stats: ScriptStats,
         start_height: usize,
+        max_height: usize,
     ) -> (ScriptStats, Option<Sha256dHash>) {
         let _timer = self.start_timer("stats_delta"); // TODO: measure also the number of txns processed.
         let history_iter = self
@@ -485,41 +2421,49 @@ impl ChainQuery {
             .filter_map(|history| {
                 self.tx_confirming_block(&history.get_txid())
                     .map(|blockid| (history, blockid))
-            });
+            })
+            .take_while(|(_, blockid)| blockid.height <= max_height);
 
         let mut stats = init_stats;
         let mut seen_txids = HashSet::new();
+        let mut seen_funding_txids = HashSet::new();
         let mut lastblock = None;
 
         for (history, blockid) in history_iter {
             if lastblock != Some(blockid.hash) {
                 seen_txids.clear();
+                seen_funding_txids.clear();
             }
 
             if seen_txids.insert(history.get_txid()) {
                 stats.tx_count += 1;
             }
 
+            // rows are iterated in height order
+            if let TxHistoryInfo::Funding(_) = history.key.txinfo {
+                if seen_funding_txids.insert(history.get_txid()) {
+                    stats.funding_tx_count += 1;
+                }
+                if stats.first_funding_height.is_none() {
+                    stats.first_funding_height = Some(blockid.height);
+                    stats.first_funding_time = Some(blockid.time);
+                }
+            }
+            stats.last_activity_height = Some(blockid.height);
+
             match history.key.txinfo {
                 #[cfg(not(feature = "liquid"))]
                 TxHistoryInfo::Funding(ref info) => {
                     stats.funded_txo_count += 1;
-                    stats.funded_txo_sum += info.value;
-                }
-                #[cfg(feature = "liquid")]
-                TxHistoryInfo::Funding(_) => {
-                    stats.funded_txo_count += 1;
+                    stats.funded_txo_sum += Amount::from_sat(info.value);
                 }
-
                 #[cfg(not(feature = "liquid"))]
                 TxHistoryInfo::Spending(ref info) => {
                     stats.spent_txo_count += 1;
-                    stats.spent_txo_sum += info.value;
+                    stats.spent_txo_sum += Amount::from_sat(info.value);
                 }
                 #[cfg(feature = "liquid")]
-                TxHistoryInfo::Spending(_) => {
-                    stats.spent_txo_count += 1;
-                }
+                ref info => stats.add_history(info),
             };
 
             lastblock = Some(blockid.hash);
@@ -556,6 +2500,19 @@ impl ChainQuery {
             .cloned()
     }
 
+    // The headers of up to `count` blocks starting at `start_height`, read under a single lock
+    pub fn headers_from(&self, start_height: usize, count: usize) -> Vec<BlockHeader> {
+        self.store
+            .indexed_headers
+            .read()
+            .unwrap()
+            .iter()
+            .skip(start_height)
+            .take(count)
+            .map(|entry| entry.header().clone())
+            .collect()
+    }
+
     pub fn hash_by_height(&self, height: usize) -> Option<Sha256dHash> {
         self.store
             .indexed_headers
@@ -584,6 +2541,100 @@ impl ChainQuery {
             .map(BlockId::from)
     }
 
+    // The height the history is indexed from (0 unless pruned with --history-start-height)
+    pub fn history_start_height(&self) -> usize {
+        self.store.history_start_height()
+    }
+
+    pub fn index_metadata(&self) -> Result<Option<IndexMetadata>> {
+        self.store.index_metadata()
+    }
+
+    pub fn utxo_snapshot(&self) -> Option<UtxoSnapshotInfo> {
+        self.store.utxo_snapshot()
+    }
+
+    // The REST JSON of a final transaction kept in the persistent cache, for the `variant` of
+    // the options changing it
+    pub fn cached_tx_json(&self, txid: &Sha256dHash, variant: u32) -> Option<Bytes> {
+        let json = self.store.cache_db.get(&TxJsonCacheRow::key(txid, variant));
+        let result = if json.is_some() { "hit" } else { "miss" };
+        self.tx_json_cache.with_label_values(&[result]).inc();
+        json
+    }
+
+    // Written without the WAL, these are persisted along with the memtables (see
+    // Store::flush_cache)
+    pub fn cache_tx_json(&self, entries: &[(Sha256dHash, Bytes)], variant: u32) {
+        let cache_db = &self.store.cache_db;
+        let mut count = self.store.tx_json_count.lock().unwrap();
+        // counted once, when also cached by concurrent requests
+        let mut rows: Vec<DBRow> = entries
+            .iter()
+            .map(|(txid, json)| TxJsonCacheRow::new(txid, variant, json).to_row())
+            .unique_by(|row| row.key.clone())
+            .filter(|row| cache_db.get(&row.key).is_none())
+            .collect();
+        if rows.is_empty() {
+            return;
+        }
+        *count += rows.len() as u64;
+        rows.push(tx_json_count_row(*count));
+        cache_db.write(rows, DBFlush::Disable);
+    }
+
+    // The stats of a block indexed before these were, computed from its stored transactions
+    #[cfg(not(feature = "liquid"))]
+    pub fn compute_block_stats(&self, hash: &Sha256dHash, network: Network) -> Option<BlockStats> {
+        let _timer = self.start_timer("compute_block_stats");
+        let (block, prevouts) = self.block_with_prevouts(hash)?;
+        let subsidy = network.block_subsidy(block.entry.height());
+        Some(BlockStats::new(&block, &prevouts, subsidy))
+    }
+
+    // The computed stats of a final block kept in the persistent cache
+    #[cfg(not(feature = "liquid"))]
+    pub fn cached_block_stats(&self, hash: &Sha256dHash) -> Option<BlockStats> {
+        self.store
+            .cache_db
+            .get(&block_stats_cache_key(hash))
+            .map(|val| bincode::deserialize(&val).expect("failed to parse cached BlockStats"))
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    pub fn cache_block_stats(&self, hash: &Sha256dHash, stats: &BlockStats) {
+        let row = DBRow {
+            key: block_stats_cache_key(hash),
+            value: bincode::serialize(stats).unwrap(),
+        };
+        self.store.cache_db.write(vec![row], DBFlush::Disable);
+    }
+
+    // The confirmed issuance and reissuances of the asset, in chain order
+    #[cfg(feature = "liquid")]
+    pub fn asset_issuances(&self, asset_id: &Sha256dHash) -> Vec<Issuance> {
+        let _timer = self.start_timer("asset_issuances");
+        self.store
+            .history_db
+            .iter_scan(&IssuanceRow::filter(asset_id))
+            .map(IssuanceRow::from_row)
+            // rows left by orphaned blocks are ignored
+            .filter(|issuance| {
+                self.tx_confirming_block(&issuance.txid)
+                    .map_or(false, |b| b.height == issuance.height)
+            })
+            .collect()
+    }
+
+    // The number of operations on each database since they were opened
+    pub fn db_operations(&self) -> Vec<(&'static str, Vec<(&'static str, usize)>)> {
+        vec![
+            ("txstore", self.store.txstore_db.stats()),
+            ("history", self.store.history_db.stats()),
+            ("cache", self.store.cache_db.stats()),
+        ]
+    }
+
     pub fn best_height(&self) -> usize {
         self.store.indexed_headers.read().unwrap().len() - 1
     }
@@ -621,7 +2672,22 @@ impl ChainQuery {
 
     pub fn lookup_raw_txn(&self, txid: &Sha256dHash) -> Option<Bytes> {
         let _timer = self.start_timer("lookup_raw_txn");
-        self.store.txstore_db.get(&TxRow::key(&txid[..]))
+        self.store
+            .txstore_db
+            .get(&TxRow::key(&txid[..]))
+            .or_else(|| self.lookup_raw_txn_from_daemon(txid))
+    }
+
+    // In light mode the raw transactions aren't stored, the confirmed ones are fetched from
+    // bitcoind instead (which requires txindex)
+    fn lookup_raw_txn_from_daemon(&self, txid: &Sha256dHash) -> Option<Bytes> {
+        let daemon = self.daemon.as_ref()?;
+        self.tx_confirming_block(txid)?;
+        let _timer = self.start_timer("lookup_raw_txn_daemon");
+        daemon
+            .gettransaction_raw(txid)
+            .map_err(|e| warn!("failed fetching tx {} from the daemon: {}", txid, e))
+            .ok()
     }
 
     pub fn lookup_txo(&self, outpoint: &OutPoint) -> Option<TxOut> {
@@ -655,6 +2721,23 @@ impl ChainQuery {
             })
     }
     pub fn tx_confirming_block(&self, txid: &Sha256dHash) -> Option<BlockId> {
+        self.tx_confirmation(txid).map(|(blockid, _)| blockid)
+    }
+
+    // Like tx_confirming_block(), along with the position of the transaction within the block
+    pub fn tx_confirming_block_pos(&self, txid: &Sha256dHash) -> Option<(BlockId, usize)> {
+        let (blockid, pos) = self.tx_confirmation(txid)?;
+        let pos = match pos {
+            Some(pos) => pos as usize,
+            None => self
+                .get_block_txids(&blockid.hash)?
+                .iter()
+                .position(|block_txid| block_txid == txid)?,
+        };
+        Some((blockid, pos))
+    }
+
+    fn tx_confirmation(&self, txid: &Sha256dHash) -> Option<(BlockId, Option<u32>)> {
         let _timer = self.start_timer("tx_confirming_block");
         let headers = self.store.indexed_headers.read().unwrap();
         self.store
@@ -663,9 +2746,13 @@ impl ChainQuery {
             .map(TxConfRow::from_row)
             // header_by_blockhash only returns blocks that are part of the best chain,
             // or None for orphaned blocks.
-            .filter_map(|conf| headers.header_by_blockhash(&parse_hash(&conf.key.blockhash)))
-            .nth(0)
-            .map(BlockId::from)
+            .filter_map(|conf| {
+                headers
+                    .header_by_blockhash(&parse_hash(&conf.key.blockhash))
+                    .map(|header| (BlockId::from(header), conf.pos))
+            })
+            // BIP30 duplicates are confirmed in multiple blocks, use the first one
+            .min_by_key(|(blockid, _)| blockid.height)
     }
 
     pub fn get_block_status(&self, hash: &Sha256dHash) -> BlockStatus {
@@ -688,6 +2775,40 @@ impl ChainQuery {
             },
         )
     }
+
+    // Returns the MuHash commitment to the UTXO set as of the given height (None if not available)
+    pub fn utxo_commitment(&self, height: usize) -> Option<(BlockId, [u8; 32])> {
+        let _timer = self.start_timer("utxo_commitment");
+        let headers = self.store.indexed_headers.read().unwrap();
+        let history_db = &self.store.history_db;
+        let blockid = headers.header_by_height(height).map(BlockId::from)?;
+
+        // commitments are only available if the deltas were recorded since the genesis block
+        let genesis_hash = headers.header_by_height(0)?.hash();
+        history_db.get(&BlockRow::utxo_delta_key(full_hash(&genesis_hash[..])))?;
+
+        // start from the closest best-chain checkpoint at or below the requested height
+        let (mut acc, start_height) = history_db
+            .iter_scan_reverse(
+                &UtxoCheckpointRow::filter(),
+                &UtxoCheckpointRow::prefix_end(height),
+            )
+            .map(UtxoCheckpointRow::from_row)
+            .find(|row| {
+                headers
+                    .header_by_height(row.key.height as usize)
+                    .map_or(false, |h| full_hash(&h.hash()[..]) == row.key.blockhash)
+            })
+            .map(|row| (MuHash::from_bytes(&row.value), row.key.height as usize))
+            .unwrap_or((MuHash::new(), 0));
+
+        for h in start_height + 1..=height {
+            let hash = headers.header_by_height(h)?.hash();
+            let delta = history_db.get(&BlockRow::utxo_delta_key(full_hash(&hash[..])))?;
+            acc.combine(&MuHash::from_bytes(&delta));
+        }
+        Some((blockid, acc.finalize()))
+    }
 }
 
 fn load_blockhashes(db: &DB, prefix: &[u8]) -> HashSet<Sha256dHash> {
@@ -708,10 +2829,10 @@ fn load_blockheaders(db: &DB) -> HashMap<Sha256dHash, BlockHeader> {
         .collect()
 }
 
-fn add_blocks(block_entries: &[BlockEntry]) -> Vec<DBRow> {
+fn add_blocks(block_entries: &[BlockEntry], light_mode: bool) -> Vec<DBRow> {
     // persist individual transactions:
-    //      T{txid} → {rawtx}
-    //      C{txid}{blockhash}{height} →
+    //      T{txid} → {rawtx} (unless in light mode)
+    //      C{txid}{blockhash} → {pos}
     //      O{txid}{index} → {txout}
     // persist block headers', block txids' and metadata rows:
     //      B{blockhash} → {header}
@@ -723,10 +2844,12 @@ fn add_blocks(block_entries: &[BlockEntry]) -> Vec<DBRow> {
             let mut rows = vec![];
             let blockhash = full_hash(&b.entry.hash()[..]);
             let txids: Vec<Sha256dHash> = b.block.txdata.iter().map(|tx| tx.txid()).collect();
-            for tx in &b.block.txdata {
-                add_transaction(tx, blockhash, &mut rows);
+            // bitcoind can't serve the genesis coinbase (it isn't part of its txindex)
+            let light_mode = light_mode && b.entry.height() > 0;
+            for (pos, tx) in b.block.txdata.iter().enumerate() {
+                add_transaction(tx, blockhash, pos, light_mode, &mut rows);
             }
-            rows.push(BlockRow::new_header(&b).to_row());
+            rows.push(BlockRow::new_header(&b.entry).to_row());
             rows.push(BlockRow::new_txids(blockhash, &txids).to_row());
             rows.push(BlockRow::new_meta(blockhash, &BlockMeta::from(b)).to_row());
             rows.push(BlockRow::new_done(blockhash).to_row()); // mark block as "added"
@@ -736,9 +2859,17 @@ fn add_blocks(block_entries: &[BlockEntry]) -> Vec<DBRow> {
         .collect()
 }
 
-fn add_transaction(tx: &Transaction, blockhash: FullHash, rows: &mut Vec<DBRow>) {
-    rows.push(TxRow::new(tx).to_row());
-    rows.push(TxConfRow::new(tx, blockhash).to_row());
+fn add_transaction(
+    tx: &Transaction,
+    blockhash: FullHash,
+    pos: usize,
+    light_mode: bool,
+    rows: &mut Vec<DBRow>,
+) {
+    if !light_mode {
+        rows.push(TxRow::new(tx).to_row());
+    }
+    rows.push(TxConfRow::new(tx, blockhash, pos as u32).to_row());
 
     let txid = full_hash(&tx.txid()[..]);
     for (txo_index, txo) in tx.output.iter().enumerate() {
@@ -788,6 +2919,21 @@ fn lookup_txos(
     })
 }
 
+// Whether a stored transaction is a coinbase, from its raw transaction or (in light mode, where it
+// isn't stored) from its position within the blocks confirming it
+fn is_stored_coinbase(txstore_db: &DB, txid: &Sha256dHash) -> bool {
+    match txstore_db.get(&TxRow::key(&txid[..])) {
+        Some(rawtx) => {
+            let tx: Transaction = deserialize(&rawtx).expect("failed to parse Transaction");
+            is_coinbase(&tx.input[0])
+        }
+        None => txstore_db
+            .iter_scan(&TxConfRow::filter(&txid[..]))
+            .map(TxConfRow::from_row)
+            .any(|conf| conf.pos == Some(0)),
+    }
+}
+
 fn lookup_txo(txstore_db: &DB, outpoint: &OutPoint) -> Option<TxOut> {
     txstore_db
         .get(&TxOutRow::key(&outpoint))
@@ -797,15 +2943,26 @@ fn lookup_txo(txstore_db: &DB, outpoint: &OutPoint) -> Option<TxOut> {
 fn index_blocks(
     block_entries: &[BlockEntry],
     previous_txos_map: &HashMap<OutPoint, TxOut>,
+    duplicate_coinbases: &HashSet<Sha256dHash>,
+    history_start_height: usize,
 ) -> Vec<DBRow> {
     block_entries
         .par_iter() // serialization is CPU-intensive
         .map(|b| {
             let mut rows = vec![];
-            for tx in &b.block.txdata {
+            let is_duplicate = duplicate_coinbases.contains(b.entry.hash());
+            // the spending edges are still indexed below the pruning height
+            let with_history = b.entry.height() >= history_start_height;
+            for (tx_index, tx) in b.block.txdata.iter().enumerate() {
+                // the coins of a duplicate coinbase were indexed for its first confirmation
+                if tx_index == 0 && is_duplicate {
+                    continue;
+                }
                 let height = b.entry.height() as u32;
-                index_transaction(tx, height, previous_txos_map, &mut rows);
+                index_transaction(tx, height, previous_txos_map, with_history, &mut rows);
             }
+            let summary = BlockSummary::new(b, previous_txos_map);
+            rows.push(BlockRow::new_summary(full_hash(&b.entry.hash()[..]), &summary).to_row());
             rows.push(BlockRow::new_done(full_hash(&b.entry.hash()[..])).to_row()); // mark block as "indexed"
             rows
         })
@@ -818,6 +2975,7 @@ fn index_transaction(
     tx: &Transaction,
     confirmed_height: u32,
     previous_txos_map: &HashMap<OutPoint, TxOut>,
+    with_history: bool,
     rows: &mut Vec<DBRow>,
 ) {
     // persist history index:
@@ -827,7 +2985,7 @@ fn index_transaction(
     //      S{funding-txid:vout}{spending-txid:vin} → ""
     let txid = full_hash(&tx.txid()[..]);
     for (txo_index, txo) in tx.output.iter().enumerate() {
-        if is_spendable(txo) {
+        if with_history && is_spendable(txo) {
             let history = TxHistoryRow::new(
                 &txo.script_pubkey,
                 confirmed_height,
@@ -835,6 +2993,8 @@ fn index_transaction(
                     txid,
                     vout: txo_index as u16,
                     value: txo.value,
+                    #[cfg(feature = "liquid")]
+                    asset: txo.asset,
                 }),
             );
             rows.push(history.to_row())
@@ -844,22 +3004,26 @@ fn index_transaction(
         if !has_prevout(txi) {
             continue;
         }
-        let prev_txo = previous_txos_map
-            .get(&txi.previous_output)
-            .expect(&format!("missing previous txo {}", txi.previous_output));
+        if with_history {
+            let prev_txo = previous_txos_map
+                .get(&txi.previous_output)
+                .expect(&format!("missing previous txo {}", txi.previous_output));
 
-        let history = TxHistoryRow::new(
-            &prev_txo.script_pubkey,
-            confirmed_height,
-            TxHistoryInfo::Spending(SpendingInfo {
-                txid,
-                vin: txi_index as u16,
-                prev_txid: full_hash(&txi.previous_output.txid[..]),
-                prev_vout: txi.previous_output.vout as u16,
-                value: prev_txo.value,
-            }),
-        );
-        rows.push(history.to_row());
+            let history = TxHistoryRow::new(
+                &prev_txo.script_pubkey,
+                confirmed_height,
+                TxHistoryInfo::Spending(SpendingInfo {
+                    txid,
+                    vin: txi_index as u16,
+                    prev_txid: full_hash(&txi.previous_output.txid[..]),
+                    prev_vout: txi.previous_output.vout as u16,
+                    value: prev_txo.value,
+                    #[cfg(feature = "liquid")]
+                    asset: prev_txo.asset,
+                }),
+            );
+            rows.push(history.to_row());
+        }
 
         let edge = TxEdgeRow::new(
             full_hash(&txi.previous_output.txid[..]),
@@ -871,6 +3035,281 @@ fn index_transaction(
     }
 }
 
+struct NewHeights {
+    heights: HashMap<Sha256dHash, usize>,
+    fork_height: usize, // the lowest new height, headers above it are replaced
+}
+
+// Sorted best-chain confirmation heights of a transaction, including the blocks being indexed
+fn confirmation_heights(
+    txstore_db: &DB,
+    headers: &HeaderList,
+    new_heights: &NewHeights,
+    txid: &Sha256dHash,
+) -> Vec<usize> {
+    let mut heights: Vec<usize> = txstore_db
+        .iter_scan(&TxConfRow::filter(&txid[..]))
+        .map(TxConfRow::from_row)
+        .filter_map(|conf| {
+            let blockhash = parse_hash(&conf.key.blockhash);
+            new_heights.heights.get(&blockhash).cloned().or_else(|| {
+                // ignore blocks that are about to be orphaned by a reorg
+                headers
+                    .header_by_blockhash(&blockhash)
+                    .map(|h| h.height())
+                    .filter(|height| *height < new_heights.fork_height)
+            })
+        })
+        .collect();
+    heights.sort();
+    heights
+}
+
+// txid => (best-chain confirmation heights, is_coinbase)
+type ConfirmationMap = HashMap<Sha256dHash, (Vec<usize>, bool)>;
+
+#[cfg(not(feature = "liquid"))]
+fn block_filter_rows(
+    block_entries: &[BlockEntry],
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+) -> Result<Vec<DBRow>> {
+    // persist the BIP158 basic filter of each block:
+    //      G{blockhash} → {filter}
+    block_entries
+        .par_iter() // hashing is CPU-intensive
+        .map(|b| {
+            let filter = block_filter(&b.block, previous_txos_map)
+                .chain_err(|| format!("failed to build the filter of block {}", b.entry.hash()))?;
+            Ok(BlockRow::new_filter(full_hash(&b.entry.hash()[..]), filter).to_row())
+        })
+        .collect()
+}
+
+#[cfg(not(feature = "liquid"))]
+fn block_stats_rows(
+    block_entries: &[BlockEntry],
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    network: Network,
+) -> Vec<DBRow> {
+    // persist the fee and reward stats of each block:
+    //      J{blockhash} → {stats}
+    block_entries
+        .par_iter()
+        .map(|b| {
+            let subsidy = network.block_subsidy(b.entry.height());
+            let stats = BlockStats::new(b, previous_txos_map, subsidy);
+            BlockRow::new_stats(full_hash(&b.entry.hash()[..]), &stats).to_row()
+        })
+        .collect()
+}
+
+#[cfg(not(feature = "liquid"))]
+fn adoption_stats_rows(
+    block_entries: &[BlockEntry],
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+) -> Vec<DBRow> {
+    // persist the segwit and taproot usage of each block, for the adoption rollups:
+    //      W{blockhash} → {adoption}
+    block_entries
+        .par_iter()
+        .map(|b| {
+            let stats = AdoptionStats::new(b, previous_txos_map);
+            BlockRow::new_adoption(full_hash(&b.entry.hash()[..]), &stats).to_row()
+        })
+        .collect()
+}
+
+#[cfg(feature = "liquid")]
+fn peg_stats_rows(
+    block_entries: &[BlockEntry],
+    parent_network: &Network,
+    parent_genesis_hash: &str,
+) -> Vec<DBRow> {
+    // persist the peg-ins and peg-outs of each block, for the monthly rollups:
+    //      L{blockhash} → {pegs}
+    block_entries
+        .par_iter()
+        .map(|b| {
+            let stats = PegStats::new(&b.block, parent_network, parent_genesis_hash);
+            BlockRow::new_pegs(full_hash(&b.entry.hash()[..]), &stats).to_row()
+        })
+        .collect()
+}
+
+fn utxo_changes_rows(
+    block_entries: &[BlockEntry],
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    duplicate_coinbases: &HashSet<Sha256dHash>,
+) -> Result<Vec<DBRow>> {
+    // persist the coins created and spent by each block, for maintaining the UTXO set:
+    //      Y{blockhash} → {created}{spent}
+    block_entries
+        .par_iter()
+        .map(|b| {
+            let mut changes = UtxoChanges {
+                created: vec![],
+                spent: vec![],
+            };
+            let is_duplicate = duplicate_coinbases.contains(b.entry.hash());
+            for (tx_index, tx) in b.block.txdata.iter().enumerate() {
+                // the coins of a duplicate coinbase are kept for its first confirmation
+                if tx_index == 0 && is_duplicate {
+                    continue;
+                }
+                let txid = full_hash(&tx.txid()[..]);
+                for (vout, txo) in tx.output.iter().enumerate() {
+                    if is_spendable(txo) {
+                        changes.created.push(UtxoCreated {
+                            scripthash: compute_script_hash(&txo.script_pubkey),
+                            txid,
+                            vout: vout as u32,
+                            value: txo.value,
+                        });
+                    }
+                }
+                for txin in tx.input.iter().filter(|txin| has_prevout(txin)) {
+                    let prev_txo = previous_txos_map
+                        .get(&txin.previous_output)
+                        .chain_err(|| format!("missing previous txo {}", txin.previous_output))?;
+                    changes.spent.push(UtxoSpent {
+                        scripthash: compute_script_hash(&prev_txo.script_pubkey),
+                        txid: full_hash(&txin.previous_output.txid[..]),
+                        vout: txin.previous_output.vout,
+                    });
+                }
+            }
+            Ok(BlockRow::new_utxo_changes(full_hash(&b.entry.hash()[..]), &changes).to_row())
+        })
+        .collect()
+}
+
+fn utxo_commitment_rows(
+    block_entries: &[BlockEntry],
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    confirmations: &ConfirmationMap,
+) -> Result<Vec<DBRow>> {
+    // persist the MuHash delta of the coins created and spent by each block:
+    //      Z{blockhash} → {muhash}
+    block_entries
+        .par_iter() // hashing is CPU-intensive
+        .map(|b| {
+            let height = b.entry.height();
+            let mut muhash = MuHash::new();
+            // the genesis coinbase is not spendable and is not part of the UTXO set
+            if height > 0 {
+                for (tx_index, tx) in b.block.txdata.iter().enumerate() {
+                    add_coins(
+                        tx,
+                        tx_index == 0, // is_coinbase
+                        height,
+                        previous_txos_map,
+                        confirmations,
+                        &mut muhash,
+                    )
+                    .chain_err(|| format!("failed to commit to block {}", b.entry.hash()))?;
+                }
+            }
+            Ok(BlockRow::new_utxo_delta(full_hash(&b.entry.hash()[..]), &muhash).to_row())
+        })
+        .collect()
+}
+
+fn add_coins(
+    tx: &Transaction,
+    is_coinbase: bool,
+    height: usize,
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    confirmations: &ConfirmationMap,
+    muhash: &mut MuHash,
+) -> Result<()> {
+    let txid = tx.txid();
+    if is_coinbase {
+        // BIP30: a duplicate coinbase overwrites the (unspent) coins of the earlier one
+        let prev_height = confirmations
+            .get(&txid)
+            .and_then(|(heights, _)| heights.iter().rev().find(|h| **h < height));
+        if let Some(prev_height) = prev_height {
+            for (vout, txo) in tx.output.iter().enumerate() {
+                if is_utxo(txo) {
+                    let outpoint = OutPoint {
+                        txid,
+                        vout: vout as u32,
+                    };
+                    muhash.remove(&coin_bytes(&outpoint, *prev_height, true, txo));
+                }
+            }
+        }
+    }
+    for (vout, txo) in tx.output.iter().enumerate() {
+        if is_utxo(txo) {
+            let outpoint = OutPoint {
+                txid,
+                vout: vout as u32,
+            };
+            muhash.insert(&coin_bytes(&outpoint, height, is_coinbase, txo));
+        }
+    }
+    for txin in tx.input.iter().filter(|txin| has_prevout(txin)) {
+        let outpoint = &txin.previous_output;
+        let prev_txo = previous_txos_map
+            .get(outpoint)
+            .chain_err(|| format!("missing previous txo {}", outpoint))?;
+        let (heights, prev_coinbase) = confirmations
+            .get(&outpoint.txid)
+            .chain_err(|| format!("missing confirmation for {}", outpoint.txid))?;
+        let prev_height = heights
+            .iter()
+            .rev()
+            .find(|h| **h <= height)
+            .chain_err(|| format!("{} spent before being confirmed", outpoint))?;
+        muhash.remove(&coin_bytes(
+            outpoint,
+            *prev_height,
+            *prev_coinbase,
+            prev_txo,
+        ));
+    }
+    Ok(())
+}
+
+// The rows of coins imported from a UTXO snapshot: their outputs in the txstore (looked up once
+// spent), and in the history the UTXO set index and the coins seeding the stats of their
+// scripthashes
+#[cfg(not(feature = "liquid"))]
+fn utxo_snapshot_rows(coins: &[SnapshotCoin]) -> (Vec<DBRow>, Vec<DBRow>) {
+    let mut txstore_rows = Vec::with_capacity(coins.len());
+    let mut history_rows = Vec::with_capacity(coins.len() * 2);
+    for coin in coins {
+        let txid = full_hash(&coin.outpoint.txid[..]);
+        let vout = coin.outpoint.vout;
+        let scripthash = compute_script_hash(&coin.txout.script_pubkey);
+        let (height, value) = (coin.height, coin.txout.value);
+        txstore_rows.push(TxOutRow::new(&txid, vout as usize, &coin.txout).to_row());
+        history_rows.push(UtxoRow::new_row(&scripthash, &txid, vout, height, value));
+        history_rows.push(UtxoRow::snapshot_row(
+            &scripthash,
+            &txid,
+            vout,
+            height,
+            value,
+        ));
+    }
+    (txstore_rows, history_rows)
+}
+
+fn is_utxo(txo: &TxOut) -> bool {
+    !txo.script_pubkey.is_op_return() && txo.script_pubkey.len() <= MAX_SCRIPT_SIZE
+}
+
+// serialized the same way as bitcoind's TxOutSer(), so that the resulting hash matches
+fn coin_bytes(outpoint: &OutPoint, height: usize, is_coinbase: bool, txo: &TxOut) -> Bytes {
+    let code = ((height as u32) << 1) | (is_coinbase as u32);
+    let mut bytes = serialize(outpoint);
+    bytes.extend_from_slice(&serialize(&code));
+    bytes.extend(serialize(txo));
+    bytes
+}
+
 // TODO: replace by a separate opaque type (similar to Sha256dHash, but without the "double")
 pub type FullHash = [u8; 32]; // serialized SHA256 result
 
@@ -928,10 +3367,11 @@ struct TxConfKey {
 
 struct TxConfRow {
     key: TxConfKey,
+    pos: Option<u32>, // missing from rows indexed by earlier versions
 }
 
 impl TxConfRow {
-    fn new(txn: &Transaction, blockhash: FullHash) -> TxConfRow {
+    fn new(txn: &Transaction, blockhash: FullHash, pos: u32) -> TxConfRow {
         let txid = full_hash(&txn.txid()[..]);
         TxConfRow {
             key: TxConfKey {
@@ -939,6 +3379,7 @@ impl TxConfRow {
                 txid,
                 blockhash,
             },
+            pos: Some(pos),
         }
     }
 
@@ -949,13 +3390,20 @@ impl TxConfRow {
     fn to_row(self) -> DBRow {
         DBRow {
             key: bincode::serialize(&self.key).unwrap(),
-            value: vec![],
+            value: self
+                .pos
+                .map_or(vec![], |pos| bincode::serialize(&pos).unwrap()),
         }
     }
 
     fn from_row(row: DBRow) -> Self {
         TxConfRow {
             key: bincode::deserialize(&row.key).expect("failed to parse TxConfKey"),
+            pos: if row.value.is_empty() {
+                None
+            } else {
+                Some(bincode::deserialize(&row.value).expect("failed to parse tx position"))
+            },
         }
     }
 }
@@ -1012,13 +3460,13 @@ struct BlockRow {
 }
 
 impl BlockRow {
-    fn new_header(block_entry: &BlockEntry) -> BlockRow {
+    fn new_header(entry: &HeaderEntry) -> BlockRow {
         BlockRow {
             key: BlockKey {
                 code: b'B',
-                hash: full_hash(&block_entry.entry.hash()[..]),
+                hash: full_hash(&entry.hash()[..]),
             },
-            value: serialize(&block_entry.block.header),
+            value: serialize(entry.header()),
         }
     }
 
@@ -1036,33 +3484,164 @@ impl BlockRow {
         }
     }
 
-    fn new_done(hash: FullHash) -> BlockRow {
+    fn new_summary(hash: FullHash, summary: &BlockSummary) -> BlockRow {
         BlockRow {
-            key: BlockKey { code: b'D', hash },
-            value: vec![],
+            key: BlockKey { code: b'I', hash },
+            value: bincode::serialize(summary).unwrap(),
         }
     }
 
-    fn header_filter() -> Bytes {
-        b"B".to_vec()
-    }
-
-    fn txids_key(hash: FullHash) -> Bytes {
-        [b"X", &hash[..]].concat()
+    #[cfg(not(feature = "liquid"))]
+    fn new_stats(hash: FullHash, stats: &BlockStats) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'J', hash },
+            value: bincode::serialize(stats).unwrap(),
+        }
     }
 
-    fn meta_key(hash: FullHash) -> Bytes {
-        [b"M", &hash[..]].concat()
+    #[cfg(not(feature = "liquid"))]
+    fn new_adoption(hash: FullHash, stats: &AdoptionStats) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'W', hash },
+            value: bincode::serialize(stats).unwrap(),
+        }
     }
 
-    fn done_filter() -> Bytes {
-        b"D".to_vec()
+    #[cfg(feature = "liquid")]
+    fn new_pegs(hash: FullHash, stats: &PegStats) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'L', hash },
+            value: bincode::serialize(stats).unwrap(),
+        }
     }
 
-    fn to_row(self) -> DBRow {
-        DBRow {
-            key: bincode::serialize(&self.key).unwrap(),
-            value: self.value,
+    fn new_utxo_delta(hash: FullHash, muhash: &MuHash) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'Z', hash },
+            value: muhash.to_bytes(),
+        }
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn new_filter(hash: FullHash, filter: Bytes) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'G', hash },
+            value: filter,
+        }
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn new_filter_header(hash: FullHash, header: &Sha256dHash) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'F', hash },
+            value: header[..].to_vec(),
+        }
+    }
+
+    fn new_utxo_changes(hash: FullHash, changes: &UtxoChanges) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'Y', hash },
+            value: bincode::serialize(changes).unwrap(),
+        }
+    }
+
+    fn new_utxo_undo(hash: FullHash, undo: &[(Bytes, Bytes)]) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'R', hash },
+            value: bincode::serialize(undo).unwrap(),
+        }
+    }
+
+    fn new_orphan(entry: &HeaderEntry) -> BlockRow {
+        BlockRow {
+            key: BlockKey {
+                code: b'S',
+                hash: full_hash(&entry.hash()[..]),
+            },
+            value: bincode::serialize(&(entry.height() as u32)).unwrap(),
+        }
+    }
+
+    fn new_done(hash: FullHash) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'D', hash },
+            value: vec![],
+        }
+    }
+
+    fn header_filter() -> Bytes {
+        b"B".to_vec()
+    }
+
+    fn header_key(hash: FullHash) -> Bytes {
+        [b"B", &hash[..]].concat()
+    }
+
+    fn txids_key(hash: FullHash) -> Bytes {
+        [b"X", &hash[..]].concat()
+    }
+
+    fn meta_key(hash: FullHash) -> Bytes {
+        [b"M", &hash[..]].concat()
+    }
+
+    fn summary_key(hash: FullHash) -> Bytes {
+        [b"I", &hash[..]].concat()
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn stats_key(hash: FullHash) -> Bytes {
+        [b"J", &hash[..]].concat()
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn adoption_key(hash: FullHash) -> Bytes {
+        [b"W", &hash[..]].concat()
+    }
+
+    #[cfg(feature = "liquid")]
+    fn pegs_key(hash: FullHash) -> Bytes {
+        [b"L", &hash[..]].concat()
+    }
+
+    fn utxo_delta_key(hash: FullHash) -> Bytes {
+        [b"Z", &hash[..]].concat()
+    }
+
+    fn utxo_changes_key(hash: FullHash) -> Bytes {
+        [b"Y", &hash[..]].concat()
+    }
+
+    fn utxo_undo_key(hash: FullHash) -> Bytes {
+        [b"R", &hash[..]].concat()
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn filter_key(hash: FullHash) -> Bytes {
+        [b"G", &hash[..]].concat()
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn filter_header_key(hash: FullHash) -> Bytes {
+        [b"F", &hash[..]].concat()
+    }
+
+    fn done_filter() -> Bytes {
+        b"D".to_vec()
+    }
+
+    fn orphan_filter() -> Bytes {
+        b"S".to_vec()
+    }
+
+    fn orphan_key(hash: FullHash) -> Bytes {
+        [b"S", &hash[..]].concat()
+    }
+
+    fn to_row(self) -> DBRow {
+        DBRow {
+            key: bincode::serialize(&self.key).unwrap(),
+            value: self.value,
         }
     }
 
@@ -1079,6 +3658,8 @@ pub struct FundingInfo {
     pub txid: FullHash, // funding transaction
     pub vout: u16,
     pub value: Value,
+    #[cfg(feature = "liquid")]
+    pub asset: Asset,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -1088,6 +3669,8 @@ pub struct SpendingInfo {
     pub prev_txid: FullHash, // funding transaction
     pub prev_vout: u16,
     pub value: Value,
+    #[cfg(feature = "liquid")]
+    pub asset: Asset,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -1096,6 +3679,23 @@ pub enum TxHistoryInfo {
     Spending(SpendingInfo),
 }
 
+#[cfg(feature = "liquid")]
+impl TxHistoryInfo {
+    // The asset id and amount of the funded or spent output, unless blinded
+    pub fn explicit_asset_value(&self) -> Option<(String, u64)> {
+        let (asset, value) = match self {
+            TxHistoryInfo::Funding(info) => (&info.asset, &info.value),
+            TxHistoryInfo::Spending(info) => (&info.asset, &info.value),
+        };
+        match (asset, value) {
+            (Asset::Explicit(asset_id), Value::Explicit(value)) => {
+                Some((asset_id.to_hex(), *value))
+            }
+            _ => None,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct TxHistoryKey {
     pub code: u8,
@@ -1227,6 +3827,8 @@ struct ScriptCacheKey {
     scripthash: FullHash,
 }
 
+// The bincode layout of the stats isn't versioned, so their key prefix is changed along with it
+// (from "A" to "S" to "R"), for the rows of the older layouts to never be misread
 struct StatsCacheRow {
     key: ScriptCacheKey,
     value: Bytes,
@@ -1236,7 +3838,7 @@ impl StatsCacheRow {
     fn new(scripthash: &[u8], stats: &ScriptStats, blockhash: &Sha256dHash) -> Self {
         StatsCacheRow {
             key: ScriptCacheKey {
-                code: b'A',
+                code: b'R',
                 scripthash: full_hash(scripthash),
             },
             value: bincode::serialize(&(stats, blockhash)).unwrap(),
@@ -1244,7 +3846,7 @@ impl StatsCacheRow {
     }
 
     pub fn key(scripthash: &[u8]) -> Bytes {
-        [b"A", scripthash].concat()
+        [b"R", scripthash].concat()
     }
 
     fn to_row(self) -> DBRow {
@@ -1313,3 +3915,1692 @@ impl UtxoCacheRow {
         }
     }
 }
+
+#[derive(Serialize, Deserialize)]
+struct TxJsonCacheKey {
+    code: u8,
+    txid: FullHash,
+    variant: u32, // of the options changing the JSON, MUST be serialized as big-endian
+}
+
+// The REST JSON of a final transaction
+struct TxJsonCacheRow {
+    key: TxJsonCacheKey,
+    value: Bytes,
+}
+
+impl TxJsonCacheRow {
+    fn new(txid: &Sha256dHash, variant: u32, json: &[u8]) -> Self {
+        TxJsonCacheRow {
+            key: TxJsonCacheKey {
+                code: b'J',
+                txid: full_hash(&txid[..]),
+                variant,
+            },
+            value: json.to_vec(),
+        }
+    }
+
+    fn key(txid: &Sha256dHash, variant: u32) -> Bytes {
+        [&b"J"[..], &txid[..], &variant.to_be_bytes()].concat()
+    }
+
+    fn to_row(self) -> DBRow {
+        DBRow {
+            key: bincode::config().big_endian().serialize(&self.key).unwrap(),
+            value: self.value,
+        }
+    }
+}
+
+#[cfg(not(feature = "liquid"))]
+fn block_stats_cache_key(hash: &Sha256dHash) -> Bytes {
+    [b"B", &hash[..]].concat()
+}
+
+#[derive(Serialize, Deserialize)]
+struct UtxoCheckpointKey {
+    code: u8,
+    height: u32, // MUST be serialized as big-endian (for correct scans).
+    blockhash: FullHash,
+}
+
+struct UtxoCheckpointRow {
+    key: UtxoCheckpointKey,
+    value: Bytes, // serialized MuHash
+}
+
+impl UtxoCheckpointRow {
+    fn new(height: usize, blockhash: &Sha256dHash, muhash: &MuHash) -> Self {
+        UtxoCheckpointRow {
+            key: UtxoCheckpointKey {
+                code: b'K',
+                height: height as u32,
+                blockhash: full_hash(&blockhash[..]),
+            },
+            value: muhash.to_bytes(),
+        }
+    }
+
+    fn key(height: usize, blockhash: &Sha256dHash) -> Bytes {
+        bincode::config()
+            .big_endian()
+            .serialize(&(b'K', height as u32, full_hash(&blockhash[..])))
+            .unwrap()
+    }
+
+    fn filter() -> Bytes {
+        b"K".to_vec()
+    }
+
+    fn prefix_end(height: usize) -> Bytes {
+        bincode::config()
+            .big_endian()
+            .serialize(&(b'K', height as u32, [0xffu8; 32]))
+            .unwrap()
+    }
+
+    fn to_row(self) -> DBRow {
+        DBRow {
+            key: bincode::config().big_endian().serialize(&self.key).unwrap(),
+            value: self.value,
+        }
+    }
+
+    fn from_row(row: DBRow) -> Self {
+        UtxoCheckpointRow {
+            key: bincode::config()
+                .big_endian()
+                .deserialize(&row.key)
+                .expect("failed to parse UtxoCheckpointKey"),
+            value: row.value,
+        }
+    }
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AdoptionPeriod {
+    Day,   // UTC days since the unix epoch, by block time
+    Epoch, // difficulty adjustment periods
+}
+
+#[cfg(not(feature = "liquid"))]
+impl AdoptionPeriod {
+    fn code(self) -> u8 {
+        match self {
+            AdoptionPeriod::Day => b'D',
+            AdoptionPeriod::Epoch => b'E',
+        }
+    }
+
+    pub fn index(self, height: usize, time: u32) -> u32 {
+        match self {
+            AdoptionPeriod::Day => time / SECONDS_PER_DAY,
+            AdoptionPeriod::Epoch => (height / DIFFICULTY_EPOCH) as u32,
+        }
+    }
+
+    // The first block height or timestamp of the period
+    pub fn start(self, index: u32) -> u64 {
+        match self {
+            AdoptionPeriod::Day => u64::from(index) * u64::from(SECONDS_PER_DAY),
+            AdoptionPeriod::Epoch => index as u64 * DIFFICULTY_EPOCH as u64,
+        }
+    }
+}
+
+// Stats summed over the blocks of a period, see Indexer::update_rollups()
+trait Rollup: Default + Serialize + DeserializeOwned {
+    fn add(&mut self, other: &Self);
+    fn sub(&mut self, other: &Self);
+}
+
+#[cfg(not(feature = "liquid"))]
+impl Rollup for AdoptionStats {
+    fn add(&mut self, other: &Self) {
+        AdoptionStats::add(self, other)
+    }
+
+    fn sub(&mut self, other: &Self) {
+        AdoptionStats::sub(self, other)
+    }
+}
+
+#[cfg(feature = "liquid")]
+impl Rollup for PegStats {
+    fn add(&mut self, other: &Self) {
+        PegStats::add(self, other)
+    }
+
+    fn sub(&mut self, other: &Self) {
+        PegStats::sub(self, other)
+    }
+}
+
+// The adoption stats summed per period, keyed by the period's index (MUST be serialized as
+// big-endian, for correct scans)
+#[cfg(not(feature = "liquid"))]
+struct AdoptionRow;
+
+#[cfg(not(feature = "liquid"))]
+impl AdoptionRow {
+    fn key(period: AdoptionPeriod, index: u32) -> Bytes {
+        bincode::config()
+            .big_endian()
+            .serialize(&(b'A', period.code(), index))
+            .unwrap()
+    }
+
+    fn filter(period: AdoptionPeriod) -> Bytes {
+        vec![b'A', period.code()]
+    }
+
+    // the rollups of all the periods
+    fn prefix() -> Bytes {
+        vec![b'A']
+    }
+
+    // the best-chain block the rollups are up to date with
+    fn tip_key() -> Bytes {
+        b"A".to_vec()
+    }
+}
+
+// The peg stats summed per month, keyed by the month's index (MUST be serialized as big-endian,
+// for correct scans)
+#[cfg(feature = "liquid")]
+struct PegRow;
+
+#[cfg(feature = "liquid")]
+impl PegRow {
+    fn key(month: u32) -> Bytes {
+        bincode::config()
+            .big_endian()
+            .serialize(&(b'Q', b'M', month))
+            .unwrap()
+    }
+
+    fn filter() -> Bytes {
+        b"QM".to_vec()
+    }
+
+    // the best-chain block the rollups are up to date with
+    fn tip_key() -> Bytes {
+        b"Q".to_vec()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct UtxoCreated {
+    scripthash: FullHash,
+    txid: FullHash,
+    vout: u32,
+    value: Value,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UtxoSpent {
+    scripthash: FullHash,
+    txid: FullHash,
+    vout: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UtxoChanges {
+    created: Vec<UtxoCreated>,
+    spent: Vec<UtxoSpent>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UtxoKey {
+    code: u8,
+    scripthash: FullHash,
+    txid: FullHash,
+    vout: u32,
+}
+
+// An unspent output of the best chain, indexed by scripthash
+struct UtxoRow {
+    key: UtxoKey,
+    height: u32,
+    value: Value,
+}
+
+impl UtxoRow {
+    fn key(scripthash: &FullHash, txid: &FullHash, vout: u32) -> Bytes {
+        bincode::config()
+            .big_endian()
+            .serialize(&(b'O', scripthash, txid, vout))
+            .unwrap()
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn new_row(
+        scripthash: &FullHash,
+        txid: &FullHash,
+        vout: u32,
+        height: u32,
+        value: Value,
+    ) -> DBRow {
+        DBRow {
+            key: UtxoRow::key(scripthash, txid, vout),
+            value: bincode::serialize(&(height, &value)).unwrap(),
+        }
+    }
+
+    fn filter(scripthash: &[u8]) -> Bytes {
+        [b"O", scripthash].concat()
+    }
+
+    // A coin of the UTXO snapshot the index was bootstrapped from, kept unlike the UTXO set's,
+    // for the stats to count the coins confirmed before the history starts
+    #[cfg(not(feature = "liquid"))]
+    fn snapshot_row(
+        scripthash: &FullHash,
+        txid: &FullHash,
+        vout: u32,
+        height: u32,
+        value: Value,
+    ) -> DBRow {
+        DBRow {
+            key: bincode::config()
+                .big_endian()
+                .serialize(&(b'c', scripthash, txid, vout))
+                .unwrap(),
+            value: bincode::serialize(&(height, &value)).unwrap(),
+        }
+    }
+
+    fn snapshot_filter(scripthash: &[u8]) -> Bytes {
+        [b"c", scripthash].concat()
+    }
+
+    // the best-chain block the UTXO set is up to date with
+    fn tip_key() -> Bytes {
+        b"T".to_vec()
+    }
+
+    fn tip_row(blockhash: &Sha256dHash) -> DBRow {
+        DBRow {
+            key: UtxoRow::tip_key(),
+            value: blockhash[..].to_vec(),
+        }
+    }
+
+    fn from_row(row: DBRow) -> Self {
+        let key = bincode::config()
+            .big_endian()
+            .deserialize(&row.key)
+            .expect("failed to parse UtxoKey");
+        let (height, value) = bincode::deserialize(&row.value).expect("failed to parse utxo");
+        UtxoRow { key, height, value }
+    }
+}
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use bitcoin::{BitcoinHash, Block, BlockHeader, TxIn};
+    use bitcoin_hashes::Hash;
+    use std::fs;
+    use tempfile;
+
+    use super::*;
+
+    fn coinbase(tag: u8, script: u8) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::from(vec![tag]),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 50 * 100_000_000,
+                script_pubkey: Script::from(vec![script]),
+            }],
+        }
+    }
+
+    // A transaction spending the output of `prev` to a single output
+    fn spending(prev: &Transaction, vout: u32, value: u64, script: u8) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: prev.txid(),
+                    vout,
+                },
+                script_sig: Script::new(),
+                sequence: 0xffff_fffd,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::from(vec![script]),
+            }],
+        }
+    }
+
+    fn chain(txdata: Vec<Vec<Transaction>>) -> Vec<BlockEntry> {
+        let mut prev_blockhash = Sha256dHash::default();
+        let blocks: Vec<Block> = txdata
+            .into_iter()
+            .enumerate()
+            .map(|(height, txdata)| {
+                let header = BlockHeader {
+                    version: 1,
+                    prev_blockhash,
+                    merkle_root: Sha256dHash::default(),
+                    time: height as u32,
+                    bits: 0,
+                    nonce: 0,
+                };
+                prev_blockhash = header.bitcoin_hash();
+                Block { header, txdata }
+            })
+            .collect();
+        let entries = HeaderList::empty().order(blocks.iter().map(|b| b.header).collect());
+        blocks
+            .into_iter()
+            .zip(entries)
+            .map(|(block, entry)| BlockEntry {
+                size: serialize(&block).len() as u32,
+                block,
+                entry,
+            })
+            .collect()
+    }
+
+    fn test_indexer(store: &Arc<Store>, metrics: &Metrics) -> Indexer {
+        Indexer {
+            store: Arc::clone(store),
+            flush: DBFlush::Disable,
+            from: FetchFrom::Bitcoind,
+            utxo_commitment_interval: None,
+            utxo_index: false,
+            light_mode: false,
+            block_filters: false,
+            utxo_snapshot: None,
+            network: Network::Regtest,
+            network_magic: Network::Regtest.magic(),
+            genesis_hash: None,
+            genesis_checked: false,
+            header_checker: HeaderChecker::new(Network::Regtest, false),
+            header_check_failures: metrics
+                .counter_vec(MetricOpts::new("test_header_check", "test"), &["reason"]),
+            header_check_alarm: metrics.gauge(MetricOpts::new("test_header_alarm", "test")),
+            duration: metrics.histogram_vec(HistogramOpts::new("test", "test"), &["step"]),
+            height: metrics.gauge_vec(MetricOpts::new("test_height", "test"), &["type"]),
+            lag: metrics.gauge(MetricOpts::new("test_lag", "test")),
+            profile: None,
+            signal: Waiter::new(),
+        }
+    }
+
+    fn connect(indexer: &Indexer, blocks: &[BlockEntry]) {
+        let new_heights = NewHeights {
+            heights: blocks
+                .iter()
+                .map(|b| (*b.entry.hash(), b.entry.height()))
+                .collect(),
+            fork_height: blocks[0].entry.height(),
+        };
+        indexer.add(blocks, None);
+        indexer.index(blocks, &new_heights, None).unwrap();
+        indexer.apply_headers(blocks.iter().map(|b| b.entry.clone()).collect());
+        if indexer.utxo_index {
+            indexer.update_utxo_set();
+        }
+    }
+
+    #[test]
+    fn test_duplicate_coinbase() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+
+        // the coinbase of the 3rd block duplicates the 1st one
+        let duplicate = coinbase(0, 0x51);
+        let blocks = chain(vec![
+            vec![duplicate.clone()],
+            vec![coinbase(1, 0x52)],
+            vec![duplicate.clone()],
+        ]);
+        let new_heights = NewHeights {
+            heights: blocks
+                .iter()
+                .map(|b| (*b.entry.hash(), b.entry.height()))
+                .collect(),
+            fork_height: 0,
+        };
+        indexer.add(&blocks, None);
+        indexer.index(&blocks, &new_heights, None).unwrap();
+        store
+            .indexed_headers
+            .write()
+            .unwrap()
+            .apply(blocks.iter().map(|b| b.entry.clone()).collect());
+
+        let query = ChainQuery::new(Arc::clone(&store), None, &metrics);
+        let txid = duplicate.txid();
+        assert_eq!(query.tx_confirming_block(&txid).unwrap().height, 0);
+
+        let scripthash = compute_script_hash(&duplicate.output[0].script_pubkey);
+        let history = query.history_txids(&scripthash[..]);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].1.height, 0);
+
+        let stats = query.stats(&scripthash[..]);
+        assert_eq!(stats.funded_txo_count, 1);
+        assert_eq!(stats.funding_tx_count, 1);
+        assert_eq!(stats.funded_txo_sum.as_sat(), duplicate.output[0].value);
+        assert_eq!(query.utxo(&scripthash[..]).len(), 1);
+    }
+
+    #[test]
+    fn test_utxo_set_reorg() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = Indexer {
+            utxo_index: true,
+            ..test_indexer(&store, &metrics)
+        };
+        let query = ChainQuery::new(Arc::clone(&store), None, &metrics);
+        let utxos = |script: u8| {
+            let scripthash = compute_script_hash(&Script::from(vec![script]));
+            query
+                .utxo_from_index(&scripthash[..])
+                .expect("utxo set is behind")
+        };
+
+        let funding = coinbase(0, 0x51);
+        let mut spending = coinbase(1, 0x53);
+        spending.input[0].previous_output = OutPoint {
+            txid: funding.txid(),
+            vout: 0,
+        };
+        let blocks = chain(vec![
+            vec![funding.clone()],
+            vec![coinbase(1, 0x52), spending],
+        ]);
+        connect(&indexer, &blocks);
+        assert_eq!(utxos(0x51).len(), 0);
+        assert_eq!(utxos(0x52).len(), 1);
+        assert_eq!(utxos(0x53)[0].confirmed.as_ref().unwrap().height, 1);
+
+        // replace the 2nd block, which reverts the spend
+        let block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: *blocks[0].entry.hash(),
+                merkle_root: Sha256dHash::default(),
+                time: 2,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata: vec![coinbase(2, 0x54)],
+        };
+        let entry = store
+            .indexed_headers
+            .read()
+            .unwrap()
+            .order(vec![block.header]);
+        let fork = vec![BlockEntry {
+            size: serialize(&block).len() as u32,
+            block,
+            entry: entry[0].clone(),
+        }];
+        connect(&indexer, &fork);
+        assert_eq!(utxos(0x51)[0].txid, funding.txid());
+        assert_eq!(utxos(0x52).len(), 0);
+        assert_eq!(utxos(0x53).len(), 0);
+        assert_eq!(utxos(0x54).len(), 1);
+    }
+
+    #[test]
+    fn test_orphaned_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+        let query = ChainQuery::new(Arc::clone(&store), None, &metrics);
+
+        let blocks = chain(vec![
+            vec![coinbase(0, 0x51)],
+            vec![coinbase(1, 0x52)],
+            vec![coinbase(2, 0x53)],
+        ]);
+        connect(&indexer, &blocks);
+        assert!(query.orphaned_blocks().is_empty());
+        let (orphan1, orphan2) = (*blocks[1].entry.hash(), *blocks[2].entry.hash());
+        assert!(query.get_orphaned_block_with_meta(&orphan1).is_none());
+
+        // a single block replacing the last two orphans both
+        let block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: *blocks[0].entry.hash(),
+                merkle_root: Sha256dHash::default(),
+                time: 3,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata: vec![coinbase(3, 0x54)],
+        };
+        let entry = store
+            .indexed_headers
+            .read()
+            .unwrap()
+            .order(vec![block.header]);
+        let fork = vec![BlockEntry {
+            size: serialize(&block).len() as u32,
+            block,
+            entry: entry[0].clone(),
+        }];
+        connect(&indexer, &fork);
+        let fork_hash = *fork[0].entry.hash();
+        assert_eq!(query.best_hash(), fork_hash);
+        assert_eq!(query.orphaned_blocks(), vec![(orphan2, 2), (orphan1, 1)]);
+        let orphaned = query.get_orphaned_block_with_meta(&orphan1).unwrap();
+        assert_eq!(orphaned.header_entry.height(), 1);
+        assert_eq!(orphaned.meta.tx_count, 1);
+        assert!(query.get_orphaned_block_with_meta(&fork_hash).is_none());
+
+        // connecting them back orphans the fork's block instead
+        connect(&indexer, &blocks[1..]);
+        assert_eq!(query.best_hash(), orphan2);
+        assert_eq!(query.orphaned_blocks(), vec![(fork_hash, 1)]);
+        assert!(query.get_orphaned_block_with_meta(&orphan1).is_none());
+        assert!(query.get_orphaned_block_with_meta(&fork_hash).is_some());
+    }
+
+    #[test]
+    fn test_block_raw() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+        let query = ChainQuery::new(Arc::clone(&store), None, &metrics);
+
+        let mut spending = coinbase(1, 0x52);
+        spending.input[0].previous_output = OutPoint {
+            txid: coinbase(0, 0x51).txid(),
+            vout: 0,
+        };
+        let blocks = chain(vec![
+            vec![coinbase(0, 0x51)],
+            vec![coinbase(1, 0x51), spending],
+        ]);
+        connect(&indexer, &blocks);
+        for b in &blocks {
+            let raw = query.get_block_raw(b.entry.hash()).unwrap();
+            assert_eq!(raw, Some(serialize(&b.block)));
+        }
+        assert_eq!(query.get_block_raw(&Sha256dHash::default()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_utxo_changes_missing_prevout() {
+        let mut spending = coinbase(1, 0x52);
+        spending.input[0].previous_output = OutPoint {
+            txid: coinbase(0, 0x51).txid(),
+            vout: 0,
+        };
+        let blocks = chain(vec![vec![coinbase(2, 0x51), spending.clone()]]);
+        assert!(utxo_changes_rows(&blocks, &HashMap::new(), &HashSet::new()).is_err());
+
+        let mut previous_txos_map = HashMap::new();
+        previous_txos_map.insert(
+            spending.input[0].previous_output,
+            coinbase(0, 0x51).output[0].clone(),
+        );
+        let rows = utxo_changes_rows(&blocks, &previous_txos_map, &HashSet::new()).unwrap();
+        assert_eq!(rows.len(), 1);
+    }
+
+    #[test]
+    fn test_utxo_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = Indexer {
+            utxo_index: true,
+            ..test_indexer(&store, &metrics)
+        };
+        let query = ChainQuery::new(Arc::clone(&store), None, &metrics);
+        let scripthash = compute_script_hash(&Script::from(vec![0x51]));
+
+        let mut txdata = vec![];
+        for tag in 0..3 {
+            let mut tx = coinbase(tag, 0x51);
+            tx.output = vec![tx.output[0].clone(); 3];
+            txdata.push(vec![tx]);
+        }
+        connect(&indexer, &chain(txdata));
+
+        let mut all = query.utxo_from_index(&scripthash[..]).unwrap();
+        all.sort_unstable_by_key(Utxo::sort_key);
+        assert_eq!(all.len(), 9);
+        let outpoints = |utxos: &[Utxo]| utxos.iter().map(OutPoint::from).collect::<Vec<_>>();
+
+        // page through the utxos, 4 at a time
+        let mut paged = vec![];
+        let mut cursor = None;
+        loop {
+            let page = query
+                .utxo_page(&scripthash[..], cursor.as_ref(), 4)
+                .unwrap();
+            assert!(page.len() <= 4);
+            cursor = page.last().map(OutPoint::from);
+            paged.extend(page);
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(outpoints(&paged), outpoints(&all));
+
+        // resume after a cursor that is no longer unspent
+        let spent = OutPoint {
+            txid: all[4].txid,
+            vout: 5,
+        };
+        let page = query.utxo_page(&scripthash[..], Some(&spent), 100).unwrap();
+        assert_eq!(outpoints(&page), outpoints(&all[6..]));
+    }
+
+    #[test]
+    fn test_utxo_snapshot_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = Indexer {
+            utxo_index: true,
+            ..test_indexer(&store, &metrics)
+        };
+        let query = ChainQuery::new(Arc::clone(&store), None, &metrics);
+        let scripthash = |script: u8| compute_script_hash(&Script::from(vec![script]));
+        let utxos = |script: u8| {
+            query
+                .utxo_from_index(&scripthash(script)[..])
+                .expect("utxo set is behind")
+        };
+
+        let funding = coinbase(0, 0x51);
+        let mut spending = coinbase(2, 0x53);
+        spending.input[0].previous_output = OutPoint {
+            txid: funding.txid(),
+            vout: 0,
+        };
+        let blocks = chain(vec![
+            vec![funding.clone()],
+            vec![coinbase(1, 0x52)],
+            vec![coinbase(2, 0x54), spending],
+        ]);
+        let headers: Vec<HeaderEntry> = blocks.iter().map(|b| b.entry.clone()).collect();
+
+        // the coinbase outputs of the first two blocks, as written by dumptxoutset
+        let mut snapshot = b"utxo\xff".to_vec();
+        snapshot.extend(serialize(&2u16));
+        snapshot.extend(serialize(&Network::Regtest.magic()));
+        snapshot.extend(serialize(headers[1].hash()));
+        snapshot.extend(serialize(&2u64));
+        for (height, b) in blocks[..2].iter().enumerate() {
+            let tx = &b.block.txdata[0];
+            snapshot.extend(serialize(&tx.txid()));
+            // 1 coin of vout 0, height and coinbase flag, 50 BTC and a raw script of 1 byte
+            let script = tx.output[0].script_pubkey.as_bytes()[0];
+            snapshot.extend(&[1, 0, height as u8 * 2 + 1, 0x32, 7, script]);
+        }
+        let snapshot_dir = tempfile::tempdir().unwrap();
+        let path = snapshot_dir.path().join("utxo.dat");
+        fs::write(&path, &snapshot).unwrap();
+
+        indexer.import_utxo_snapshot(&path, &headers).unwrap();
+        let info = store.utxo_snapshot().unwrap();
+        assert_eq!(info.blockhash, *headers[1].hash());
+        assert_eq!((info.height, info.coins), (1, 2));
+        assert_eq!(store.history_start_height(), 2);
+        assert_eq!(indexer.headers_to_add(&headers), vec![headers[2].clone()]);
+        assert_eq!(indexer.headers_to_index(&headers), vec![headers[2].clone()]);
+
+        // the following blocks are indexed on top of the imported coins
+        store
+            .indexed_headers
+            .write()
+            .unwrap()
+            .apply(headers[..2].to_vec());
+        connect(&indexer, &blocks[2..]);
+        assert_eq!(utxos(0x51).len(), 0);
+        assert_eq!(utxos(0x52)[0].confirmed.as_ref().unwrap().height, 1);
+        assert_eq!(utxos(0x53)[0].confirmed.as_ref().unwrap().height, 2);
+        assert_eq!(query.history_txids(&scripthash(0x52)[..]).len(), 0);
+        assert_eq!(query.history_txids(&scripthash(0x53)[..]).len(), 1);
+        // the imported coins aren't in the history, which doesn't make their scripts unused
+        assert!(query.has_unindexed_coins(&scripthash(0x52)[..]));
+        assert!(query.has_unindexed_coins(&scripthash(0x51)[..]));
+        assert!(!query.has_unindexed_coins(&scripthash(0x53)[..]));
+
+        // the stats count the imported coins, as of the snapshot's block
+        let stats = query.stats(&scripthash(0x52)[..]);
+        assert_eq!((stats.tx_count, stats.funding_tx_count), (1, 1));
+        assert_eq!((stats.funded_txo_count, stats.spent_txo_count), (1, 0));
+        assert_eq!(stats.funded_txo_sum, Amount::from_sat(50 * 100_000_000));
+        assert_eq!(stats.first_funding_height, Some(1));
+        assert_eq!(stats.first_funding_time, Some(headers[1].header().time));
+        assert_eq!(stats.last_activity_height, Some(1));
+        let stats = query.stats(&scripthash(0x51)[..]);
+        assert_eq!((stats.tx_count, stats.funded_txo_count), (2, 1));
+        assert_eq!(stats.spent_txo_count, 1);
+        assert_eq!(stats.funded_txo_sum, stats.spent_txo_sum);
+        assert_eq!(stats.first_funding_height, Some(0));
+        assert_eq!(stats.last_activity_height, Some(2));
+        let stats = query.stats_at(&scripthash(0x51)[..], 1);
+        assert_eq!((stats.funded_txo_count, stats.spent_txo_count), (1, 0));
+        // the coins spent before the snapshot aren't known
+        assert_eq!(query.stats_at(&scripthash(0x51)[..], 0).funded_txo_count, 0);
+
+        // the snapshot's block must be on the best chain
+        let other_dir = tempfile::tempdir().unwrap();
+        let indexer = Indexer {
+            store: Arc::new(Store::open(other_dir.path())),
+            ..indexer
+        };
+        assert!(indexer.import_utxo_snapshot(&path, &headers[..1]).is_err());
+    }
+
+    #[test]
+    fn test_fresh_address() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+        let query = ChainQuery::new(Arc::clone(&store), None, &metrics);
+        let scripthash = |script: u8| compute_script_hash(&Script::from(vec![script]));
+
+        let blocks = chain(vec![vec![coinbase(0, 0x51)], vec![coinbase(1, 0x52)]]);
+        connect(&indexer, &blocks);
+        assert!(query.has_history(&scripthash(0x51)[..]));
+        assert!(query.has_history(&scripthash(0x52)[..]));
+
+        let fresh = scripthash(0x53);
+        assert!(!query.has_history(&fresh[..]));
+        assert!(query.history(&fresh[..], None, 25).unwrap().is_empty());
+        assert!(query.history_txids(&fresh[..]).is_empty());
+        assert!(query.utxo(&fresh[..]).is_empty());
+        let stats = query.stats(&fresh[..]);
+        assert_eq!(stats.tx_count, 0);
+        assert_eq!(stats.first_funding_height, None);
+
+        // the history of orphaned blocks doesn't count
+        let block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: *blocks[0].entry.hash(),
+                merkle_root: Sha256dHash::default(),
+                time: 2,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata: vec![coinbase(2, 0x54)],
+        };
+        let entry = store
+            .indexed_headers
+            .read()
+            .unwrap()
+            .order(vec![block.header]);
+        let fork = vec![BlockEntry {
+            size: serialize(&block).len() as u32,
+            block,
+            entry: entry[0].clone(),
+        }];
+        connect(&indexer, &fork);
+        assert!(!query.has_history(&scripthash(0x52)[..]));
+        assert!(query.has_history(&scripthash(0x54)[..]));
+    }
+
+    #[test]
+    fn test_stats_at_cache_ahead() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+        let query = ChainQuery::new(Arc::clone(&store), None, &metrics);
+        let scripthash = compute_script_hash(&Script::from(vec![0x51]));
+
+        let first = coinbase(0, 0x51);
+        let blocks = chain(vec![
+            vec![coinbase(1, 0x52)],
+            vec![first.clone()],
+            vec![coinbase(2, 0x52)],
+            vec![coinbase(3, 0x52), spending(&first, 0, 10_000, 0x51)],
+            vec![coinbase(4, 0x51)],
+        ]);
+        connect(&indexer, &blocks);
+
+        // too little history to be cached, these are computed from scratch
+        let fresh: Vec<_> = (0..blocks.len())
+            .map(|height| serde_json::to_value(query.stats_at(&scripthash[..], height)).unwrap())
+            .collect();
+        assert_eq!(fresh[0]["tx_count"], 0);
+        assert_eq!(fresh[4]["tx_count"], 3);
+        assert!(store
+            .cache_db
+            .get(&StatsCacheRow::key(&scripthash[..]))
+            .is_none());
+
+        // the stats cached at the tip are rolled back to each height, and kept as they are
+        let tip = query.stats_at(&scripthash[..], blocks.len() - 1);
+        let row = StatsCacheRow::new(&scripthash[..], &tip, blocks[4].entry.hash()).to_row();
+        let cached = row.value.clone();
+        store.cache_db.write(vec![row], DBFlush::Disable);
+        for (height, fresh) in fresh.iter().enumerate() {
+            let stats = query.stats_at(&scripthash[..], height);
+            assert_eq!(
+                &serde_json::to_value(stats).unwrap(),
+                fresh,
+                "height {}",
+                height
+            );
+        }
+        assert_eq!(
+            store.cache_db.get(&StatsCacheRow::key(&scripthash[..])),
+            Some(cached)
+        );
+    }
+
+    #[test]
+    fn test_light_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = Indexer {
+            light_mode: true,
+            ..test_indexer(&store, &metrics)
+        };
+        let query = ChainQuery::new(Arc::clone(&store), None, &metrics);
+        let scripthash = |script: u8| compute_script_hash(&Script::from(vec![script]));
+
+        let blocks = chain(vec![vec![coinbase(0, 0x51)], vec![coinbase(1, 0x52)]]);
+        connect(&indexer, &blocks);
+
+        // only the genesis coinbase is stored, bitcoind doesn't serve it
+        let genesis_coinbase = &blocks[0].block.txdata[0];
+        assert!(query.lookup_txn(&genesis_coinbase.txid()).is_some());
+        assert_eq!(
+            query
+                .history(&scripthash(0x51)[..], None, 25)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(query
+            .get_block_raw(blocks[0].entry.hash())
+            .unwrap()
+            .is_some());
+
+        // the others are fetched from the daemon, the lookups fail without panicking
+        assert!(query
+            .lookup_txn(&blocks[1].block.txdata[0].txid())
+            .is_none());
+        assert!(query.history(&scripthash(0x52)[..], None, 25).is_err());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert!(query
+            .history_page(&scripthash(0x52)[..], None, 25, std::usize::MAX, deadline)
+            .is_err());
+        assert!(query.get_block_raw(blocks[1].entry.hash()).is_err());
+        assert_eq!(query.history_txids(&scripthash(0x52)[..]).len(), 1);
+    }
+
+    #[test]
+    fn test_history_start_height() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+
+        // it can be changed until the history starts being indexed
+        assert_eq!(store.history_start_height(), 0);
+        store.init_history_start_height(5).unwrap();
+        store.init_history_start_height(1).unwrap();
+        assert_eq!(store.history_start_height(), 1);
+
+        connect(&indexer, &chain(vec![vec![coinbase(0, 0x51)]]));
+        store.init_history_start_height(1).unwrap();
+        assert!(store.init_history_start_height(0).is_err());
+        assert_eq!(store.history_start_height(), 1);
+
+        // and is kept across restarts
+        drop(indexer);
+        drop(store);
+        let store = Store::open(dir.path());
+        assert_eq!(store.history_start_height(), 1);
+        assert!(store.init_history_start_height(2).is_err());
+    }
+
+    #[test]
+    fn test_history_start_height_coins() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = Indexer {
+            utxo_index: true,
+            ..test_indexer(&store, &metrics)
+        };
+        let query = ChainQuery::new(Arc::clone(&store), None, &metrics);
+        let scripthash = |script: u8| compute_script_hash(&Script::from(vec![script]));
+
+        store.init_history_start_height(1).unwrap();
+        connect(
+            &indexer,
+            &chain(vec![vec![coinbase(0, 0x51)], vec![coinbase(1, 0x52)]]),
+        );
+        // the coin of the skipped block is only in the UTXO set
+        assert!(query.history_txids(&scripthash(0x51)[..]).is_empty());
+        assert!(!query.has_history(&scripthash(0x51)[..]));
+        assert!(query.has_unindexed_coins(&scripthash(0x51)[..]));
+        assert_eq!(query.utxo(&scripthash(0x51)[..]).len(), 1);
+        assert_eq!(query.history_txids(&scripthash(0x52)[..]).len(), 1);
+        assert!(!query.has_unindexed_coins(&scripthash(0x52)[..]));
+        assert!(!query.has_unindexed_coins(&scripthash(0x53)[..]));
+    }
+
+    #[test]
+    fn test_index_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = Indexer {
+            from: FetchFrom::BlkFiles {
+                readers: 1,
+                parsers: 1,
+                magic: Network::Regtest.magic(),
+            },
+            ..test_indexer(&store, &metrics)
+        };
+        assert_eq!(store.added_progress(), None);
+        assert_eq!(indexer.resume_file("adding transactions", None), None);
+
+        // interrupted after adding 3 blocks and indexing 2 of them
+        let blocks = chain((0..3).map(|i| vec![coinbase(i, 0x51)]).collect());
+        let new_heights = NewHeights {
+            heights: blocks
+                .iter()
+                .map(|b| (*b.entry.hash(), b.entry.height()))
+                .collect(),
+            fork_height: 0,
+        };
+        indexer.add(&blocks[..2], Some(1));
+        indexer.add(&blocks[2..], Some(4));
+        indexer.index(&blocks[..2], &new_heights, Some(3)).unwrap();
+        drop(indexer);
+        drop(store);
+
+        let store = Store::open(dir.path());
+        assert!(!store.initial_sync_done());
+        let added = store.added_progress().unwrap();
+        assert_eq!(
+            added,
+            IndexProgress {
+                height: 2,
+                blk_file: Some(4)
+            }
+        );
+        let indexed = store.indexed_progress().unwrap();
+        assert_eq!(
+            indexed,
+            IndexProgress {
+                height: 1,
+                blk_file: Some(3)
+            }
+        );
+        assert_eq!(store.added_blockhashes.read().unwrap().len(), 3);
+        assert_eq!(store.indexed_blockhashes.read().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_unspendable_and_zero_value_outputs() {
+        let mut tx = coinbase(0, 0x51);
+        tx.output = vec![
+            TxOut {
+                value: 0,
+                script_pubkey: Script::from(vec![0x6a]), // OP_RETURN
+            },
+            TxOut {
+                value: 0,
+                script_pubkey: Script::new(), // empty script, spendable by anyone
+            },
+        ];
+        let mut rows = vec![];
+        index_transaction(&tx, 1, &HashMap::new(), true, &mut rows);
+        assert_eq!(rows.len(), 1);
+
+        let history = TxHistoryRow::from_row(rows.remove(0));
+        assert_eq!(history.key.scripthash, compute_script_hash(&Script::new()));
+        let outpoint = OutPoint {
+            txid: tx.txid(),
+            vout: 1,
+        };
+        assert_eq!(history.get_outpoint(), outpoint);
+    }
+
+    #[test]
+    fn test_block_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+        let query = ChainQuery::new(Arc::clone(&store), None, &metrics);
+
+        let funding = coinbase(0, 0x51);
+        let mut spending = coinbase(1, 0x53);
+        spending.input[0].previous_output = OutPoint {
+            txid: funding.txid(),
+            vout: 0,
+        };
+        spending.output[0].value -= 10_000;
+        let blocks = chain(vec![vec![funding], vec![coinbase(1, 0x52), spending]]);
+        connect(&indexer, &blocks);
+
+        let hash = blocks[1].entry.hash();
+        let summary = query.get_block_summary(hash).unwrap();
+        assert_eq!(summary.tx_count, 2);
+        assert_eq!(summary.size, blocks[1].size);
+        // without witnesses, every byte (including the header's) weighs 4 units
+        assert_eq!(summary.weight, blocks[1].size * 4);
+        assert_eq!(summary.total_fees, 10_000);
+
+        // blocks indexed before the summaries were have theirs computed on the fly
+        store
+            .history_db
+            .delete(vec![BlockRow::summary_key(full_hash(&hash[..]))]);
+        assert!(query.get_stored_block_summary(hash).is_none());
+        let computed = query.get_block_summary(hash).unwrap();
+        assert_eq!(computed.weight, summary.weight);
+        assert_eq!(computed.total_fees, summary.total_fees);
+        assert_eq!(computed.median_feerate, summary.median_feerate);
+    }
+
+    #[test]
+    fn test_mempool_add_announced() {
+        use crate::new_index::Mempool;
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+        let query = Arc::new(ChainQuery::new(Arc::clone(&store), None, &metrics));
+
+        let funding = coinbase(0, 0x51);
+        let spend = |prev: &Transaction, script: u8| {
+            let mut tx = coinbase(2, script);
+            tx.input[0].previous_output = OutPoint {
+                txid: prev.txid(),
+                vout: 0,
+            };
+            tx
+        };
+        let confirmed = spend(&funding, 0x52);
+        let unconfirmed = spend(&confirmed, 0x53);
+        let block_coinbase = coinbase(1, 0x54);
+        let blocks = chain(vec![
+            vec![funding],
+            vec![block_coinbase.clone(), confirmed.clone()],
+        ]);
+        connect(&indexer, &blocks);
+
+        // as announced by bitcoind over ZMQ, along with the transactions of the new block
+        let mut mempool = Mempool::new(query, &metrics);
+        mempool.add_announced(vec![
+            block_coinbase.clone(),
+            confirmed.clone(),
+            unconfirmed.clone(),
+        ]);
+        assert!(mempool.lookup_txn(&unconfirmed.txid()).is_some());
+        assert!(mempool.lookup_txn(&confirmed.txid()).is_none());
+        assert!(mempool.lookup_txn(&block_coinbase.txid()).is_none());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        use crate::new_index::query::snapshot;
+        use crate::new_index::Mempool;
+        use std::cell::Cell;
+        use std::sync::RwLock;
+        use std::time::{Duration, Instant};
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+        let chain_query = Arc::new(ChainQuery::new(Arc::clone(&store), None, &metrics));
+
+        let funding = coinbase(0, 0x51);
+        let blocks = chain(vec![vec![funding.clone()], vec![coinbase(1, 0x52)]]);
+        connect(&indexer, &blocks);
+        let tip = *blocks[1].entry.hash();
+        let mempool = RwLock::new(Mempool::new(Arc::clone(&chain_query), &metrics));
+
+        // not available until the mempool is synced to the tip
+        let timeout = Duration::from_millis(500);
+        let started = Instant::now();
+        assert!(snapshot(&chain_query, &mempool, timeout, |_| (), |_| ()).is_err());
+        assert!(started.elapsed() >= Duration::from_millis(400));
+
+        mempool.write().unwrap().set_synced_tip(tip);
+        let (state, height) = snapshot(
+            &chain_query,
+            &mempool,
+            timeout,
+            |chain| chain.best_height(),
+            |height| {
+                // the mempool isn't locked while evaluating
+                assert!(mempool.try_write().is_ok());
+                height
+            },
+        )
+        .unwrap();
+        assert_eq!(height, 1);
+        assert_eq!(state.tip_hash, tip);
+        assert_eq!(state.tip_height, 1);
+        assert_eq!(state.mempool_sequence, mempool.read().unwrap().sequence());
+
+        // retried when the mempool changes meanwhile
+        let mut unconfirmed = coinbase(2, 0x53);
+        unconfirmed.input[0].previous_output = OutPoint {
+            txid: funding.txid(),
+            vout: 0,
+        };
+        let attempts = Cell::new(0);
+        let (state, attempt) = snapshot(
+            &chain_query,
+            &mempool,
+            Duration::from_secs(5),
+            |_| (),
+            |()| {
+                attempts.set(attempts.get() + 1);
+                if attempts.get() == 1 {
+                    mempool
+                        .write()
+                        .unwrap()
+                        .add_announced(vec![unconfirmed.clone()]);
+                }
+                attempts.get()
+            },
+        )
+        .unwrap();
+        assert_eq!(attempt, 2);
+        assert_eq!(state.mempool_sequence, mempool.read().unwrap().sequence());
+        assert!(mempool
+            .read()
+            .unwrap()
+            .lookup_txn(&unconfirmed.txid())
+            .is_some());
+    }
+
+    #[test]
+    fn test_status_hash() {
+        use crate::new_index::query::confirmed_status_hasher;
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+        let query = ChainQuery::new(Arc::clone(&store), None, &metrics);
+        let scripthash = compute_script_hash(&Script::from(vec![0x51]));
+        let full = || confirmed_status_hasher(&query, &scripthash[..], None).result();
+        let expected = |history: Vec<(&Transaction, usize)>| {
+            let mut sha2 = Sha256::new();
+            for (tx, height) in history {
+                sha2.input(format!("{}:{}:", tx.txid().to_hex(), height).as_bytes());
+            }
+            let mut hash = FullHash::default();
+            sha2.result(&mut hash);
+            Some(hash)
+        };
+
+        let first = coinbase(0, 0x51);
+        let spend = spending(&first, 0, 10_000, 0x51);
+        let blocks = chain(vec![
+            vec![first.clone()],
+            vec![coinbase(1, 0x52)],
+            vec![coinbase(2, 0x52), spend.clone()],
+            vec![coinbase(3, 0x51)],
+        ]);
+
+        // resumed from the previous tip, it matches the status hashed from scratch
+        let mut cached = None;
+        for block in blocks.chunks(1) {
+            connect(&indexer, block);
+            let hasher = confirmed_status_hasher(&query, &scripthash[..], cached);
+            assert_eq!(hasher.clone().result(), full());
+            cached = Some(hasher);
+        }
+        let last = &blocks[3].block.txdata[0];
+        assert_eq!(full(), expected(vec![(&first, 0), (&spend, 2), (last, 3)]));
+
+        // hashed from scratch once its block gets orphaned
+        let mut block = blocks[3].block.clone();
+        block.header.nonce = 1;
+        block.txdata = vec![coinbase(4, 0x51)];
+        let entry = store
+            .indexed_headers
+            .read()
+            .unwrap()
+            .order(vec![block.header]);
+        let fork = vec![BlockEntry {
+            size: serialize(&block).len() as u32,
+            block,
+            entry: entry[0].clone(),
+        }];
+        connect(&indexer, &fork);
+        let hasher = confirmed_status_hasher(&query, &scripthash[..], cached);
+        assert_eq!(hasher.result(), full());
+        let last = &fork[0].block.txdata[0];
+        assert_eq!(full(), expected(vec![(&first, 0), (&spend, 2), (last, 3)]));
+
+        let fresh = compute_script_hash(&Script::from(vec![0x53]));
+        assert_eq!(
+            confirmed_status_hasher(&query, &fresh[..], None).result(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_mempool_replacements() {
+        use crate::new_index::Mempool;
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+        let query = Arc::new(ChainQuery::new(Arc::clone(&store), None, &metrics));
+
+        let funding = coinbase(0, 0x51);
+        connect(&indexer, &chain(vec![vec![funding.clone()]]));
+        let mut mempool = Mempool::new(query, &metrics);
+
+        // conflicting spends of the same output, each paying a higher fee
+        let first = spending(&funding, 0, 49_9999_0000, 0x52);
+        let second = spending(&funding, 0, 49_9998_0000, 0x53);
+        let third = spending(&funding, 0, 49_9997_0000, 0x54);
+        mempool.add_announced(vec![first.clone()]);
+        assert!(mempool.replacements(&first.txid()).is_none());
+        mempool.add_announced(vec![second.clone()]);
+        mempool.add_announced(vec![third.clone()]);
+        assert_eq!(mempool.replaced_by(&first.txid()), Some(second.txid()));
+        assert_eq!(mempool.replaced_by(&second.txid()), Some(third.txid()));
+        assert_eq!(mempool.replaced_by(&third.txid()), None);
+
+        // the same tree from any of its transactions, rooted at the latest replacement
+        for txid in &[first.txid(), second.txid(), third.txid()] {
+            let latest = mempool.replacements(txid).unwrap();
+            assert_eq!(latest.txid, third.txid());
+            assert_eq!(latest.replaced_at, None);
+            assert_eq!(latest.replaces.len(), 1);
+            let replaced = &latest.replaces[0];
+            assert_eq!(replaced.txid, second.txid());
+            assert!(replaced.replaced_at.is_some());
+            assert_eq!(replaced.replaces.len(), 1);
+            assert_eq!(replaced.replaces[0].txid, first.txid());
+            assert!(replaced.replaces[0].replaces.is_empty());
+            assert!(!replaced.replaces[0].confirmed);
+            assert_eq!(latest.confirmed_txid(), None);
+        }
+    }
+
+    #[test]
+    fn test_mempool_packages() {
+        use crate::new_index::mempool::TemplatePackage;
+        use crate::new_index::Mempool;
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+        let query = Arc::new(ChainQuery::new(Arc::clone(&store), None, &metrics));
+
+        let genesis = coinbase(0, 0x51);
+        let other = coinbase(1, 0x52);
+        connect(
+            &indexer,
+            &chain(vec![vec![genesis.clone()], vec![other.clone()]]),
+        );
+        let mut mempool = Mempool::new(query, &metrics);
+
+        // the transactions are 61 vbytes each, paying 1 sat/vbyte for the parent, 11 for its
+        // child (bumping the parent to 6 with CPFP) and 5 for an unrelated transaction
+        let parent = spending(&genesis, 0, 50_0000_0000 - 61, 0x53);
+        let child = spending(&parent, 0, 50_0000_0000 - 61 - 671, 0x54);
+        let unrelated = spending(&other, 0, 50_0000_0000 - 305, 0x55);
+        mempool.add_announced(vec![parent.clone(), unrelated.clone()]);
+        assert_eq!(mempool.effective_feerate(&parent.txid()), Some(1.0));
+        assert_eq!(mempool.effective_feerate(&unrelated.txid()), Some(5.0));
+
+        // the parent's package and feerate are updated along with the child's
+        mempool.add_announced(vec![child.clone()]);
+        let package = mempool.get_package(&child.txid()).unwrap();
+        assert_eq!(package.ancestor_count, 2);
+        assert_eq!(package.ancestor_size, 122);
+        assert_eq!(package.ancestor_fee, 732);
+        assert_eq!(package.descendant_count, 1);
+        assert_eq!(package.ancestor_feerate(), 6.0);
+        let package = mempool.get_package(&parent.txid()).unwrap();
+        assert_eq!(package.ancestor_count, 1);
+        assert_eq!(package.descendant_count, 2);
+        assert_eq!(package.descendant_size, 122);
+        assert_eq!(package.descendant_fee, 732);
+
+        // mined along with its parent, at their package feerate
+        assert_eq!(mempool.effective_feerate(&parent.txid()), Some(6.0));
+        assert_eq!(mempool.effective_feerate(&child.txid()), Some(6.0));
+        assert_eq!(mempool.effective_feerate(&unrelated.txid()), Some(5.0));
+
+        // the child's package is selected first, then the unrelated transaction (the parent is
+        // already part of the template)
+        let txids = |packages: Vec<TemplatePackage>| -> Vec<Vec<Sha256dHash>> {
+            packages.into_iter().map(|p| p.txids).collect()
+        };
+        let packages = mempool.template_hints(4_000_000, 10);
+        assert_eq!(packages[0].total_weight, 488);
+        assert_eq!(packages[0].total_fee, 732);
+        assert_eq!(packages[0].package_feerate, 6.0);
+        assert_eq!(
+            txids(packages),
+            vec![vec![parent.txid(), child.txid()], vec![unrelated.txid()]]
+        );
+        assert_eq!(txids(mempool.template_hints(4_000_000, 1)).len(), 1);
+        // packages that don't fit are skipped for the next ones
+        assert_eq!(
+            txids(mempool.template_hints(487, 10)),
+            vec![vec![unrelated.txid()]]
+        );
+        assert!(mempool.template_hints(243, 10).is_empty());
+    }
+
+    #[test]
+    fn test_mempool_lookup_txos() {
+        use crate::new_index::Mempool;
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+        let query = Arc::new(ChainQuery::new(Arc::clone(&store), None, &metrics));
+
+        let funding = coinbase(0, 0x51);
+        connect(&indexer, &chain(vec![vec![funding.clone()]]));
+        let mut mempool = Mempool::new(query, &metrics);
+        let unconfirmed = spending(&funding, 0, 1000, 0x52);
+        mempool.add_announced(vec![unconfirmed.clone()]);
+
+        let outpoint = |tx: &Transaction, vout| OutPoint {
+            txid: tx.txid(),
+            vout,
+        };
+        let outpoints: BTreeSet<OutPoint> = vec![
+            outpoint(&funding, 0),
+            outpoint(&unconfirmed, 0),
+            outpoint(&unconfirmed, 1), // no such output
+        ]
+        .into_iter()
+        .collect();
+
+        // the mempool outputs only, the confirmed ones being left to the caller
+        let txos = mempool.lookup_unconfirmed_txos(&outpoints);
+        assert_eq!(txos.len(), 1);
+        assert_eq!(txos[&outpoint(&unconfirmed, 0)], unconfirmed.output[0]);
+
+        let txos = mempool.lookup_avail_txos(&outpoints);
+        assert_eq!(txos.len(), 2);
+        assert_eq!(txos[&outpoint(&funding, 0)], funding.output[0]);
+        assert!(mempool.lookup_txos(&outpoints).is_err());
+    }
+
+    #[test]
+    fn test_mempool_history_paging() {
+        use crate::new_index::Mempool;
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+        let query = Arc::new(ChainQuery::new(Arc::clone(&store), None, &metrics));
+
+        let fundings: Vec<Transaction> = (0..3).map(|tag| coinbase(tag, 0x51)).collect();
+        connect(
+            &indexer,
+            &chain(fundings.iter().map(|tx| vec![tx.clone()]).collect()),
+        );
+        let mut mempool = Mempool::new(query, &metrics);
+
+        // three transactions paying to 0x52, the second one spending and funding it
+        let first = spending(&fundings[0], 0, 1000, 0x52);
+        let second = spending(&first, 0, 900, 0x52);
+        let third = spending(&fundings[1], 0, 1000, 0x52);
+        for tx in &[&first, &second, &third] {
+            mempool.add_announced(vec![(*tx).clone()]);
+        }
+        let scripthash = compute_script_hash(&Script::from(vec![0x52]));
+        let page = |after: Option<&Transaction>, limit| -> Vec<Sha256dHash> {
+            let after = after.map(Transaction::txid);
+            mempool
+                .history(&scripthash[..], after.as_ref(), limit)
+                .iter()
+                .map(Transaction::txid)
+                .collect()
+        };
+
+        // in the order they entered the mempool, each listed once
+        assert_eq!(
+            page(None, 10),
+            vec![first.txid(), second.txid(), third.txid()]
+        );
+        assert_eq!(page(None, 2), vec![first.txid(), second.txid()]);
+        assert_eq!(page(Some(&second), 2), vec![third.txid()]);
+        assert!(page(Some(&third), 2).is_empty());
+        // an unknown cursor (e.g. evicted from the mempool) matches nothing
+        assert!(page(Some(&fundings[2]), 2).is_empty());
+    }
+
+    #[test]
+    fn test_mempool_txids_by_feerate() {
+        use crate::new_index::Mempool;
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+        let query = Arc::new(ChainQuery::new(Arc::clone(&store), None, &metrics));
+
+        let genesis = coinbase(0, 0x51);
+        let other = coinbase(1, 0x52);
+        connect(
+            &indexer,
+            &chain(vec![vec![genesis.clone()], vec![other.clone()]]),
+        );
+        let mut mempool = Mempool::new(query, &metrics);
+
+        // 61 vbytes each, paying 1 sat/vbyte for the parent, 11 for its child (6 for their
+        // package) and 5 for an unrelated transaction
+        let parent = spending(&genesis, 0, 50_0000_0000 - 61, 0x53);
+        let child = spending(&parent, 0, 50_0000_0000 - 61 - 671, 0x54);
+        let unrelated = spending(&other, 0, 50_0000_0000 - 305, 0x55);
+        mempool.add_announced(vec![parent.clone(), child.clone(), unrelated.clone()]);
+
+        // the child is sorted by its package feerate, not by its own higher one
+        let sorted = mempool.txids_by_feerate(10);
+        let txids: Vec<Sha256dHash> = sorted.iter().map(|tx| tx.txid).collect();
+        assert_eq!(txids, vec![child.txid(), unrelated.txid(), parent.txid()]);
+        let feerates: Vec<f32> = sorted.iter().map(|tx| tx.feerate).collect();
+        assert_eq!(feerates, vec![6.0, 5.0, 1.0]);
+
+        // the highest ones are kept
+        let txids: Vec<Sha256dHash> = mempool
+            .txids_by_feerate(2)
+            .into_iter()
+            .map(|tx| tx.txid)
+            .collect();
+        assert_eq!(txids, vec![child.txid(), unrelated.txid()]);
+    }
+
+    #[test]
+    fn test_adoption_rollups() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+        let query = ChainQuery::new(Arc::clone(&store), None, &metrics);
+        let rollups = || {
+            let epochs = query.adoption_stats(AdoptionPeriod::Epoch, None, 10);
+            assert_eq!(epochs, query.adoption_stats(AdoptionPeriod::Day, None, 10));
+            assert_eq!(epochs.len(), 1);
+            assert_eq!(epochs[0].0, 0);
+            epochs.into_iter().next().unwrap().1
+        };
+        // a chain sharing the genesis block, forking at height 1
+        let fork = |txdata: Vec<Transaction>, nonce: u32| {
+            let mut blocks = chain(vec![vec![coinbase(0, 0x51)], txdata]);
+            blocks[1].block.header.nonce = nonce;
+            let headers = blocks.iter().map(|b| b.block.header).collect();
+            blocks[1].entry = HeaderList::empty().order(headers).pop().unwrap();
+            blocks.pop().unwrap()
+        };
+
+        let genesis = coinbase(0, 0x51);
+        let mut segwit_spend = spending(&genesis, 0, 49_9999_0000, 0x52);
+        segwit_spend.input[0].witness = vec![vec![0x01]];
+        let blocks = chain(vec![
+            vec![genesis],
+            vec![coinbase(1, 0x53), segwit_spend],
+            vec![coinbase(2, 0x54)],
+        ]);
+        connect(&indexer, &blocks);
+        indexer.update_adoption_stats();
+        let stats = rollups();
+        assert_eq!(stats.block_count, 3);
+        assert_eq!(stats.tx_count, 1);
+        assert_eq!(stats.segwit_tx_count, 1);
+        assert_eq!(stats.segwit_input_count, 1);
+        assert_eq!(stats.output_count, 4);
+
+        // the stats of the orphaned blocks are subtracted
+        connect(&indexer, &[fork(vec![coinbase(3, 0x55)], 1)]);
+        let orphaned = blocks[1..]
+            .iter()
+            .map(|b| BlockRow::new_orphan(&b.entry).to_row())
+            .collect();
+        store.txstore_db.write(orphaned, DBFlush::Disable);
+        indexer.update_adoption_stats();
+        let stats = rollups();
+        assert_eq!(stats.block_count, 2);
+        assert_eq!(stats.tx_count, 0);
+        assert_eq!(stats.segwit_tx_count, 0);
+        assert_eq!(stats.output_count, 2);
+
+        // rebuilt when the orphaned blocks can't be walked back
+        connect(&indexer, &[fork(vec![coinbase(4, 0x56)], 2)]);
+        indexer.update_adoption_stats();
+        let stats = rollups();
+        assert_eq!(stats.block_count, 2);
+        assert_eq!(stats.output_count, 2);
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    #[test]
+    fn test_block_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let indexer = test_indexer(&store, &metrics);
+        let query = ChainQuery::new(Arc::clone(&store), None, &metrics);
+
+        let funding = coinbase(0, 0x51);
+        let mut spending = coinbase(1, 0x53);
+        spending.input[0].previous_output = OutPoint {
+            txid: funding.txid(),
+            vout: 0,
+        };
+        spending.input[0].witness = vec![vec![0x01]];
+        spending.output[0].value -= 10_000;
+        let vsize = spending.get_weight() / 4;
+        let blocks = chain(vec![vec![funding], vec![coinbase(1, 0x52), spending]]);
+        connect(&indexer, &blocks);
+
+        let stats = query.get_block_stats(blocks[1].entry.hash()).unwrap();
+        assert_eq!(stats.total_fees, Amount::from_sat(10_000));
+        assert_eq!(stats.subsidy, Amount::from_sat(50 * 100_000_000));
+        assert_eq!(
+            stats.total_output,
+            Amount::from_sat(100 * 100_000_000 - 10_000)
+        );
+        assert_eq!(stats.avg_feerate, 10_000.0 / vsize as f32);
+        assert_eq!(stats.median_feerate, stats.avg_feerate);
+        // the coinbase isn't counted
+        assert_eq!(stats.segwit_tx_count, 1);
+        assert_eq!(stats.segwit_ratio, 1.0);
+
+        // no fees in a coinbase-only block
+        let stats = query.get_block_stats(blocks[0].entry.hash()).unwrap();
+        assert_eq!(stats.total_fees, Amount::from_sat(0));
+        assert_eq!(stats.avg_feerate, 0.0);
+        assert_eq!(stats.segwit_ratio, 0.0);
+
+        // blocks indexed before the stats were can have theirs computed, and cached
+        let hash = blocks[1].entry.hash();
+        store
+            .history_db
+            .delete(vec![BlockRow::stats_key(full_hash(&hash[..]))]);
+        assert!(query.get_block_stats(hash).is_none());
+        let computed = query.compute_block_stats(hash, Network::Regtest).unwrap();
+        assert_eq!(computed.total_fees, Amount::from_sat(10_000));
+        assert_eq!(computed.segwit_tx_count, 1);
+        assert!(query.cached_block_stats(hash).is_none());
+        query.cache_block_stats(hash, &computed);
+        let cached = query.cached_block_stats(hash).unwrap();
+        assert_eq!(cached.avg_feerate, computed.avg_feerate);
+    }
+
+    #[test]
+    fn test_tx_json_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let query = ChainQuery::new(Arc::clone(&store), None, &metrics);
+
+        let txid = |i: u8| Sha256dHash::from_slice(&[i; 32]).unwrap();
+        let entries: Vec<(Sha256dHash, Bytes)> = (0..10).map(|i| (txid(i), vec![i])).collect();
+        query.cache_tx_json(&entries, 1);
+        // cached again by a concurrent request, and in another variant
+        query.cache_tx_json(&entries[..2], 1);
+        query.cache_tx_json(&entries[..2], 2);
+        assert_eq!(query.cached_tx_json(&txid(3), 1), Some(vec![3]));
+        assert_eq!(query.cached_tx_json(&txid(3), 2), None);
+        assert_eq!(query.cached_tx_json(&txid(1), 2), Some(vec![1]));
+        assert_eq!(*store.tx_json_count.lock().unwrap(), 12);
+
+        let mut cursor = b"J".to_vec();
+        assert_eq!(store.evict_tx_json(12, &mut cursor), 0);
+        // down to 90% of the maximum, from the cursor on
+        assert_eq!(store.evict_tx_json(10, &mut cursor), 3);
+        assert_eq!(query.cached_tx_json(&txid(0), 1), None);
+        assert_eq!(query.cached_tx_json(&txid(0), 2), None);
+        assert_eq!(query.cached_tx_json(&txid(1), 1), None);
+        assert_eq!(query.cached_tx_json(&txid(1), 2), Some(vec![1]));
+        assert_eq!(store.evict_tx_json(8, &mut cursor), 1);
+        assert_eq!(query.cached_tx_json(&txid(1), 2), None);
+        assert_eq!(query.cached_tx_json(&txid(2), 1), Some(vec![2]));
+
+        // the count is kept across restarts
+        store.flush_cache();
+        drop(query);
+        drop(store);
+        let store = Store::open(dir.path());
+        assert_eq!(*store.tx_json_count.lock().unwrap(), 8);
+    }
+}
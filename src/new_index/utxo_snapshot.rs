@@ -0,0 +1,331 @@
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin::consensus::encode::{Decodable, VarInt};
+use bitcoin::{OutPoint, TxOut};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use secp256k1::PublicKey;
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use crate::errors::*;
+
+// The start of the snapshots written by bitcoind 28.0 and later, followed by their version
+const SNAPSHOT_MAGIC: &[u8] = b"utxo\xff";
+const SNAPSHOT_VERSION: u16 = 2;
+
+// The compressed scripts of the special types (p2pkh, p2sh and p2pk), the size of the others is
+// shifted by their number
+const SPECIAL_SCRIPTS: u64 = 6;
+
+// outputs with larger scripts are never added to bitcoind's UTXO set
+const MAX_SCRIPT_SIZE: u64 = 10_000;
+
+// An unspent output of the snapshot
+#[derive(Debug, PartialEq)]
+pub struct SnapshotCoin {
+    pub outpoint: OutPoint,
+    pub height: u32,
+    pub is_coinbase: bool,
+    pub txout: TxOut,
+}
+
+// Reads the UTXO set snapshots written by bitcoind's `dumptxoutset` RPC: the block the snapshot
+// was taken at and the number of coins, followed by the coins grouped by transaction (serialized
+// like in bitcoind's chainstate, with their amounts and scripts compressed)
+pub struct UtxoSnapshot<R> {
+    reader: R,
+    pub blockhash: Sha256dHash,
+    pub coins_count: u64,
+    remaining: u64,
+    txid: Sha256dHash,
+    txid_remaining: u64, // coins of `txid` left to read
+}
+
+impl UtxoSnapshot<BufReader<File>> {
+    pub fn open(path: &Path, network_magic: u32) -> Result<Self> {
+        let file = File::open(path).chain_err(|| format!("failed to open {}", path.display()))?;
+        UtxoSnapshot::new(BufReader::new(file), network_magic)
+            .chain_err(|| format!("invalid UTXO snapshot {}", path.display()))
+    }
+}
+
+impl<R: Read> UtxoSnapshot<R> {
+    // `network_magic` is the message start of the chain the snapshot must be for
+    pub fn new(mut reader: R, network_magic: u32) -> Result<Self> {
+        let magic = read_bytes(&mut reader, SNAPSHOT_MAGIC.len())?;
+        if magic != SNAPSHOT_MAGIC {
+            bail!("unsupported format, expected a snapshot written by bitcoind 28.0 or later");
+        }
+        let version: u16 = decode(&mut reader)?;
+        if version != SNAPSHOT_VERSION {
+            bail!("unsupported snapshot version {}", version);
+        }
+        let snapshot_magic: u32 = decode(&mut reader)?;
+        if snapshot_magic != network_magic {
+            bail!(
+                "the snapshot is for another network (magic {:08x}, expected {:08x})",
+                snapshot_magic.swap_bytes(),
+                network_magic.swap_bytes()
+            );
+        }
+        let blockhash = decode(&mut reader)?;
+        let coins_count = decode(&mut reader)?;
+        Ok(UtxoSnapshot {
+            reader,
+            blockhash,
+            coins_count,
+            remaining: coins_count,
+            txid: Sha256dHash::default(),
+            txid_remaining: 0,
+        })
+    }
+
+    fn read_coin(&mut self) -> Result<SnapshotCoin> {
+        if self.txid_remaining == 0 {
+            self.txid = decode(&mut self.reader)?;
+            self.txid_remaining = decode::<VarInt, _>(&mut self.reader)?.0;
+            if self.txid_remaining == 0 {
+                bail!("no coins for transaction {}", self.txid);
+            }
+        }
+        self.txid_remaining -= 1;
+        let vout = decode::<VarInt, _>(&mut self.reader)?.0;
+        if vout > u64::from(u32::max_value()) {
+            bail!("invalid output index {}", vout);
+        }
+        // the height and whether it's a coinbase output, then the compressed output
+        let code = read_varint(&mut self.reader)?;
+        let value = decompress_amount(read_varint(&mut self.reader)?);
+        let script_pubkey = read_script(&mut self.reader)?;
+        Ok(SnapshotCoin {
+            outpoint: OutPoint {
+                txid: self.txid,
+                vout: vout as u32,
+            },
+            height: (code >> 1) as u32,
+            is_coinbase: code & 1 == 1,
+            txout: TxOut {
+                value,
+                script_pubkey,
+            },
+        })
+    }
+}
+
+impl<R: Read> Iterator for UtxoSnapshot<R> {
+    type Item = Result<SnapshotCoin>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        Some(self.read_coin())
+    }
+}
+
+fn decode<T: Decodable, R: Read>(reader: &mut R) -> Result<T> {
+    T::consensus_decode(reader).chain_err(|| "truncated snapshot")
+}
+
+fn read_bytes<R: Read>(reader: &mut R, len: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    reader
+        .read_exact(&mut bytes)
+        .chain_err(|| "truncated snapshot")?;
+    Ok(bytes)
+}
+
+// bitcoind's VARINT (base 128, most significant digit first, with each continued digit offset by
+// one so that the encodings are unique), not the CompactSize of the P2P messages
+fn read_varint<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut n: u64 = 0;
+    loop {
+        let byte: u8 = decode(reader)?;
+        if n > u64::max_value() >> 7 {
+            bail!("VARINT too large");
+        }
+        n = (n << 7) | u64::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+        n = n.checked_add(1).chain_err(|| "VARINT too large")?;
+    }
+}
+
+// The amounts are compressed by their trailing zeros (see bitcoind's CompressAmount)
+fn decompress_amount(x: u64) -> u64 {
+    if x == 0 {
+        return 0;
+    }
+    let mut x = x - 1;
+    let mut exponent = x % 10;
+    x /= 10;
+    let mut n = if exponent < 9 {
+        let digit = (x % 9) + 1;
+        x /= 9;
+        x * 10 + digit
+    } else {
+        x + 1
+    };
+    while exponent > 0 {
+        n *= 10;
+        exponent -= 1;
+    }
+    n
+}
+
+fn read_script<R: Read>(reader: &mut R) -> Result<Script> {
+    let size = read_varint(reader)?;
+    let script = match size {
+        0 => Builder::new()
+            .push_opcode(opcodes::all::OP_DUP)
+            .push_opcode(opcodes::all::OP_HASH160)
+            .push_slice(&read_bytes(reader, 20)?)
+            .push_opcode(opcodes::all::OP_EQUALVERIFY)
+            .push_opcode(opcodes::all::OP_CHECKSIG)
+            .into_script(),
+        1 => Builder::new()
+            .push_opcode(opcodes::all::OP_HASH160)
+            .push_slice(&read_bytes(reader, 20)?)
+            .push_opcode(opcodes::all::OP_EQUAL)
+            .into_script(),
+        // p2pk with a compressed public key, stored as is
+        2 | 3 => {
+            let mut key = vec![size as u8];
+            key.extend(read_bytes(reader, 32)?);
+            p2pk(&key)
+        }
+        // p2pk with an uncompressed public key, stored compressed
+        4 | 5 => {
+            let mut key = vec![size as u8 - 2];
+            key.extend(read_bytes(reader, 32)?);
+            let key = PublicKey::from_slice(&key).chain_err(|| "invalid public key")?;
+            p2pk(&key.serialize_uncompressed())
+        }
+        _ => {
+            let size = size - SPECIAL_SCRIPTS;
+            if size > MAX_SCRIPT_SIZE {
+                bail!("script of {} bytes is too large", size);
+            }
+            Script::from(read_bytes(reader, size as usize)?)
+        }
+    };
+    Ok(script)
+}
+
+fn p2pk(key: &[u8]) -> Script {
+    Builder::new()
+        .push_slice(key)
+        .push_opcode(opcodes::all::OP_CHECKSIG)
+        .into_script()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::Network;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::network::constants::Network as BNetwork;
+    use bitcoin::util::hash::BitcoinHash;
+
+    fn header(network: Network, blockhash: &Sha256dHash, coins_count: u64) -> Vec<u8> {
+        let mut bytes = SNAPSHOT_MAGIC.to_vec();
+        bytes.extend(serialize(&SNAPSHOT_VERSION));
+        bytes.extend(serialize(&network.magic()));
+        bytes.extend(serialize(blockhash));
+        bytes.extend(serialize(&coins_count));
+        bytes
+    }
+
+    #[test]
+    fn test_varint() {
+        let cases: &[(&[u8], u64)] = &[
+            (&[0x00], 0),
+            (&[0x7f], 0x7f),
+            (&[0x80, 0x00], 0x80),
+            (&[0xa3, 0x34], 0x1234),
+            (&[0x82, 0xfe, 0x7f], 0xffff),
+            (&[0xc7, 0xe7, 0x56], 0x12_3456),
+        ];
+        for (bytes, n) in cases {
+            assert_eq!(read_varint(&mut &bytes[..]).unwrap(), *n);
+        }
+        assert!(read_varint(&mut &[0x80][..]).is_err());
+    }
+
+    #[test]
+    fn test_decompress_amount() {
+        assert_eq!(decompress_amount(0x0), 0);
+        assert_eq!(decompress_amount(0x1), 1);
+        assert_eq!(decompress_amount(0x7), 1_000_000);
+        assert_eq!(decompress_amount(0x9), 100_000_000);
+        assert_eq!(decompress_amount(0x32), 50 * 100_000_000);
+        assert_eq!(decompress_amount(0x140_6f40), 21_000_000 * 100_000_000);
+    }
+
+    #[test]
+    fn test_utxo_snapshot() {
+        let genesis = genesis_block(BNetwork::Regtest);
+        let genesis_script = &genesis.txdata[0].output[0].script_pubkey;
+        let blockhash = genesis.header.bitcoin_hash();
+        let txids = [Sha256dHash::default(), genesis.txdata[0].txid()];
+
+        let mut bytes = header(Network::Regtest, &blockhash, 3);
+        // a p2pkh output and a raw script of the first transaction
+        bytes.extend(serialize(&txids[0]));
+        bytes.extend(&[2, 1]); // 2 coins, vout 1
+        bytes.extend(&[0x8e, 0x50]); // height 1000, not coinbase
+        bytes.push(0x9); // 1 BTC
+        bytes.push(0);
+        bytes.extend(&[0xab; 20]);
+        bytes.extend(&[0xfd, 0x2c, 0x01]); // vout 300
+        bytes.extend(&[0x8e, 0x50, 0x7, 8, 0x6a, 0x01]);
+        // the uncompressed p2pk coinbase output of the genesis block
+        bytes.extend(serialize(&txids[1]));
+        bytes.extend(&[1, 0, 1, 0x32, 5]);
+        bytes.extend(&genesis_script[2..34]);
+
+        let snapshot = UtxoSnapshot::new(&bytes[..], Network::Regtest.magic()).unwrap();
+        assert_eq!(snapshot.blockhash, blockhash);
+        assert_eq!(snapshot.coins_count, 3);
+        let coins: Vec<SnapshotCoin> = snapshot.map(|coin| coin.unwrap()).collect();
+        assert_eq!(coins.len(), 3);
+        assert_eq!(
+            coins[0].outpoint,
+            OutPoint {
+                txid: txids[0],
+                vout: 1
+            }
+        );
+        assert_eq!(coins[0].height, 1000);
+        assert!(!coins[0].is_coinbase);
+        assert_eq!(coins[0].txout.value, 100_000_000);
+        assert!(coins[0].txout.script_pubkey.is_p2pkh());
+        assert_eq!(coins[1].outpoint.vout, 300);
+        assert_eq!(coins[1].txout.value, 1_000_000);
+        assert_eq!(coins[1].txout.script_pubkey, Script::from(vec![0x6a, 0x01]));
+        assert_eq!(
+            coins[2].outpoint,
+            OutPoint {
+                txid: txids[1],
+                vout: 0
+            }
+        );
+        assert_eq!(coins[2].height, 0);
+        assert!(coins[2].is_coinbase);
+        assert_eq!(coins[2].txout, genesis.txdata[0].output[0]);
+
+        // truncated
+        let mut snapshot =
+            UtxoSnapshot::new(&bytes[..bytes.len() - 1], Network::Regtest.magic()).unwrap();
+        assert!(snapshot.nth(2).unwrap().is_err());
+
+        // another network, or not a snapshot
+        assert!(UtxoSnapshot::new(&bytes[..], Network::Bitcoin.magic()).is_err());
+        assert!(UtxoSnapshot::new(&bytes[5..], Network::Regtest.magic()).is_err());
+    }
+}
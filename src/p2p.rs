@@ -0,0 +1,562 @@
+use bitcoin::consensus::encode::{serialize, Decodable, VarInt};
+use bitcoin::{BitcoinHash, BlockHeader};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
+use error_chain::ChainedError;
+use std::io::{Cursor, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::errors::*;
+use crate::metrics::{Gauge, MetricOpts, Metrics};
+use crate::new_index::Query;
+use crate::util::{spawn_thread, Channel};
+
+// A minimal inbound P2P listener serving the headers and the BIP158 filters of the index to
+// BIP157 light clients: `getheaders`, `getcfilters`, `getcfheaders` and `getcfcheckpt` are
+// answered, `ping`s are ponged and everything else is ignored. No blocks, transactions or
+// addresses are served or relayed.
+
+const PROTOCOL_VERSION: i32 = 70016;
+const NODE_COMPACT_FILTERS: u64 = 1 << 6;
+const USER_AGENT: &str = concat!("/electrs:", env!("CARGO_PKG_VERSION"), "/");
+
+const HEADER_SIZE: usize = 24;
+const MAX_PAYLOAD_SIZE: u32 = 64 * 1024; // requests are small
+const MAX_CONNECTIONS: usize = 100;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(20 * 60);
+
+// BIP157 limits
+const FILTER_TYPE_BASIC: u8 = 0;
+const MAX_GETCFILTERS_SIZE: usize = 1000;
+const MAX_GETCFHEADERS_SIZE: usize = 2000;
+const CFCHECKPT_INTERVAL: usize = 1000;
+const MAX_HEADERS_RESULTS: usize = 2000;
+
+#[derive(Debug, PartialEq)]
+struct RawMessage {
+    command: String,
+    payload: Vec<u8>,
+}
+
+fn encode_message(magic: u32, command: &str, payload: &[u8]) -> Vec<u8> {
+    assert!(command.len() <= 12, "command too long");
+    let mut msg = Vec::with_capacity(HEADER_SIZE + payload.len());
+    msg.extend_from_slice(&magic.to_le_bytes());
+    let mut name = [0u8; 12];
+    name[..command.len()].copy_from_slice(command.as_bytes());
+    msg.extend_from_slice(&name);
+    msg.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    msg.extend_from_slice(&Sha256dHash::hash(payload)[..4]);
+    msg.extend_from_slice(payload);
+    msg
+}
+
+fn read_message<R: Read>(reader: &mut R, magic: u32) -> Result<RawMessage> {
+    let mut header = [0u8; HEADER_SIZE];
+    reader
+        .read_exact(&mut header)
+        .chain_err(|| "failed to read message header")?;
+    ensure!(
+        u32::from_le_bytes(*array_ref![header, 0, 4]) == magic,
+        "unexpected network magic"
+    );
+    let command = &header[4..16];
+    let command_len = command.iter().position(|b| *b == 0).unwrap_or(12);
+    ensure!(
+        command[command_len..].iter().all(|b| *b == 0),
+        "invalid command padding"
+    );
+    let command =
+        String::from_utf8(command[..command_len].to_vec()).chain_err(|| "non-ascii command")?;
+    let len = u32::from_le_bytes(*array_ref![header, 16, 4]);
+    ensure!(len <= MAX_PAYLOAD_SIZE, "message too large ({} bytes)", len);
+
+    let mut payload = vec![0u8; len as usize];
+    reader
+        .read_exact(&mut payload)
+        .chain_err(|| "failed to read message payload")?;
+    ensure!(
+        Sha256dHash::hash(&payload)[..4] == header[20..24],
+        "invalid checksum for {}",
+        command
+    );
+    Ok(RawMessage { command, payload })
+}
+
+fn decode<T: Decodable>(reader: &mut Cursor<&[u8]>) -> Result<T> {
+    T::consensus_decode(reader).chain_err(|| "truncated message")
+}
+
+// The network address fields of the version message (services, IPv6 address and port)
+fn encode_net_addr(services: u64, addr: &SocketAddr) -> Vec<u8> {
+    let ip = match addr {
+        SocketAddr::V4(addr) => addr.ip().to_ipv6_mapped(),
+        SocketAddr::V6(addr) => *addr.ip(),
+    };
+    let mut bytes = services.to_le_bytes().to_vec();
+    bytes.extend_from_slice(&ip.octets());
+    bytes.extend_from_slice(&addr.port().to_be_bytes());
+    bytes
+}
+
+fn version_payload(peer: &SocketAddr, local: &SocketAddr, start_height: usize) -> Vec<u8> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let timestamp = now.as_secs() as i64;
+    // only used by the peer to detect connections to itself
+    let nonce = now.as_secs() << 32 | u64::from(now.subsec_nanos());
+    let mut payload = PROTOCOL_VERSION.to_le_bytes().to_vec();
+    payload.extend_from_slice(&NODE_COMPACT_FILTERS.to_le_bytes());
+    payload.extend_from_slice(&timestamp.to_le_bytes());
+    payload.extend(encode_net_addr(0, peer));
+    payload.extend(encode_net_addr(NODE_COMPACT_FILTERS, local));
+    payload.extend_from_slice(&nonce.to_le_bytes());
+    payload.extend(serialize(&VarInt(USER_AGENT.len() as u64)));
+    payload.extend_from_slice(USER_AGENT.as_bytes());
+    payload.extend_from_slice(&(start_height as i32).to_le_bytes());
+    payload.push(0); // no transaction relay
+    payload
+}
+
+// `getcfilters` and `getcfheaders`: the filter type, the start height and the stop hash
+#[derive(Debug, PartialEq)]
+struct FilterRange {
+    filter_type: u8,
+    start_height: u32,
+    stop_hash: Sha256dHash,
+}
+
+impl FilterRange {
+    fn parse(payload: &[u8]) -> Result<Self> {
+        let mut reader = Cursor::new(payload);
+        Ok(FilterRange {
+            filter_type: decode(&mut reader)?,
+            start_height: decode(&mut reader)?,
+            stop_hash: decode(&mut reader)?,
+        })
+    }
+}
+
+// `getheaders`: the block locator (most recent first) and the stop hash (zero for no limit)
+#[derive(Debug, PartialEq)]
+struct HeadersRequest {
+    locator: Vec<Sha256dHash>,
+    stop_hash: Sha256dHash,
+}
+
+impl HeadersRequest {
+    fn parse(payload: &[u8]) -> Result<Self> {
+        let mut reader = Cursor::new(payload);
+        let _version: u32 = decode(&mut reader)?;
+        let count = decode::<VarInt>(&mut reader)?.0;
+        ensure!(count <= 101, "too many locator hashes ({})", count);
+        let locator = (0..count)
+            .map(|_| decode(&mut reader))
+            .collect::<Result<_>>()?;
+        Ok(HeadersRequest {
+            locator,
+            stop_hash: decode(&mut reader)?,
+        })
+    }
+}
+
+fn encode_headers(headers: &[BlockHeader]) -> Vec<u8> {
+    let mut payload = serialize(&VarInt(headers.len() as u64));
+    for header in headers {
+        payload.extend(serialize(header));
+        payload.push(0); // no transactions
+    }
+    payload
+}
+
+struct Peer {
+    query: Arc<Query>,
+    magic: u32,
+    stream: TcpStream,
+    addr: SocketAddr,
+    sent_version: bool,
+}
+
+impl Peer {
+    fn send(&mut self, command: &str, payload: &[u8]) -> Result<()> {
+        self.stream
+            .write_all(&encode_message(self.magic, command, payload))
+            .chain_err(|| format!("failed to send {}", command))
+    }
+
+    // The height of the stop hash, which must be a best-chain block at most `max_count` blocks
+    // after the start height
+    fn stop_height(&self, range: &FilterRange, max_count: usize) -> Result<usize> {
+        ensure!(
+            range.filter_type == FILTER_TYPE_BASIC,
+            "unsupported filter type {}",
+            range.filter_type
+        );
+        let stop_height = self
+            .query
+            .chain()
+            .blockid_by_hash(&range.stop_hash)
+            .chain_err(|| format!("unknown stop hash {}", range.stop_hash))?
+            .height;
+        let start_height = range.start_height as usize;
+        ensure!(
+            start_height <= stop_height && stop_height - start_height < max_count,
+            "invalid filter range {}..={}",
+            start_height,
+            stop_height
+        );
+        Ok(stop_height)
+    }
+
+    fn filter(&self, height: usize) -> Result<(Sha256dHash, Vec<u8>, Sha256dHash)> {
+        let chain = self.query.chain();
+        let hash = chain
+            .hash_by_height(height)
+            .chain_err(|| format!("missing block at height {}", height))?;
+        let (filter, header) = chain
+            .get_block_filter(&hash)
+            .chain_err(|| format!("missing filter for block {}", hash))?;
+        Ok((hash, filter, header))
+    }
+
+    fn handle_getcfilters(&mut self, payload: &[u8]) -> Result<()> {
+        let range = FilterRange::parse(payload)?;
+        let stop_height = self.stop_height(&range, MAX_GETCFILTERS_SIZE)?;
+        for height in range.start_height as usize..=stop_height {
+            let (hash, filter, _) = self.filter(height)?;
+            let mut payload = vec![range.filter_type];
+            payload.extend_from_slice(&hash[..]);
+            payload.extend(serialize(&VarInt(filter.len() as u64)));
+            payload.extend(filter);
+            self.send("cfilter", &payload)?;
+        }
+        Ok(())
+    }
+
+    fn handle_getcfheaders(&mut self, payload: &[u8]) -> Result<()> {
+        let range = FilterRange::parse(payload)?;
+        let stop_height = self.stop_height(&range, MAX_GETCFHEADERS_SIZE)?;
+        let start_height = range.start_height as usize;
+        let prev_header = match start_height {
+            0 => Sha256dHash::default(),
+            height => self.filter(height - 1)?.2,
+        };
+
+        let mut payload = vec![range.filter_type];
+        payload.extend_from_slice(&range.stop_hash[..]);
+        payload.extend_from_slice(&prev_header[..]);
+        payload.extend(serialize(&VarInt((stop_height - start_height + 1) as u64)));
+        for height in start_height..=stop_height {
+            let (_, filter, _) = self.filter(height)?;
+            payload.extend_from_slice(&Sha256dHash::hash(&filter)[..]);
+        }
+        self.send("cfheaders", &payload)
+    }
+
+    fn handle_getcfcheckpt(&mut self, payload: &[u8]) -> Result<()> {
+        let mut reader = Cursor::new(payload);
+        let filter_type: u8 = decode(&mut reader)?;
+        let stop_hash: Sha256dHash = decode(&mut reader)?;
+        ensure!(
+            filter_type == FILTER_TYPE_BASIC,
+            "unsupported filter type {}",
+            filter_type
+        );
+        let stop_height = self
+            .query
+            .chain()
+            .blockid_by_hash(&stop_hash)
+            .chain_err(|| format!("unknown stop hash {}", stop_hash))?
+            .height;
+
+        let count = stop_height / CFCHECKPT_INTERVAL;
+        let mut payload = vec![filter_type];
+        payload.extend_from_slice(&stop_hash[..]);
+        payload.extend(serialize(&VarInt(count as u64)));
+        for i in 1..=count {
+            let (_, _, header) = self.filter(i * CFCHECKPT_INTERVAL)?;
+            payload.extend_from_slice(&header[..]);
+        }
+        self.send("cfcheckpt", &payload)
+    }
+
+    fn handle_getheaders(&mut self, payload: &[u8]) -> Result<()> {
+        let request = HeadersRequest::parse(payload)?;
+        let chain = self.query.chain();
+        // the headers following the first best-chain block of the locator (or the genesis)
+        let start_height = request
+            .locator
+            .iter()
+            .filter_map(|hash| chain.blockid_by_hash(hash))
+            .next()
+            .map_or(1, |blockid| blockid.height + 1);
+        let mut headers = chain.headers_from(start_height, MAX_HEADERS_RESULTS);
+        if let Some(pos) = headers
+            .iter()
+            .position(|header| header.bitcoin_hash() == request.stop_hash)
+        {
+            headers.truncate(pos + 1);
+        }
+        self.send("headers", &encode_headers(&headers))
+    }
+
+    fn handle_message(&mut self, msg: RawMessage) -> Result<()> {
+        match msg.command.as_str() {
+            "version" => {
+                if !self.sent_version {
+                    let local = self.stream.local_addr().chain_err(|| "no local address")?;
+                    let best_height = self.query.chain().best_height();
+                    self.send("version", &version_payload(&self.addr, &local, best_height))?;
+                    self.sent_version = true;
+                }
+                self.send("verack", &[])
+            }
+            "ping" => self.send("pong", &msg.payload),
+            "getheaders" => self.handle_getheaders(&msg.payload),
+            "getcfilters" => self.handle_getcfilters(&msg.payload),
+            "getcfheaders" => self.handle_getcfheaders(&msg.payload),
+            "getcfcheckpt" => self.handle_getcfcheckpt(&msg.payload),
+            command => {
+                trace!("[{}] ignoring {}", self.addr, command);
+                Ok(())
+            }
+        }
+    }
+
+    fn run(mut self) -> Result<()> {
+        self.stream
+            .set_read_timeout(Some(IDLE_TIMEOUT))
+            .chain_err(|| "failed to set read timeout")?;
+        let mut reader = self
+            .stream
+            .try_clone()
+            .chain_err(|| "failed to clone stream")?;
+        loop {
+            let msg = read_message(&mut reader, self.magic)?;
+            self.handle_message(msg)?;
+        }
+    }
+}
+
+pub struct P2PServer {
+    acceptor: Sender<Option<TcpStream>>,
+    server: Option<thread::JoinHandle<()>>,
+}
+
+impl P2PServer {
+    fn start_acceptor(addr: SocketAddr, chan: &Channel<Option<TcpStream>>) {
+        let acceptor = chan.sender();
+        spawn_thread("p2p-acceptor", move || {
+            let listener = TcpListener::bind(addr).expect(&format!("bind({}) failed", addr));
+            info!("P2P server running on {}", addr);
+            loop {
+                let (stream, _) = listener.accept().expect("accept failed");
+                stream
+                    .set_nonblocking(false)
+                    .expect("failed to set connection as blocking");
+                if acceptor.send(Some(stream)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn start_peer(
+        stream: TcpStream,
+        query: Arc<Query>,
+        magic: u32,
+        streams: Arc<Mutex<Vec<(SocketAddr, TcpStream)>>>,
+        connections: Gauge,
+    ) -> Result<thread::JoinHandle<()>> {
+        let addr = stream.peer_addr().chain_err(|| "no peer address")?;
+        {
+            let mut streams = streams.lock().unwrap();
+            if streams.len() >= MAX_CONNECTIONS {
+                let _ = stream.shutdown(Shutdown::Both);
+                bail!("too many connections, rejecting {}", addr);
+            }
+            let clone = stream.try_clone().chain_err(|| "failed to clone stream")?;
+            streams.push((addr, clone));
+        }
+        Ok(spawn_thread("p2p-peer", move || {
+            info!("[{}] connected P2P peer", addr);
+            connections.inc();
+            let peer = Peer {
+                query,
+                magic,
+                stream,
+                addr,
+                sent_version: false,
+            };
+            if let Err(e) = peer.run() {
+                debug!("[{}] P2P peer failed: {}", addr, e.display_chain());
+            }
+            streams.lock().unwrap().retain(|(a, _)| *a != addr);
+            connections.dec();
+            info!("[{}] disconnected P2P peer", addr);
+        }))
+    }
+
+    pub fn start(addr: SocketAddr, config: &Config, query: Arc<Query>, metrics: &Metrics) -> Self {
+        let connections = metrics.gauge(MetricOpts::new("p2p_connections", "# of P2P connections"));
+        let magic = config.network_magic;
+        let chan = Channel::new();
+        P2PServer::start_acceptor(addr, &chan);
+        P2PServer {
+            acceptor: chan.sender(),
+            server: Some(spawn_thread("p2p", move || {
+                let streams = Arc::new(Mutex::new(vec![]));
+                let mut children = vec![];
+                while let Some(stream) = chan.receiver().recv().unwrap() {
+                    match P2PServer::start_peer(
+                        stream,
+                        query.clone(),
+                        magic,
+                        streams.clone(),
+                        connections.clone(),
+                    ) {
+                        Ok(child) => children.push(child),
+                        Err(e) => warn!("{}", e),
+                    }
+                }
+                trace!("closing {} P2P connections", streams.lock().unwrap().len());
+                for (_, stream) in streams.lock().unwrap().iter() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                for child in children {
+                    let _ = child.join();
+                }
+                trace!("P2P connections are closed");
+            })),
+        }
+    }
+}
+
+impl Drop for P2PServer {
+    fn drop(&mut self) {
+        trace!("stop accepting new P2P connections");
+        self.acceptor.send(None).unwrap();
+        self.server.take().map(|t| t.join().unwrap());
+        trace!("P2P server is stopped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        encode_headers, encode_message, read_message, version_payload, FilterRange, HeadersRequest,
+        RawMessage, HEADER_SIZE, MAX_PAYLOAD_SIZE,
+    };
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::network::constants::Network;
+    use bitcoin_hashes::hex::{FromHex, ToHex};
+    use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+    use std::io::Cursor;
+
+    const MAGIC: u32 = 0xD9B4BEF9;
+
+    #[test]
+    fn test_message_framing() {
+        // an empty verack, as sent by bitcoind
+        let verack = encode_message(MAGIC, "verack", &[]);
+        assert_eq!(
+            verack.to_hex(),
+            "f9beb4d976657261636b000000000000000000005df6e0e2"
+        );
+        let msg = read_message(&mut Cursor::new(&verack), MAGIC).unwrap();
+        assert_eq!(
+            msg,
+            RawMessage {
+                command: "verack".to_string(),
+                payload: vec![],
+            }
+        );
+
+        let ping = encode_message(MAGIC, "ping", &[1, 2, 3, 4, 5, 6, 7, 8]);
+        let msg = read_message(&mut Cursor::new(&ping), MAGIC).unwrap();
+        assert_eq!(msg.command, "ping");
+        assert_eq!(msg.payload, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // another network, a corrupted payload and a truncated message
+        assert!(read_message(&mut Cursor::new(&ping), 0x0709110B).is_err());
+        let mut corrupted = ping.clone();
+        corrupted[HEADER_SIZE] ^= 1;
+        assert!(read_message(&mut Cursor::new(&corrupted), MAGIC).is_err());
+        assert!(read_message(&mut Cursor::new(&ping[..ping.len() - 1]), MAGIC).is_err());
+
+        // oversized payloads are rejected before being read
+        let mut oversized = encode_message(MAGIC, "ping", &[]);
+        oversized[16..20].copy_from_slice(&(MAX_PAYLOAD_SIZE + 1).to_le_bytes());
+        assert!(read_message(&mut Cursor::new(&oversized), MAGIC).is_err());
+    }
+
+    #[test]
+    fn test_version_payload() {
+        let peer = "127.0.0.1:8333".parse().unwrap();
+        let local = "[::1]:8334".parse().unwrap();
+        let payload = version_payload(&peer, &local, 650000);
+        assert_eq!(&payload[0..4], &70016i32.to_le_bytes());
+        assert_eq!(&payload[4..12], &64u64.to_le_bytes());
+        // the peer's address, as an IPv4-mapped address
+        assert_eq!(
+            payload[28..46].to_hex(),
+            "00000000000000000000ffff7f000001208d"
+        );
+        assert_eq!(&payload[54..70], &local.ip().octets());
+        let user_agent_len = payload[80] as usize;
+        assert!(payload[81..81 + user_agent_len].starts_with(b"/electrs:"));
+        let rest = &payload[81 + user_agent_len..];
+        assert_eq!(rest, &[0x10, 0xeb, 0x09, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_parse_requests() {
+        let stop_hash = Sha256dHash::from_hex(
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+        )
+        .unwrap();
+        let mut payload = vec![0];
+        payload.extend_from_slice(&1000u32.to_le_bytes());
+        payload.extend_from_slice(&stop_hash[..]);
+        assert_eq!(
+            FilterRange::parse(&payload).unwrap(),
+            FilterRange {
+                filter_type: 0,
+                start_height: 1000,
+                stop_hash,
+            }
+        );
+        assert!(FilterRange::parse(&payload[..payload.len() - 1]).is_err());
+
+        let mut payload = 70016u32.to_le_bytes().to_vec();
+        payload.push(1);
+        payload.extend_from_slice(&stop_hash[..]);
+        payload.extend_from_slice(&[0; 32]);
+        assert_eq!(
+            HeadersRequest::parse(&payload).unwrap(),
+            HeadersRequest {
+                locator: vec![stop_hash],
+                stop_hash: Sha256dHash::default(),
+            }
+        );
+        payload[4] = 102;
+        assert!(HeadersRequest::parse(&payload).is_err());
+    }
+
+    #[test]
+    fn test_encode_headers() {
+        let genesis = genesis_block(Network::Bitcoin).header;
+        let payload = encode_headers(&[genesis.clone(), genesis.clone()]);
+        assert_eq!(payload.len(), 1 + 2 * 81);
+        assert_eq!(payload[0], 2);
+        assert_eq!(&payload[1..81], &serialize(&genesis)[..]);
+        assert_eq!(payload[81], 0);
+    }
+}
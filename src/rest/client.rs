@@ -0,0 +1,219 @@
+// A minimal blocking client of the REST API, deserializing the responses into rest::types
+// (enabled with the `client` feature)
+
+use bitcoin_hashes::hex::{FromHex, ToHex};
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use reqwest::{Response, StatusCode};
+use serde::de::DeserializeOwned;
+
+use crate::errors::*;
+use crate::rest::types::{
+    BlockValue, SpendingValue, TransactionStatus, TransactionValue, UtxoValue,
+};
+
+pub struct RestClient {
+    base_url: String,
+    http: reqwest::Client,
+}
+
+impl RestClient {
+    // e.g. "http://localhost:3000", or with the path prefix of a proxy
+    pub fn new(base_url: &str) -> Self {
+        RestClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn tip_height(&self) -> Result<usize> {
+        let height = self.get_text("/blocks/tip/height")?;
+        height
+            .parse()
+            .chain_err(|| format!("invalid tip height: {}", height))
+    }
+
+    pub fn tip_hash(&self) -> Result<Sha256dHash> {
+        let hash = self.get_text("/blocks/tip/hash")?;
+        Sha256dHash::from_hex(&hash).chain_err(|| format!("invalid tip hash: {}", hash))
+    }
+
+    pub fn block(&self, hash: &Sha256dHash) -> Result<Option<BlockValue>> {
+        self.get_json(&format!("/block/{}", hash.to_hex()))
+    }
+
+    pub fn block_txids(&self, hash: &Sha256dHash) -> Result<Option<Vec<Sha256dHash>>> {
+        self.get_json(&format!("/block/{}/txids", hash.to_hex()))
+    }
+
+    pub fn tx(&self, txid: &Sha256dHash) -> Result<Option<TransactionValue>> {
+        self.get_json(&format!("/tx/{}", txid.to_hex()))
+    }
+
+    pub fn tx_status(&self, txid: &Sha256dHash) -> Result<Option<TransactionStatus>> {
+        self.get_json(&format!("/tx/{}/status", txid.to_hex()))
+    }
+
+    pub fn outspends(&self, txid: &Sha256dHash) -> Result<Option<Vec<SpendingValue>>> {
+        self.get_json(&format!("/tx/{}/outspends", txid.to_hex()))
+    }
+
+    // The most recent transactions of the address, use address_txs_chain() to page through the
+    // older ones
+    pub fn address_txs(&self, address: &str) -> Result<Vec<TransactionValue>> {
+        self.get_json(&format!("/address/{}/txs", address))
+            .map(Option::unwrap_or_default)
+    }
+
+    pub fn address_txs_chain(
+        &self,
+        address: &str,
+        last_seen: Option<&Sha256dHash>,
+    ) -> Result<Vec<TransactionValue>> {
+        let path = match last_seen {
+            Some(txid) => format!("/address/{}/txs/chain/{}", address, txid.to_hex()),
+            None => format!("/address/{}/txs/chain", address),
+        };
+        self.get_json(&path).map(Option::unwrap_or_default)
+    }
+
+    pub fn address_utxo(&self, address: &str) -> Result<Vec<UtxoValue>> {
+        self.get_json(&format!("/address/{}/utxo", address))
+            .map(Option::unwrap_or_default)
+    }
+
+    pub fn broadcast(&self, txhex: &str) -> Result<Sha256dHash> {
+        let resp = self
+            .http
+            .post(&self.url("/tx"))
+            .body(txhex.to_string())
+            .send()
+            .chain_err(|| "failed to broadcast transaction")?;
+        let txid = check_status(resp)?
+            .text()
+            .chain_err(|| "failed to read broadcast response")?;
+        Sha256dHash::from_hex(&txid).chain_err(|| format!("invalid txid: {}", txid))
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    // None when not found
+    fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<Option<T>> {
+        let resp = self
+            .http
+            .get(&self.url(path))
+            .send()
+            .chain_err(|| format!("failed to request {}", path))?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        check_status(resp)?
+            .json()
+            .map(Some)
+            .chain_err(|| format!("invalid response for {}", path))
+    }
+
+    fn get_text(&self, path: &str) -> Result<String> {
+        let resp = self
+            .http
+            .get(&self.url(path))
+            .send()
+            .chain_err(|| format!("failed to request {}", path))?;
+        check_status(resp)?
+            .text()
+            .chain_err(|| format!("invalid response for {}", path))
+    }
+}
+
+// Fail with the error message of the response, if any
+fn check_status(mut resp: Response) -> Result<Response> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let message = resp.text().unwrap_or_default();
+    bail!("{} ({}): {}", resp.url(), status, message.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Serve the canned (status, body) responses to the next requests, in order
+    fn serve(responses: Vec<(&'static str, &'static str)>) -> RestClient {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    let line = line.to_lowercase();
+                    if line.starts_with("content-length:") {
+                        content_length = line["content-length:".len()..].trim().parse().unwrap();
+                    }
+                }
+                let mut request_body = vec![0; content_length];
+                reader.read_exact(&mut request_body).unwrap();
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        RestClient::new(&format!("http://{}/", addr))
+    }
+
+    #[test]
+    fn test_responses() {
+        let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        let client = serve(vec![
+            ("200 OK", "680000"),
+            ("404 Not Found", "Transaction not found"),
+            ("200 OK", "not a height"),
+            (
+                "400 Bad Request",
+                "sendrawtransaction RPC error: bad-txns-inputs-missingorspent",
+            ),
+        ]);
+        assert_eq!(client.tip_height().unwrap(), 680000);
+        let txid = Sha256dHash::from_hex(txid).unwrap();
+        assert!(client.tx(&txid).unwrap().is_none());
+        assert!(client.tip_height().is_err());
+        let err = client.broadcast("00").unwrap_err();
+        assert!(err.to_string().contains("bad-txns-inputs-missingorspent"));
+    }
+
+    #[test]
+    fn test_older_server() {
+        // without the fields added since, which deserialize as their default
+        let client = serve(vec![(
+            "200 OK",
+            r#"{"txid":"4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+                "version":1,"locktime":0,"vin":[],"vout":[],"size":204,"weight":816,"fee":0,
+                "status":{"confirmed":true,"block_height":0,"block_hash":null,"block_time":null}}"#,
+        )]);
+        let txid = Sha256dHash::default();
+        let tx = client.tx(&txid).unwrap().unwrap();
+        assert_eq!(tx.version, 1);
+        assert!(!tx.rbf_signaled);
+        assert!(!tx.is_non_standard_version);
+        assert!(tx.min_relay_ok.is_none());
+        assert!(tx.total_input_value.is_none());
+        assert_eq!(tx.status.unwrap().block_height, Some(0));
+    }
+}
@@ -0,0 +1,71 @@
+// TLS termination for the REST server, with the certificate and key reloaded from their files on
+// SIGHUP (e.g. after a renewal) for the connections accepted from then on
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{NoClientAuth, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::errors::*;
+
+pub struct TlsCerts {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+impl TlsCerts {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        Ok(TlsCerts {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            acceptor: RwLock::new(load_acceptor(cert_path, key_path)?),
+        })
+    }
+
+    // Keeps the current certificate if the files are invalid (e.g. while being rewritten)
+    pub fn reload(&self) -> Result<()> {
+        let acceptor = load_acceptor(&self.cert_path, &self.key_path)?;
+        *self.acceptor.write().unwrap() = acceptor;
+        Ok(())
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+}
+
+fn load_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor> {
+    let chain = certs(&mut open(cert_path)?)
+        .map_err(|_| format!("invalid certificate file {}", cert_path.display()))?;
+    if chain.is_empty() {
+        bail!("no certificate found in {}", cert_path.display());
+    }
+    let key = load_key(key_path)?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(chain, key)
+        .chain_err(|| "invalid TLS certificate or key")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// The first PKCS#8 or (PKCS#1) RSA key of the file
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let invalid = || format!("invalid key file {}", path.display());
+    let mut keys = pkcs8_private_keys(&mut open(path)?).map_err(|_| invalid())?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(path)?).map_err(|_| invalid())?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| format!("no private key found in {}", path.display()).into())
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).chain_err(|| format!("failed to open {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
@@ -0,0 +1,190 @@
+// The JSON values of the REST API, for clients deserializing the responses (see rest::client).
+//
+// These are kept stable within a major version: fields may be added (so clients shouldn't deny
+// unknown fields), but existing ones aren't renamed, removed or changed to another type. Fields
+// skipped when empty, like the asm and the BTC-denominated values, deserialize as None, and the
+// fields missing from the responses of older servers deserialize as their default.
+
+use bitcoin::Script;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+
+pub use crate::util::{Amount, TransactionStatus};
+
+#[cfg(feature = "liquid")]
+pub use crate::util::{BlockProofValue, IssuanceValue, PegOutRequest};
+
+// A block header and its metadata, as returned by /block/:hash and the blocks lists
+#[derive(Serialize, Deserialize)]
+pub struct BlockValue {
+    pub id: String,
+    pub height: u32,
+    pub version: u32,
+    pub timestamp: u32,
+    pub tx_count: u32,
+    pub size: u32,
+    pub weight: u32,
+    pub merkle_root: String,
+    pub previousblockhash: Option<String>,
+    pub in_best_chain: bool,
+    #[cfg(not(feature = "liquid"))]
+    pub nonce: u32,
+    #[cfg(not(feature = "liquid"))]
+    pub bits: u32,
+    #[cfg(feature = "liquid")]
+    pub proof: Option<BlockProofValue>,
+}
+
+// A transaction with its prevouts and confirmation status, as returned by /tx/:txid and the
+// transactions lists
+#[derive(Serialize, Deserialize)]
+pub struct TransactionValue {
+    pub txid: Sha256dHash,
+    pub version: u32,
+    pub locktime: u32,
+    pub vin: Vec<TxInValue>,
+    pub vout: Vec<TxOutValue>,
+    pub size: u32,
+    pub weight: u32,
+    // null when any of the values is unknown (missing prevouts, or confidential in liquid)
+    pub total_input_value: Option<Amount>,
+    pub total_output_value: Option<Amount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_input_value_btc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_output_value_btc: Option<String>,
+    #[cfg(feature = "liquid")]
+    #[serde(default)]
+    pub blinded_input_count: usize,
+    #[cfg(feature = "liquid")]
+    #[serde(default)]
+    pub blinded_output_count: usize,
+    pub fee: Option<Amount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_btc: Option<String>,
+    // set when the prevouts don't add up, pointing to an index inconsistency
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_error: Option<String>,
+    // BIP125 opt-in signaling by any of the inputs
+    #[serde(default)]
+    pub rbf_signaled: bool,
+    #[serde(default)]
+    pub is_non_standard_version: bool,
+    // whether the fee pays for the minimum relay feerate, null when the fee is unknown
+    pub min_relay_ok: Option<bool>,
+    pub status: Option<TransactionStatus>,
+    // the package-aware feerate of unconfirmed transactions (in sat/vbyte), accounting for
+    // their unconfirmed ancestors and for descendants paying for them (CPFP)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub effective_feerate: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hex: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TxInValue {
+    pub txid: Sha256dHash,
+    pub vout: u32,
+    pub prevout: Option<TxOutValue>,
+    pub scriptsig: Script,
+    // the asm and witness are omitted in the compact (non-verbose) form
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scriptsig_asm: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_some"
+    )]
+    pub witness: Option<Option<Vec<String>>>, // null without a witness
+    pub is_coinbase: bool,
+    pub sequence: u32,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inner_redeemscript_asm: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inner_witnessscript_asm: Option<String>,
+
+    #[cfg(feature = "liquid")]
+    pub is_pegin: bool,
+    #[cfg(feature = "liquid")]
+    pub issuance: Option<IssuanceValue>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TxOutValue {
+    pub scriptpubkey: Script,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scriptpubkey_asm: Option<String>, // omitted in the compact (non-verbose) form
+    pub scriptpubkey_address: Option<String>,
+    pub scriptpubkey_type: String,
+
+    #[cfg(not(feature = "liquid"))]
+    pub value: Amount,
+
+    #[cfg(feature = "liquid")]
+    pub value: Option<Amount>,
+    #[cfg(feature = "liquid")]
+    pub valuecommitment: Option<String>,
+    #[cfg(feature = "liquid")]
+    pub asset: Option<String>,
+    #[cfg(feature = "liquid")]
+    pub assetcommitment: Option<String>,
+    #[cfg(feature = "liquid")]
+    pub pegout: Option<PegOutRequest>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_btc: Option<String>,
+}
+
+// Tells a null field (Some(None)) from a missing one (None)
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: serde::Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    serde::Deserialize::deserialize(deserializer).map(Some)
+}
+
+// An unspent output of an address or scripthash, as returned by /address/:address/utxo
+#[derive(Serialize, Deserialize)]
+pub struct UtxoValue {
+    pub txid: Sha256dHash,
+    pub vout: u32,
+    pub status: TransactionStatus,
+    #[cfg(not(feature = "liquid"))]
+    pub value: Amount,
+    #[cfg(feature = "liquid")]
+    pub value: Option<Amount>,
+    #[cfg(feature = "liquid")]
+    pub valuecommitment: Option<String>,
+}
+
+// The spending status of an output, as returned by /tx/:txid/outspend/:vout
+#[derive(Serialize, Deserialize)]
+pub struct SpendingValue {
+    pub spent: bool,
+    pub txid: Option<Sha256dHash>,
+    pub vin: Option<u32>,
+    pub status: Option<TransactionStatus>,
+    // the block the spent output was confirmed in, when spent (and confirmed)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub funding_height: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub funding_time: Option<u32>,
+    // the blocks between the funding and the spending, when both are confirmed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coin_age: Option<usize>,
+}
+
+impl Default for SpendingValue {
+    fn default() -> Self {
+        SpendingValue {
+            spent: false,
+            txid: None,
+            vin: None,
+            status: None,
+            funding_height: None,
+            funding_time: None,
+            coin_age: None,
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use chan;
 use chan_signal;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use crate::errors::*;
@@ -7,23 +8,57 @@ use crate::errors::*;
 #[derive(Clone)] // so multiple threads could wait on signals
 pub struct Waiter {
     signal: chan::Receiver<chan_signal::Signal>,
+    reload: Option<chan::Receiver<chan_signal::Signal>>,
+    // set once any of the clones received a signal, so that all of them stop (a signal is only
+    // received by one of them)
+    interrupted: Arc<Mutex<Option<chan_signal::Signal>>>,
 }
 
 impl Waiter {
     pub fn new() -> Waiter {
         Waiter {
             signal: chan_signal::notify(&[chan_signal::Signal::INT, chan_signal::Signal::TERM]),
+            reload: None,
+            interrupted: Arc::new(Mutex::new(None)),
         }
     }
+    // Also handles SIGHUP, as a request to reload the files read at startup (e.g. the TLS
+    // certificate). Otherwise SIGHUP keeps its default action of terminating the process.
+    pub fn new_with_reloads() -> Waiter {
+        Waiter {
+            reload: Some(chan_signal::notify(&[chan_signal::Signal::HUP])),
+            ..Waiter::new()
+        }
+    }
+    pub fn reloads(&self) -> Option<chan::Receiver<chan_signal::Signal>> {
+        self.reload.clone()
+    }
     pub fn wait(&self, duration: Duration) -> Result<()> {
+        self.check_interrupted()?;
+        let signal = &self.signal;
+        let timeout = chan::after(duration);
+        chan_select! {
+            signal.recv() -> s => {
+                if let Some(sig) = s {
+                    return self.interrupt(sig);
+                }
+            },
+            timeout.recv() => {},
+        }
+        Ok(())
+    }
+    // Like wait(), but returns early when a message is received on the wakeup channel
+    pub fn wait_or_wakeup(&self, duration: Duration, wakeup: &chan::Receiver<()>) -> Result<()> {
+        self.check_interrupted()?;
         let signal = &self.signal;
         let timeout = chan::after(duration);
         chan_select! {
             signal.recv() -> s => {
                 if let Some(sig) = s {
-                    bail!(ErrorKind::Interrupt(sig));
+                    return self.interrupt(sig);
                 }
             },
+            wakeup.recv() => {},
             timeout.recv() => {},
         }
         Ok(())
@@ -31,4 +66,14 @@ impl Waiter {
     pub fn poll(&self) -> Result<()> {
         self.wait(Duration::from_secs(0))
     }
+    fn interrupt(&self, sig: chan_signal::Signal) -> Result<()> {
+        *self.interrupted.lock().unwrap() = Some(sig);
+        bail!(ErrorKind::Interrupt(sig))
+    }
+    fn check_interrupted(&self) -> Result<()> {
+        match *self.interrupted.lock().unwrap() {
+            Some(sig) => bail!(ErrorKind::Interrupt(sig)),
+            None => Ok(()),
+        }
+    }
 }
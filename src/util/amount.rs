@@ -0,0 +1,98 @@
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+
+const SAT_PER_BTC: u64 = 100_000_000;
+
+// An amount in satoshis, serialized as a plain integer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub fn from_sat(sat: u64) -> Self {
+        Amount(sat)
+    }
+
+    pub fn as_sat(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    // For balances that may go negative, e.g. when unconfirmed transactions spend confirmed coins
+    pub fn signed_sub(self, other: Amount) -> i64 {
+        self.0 as i64 - other.0 as i64
+    }
+
+    // BTC-denominated decimal string, avoiding the precision loss of floats
+    pub fn to_btc_string(self) -> String {
+        format!("{}.{:08}", self.0 / SAT_PER_BTC, self.0 % SAT_PER_BTC)
+    }
+}
+
+impl Add for Amount {
+    type Output = Amount;
+
+    fn add(self, other: Amount) -> Amount {
+        Amount(self.0 + other.0)
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, other: Amount) {
+        self.0 += other.0
+    }
+}
+
+// For amounts known to add up, use checked_sub() or signed_sub() otherwise
+impl Sub for Amount {
+    type Output = Amount;
+
+    fn sub(self, other: Amount) -> Amount {
+        Amount(self.0 - other.0)
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, other: Amount) {
+        self.0 -= other.0
+    }
+}
+
+impl Sum for Amount {
+    fn sum<I: Iterator<Item = Amount>>(iter: I) -> Amount {
+        iter.fold(Amount::default(), Add::add)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} sat", self.0)
+    }
+}
+
+// A feerate in satoshis per virtual byte
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FeeRate(f32);
+
+impl FeeRate {
+    pub fn from_sat_per_vbyte(rate: f32) -> Self {
+        FeeRate(rate)
+    }
+
+    pub fn from_fee(fee: Amount, vsize: u32) -> Self {
+        FeeRate(fee.as_sat() as f32 / vsize as f32)
+    }
+
+    pub fn as_sat_per_vbyte(self) -> f32 {
+        self.0
+    }
+
+    pub fn fee_for_vsize(self, vsize: u64) -> Amount {
+        Amount((self.0 as f64 * vsize as f64).ceil() as u64)
+    }
+}
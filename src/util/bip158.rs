@@ -0,0 +1,273 @@
+use bitcoin::consensus::encode::{serialize, VarInt};
+use bitcoin::{BitcoinHash, Block, OutPoint, TxOut};
+use bitcoin_hashes::{sha256d::Hash as Sha256dHash, Hash};
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::errors::*;
+
+// BIP158 basic filter parameters
+const P: u8 = 19;
+const M: u64 = 784_931;
+
+// Build the BIP158 basic filter of a block, containing the scripts of its outputs and of the
+// outputs spent by its inputs. Serialized as the number of items followed by the Golomb-Rice
+// coded set.
+pub fn block_filter(
+    block: &Block,
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+) -> Result<Vec<u8>> {
+    let mut scripts: BTreeSet<&[u8]> = BTreeSet::new();
+    for (tx_index, tx) in block.txdata.iter().enumerate() {
+        for txo in &tx.output {
+            let script = txo.script_pubkey.as_bytes();
+            if !script.is_empty() && !txo.script_pubkey.is_op_return() {
+                scripts.insert(script);
+            }
+        }
+        if tx_index == 0 {
+            continue; // coinbase
+        }
+        for txin in &tx.input {
+            let prev_txo = previous_txos_map
+                .get(&txin.previous_output)
+                .chain_err(|| format!("missing previous txo {}", txin.previous_output))?;
+            if !prev_txo.script_pubkey.is_empty() {
+                scripts.insert(prev_txo.script_pubkey.as_bytes());
+            }
+        }
+    }
+
+    let blockhash = block.bitcoin_hash();
+    let k0 = u64::from_le_bytes(*array_ref![blockhash[..], 0, 8]);
+    let k1 = u64::from_le_bytes(*array_ref![blockhash[..], 8, 8]);
+    let range = scripts.len() as u128 * M as u128;
+    let mut values: Vec<u64> = scripts
+        .iter()
+        .map(|script| ((siphash24(k0, k1, script) as u128 * range) >> 64) as u64)
+        .collect();
+    values.sort_unstable();
+
+    let mut writer = BitWriter::new(serialize(&VarInt(values.len() as u64)));
+    let mut last = 0;
+    for value in values {
+        let delta = value - last;
+        last = value;
+        for _ in 0..(delta >> P) {
+            writer.write_bit(true);
+        }
+        writer.write_bit(false);
+        for i in (0..P).rev() {
+            writer.write_bit((delta >> i) & 1 == 1);
+        }
+    }
+    Ok(writer.finish())
+}
+
+// The filter header commits to the filter and to the previous block's filter header
+pub fn filter_header(filter: &[u8], prev_header: &Sha256dHash) -> Sha256dHash {
+    let filter_hash = Sha256dHash::hash(filter);
+    Sha256dHash::hash(&[&filter_hash[..], &prev_header[..]].concat())
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    current: u8,
+    bits: u8,
+}
+
+impl BitWriter {
+    fn new(bytes: Vec<u8>) -> Self {
+        BitWriter {
+            bytes,
+            current: 0,
+            bits: 0,
+        }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        self.current |= (bit as u8) << (7 - self.bits);
+        self.bits += 1;
+        if self.bits == 8 {
+            self.bytes.push(self.current);
+            self.current = 0;
+            self.bits = 0;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.bytes.push(self.current);
+        }
+        self.bytes
+    }
+}
+
+fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+    let round = |v: &mut [u64; 4]| {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    };
+
+    let chunks = data.chunks_exact(8);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let m = u64::from_le_bytes(*array_ref![chunk, 0, 8]);
+        v[3] ^= m;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= m;
+    }
+    let mut last = (data.len() as u64) << 56;
+    for (i, byte) in tail.iter().enumerate() {
+        last |= (*byte as u64) << (i * 8);
+    }
+    v[3] ^= last;
+    round(&mut v);
+    round(&mut v);
+    v[0] ^= last;
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{block_filter, filter_header, siphash24, M, P};
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+    use bitcoin::{BitcoinHash, OutPoint, Script, TxIn, TxOut};
+    use bitcoin_hashes::hex::ToHex;
+    use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+    use hex;
+    use std::collections::HashMap;
+
+    // Decode the Golomb-Rice coded set back to its sorted values
+    fn decode_filter(filter: &[u8]) -> Vec<u64> {
+        let count = filter[0] as usize; // small filters only
+        let bit = |pos: usize| (filter[1 + pos / 8] >> (7 - pos % 8)) & 1 == 1;
+        let mut pos = 0;
+        let mut last = 0;
+        let mut values = vec![];
+        for _ in 0..count {
+            let mut quotient = 0;
+            while bit(pos) {
+                quotient += 1;
+                pos += 1;
+            }
+            pos += 1;
+            let mut remainder = 0;
+            for _ in 0..P {
+                remainder = (remainder << 1) | bit(pos) as u64;
+                pos += 1;
+            }
+            last += (quotient << P) | remainder;
+            values.push(last);
+        }
+        values
+    }
+
+    #[test]
+    fn test_genesis_filter() {
+        // test vector from BIP158 (testnet block 0)
+        let filter = block_filter(&genesis_block(Network::Testnet), &HashMap::new()).unwrap();
+        assert_eq!(hex::encode(&filter), "019dfca8");
+        assert_eq!(
+            filter_header(&filter, &Sha256dHash::default()).to_hex(),
+            "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750"
+        );
+    }
+
+    #[test]
+    fn test_siphash24() {
+        // test vectors from the SipHash reference implementation (key 00..0f)
+        let (k0, k1) = (0x0706_0504_0302_0100, 0x0f0e_0d0c_0b0a_0908);
+        assert_eq!(siphash24(k0, k1, &[]), 0x726f_db47_dd0e_0e31);
+        let data: Vec<u8> = (0..15).collect();
+        assert_eq!(siphash24(k0, k1, &data[..8]), 0x93f5_f579_9a93_2462);
+        assert_eq!(siphash24(k0, k1, &data), 0xa129_ca61_49be_45e5);
+    }
+
+    #[test]
+    fn test_spending_block_filter() {
+        let mut block = genesis_block(Network::Regtest);
+        let prevout = OutPoint {
+            txid: block.txdata[0].txid(),
+            vout: 0,
+        };
+        let mut spending = block.txdata[0].clone();
+        spending.input = vec![TxIn {
+            previous_output: prevout,
+            script_sig: Script::new(),
+            sequence: 0xffff_ffff,
+            witness: vec![],
+        }];
+        spending.output = (1..=20u8)
+            .map(|i| TxOut {
+                value: 1000,
+                script_pubkey: Script::from(vec![0x51, i]),
+            })
+            .chain(vec![
+                // neither empty nor OP_RETURN scripts are included, nor duplicates
+                TxOut {
+                    value: 0,
+                    script_pubkey: Script::new(),
+                },
+                TxOut {
+                    value: 0,
+                    script_pubkey: Script::from(vec![0x6a, 0x01, 0x00]),
+                },
+                TxOut {
+                    value: 0,
+                    script_pubkey: Script::from(vec![0x51, 1]),
+                },
+            ])
+            .collect();
+        block.txdata.push(spending);
+
+        // the spent output must be known
+        assert!(block_filter(&block, &HashMap::new()).is_err());
+
+        let mut previous_txos_map = HashMap::new();
+        previous_txos_map.insert(prevout, block.txdata[0].output[0].clone());
+        let filter = block_filter(&block, &previous_txos_map).unwrap();
+
+        // the coinbase output script (also spent) and the 20 new ones
+        let blockhash = block.bitcoin_hash();
+        let k0 = u64::from_le_bytes(*array_ref![blockhash[..], 0, 8]);
+        let k1 = u64::from_le_bytes(*array_ref![blockhash[..], 8, 8]);
+        let scripts: Vec<Vec<u8>> = (1..=20u8)
+            .map(|i| vec![0x51, i])
+            .chain(vec![block.txdata[0].output[0]
+                .script_pubkey
+                .as_bytes()
+                .to_vec()])
+            .collect();
+        let mut expected: Vec<u64> = scripts
+            .iter()
+            .map(|script| {
+                let hash = siphash24(k0, k1, script) as u128;
+                ((hash * (scripts.len() as u128 * M as u128)) >> 64) as u64
+            })
+            .collect();
+        expected.sort_unstable();
+        assert_eq!(decode_filter(&filter), expected);
+    }
+}
@@ -1,8 +1,11 @@
-use crate::chain::{Block, BlockHeader};
+use crate::chain::{Block, BlockHeader, OutPoint, TxOut};
 use crate::errors::*;
 use crate::new_index::BlockEntry;
+use crate::util::fees::TxFeeInfo;
+#[cfg(not(feature = "liquid"))]
+use crate::util::{is_v1_p2tr, Amount};
 
-use bitcoin::consensus::encode::serialize;
+use bitcoin::consensus::encode::{serialize, VarInt};
 use bitcoin::util::hash::BitcoinHash;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 
@@ -37,6 +40,14 @@ pub struct HeaderEntry {
 }
 
 impl HeaderEntry {
+    pub fn new(height: usize, header: BlockHeader) -> HeaderEntry {
+        HeaderEntry {
+            height,
+            hash: header.bitcoin_hash(),
+            header,
+        }
+    }
+
     pub fn hash(&self) -> &Sha256dHash {
         &self.hash
     }
@@ -120,7 +131,8 @@ impl HeaderList {
             .collect()
     }
 
-    pub fn apply(&mut self, new_headers: Vec<HeaderEntry>) {
+    // Returns the entries disconnected from the best chain (if the new headers cause a reorg)
+    pub fn apply(&mut self, new_headers: Vec<HeaderEntry>) -> Vec<HeaderEntry> {
         // new_headers[i] -> new_headers[i - 1] (i.e. new_headers.last() is the tip)
         for i in 1..new_headers.len() {
             assert_eq!(new_headers[i - 1].height() + 1, new_headers[i].height());
@@ -140,14 +152,14 @@ impl HeaderList {
                 assert_eq!(entry.header().prev_blockhash, expected_prev_blockhash);
                 height
             }
-            None => return,
+            None => return vec![],
         };
         debug!(
             "applying {} new headers from height {}",
             new_headers.len(),
             new_height
         );
-        self.headers.split_off(new_height); // keep [0..new_height) entries
+        let disconnected = self.headers.split_off(new_height); // keep [0..new_height) entries
         for new_header in new_headers {
             let height = new_header.height();
             assert_eq!(height, self.headers.len());
@@ -155,6 +167,7 @@ impl HeaderList {
             self.headers.push(new_header);
             self.heights.insert(self.tip, height);
         }
+        disconnected
     }
 
     pub fn header_by_blockhash(&self, blockhash: &Sha256dHash) -> Option<&HeaderEntry> {
@@ -230,6 +243,182 @@ pub struct BlockMeta {
     pub weight: u32,
 }
 
+// Compact block stats, computed at index time (when the previous outputs are readily available)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlockSummary {
+    pub tx_count: u32,
+    pub size: u32,
+    pub weight: u32,
+    pub total_fees: u64,
+    pub median_feerate: f32,
+}
+
+// Fee info of the non-coinbase transactions, sorted by feerate
+fn block_fees(b: &BlockEntry, previous_txos_map: &HashMap<OutPoint, TxOut>) -> Vec<TxFeeInfo> {
+    let mut fees: Vec<TxFeeInfo> = b
+        .block
+        .txdata
+        .iter()
+        .skip(1) // coinbase
+        .map(|tx| {
+            let prevouts = tx
+                .input
+                .iter()
+                .enumerate()
+                .filter_map(|(index, txin)| {
+                    previous_txos_map
+                        .get(&txin.previous_output)
+                        .map(|txo| (index as u32, txo))
+                })
+                .collect();
+            TxFeeInfo::new(tx, &prevouts)
+        })
+        .collect();
+    fees.sort_unstable_by(|f1, f2| f1.fee_per_vbyte.partial_cmp(&f2.fee_per_vbyte).unwrap());
+    fees
+}
+
+// The weight of the block, including the (non-witness) header and transaction count
+fn block_weight(block: &Block) -> u32 {
+    let overhead =
+        serialize(&block.header).len() + serialize(&VarInt(block.txdata.len() as u64)).len();
+    let txs_weight: usize = block.txdata.iter().map(|tx| tx.get_weight()).sum();
+    (overhead * 4 + txs_weight) as u32
+}
+
+impl BlockSummary {
+    pub fn new(b: &BlockEntry, previous_txos_map: &HashMap<OutPoint, TxOut>) -> Self {
+        let fees = block_fees(b, previous_txos_map);
+
+        BlockSummary {
+            tx_count: b.block.txdata.len() as u32,
+            size: b.size,
+            weight: block_weight(&b.block),
+            total_fees: fees.iter().map(|f| f.fee).sum(),
+            median_feerate: fees.get(fees.len() / 2).map_or(0.0, |f| f.fee_per_vbyte),
+        }
+    }
+}
+
+// Aggregate fee and reward stats, computed at index time along with the summary
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlockStats {
+    pub total_fees: Amount,
+    pub subsidy: Amount,
+    pub total_output: Amount, // including the coinbase
+    pub avg_feerate: f32,     // in sat/vbyte, of the non-coinbase transactions
+    pub median_feerate: f32,
+    pub segwit_tx_count: u32,
+    pub segwit_ratio: f32, // of the non-coinbase transactions
+}
+
+#[cfg(not(feature = "liquid"))]
+impl BlockStats {
+    pub fn new(b: &BlockEntry, previous_txos_map: &HashMap<OutPoint, TxOut>, subsidy: u64) -> Self {
+        let fees = block_fees(b, previous_txos_map);
+        let total_fees: u64 = fees.iter().map(|f| f.fee).sum();
+        let total_vsize: u64 = fees.iter().map(|f| f.vsize as u64).sum();
+        let txs = &b.block.txdata[1..];
+        let segwit_tx_count = txs
+            .iter()
+            .filter(|tx| tx.input.iter().any(|txin| !txin.witness.is_empty()))
+            .count();
+
+        BlockStats {
+            total_fees: Amount::from_sat(total_fees),
+            subsidy: Amount::from_sat(subsidy),
+            total_output: b
+                .block
+                .txdata
+                .iter()
+                .flat_map(|tx| tx.output.iter())
+                .map(|txo| Amount::from_sat(txo.value))
+                .sum(),
+            avg_feerate: match total_vsize {
+                0 => 0.0,
+                vsize => total_fees as f32 / vsize as f32,
+            },
+            median_feerate: fees.get(fees.len() / 2).map_or(0.0, |f| f.fee_per_vbyte),
+            segwit_tx_count: segwit_tx_count as u32,
+            segwit_ratio: match txs.len() {
+                0 => 0.0,
+                count => segwit_tx_count as f32 / count as f32,
+            },
+        }
+    }
+}
+
+// Segwit and taproot usage counts of a block, summed per day and per difficulty epoch for the
+// adoption rollups. Coinbase transactions aren't counted.
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct AdoptionStats {
+    pub block_count: u32,
+    pub tx_count: u64,
+    pub segwit_tx_count: u64, // spending at least one segwit input
+    pub input_count: u64,
+    pub segwit_input_count: u64,  // with a witness
+    pub taproot_input_count: u64, // spending a v1 p2tr output
+    pub output_count: u64,
+    pub taproot_output_count: u64,
+}
+
+#[cfg(not(feature = "liquid"))]
+impl AdoptionStats {
+    pub fn new(b: &BlockEntry, previous_txos_map: &HashMap<OutPoint, TxOut>) -> Self {
+        let mut stats = AdoptionStats {
+            block_count: 1,
+            ..Default::default()
+        };
+        for tx in &b.block.txdata[1..] {
+            let segwit_inputs = tx.input.iter().filter(|txin| !txin.witness.is_empty());
+            let taproot_inputs = tx.input.iter().filter(|txin| {
+                previous_txos_map
+                    .get(&txin.previous_output)
+                    .map_or(false, |prevout| is_v1_p2tr(&prevout.script_pubkey))
+            });
+            let segwit_input_count = segwit_inputs.count() as u64;
+            stats.tx_count += 1;
+            stats.segwit_tx_count += (segwit_input_count > 0) as u64;
+            stats.input_count += tx.input.len() as u64;
+            stats.segwit_input_count += segwit_input_count;
+            stats.taproot_input_count += taproot_inputs.count() as u64;
+        }
+        for tx in &b.block.txdata {
+            stats.output_count += tx.output.len() as u64;
+            stats.taproot_output_count += tx
+                .output
+                .iter()
+                .filter(|txout| is_v1_p2tr(&txout.script_pubkey))
+                .count() as u64;
+        }
+        stats
+    }
+
+    pub fn add(&mut self, other: &AdoptionStats) {
+        self.block_count += other.block_count;
+        self.tx_count += other.tx_count;
+        self.segwit_tx_count += other.segwit_tx_count;
+        self.input_count += other.input_count;
+        self.segwit_input_count += other.segwit_input_count;
+        self.taproot_input_count += other.taproot_input_count;
+        self.output_count += other.output_count;
+        self.taproot_output_count += other.taproot_output_count;
+    }
+
+    pub fn sub(&mut self, other: &AdoptionStats) {
+        self.block_count -= other.block_count;
+        self.tx_count -= other.tx_count;
+        self.segwit_tx_count -= other.segwit_tx_count;
+        self.input_count -= other.input_count;
+        self.segwit_input_count -= other.segwit_input_count;
+        self.taproot_input_count -= other.taproot_input_count;
+        self.output_count -= other.output_count;
+        self.taproot_output_count -= other.taproot_output_count;
+    }
+}
+
 pub struct BlockHeaderMeta {
     pub header_entry: HeaderEntry,
     pub meta: BlockMeta,
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::errors::*;
+
+const TTL_LONG: u32 = 157784630; // ttl for static resources (5 years)
+const TTL_SHORT: u32 = 10; // ttl for volatile resources
+
+// Classes of REST endpoints sharing the same cacheability
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheClass {
+    Final,    // can no longer change (e.g. deeply confirmed blocks and transactions)
+    Recent,   // may still change, but rarely (e.g. shallowly confirmed blocks and transactions)
+    Tip,      // changes with every new block
+    Mempool,  // changes with the mempool contents (including address histories and balances)
+    Uncached, // side effects and operator endpoints, not cached by default
+}
+
+impl CacheClass {
+    pub fn names() -> Vec<&'static str> {
+        vec!["final", "recent", "tip", "mempool", "uncached"]
+    }
+
+    fn default_ttl(self) -> u32 {
+        match self {
+            CacheClass::Final => TTL_LONG,
+            CacheClass::Recent | CacheClass::Tip | CacheClass::Mempool => TTL_SHORT,
+            CacheClass::Uncached => 0,
+        }
+    }
+
+    // Whether the responses only change with new blocks, so that the tip hash can validate them
+    pub fn follows_tip(self) -> bool {
+        match self {
+            CacheClass::Final | CacheClass::Recent | CacheClass::Tip => true,
+            CacheClass::Mempool | CacheClass::Uncached => false,
+        }
+    }
+}
+
+impl FromStr for CacheClass {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        Ok(match name {
+            "final" => CacheClass::Final,
+            "recent" => CacheClass::Recent,
+            "tip" => CacheClass::Tip,
+            "mempool" => CacheClass::Mempool,
+            "uncached" => CacheClass::Uncached,
+            _ => bail!(
+                "unknown cache class {:?} (expected one of: {})",
+                name,
+                CacheClass::names().join(", ")
+            ),
+        })
+    }
+}
+
+// Caching headers of the REST API responses, by endpoint class
+#[derive(Debug, Clone)]
+pub struct CachePolicy {
+    ttls: HashMap<CacheClass, u32>, // overrides of the default ttls
+    pub stale_while_revalidate: Option<u32>,
+    pub etag: bool, // add an ETag derived from the tip hash to responses that follow the tip
+}
+
+impl CachePolicy {
+    pub fn new(
+        ttls: HashMap<CacheClass, u32>,
+        stale_while_revalidate: Option<u32>,
+        etag: bool,
+    ) -> Self {
+        CachePolicy {
+            ttls,
+            stale_while_revalidate,
+            etag,
+        }
+    }
+
+    pub fn ttl(&self, class: CacheClass) -> u32 {
+        self.ttls
+            .get(&class)
+            .cloned()
+            .unwrap_or_else(|| class.default_ttl())
+    }
+
+    pub fn cache_control(&self, class: CacheClass) -> String {
+        let ttl = self.ttl(class);
+        match self.stale_while_revalidate {
+            // uncached responses must not be served stale either
+            Some(stale) if ttl > 0 => {
+                format!("public, max-age={}, stale-while-revalidate={}", ttl, stale)
+            }
+            _ => format!("public, max-age={}", ttl),
+        }
+    }
+}
+
+// Parse a ttl override, formatted as "<class>=<seconds>"
+pub fn parse_cache_ttl(value: &str) -> Result<(CacheClass, u32)> {
+    let mut parts = value.splitn(2, '=');
+    let class = parts.next().unwrap().parse::<CacheClass>()?;
+    let ttl = parts
+        .next()
+        .chain_err(|| format!("invalid cache ttl {:?}, expected <class>=<seconds>", value))?
+        .parse::<u32>()
+        .chain_err(|| format!("invalid cache ttl {:?}", value))?;
+    Ok((class, ttl))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_policy() {
+        let ttls = vec!["final=3600", "mempool=2"]
+            .into_iter()
+            .map(|value| parse_cache_ttl(value).unwrap())
+            .collect();
+        let policy = CachePolicy::new(ttls, Some(30), false);
+        assert_eq!(policy.ttl(CacheClass::Final), 3600);
+        assert_eq!(policy.ttl(CacheClass::Tip), TTL_SHORT);
+        assert_eq!(
+            policy.cache_control(CacheClass::Mempool),
+            "public, max-age=2, stale-while-revalidate=30"
+        );
+        assert_eq!(
+            policy.cache_control(CacheClass::Uncached),
+            "public, max-age=0"
+        );
+
+        assert!(parse_cache_ttl("final").is_err());
+        assert!(parse_cache_ttl("forever=10").is_err());
+        assert!(parse_cache_ttl("tip=-1").is_err());
+    }
+}
@@ -0,0 +1,361 @@
+use bitcoin::consensus::encode::serialize;
+use bitcoin::{Script, TxOut};
+
+use std::str::FromStr;
+
+use crate::util::{dummy_txin, get_script_type, is_v1_p2tr, FeeRate};
+
+// The version, locktime, input and output counts, and the segwit marker (rounded up)
+pub const TX_OVERHEAD_VSIZE: u64 = 11;
+
+// Outputs worth less than spending them at 3 sat/vbyte are dust, as for bitcoind's relay policy
+const DUST_RELAY_FEERATE: u64 = 3;
+
+// Give up the branch and bound search after that many steps, as Bitcoin Core does
+const BNB_MAX_TRIES: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    // Look for coins matching the payment without a change output, falling back to LargestFirst
+    BranchAndBound,
+    LargestFirst,
+}
+
+impl Strategy {
+    pub fn name(self) -> &'static str {
+        match self {
+            Strategy::BranchAndBound => "bnb",
+            Strategy::LargestFirst => "largest",
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bnb" => Ok(Strategy::BranchAndBound),
+            "largest" => Ok(Strategy::LargestFirst),
+            _ => Err(format!(
+                "Unsupported strategy {}, expected bnb or largest",
+                s
+            )),
+        }
+    }
+}
+
+// A spendable coin, with the vsize its input adds to the transaction
+#[derive(Debug, Clone, Copy)]
+pub struct Coin {
+    pub value: u64,
+    pub input_vsize: u64,
+}
+
+// Paying `target` (excluding the fees) at `feerate`, in a transaction of `base_vsize` without its
+// inputs and change output. The change output of `change_vsize` is only added when the excess is
+// worth more than the output and the input later spending it.
+pub struct Payment {
+    pub target: u64,
+    pub feerate: FeeRate,
+    pub base_vsize: u64,
+    pub change_vsize: u64,
+    pub change_input_vsize: u64,
+}
+
+impl Payment {
+    // The value the inputs must add up to (net of their own fees), None if it overflows
+    pub fn inputs_target(&self) -> Option<u64> {
+        self.target.checked_add(self.fee(self.base_vsize))
+    }
+
+    fn fee(&self, vsize: u64) -> u64 {
+        self.feerate.fee_for_vsize(vsize).as_sat()
+    }
+
+    fn dust_limit(&self) -> u64 {
+        DUST_RELAY_FEERATE * (self.change_vsize + self.change_input_vsize)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Selection {
+    pub coins: Vec<usize>, // by their index in the coins
+    pub fee: u64,
+    pub change: u64,
+    pub vsize: u64,
+    pub strategy: Strategy, // the one that found the selection
+}
+
+// The vsize of an input spending `script`, with a dummy signature as for /tx/estimate-size.
+// p2sh outputs are assumed to be p2sh-p2wpkh, other scripts can't be estimated.
+pub fn input_vsize(script: &Script) -> Option<u64> {
+    let input_type = match get_script_type(script) {
+        _ if is_v1_p2tr(script) => "v1_p2tr",
+        "p2sh" => "p2sh-p2wpkh",
+        script_type => script_type,
+    };
+    let txin = dummy_txin(input_type)?;
+    // the witness isn't part of the input's serialization, and is discounted
+    let witness_weight = if txin.witness.is_empty() {
+        0
+    } else {
+        serialize(&txin.witness).len()
+    };
+    let weight = serialize(&txin).len() * 4 + witness_weight;
+    Some(((weight + 3) / 4) as u64)
+}
+
+pub fn output_vsize(script: &Script) -> u64 {
+    let txout = TxOut {
+        value: 0,
+        script_pubkey: script.clone(),
+    };
+    serialize(&txout).len() as u64
+}
+
+// Select the coins paying for `payment`, or None if they can't cover it
+pub fn select_coins(coins: &[Coin], payment: &Payment, strategy: Strategy) -> Option<Selection> {
+    // the coins by their value net of the fee of their input, the largest first
+    let mut candidates: Vec<(usize, u64)> = coins
+        .iter()
+        .enumerate()
+        .filter_map(|(index, coin)| {
+            let value = coin.value.checked_sub(payment.fee(coin.input_vsize))?;
+            Some((index, value)).filter(|(_, value)| *value > 0)
+        })
+        .collect();
+    candidates.sort_by_key(|(index, value)| (std::cmp::Reverse(*value), *index));
+    let target = payment.inputs_target()?;
+
+    if strategy == Strategy::BranchAndBound {
+        // without a change output, the excess is lost to the fees: up to what the change costs
+        let cost_of_change = payment.fee(payment.change_vsize + payment.change_input_vsize);
+        if let Some(selected) = branch_and_bound(&candidates, target, cost_of_change) {
+            return Some(selection(coins, payment, selected, false, strategy));
+        }
+    }
+
+    let mut selected = vec![];
+    let mut total = 0;
+    for (index, value) in candidates {
+        if total >= target {
+            break;
+        }
+        selected.push(index);
+        total += value;
+    }
+    if total < target {
+        return None;
+    }
+    let excess = total - target;
+    let with_change = excess > payment.fee(payment.change_vsize) + payment.dust_limit();
+    Some(selection(
+        coins,
+        payment,
+        selected,
+        with_change,
+        Strategy::LargestFirst,
+    ))
+}
+
+fn selection(
+    coins: &[Coin],
+    payment: &Payment,
+    selected: Vec<usize>,
+    with_change: bool,
+    strategy: Strategy,
+) -> Selection {
+    let inputs_vsize: u64 = selected.iter().map(|i| coins[*i].input_vsize).sum();
+    let inputs_value: u64 = selected.iter().map(|i| coins[*i].value).sum();
+    let mut vsize = payment.base_vsize + inputs_vsize;
+    let change = if with_change {
+        vsize += payment.change_vsize;
+        inputs_value - payment.target - payment.fee(vsize)
+    } else {
+        0
+    };
+    Selection {
+        coins: selected,
+        fee: inputs_value - payment.target - change,
+        change,
+        vsize,
+        strategy,
+    }
+}
+
+// Depth-first search of the candidates (by decreasing value) summing to between `target` and
+// `target + tolerance`, keeping the one with the least excess. The candidates are either included
+// or omitted, the included ones first, backtracking to omit the last included one.
+fn branch_and_bound(
+    candidates: &[(usize, u64)],
+    target: u64,
+    tolerance: u64,
+) -> Option<Vec<usize>> {
+    let max_total = target.saturating_add(tolerance);
+    // the value of the candidates from each position on
+    let mut remaining = vec![0u64; candidates.len() + 1];
+    for (pos, (_, value)) in candidates.iter().enumerate().rev() {
+        remaining[pos] = remaining[pos + 1].saturating_add(*value);
+    }
+
+    let mut included: Vec<usize> = vec![]; // by their position in the candidates
+    let mut total = 0;
+    let mut next = 0;
+    let mut best: Option<(u64, Vec<usize>)> = None; // with its excess
+    for _ in 0..BNB_MAX_TRIES {
+        let backtrack = if total > max_total || total.saturating_add(remaining[next]) < target {
+            true
+        } else if total >= target {
+            let excess = total - target;
+            if best.as_ref().map_or(true, |(best, _)| excess < *best) {
+                let selected = included.iter().map(|pos| candidates[*pos].0).collect();
+                best = Some((excess, selected));
+            }
+            if excess == 0 {
+                break;
+            }
+            true
+        } else {
+            false
+        };
+        if backtrack {
+            match included.pop() {
+                Some(pos) => {
+                    total -= candidates[pos].1;
+                    next = pos + 1;
+                }
+                None => break, // every branch was explored
+            }
+        } else {
+            // below the target with enough remaining value, so there is a next candidate
+            included.push(next);
+            total += candidates[next].1;
+            next += 1;
+        }
+    }
+    best.map(|(_, selected)| selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::opcodes;
+    use bitcoin::blockdata::script::Builder;
+
+    fn p2wpkh() -> Script {
+        Builder::new()
+            .push_opcode(opcodes::all::OP_PUSHBYTES_0)
+            .push_slice(&[0; 20])
+            .into_script()
+    }
+
+    fn payment(target: u64) -> Payment {
+        let script = p2wpkh();
+        Payment {
+            target,
+            feerate: FeeRate::from_sat_per_vbyte(1.0),
+            base_vsize: TX_OVERHEAD_VSIZE + output_vsize(&script),
+            change_vsize: output_vsize(&script),
+            change_input_vsize: input_vsize(&script).unwrap(),
+        }
+    }
+
+    fn coins(values: &[u64]) -> Vec<Coin> {
+        values
+            .iter()
+            .map(|value| Coin {
+                value: *value,
+                input_vsize: 68,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sizes() {
+        let script = p2wpkh();
+        assert_eq!(input_vsize(&script), Some(68));
+        assert_eq!(output_vsize(&script), 31);
+        let p2pkh = Builder::new()
+            .push_opcode(opcodes::all::OP_DUP)
+            .push_opcode(opcodes::all::OP_HASH160)
+            .push_slice(&[0; 20])
+            .push_opcode(opcodes::all::OP_EQUALVERIFY)
+            .push_opcode(opcodes::all::OP_CHECKSIG)
+            .into_script();
+        assert_eq!(input_vsize(&p2pkh), Some(148));
+        assert_eq!(output_vsize(&p2pkh), 34);
+        assert_eq!(input_vsize(&Script::new()), None);
+    }
+
+    #[test]
+    fn test_select_coins() {
+        // base vsize of 42, 68 per input, 31 for the change output and 68 to spend it
+        let coins = coins(&[100_000, 50_068, 30_000, 20_068, 500]);
+        assert_eq!("bnb".parse(), Ok(Strategy::BranchAndBound));
+        assert!("random".parse::<Strategy>().is_err());
+
+        // 50_068 and 20_068 pay for it exactly, with 178 vbytes
+        let selection = select_coins(&coins, &payment(69_958), Strategy::BranchAndBound).unwrap();
+        assert_eq!(selection.coins, vec![1, 3]);
+        assert_eq!(selection.fee, 178);
+        assert_eq!(selection.change, 0);
+        assert_eq!(selection.vsize, 178);
+        assert_eq!(selection.strategy, Strategy::BranchAndBound);
+
+        // the largest coin first, with change
+        let selection = select_coins(&coins, &payment(69_958), Strategy::LargestFirst).unwrap();
+        assert_eq!(selection.coins, vec![0]);
+        assert_eq!(selection.vsize, 42 + 68 + 31);
+        assert_eq!(selection.fee, 141);
+        assert_eq!(selection.change, 100_000 - 69_958 - 141);
+
+        // no exact match: falls back to the largest first
+        let selection = select_coins(&coins, &payment(120_000), Strategy::BranchAndBound).unwrap();
+        assert_eq!(selection.coins, vec![0, 1]);
+        assert_eq!(selection.strategy, Strategy::LargestFirst);
+        assert_eq!(selection.fee + selection.change + 120_000, 150_068);
+
+        // the excess below the dust limit goes to the fees
+        let selection = select_coins(&coins, &payment(99_800), Strategy::LargestFirst).unwrap();
+        assert_eq!(selection.coins, vec![0]);
+        assert_eq!(selection.change, 0);
+        assert_eq!(selection.fee, 200);
+
+        // insufficient funds
+        assert_eq!(
+            select_coins(&coins, &payment(210_000), Strategy::BranchAndBound),
+            None
+        );
+        assert_eq!(payment(std::u64::MAX).inputs_target(), None);
+        assert_eq!(
+            select_coins(&coins, &payment(std::u64::MAX), Strategy::LargestFirst),
+            None
+        );
+    }
+
+    #[test]
+    fn test_branch_and_bound() {
+        // the matches with less excess found later in the search are kept, up to an exact one
+        let candidates: Vec<(usize, u64)> = [80, 70, 40, 25, 12, 8, 5]
+            .iter()
+            .enumerate()
+            .map(|(index, value)| (index, *value))
+            .collect();
+        assert_eq!(branch_and_bound(&candidates, 97, 10), Some(vec![0, 4, 6]));
+        assert_eq!(branch_and_bound(&candidates, 98, 10), Some(vec![0, 4, 5]));
+        assert_eq!(branch_and_bound(&candidates, 98, 0), None);
+        assert_eq!(branch_and_bound(&candidates, 34, 10), Some(vec![3, 4]));
+        assert_eq!(branch_and_bound(&candidates, 300, 10), None);
+        assert_eq!(branch_and_bound(&candidates, 4, 0), None);
+        assert_eq!(branch_and_bound(&[], 1, 10), None);
+
+        // many candidates don't overflow the stack, and the search gives up after the max tries
+        let candidates: Vec<(usize, u64)> = (0..100_000).map(|index| (index, 2)).collect();
+        assert_eq!(
+            branch_and_bound(&candidates, 1_000, 0).map(|s| s.len()),
+            Some(500)
+        );
+        assert_eq!(branch_and_bound(&candidates, 1_001, 0), None);
+    }
+}
@@ -0,0 +1,211 @@
+use bitcoin::consensus::encode::serialize;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use bitcoin_hashes::Hash;
+use elements::confidential::{Asset, Value};
+use secp256k1_zkp::{
+    All, Generator, PedersenCommitment, PublicKey, RangeProof, Secp256k1, SecretKey, Tag, Tweak,
+};
+
+use crate::chain::TxOut;
+use crate::errors::*;
+use crate::util::{full_hash, FullHash};
+
+// The blinding factors of a confidential output, as known by its owner (or recovered from its
+// range proof with the blinding key). Verified by recomputing the output's commitments.
+pub struct OutputBlinders {
+    pub value: u64,
+    pub asset: Sha256dHash,
+    pub value_blinder: FullHash,
+    pub asset_blinder: FullHash,
+}
+
+impl OutputBlinders {
+    // Fails unless the blinders open both commitments of the output (explicit values and assets
+    // have to match as-is)
+    pub fn verify(&self, secp: &Secp256k1<All>, txout: &TxOut) -> Result<()> {
+        let asset_blinder =
+            Tweak::from_slice(&self.asset_blinder).chain_err(|| "invalid blinder")?;
+        let generator =
+            Generator::new_blinded(secp, Tag::from(self.asset.into_inner()), asset_blinder);
+        let asset_matches = match txout.asset {
+            Asset::Explicit(asset) => asset == self.asset,
+            Asset::Confidential(..) => serialize(&txout.asset)[..] == generator.serialize()[..],
+            _ => false,
+        };
+        if !asset_matches {
+            bail!("the asset doesn't match the output's");
+        }
+        let value_matches = match txout.value {
+            Value::Explicit(value) => value == self.value,
+            Value::Confidential(..) => {
+                let value_blinder =
+                    Tweak::from_slice(&self.value_blinder).chain_err(|| "invalid blinder")?;
+                let commitment =
+                    PedersenCommitment::new(secp, self.value, value_blinder, generator);
+                serialize(&txout.value)[..] == commitment.serialize()[..]
+            }
+            _ => false,
+        };
+        if !value_matches {
+            bail!("the value doesn't match the output's");
+        }
+        Ok(())
+    }
+
+    // Rewind the range proof of a confidential output, with the nonce shared with its sender by
+    // ECDH between the blinding key and the output's nonce commitment (like elementsd's
+    // unblindrawtransaction). The asset and its blinder are the message of the proof.
+    pub fn unblind(secp: &Secp256k1<All>, txout: &TxOut, blinding_key: &SecretKey) -> Result<Self> {
+        let sender_key = PublicKey::from_slice(&serialize(&txout.nonce))
+            .chain_err(|| "not a confidential output")?;
+        let shared_point = sender_key
+            .mul_tweak(secp, &(*blinding_key).into())
+            .chain_err(|| "invalid blinding key")?;
+        let nonce = Sha256dHash::hash(&shared_point.serialize());
+        let nonce = SecretKey::from_slice(&nonce[..]).chain_err(|| "invalid nonce")?;
+
+        let commitment = PedersenCommitment::from_slice(&serialize(&txout.value))
+            .chain_err(|| "not a confidential value")?;
+        let generator = Generator::from_slice(&serialize(&txout.asset))
+            .chain_err(|| "not a confidential asset")?;
+        let proof = RangeProof::from_slice(&txout.witness.rangeproof)
+            .chain_err(|| "invalid range proof")?;
+        let (opening, _) = proof
+            .rewind(
+                secp,
+                commitment,
+                nonce,
+                txout.script_pubkey.as_bytes(),
+                generator,
+            )
+            .chain_err(|| "the blinding key doesn't open the output")?;
+        if opening.message.len() < 64 {
+            bail!("the range proof doesn't hold the asset");
+        }
+        let blinders = OutputBlinders {
+            value: opening.value,
+            asset: Sha256dHash::from_slice(&opening.message[..32]).unwrap(),
+            value_blinder: full_hash(opening.blinding_factor.as_ref()),
+            asset_blinder: full_hash(&opening.message[32..64]),
+        };
+        blinders.verify(secp, txout)?;
+        Ok(blinders)
+    }
+}
+
+// The blinders are displayed like hashes by Elements (in reverse byte order)
+pub fn parse_blinder(hex: &str) -> Result<FullHash> {
+    let mut blinder = FullHash::default();
+    let bytes = hex::decode(hex).chain_err(|| "invalid blinder hex")?;
+    if bytes.len() != blinder.len() {
+        bail!("invalid blinder length");
+    }
+    for (i, byte) in bytes.iter().rev().enumerate() {
+        blinder[i] = *byte;
+    }
+    Ok(blinder)
+}
+
+// The blinding keys are displayed as-is (by elementsd's dumpblindingkey)
+pub fn parse_blinding_key(hex: &str) -> Result<SecretKey> {
+    let bytes = hex::decode(hex).chain_err(|| "invalid blinding key hex")?;
+    SecretKey::from_slice(&bytes).chain_err(|| "invalid blinding key")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::consensus::encode::deserialize;
+    use elements::confidential::Nonce;
+    use elements::TxOutWitness;
+
+    use crate::chain::Script;
+
+    // A confidential output to the blinding public key of `blinding_key`, as blinded by a sender
+    // with the ephemeral key `sender_key`
+    fn blinded_txout(
+        secp: &Secp256k1<All>,
+        blinders: &OutputBlinders,
+        blinding_key: &SecretKey,
+        sender_key: &SecretKey,
+    ) -> TxOut {
+        let script_pubkey = Script::from(vec![0x51]);
+        let asset_blinder = Tweak::from_slice(&blinders.asset_blinder).unwrap();
+        let value_blinder = Tweak::from_slice(&blinders.value_blinder).unwrap();
+        let tag = Tag::from(blinders.asset.into_inner());
+        let generator = Generator::new_blinded(secp, tag, asset_blinder);
+        let commitment = PedersenCommitment::new(secp, blinders.value, value_blinder, generator);
+
+        let shared_point = PublicKey::from_secret_key(secp, blinding_key)
+            .mul_tweak(secp, &(*sender_key).into())
+            .unwrap();
+        let nonce = Sha256dHash::hash(&shared_point.serialize());
+        let mut message = blinders.asset.into_inner().to_vec();
+        message.extend_from_slice(&blinders.asset_blinder);
+        let rangeproof = RangeProof::new(
+            secp,
+            1,
+            commitment,
+            blinders.value,
+            value_blinder,
+            &message,
+            script_pubkey.as_bytes(),
+            SecretKey::from_slice(&nonce[..]).unwrap(),
+            0,
+            52,
+            generator,
+        )
+        .unwrap();
+
+        let sender_pubkey = PublicKey::from_secret_key(secp, sender_key);
+        let nonce: Nonce = deserialize(&sender_pubkey.serialize()).unwrap();
+        TxOut {
+            asset: deserialize(&generator.serialize()).unwrap(),
+            value: deserialize(&commitment.serialize()).unwrap(),
+            nonce,
+            script_pubkey,
+            witness: TxOutWitness {
+                surjection_proof: vec![],
+                rangeproof: rangeproof.serialize(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_unblind() {
+        let secp = Secp256k1::new();
+        let blinding_key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let blinders = OutputBlinders {
+            value: 100_000,
+            asset: Sha256dHash::hash(b"asset"),
+            value_blinder: [2; 32],
+            asset_blinder: [3; 32],
+        };
+        let sender_key = SecretKey::from_slice(&[4; 32]).unwrap();
+        let txout = blinded_txout(&secp, &blinders, &blinding_key, &sender_key);
+        blinders.verify(&secp, &txout).unwrap();
+
+        let unblinded = OutputBlinders::unblind(&secp, &txout, &blinding_key).unwrap();
+        assert_eq!(unblinded.value, blinders.value);
+        assert_eq!(unblinded.asset, blinders.asset);
+        assert_eq!(unblinded.value_blinder, blinders.value_blinder);
+        assert_eq!(unblinded.asset_blinder, blinders.asset_blinder);
+
+        // another key can't rewind the proof, and wrong blinders don't open the commitments
+        let other_key = SecretKey::from_slice(&[5; 32]).unwrap();
+        assert!(OutputBlinders::unblind(&secp, &txout, &other_key).is_err());
+        let wrong_value = OutputBlinders {
+            value: blinders.value + 1,
+            ..unblinded
+        };
+        assert!(wrong_value.verify(&secp, &txout).is_err());
+
+        assert_eq!(
+            parse_blinder(&format!("01{}", "00".repeat(31))).unwrap()[31],
+            1
+        );
+        assert!(parse_blinder("00").is_err());
+        assert_eq!(parse_blinding_key(&"01".repeat(32)).unwrap(), blinding_key);
+        assert!(parse_blinding_key(&"00".repeat(32)).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+// The reads done while handling a REST request that opted in with the `X-Debug-Timing` header,
+// by layer. Only the reads of the request's thread are accounted (not those offloaded to the
+// rayon pool, e.g. while looking up the previous outputs).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DebugTiming {
+    pub db: Duration,
+    pub rows: usize,
+    pub daemon: Duration,
+}
+
+#[derive(Clone, Copy)]
+pub enum Layer {
+    Database,
+    Daemon,
+}
+
+thread_local! {
+    static CURRENT: Cell<Option<DebugTiming>> = Cell::new(None);
+}
+
+impl DebugTiming {
+    // Start accounting the reads of the current thread
+    pub fn start() {
+        CURRENT.with(|current| current.set(Some(DebugTiming::default())));
+    }
+
+    // Stop accounting, returning the reads since start() (if it was called)
+    pub fn finish() -> Option<DebugTiming> {
+        CURRENT.with(|current| current.replace(None))
+    }
+
+    // Times the layer until dropped, a no-op unless accounting
+    pub fn timer(layer: Layer) -> LayerTimer {
+        let accounting = CURRENT.with(|current| current.get().is_some());
+        LayerTimer {
+            layer,
+            started: if accounting {
+                Some(Instant::now())
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn add_rows(count: usize) {
+        update(|timing| timing.rows += count);
+    }
+
+    // e.g. "total=15ms;db=12ms;rows=3400;daemon=0"
+    pub fn header_value(&self, total: Duration) -> String {
+        format!(
+            "total={};db={};rows={};daemon={}",
+            format_ms(total),
+            format_ms(self.db),
+            self.rows,
+            format_ms(self.daemon)
+        )
+    }
+}
+
+pub struct LayerTimer {
+    layer: Layer,
+    started: Option<Instant>,
+}
+
+impl Drop for LayerTimer {
+    fn drop(&mut self) {
+        if let Some(started) = self.started {
+            let elapsed = started.elapsed();
+            let layer = self.layer;
+            update(|timing| match layer {
+                Layer::Database => timing.db += elapsed,
+                Layer::Daemon => timing.daemon += elapsed,
+            });
+        }
+    }
+}
+
+fn update<F: FnOnce(&mut DebugTiming)>(f: F) {
+    CURRENT.with(|current| {
+        if let Some(mut timing) = current.get() {
+            f(&mut timing);
+            current.set(Some(timing));
+        }
+    });
+}
+
+// Layers that weren't used at all are reported as "0"
+fn format_ms(duration: Duration) -> String {
+    if duration == Duration::default() {
+        return "0".to_string();
+    }
+    let ms = duration.as_secs() * 1000 + u64::from(duration.subsec_millis());
+    format!("{}ms", ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_timing() {
+        // nothing is accounted until started
+        DebugTiming::add_rows(10);
+        assert!(DebugTiming::timer(Layer::Database).started.is_none());
+        assert_eq!(DebugTiming::finish(), None);
+
+        DebugTiming::start();
+        {
+            let _timer = DebugTiming::timer(Layer::Database);
+            DebugTiming::add_rows(3);
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        DebugTiming::add_rows(2);
+        let timing = DebugTiming::finish().unwrap();
+        assert_eq!(timing.rows, 5);
+        assert!(timing.db >= Duration::from_millis(2));
+        assert_eq!(timing.daemon, Duration::default());
+        assert_eq!(DebugTiming::finish(), None);
+
+        let timing = DebugTiming {
+            db: Duration::from_micros(12_400),
+            rows: 3400,
+            daemon: Duration::default(),
+        };
+        assert_eq!(
+            timing.header_value(Duration::from_millis(15)),
+            "total=15ms;db=12ms;rows=3400;daemon=0"
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use bitcoin::network::constants::Network as BNetwork;
+use bitcoin::util::address::Address;
+use bitcoin::util::bip32::{ChildNumber, ExtendedPubKey};
+use bitcoin::Script;
+use secp256k1::{Secp256k1, Verification};
+
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScriptKind {
+    Pkh,
+    Wpkh,
+    ShWpkh,
+}
+
+// A (subset of) output descriptor with a single extended public key, i.e. `pkh(KEY)`,
+// `wpkh(KEY)` and `sh(wpkh(KEY))`. Bare xpubs are treated as `pkh(xpub/{0,1}/*)`.
+#[derive(Debug)]
+pub struct XpubDescriptor {
+    kind: ScriptKind,
+    xpub: ExtendedPubKey,
+    chains: Vec<Vec<ChildNumber>>, // derivation paths up to the wildcard index
+}
+
+impl XpubDescriptor {
+    pub fn parse(desc: &str, network: BNetwork) -> Result<Self, String> {
+        // drop the optional checksum
+        let desc = desc.splitn(2, '#').next().unwrap();
+
+        let (kind, key) = if let Some(key) = unwrap_fn(desc, "sh(wpkh(", "))") {
+            (ScriptKind::ShWpkh, key)
+        } else if let Some(key) = unwrap_fn(desc, "wpkh(", ")") {
+            (ScriptKind::Wpkh, key)
+        } else if let Some(key) = unwrap_fn(desc, "pkh(", ")") {
+            (ScriptKind::Pkh, key)
+        } else if !desc.contains('(') {
+            (ScriptKind::Pkh, desc)
+        } else {
+            return Err("unsupported descriptor".to_string());
+        };
+
+        // drop the key origin info, i.e. `[fingerprint/path]`
+        let key = match (key.starts_with('['), key.find(']')) {
+            (true, Some(pos)) => &key[pos + 1..],
+            _ => key,
+        };
+
+        let mut parts = key.split('/');
+        let xpub = ExtendedPubKey::from_str(parts.next().unwrap())
+            .map_err(|_| "invalid extended public key".to_string())?;
+        if (xpub.network == BNetwork::Bitcoin) != (network == BNetwork::Bitcoin) {
+            return Err("extended public key is for a different network".to_string());
+        }
+
+        let path: Vec<&str> = parts.collect();
+        let chains = match path.split_last() {
+            None => vec![
+                vec![ChildNumber::Normal { index: 0 }],
+                vec![ChildNumber::Normal { index: 1 }],
+            ],
+            Some((&"*", prefix)) => vec![prefix
+                .iter()
+                .map(|step| parse_child_number(step))
+                .collect::<Result<Vec<ChildNumber>, String>>()?],
+            Some(_) => return Err("descriptor must end with a /* wildcard".to_string()),
+        };
+
+        Ok(XpubDescriptor { kind, xpub, chains })
+    }
+
+    pub fn chains(&self) -> usize {
+        self.chains.len()
+    }
+
+    pub fn derive<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        chain: usize,
+        index: u32,
+    ) -> Result<Script, String> {
+        let mut path = self.chains[chain].clone();
+        path.push(ChildNumber::Normal { index });
+        let key = self
+            .xpub
+            .derive_pub(secp, &path)
+            .map_err(|e| format!("derivation failed: {}", e))?
+            .public_key;
+        // the network only affects the address encoding, not the script
+        let network = self.xpub.network;
+        let address = match self.kind {
+            ScriptKind::Pkh => Address::p2pkh(&key, network),
+            ScriptKind::Wpkh => Address::p2wpkh(&key, network),
+            ScriptKind::ShWpkh => Address::p2shwpkh(&key, network),
+        };
+        Ok(address.script_pubkey())
+    }
+}
+
+fn unwrap_fn<'a>(desc: &'a str, prefix: &str, suffix: &str) -> Option<&'a str> {
+    if desc.starts_with(prefix) && desc.ends_with(suffix) {
+        Some(&desc[prefix.len()..desc.len() - suffix.len()])
+    } else {
+        None
+    }
+}
+
+fn parse_child_number(step: &str) -> Result<ChildNumber, String> {
+    if step.ends_with('\'') || step.ends_with('h') {
+        return Err("hardened derivation requires private keys".to_string());
+    }
+    step.parse::<u32>()
+        .ok()
+        .filter(|index| *index < (1 << 31))
+        .map(|index| ChildNumber::Normal { index })
+        .ok_or_else(|| format!("invalid derivation step {}", step))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::XpubDescriptor;
+    use bitcoin::network::constants::Network as BNetwork;
+    use hex;
+    use secp256k1::Secp256k1;
+
+    // the m/0H extended public key of the first BIP32 test vector
+    const XPUB: &str = "xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnw";
+
+    fn derive(desc: &str, chain: usize, index: u32) -> String {
+        let desc = XpubDescriptor::parse(desc, BNetwork::Bitcoin).unwrap();
+        let script = desc
+            .derive(&Secp256k1::verification_only(), chain, index)
+            .unwrap();
+        hex::encode(script.as_bytes())
+    }
+
+    #[test]
+    fn test_script_kinds() {
+        assert_eq!(
+            derive(&format!("pkh({}/1/*)", XPUB), 0, 0),
+            "76a914bb653f150f4d080f2fea84c458f978d4a0bf522c88ac"
+        );
+        assert_eq!(
+            derive(&format!("wpkh({}/1/*)", XPUB), 0, 0),
+            "0014bb653f150f4d080f2fea84c458f978d4a0bf522c"
+        );
+        assert_eq!(
+            derive(&format!("sh(wpkh({}/1/*))", XPUB), 0, 0),
+            "a914d2abf8d7e626fefae789e16d1820f63b4e970e2f87"
+        );
+    }
+
+    #[test]
+    fn test_derivation_paths() {
+        // bare xpubs are pkh descriptors of both the receive and the change chains
+        let desc = XpubDescriptor::parse(XPUB, BNetwork::Bitcoin).unwrap();
+        assert_eq!(desc.chains(), 2);
+        assert_eq!(
+            derive(XPUB, 0, 5),
+            "76a9147d34147ff8fecba4ac73e79214b80a793867c0e588ac"
+        );
+        assert_eq!(
+            derive(XPUB, 1, 1),
+            "76a9142ee48695dfc443836bd0e73e6cc4ea450c7eba2c88ac"
+        );
+
+        // with the key origin, a longer path and a (stripped, unverified) checksum
+        let desc = format!("wpkh([d34db33f/84'/0'/0']{}/7/3/*)#qwlqgth7", XPUB);
+        assert_eq!(
+            XpubDescriptor::parse(&desc, BNetwork::Bitcoin)
+                .unwrap()
+                .chains(),
+            1
+        );
+        assert_eq!(
+            derive(&desc, 0, 0),
+            "0014cf19694447c039994e41e5bebe671b114048a4cf"
+        );
+    }
+
+    #[test]
+    fn test_invalid_descriptors() {
+        let parse = |desc: String| XpubDescriptor::parse(&desc, BNetwork::Bitcoin);
+        assert!(parse(format!("tr({}/0/*)", XPUB)).is_err());
+        assert!(parse(format!("wpkh({}/0)", XPUB)).is_err()); // no wildcard
+        assert!(parse(format!("wpkh({}/0'/*)", XPUB)).is_err()); // hardened
+        assert!(parse(format!("wpkh({}/0h/*)", XPUB)).is_err());
+        assert!(parse(format!("wpkh({}/2147483648/*)", XPUB)).is_err());
+        assert!(parse(format!("wpkh({}/x/*)", XPUB)).is_err());
+        assert!(parse("wpkh(xpub123/0/*)".to_string()).is_err());
+        assert!(XpubDescriptor::parse(XPUB, BNetwork::Testnet).is_err());
+    }
+}
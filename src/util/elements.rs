@@ -2,19 +2,21 @@ use bitcoin::blockdata::script::Instruction::PushBytes;
 use bitcoin::consensus::encode::serialize;
 use bitcoin::Script;
 use elements::confidential::Value;
-use elements::{AssetIssuance, Proof};
+use elements::{AssetIssuance, Block, Proof, TxIn};
 
 use hex;
 
 use crate::chain::Network;
-use crate::util::{get_script_asm, script_to_address};
+use crate::util::{get_script_asm, script_to_address, AddressStyle};
+
+const SECONDS_PER_DAY: u32 = 86_400;
 
 #[derive(Serialize, Deserialize)]
 pub struct BlockProofValue {
-    challenge: Script,
-    challenge_asm: String,
-    solution: Script,
-    solution_asm: String,
+    pub challenge: Script,
+    pub challenge_asm: String,
+    pub solution: Script,
+    pub solution_asm: String,
 }
 
 impl From<&Proof> for BlockProofValue {
@@ -118,7 +120,11 @@ impl PegOutRequest {
         }
 
         let scriptpubkey_asm = get_script_asm(&scriptpubkey);
-        let scriptpubkey_address = script_to_address(&scriptpubkey, parent_network);
+        let scriptpubkey_address = script_to_address(
+            &scriptpubkey,
+            parent_network.address_params(),
+            &AddressStyle::default(),
+        );
 
         Some(PegOutRequest {
             genesis_hash,
@@ -128,3 +134,125 @@ impl PegOutRequest {
         })
     }
 }
+
+// The peg-ins and peg-outs of a block, or summed over a period
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct PegStats {
+    pub block_count: u32,
+    pub pegin_count: u64,
+    pub pegin_amount: u64, // in satoshis
+    pub pegout_count: u64,
+    pub pegout_amount: u64,
+}
+
+impl PegStats {
+    pub fn new(block: &Block, parent_network: &Network, parent_genesis_hash: &str) -> Self {
+        let mut stats = PegStats {
+            block_count: 1,
+            ..Default::default()
+        };
+        for tx in &block.txdata {
+            for txin in tx.input.iter().filter(|txin| txin.is_pegin) {
+                stats.pegin_count += 1;
+                stats.pegin_amount += pegin_amount(txin).unwrap_or(0);
+            }
+            for txout in &tx.output {
+                let script = &txout.script_pubkey;
+                if PegOutRequest::parse(script, parent_network, parent_genesis_hash).is_none() {
+                    continue;
+                }
+                stats.pegout_count += 1;
+                // peg-outs can't be blinded
+                if let Value::Explicit(value) = txout.value {
+                    stats.pegout_amount += value;
+                }
+            }
+        }
+        stats
+    }
+
+    pub fn add(&mut self, other: &PegStats) {
+        self.block_count += other.block_count;
+        self.pegin_count += other.pegin_count;
+        self.pegin_amount += other.pegin_amount;
+        self.pegout_count += other.pegout_count;
+        self.pegout_amount += other.pegout_amount;
+    }
+
+    pub fn sub(&mut self, other: &PegStats) {
+        self.block_count -= other.block_count;
+        self.pegin_count -= other.pegin_count;
+        self.pegin_amount -= other.pegin_amount;
+        self.pegout_count -= other.pegout_count;
+        self.pegout_amount -= other.pegout_amount;
+    }
+
+    // The value pegged in that wasn't pegged out since
+    pub fn net_amount(&self) -> i64 {
+        self.pegin_amount as i64 - self.pegout_amount as i64
+    }
+}
+
+// The claimed amount is the first item of the peg-in witness (as little-endian)
+fn pegin_amount(txin: &TxIn) -> Option<u64> {
+    let amount = txin.witness.pegin_witness.get(0)?;
+    if amount.len() != 8 {
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(amount);
+    Some(u64::from_le_bytes(bytes))
+}
+
+// UTC months since January 1970, by block time
+pub fn month_index(time: u32) -> u32 {
+    let (year, month) = civil_month(time / SECONDS_PER_DAY);
+    (year - 1970) * 12 + month - 1
+}
+
+// As YYYY-MM
+pub fn month_name(index: u32) -> String {
+    format!("{:04}-{:02}", 1970 + index / 12, index % 12 + 1)
+}
+
+pub fn parse_month(name: &str) -> Option<u32> {
+    let mut parts = name.splitn(2, '-');
+    let year = parts.next()?.parse::<u32>().ok().filter(|y| *y >= 1970)?;
+    let month = parts
+        .next()?
+        .parse::<u32>()
+        .ok()
+        .filter(|m| *m >= 1 && *m <= 12)?;
+    Some((year - 1970) * 12 + month - 1)
+}
+
+// The year and month of a day since the unix epoch (Howard Hinnant's civil_from_days)
+fn civil_month(days: u32) -> (u32, u32) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_month_index() {
+        assert_eq!(month_index(0), 0);
+        assert_eq!(month_name(month_index(951_782_400)), "2000-02"); // 2000-02-29
+        assert_eq!(month_name(month_index(951_868_799)), "2000-02");
+        assert_eq!(month_name(month_index(951_868_800)), "2000-03");
+        assert_eq!(month_name(month_index(1_538_352_000)), "2018-10"); // 2018-10-01
+        assert_eq!(parse_month("2018-10"), Some(month_index(1_538_352_000)));
+        assert_eq!(parse_month("2018-13"), None);
+        assert_eq!(parse_month("1969-12"), None);
+        assert_eq!(parse_month("2018"), None);
+    }
+}
@@ -4,6 +4,27 @@ use crate::chain::{Transaction, TxOut};
 use std::collections::HashMap;
 
 const VSIZE_BIN_WIDTH: u32 = 50_000; // in vbytes
+const BLOCK_VSIZE: u64 = 1_000_000; // in vbytes
+
+#[cfg(not(feature = "liquid"))]
+const BLOCK_INTERVAL_MINUTES: u32 = 10;
+#[cfg(feature = "liquid")]
+const BLOCK_INTERVAL_MINUTES: u32 = 1;
+
+#[cfg(not(feature = "liquid"))]
+const MIN_RELAY_FEERATE: f32 = 1.0; // in sat/vbyte
+#[cfg(feature = "liquid")]
+const MIN_RELAY_FEERATE: f32 = 0.1;
+
+// backlog (in blocks beyond the next one) at which the congestion score saturates
+const CONGESTED_BACKLOG_BLOCKS: f32 = 6.0;
+
+// Lower bounds of the fixed feerate buckets (in sat/vbyte), matching mempool.space
+const FEERATE_BUCKETS: [f32; 39] = [
+    0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0, 10.0, 12.0, 15.0, 20.0, 30.0, 40.0, 50.0, 60.0, 70.0,
+    80.0, 90.0, 100.0, 125.0, 150.0, 175.0, 200.0, 250.0, 300.0, 350.0, 400.0, 500.0, 600.0, 700.0,
+    800.0, 900.0, 1000.0, 1200.0, 1400.0, 1600.0, 1800.0, 2000.0,
+];
 
 pub struct TxFeeInfo {
     pub fee: u64,   // in satoshis
@@ -16,7 +37,15 @@ impl TxFeeInfo {
     pub fn new(tx: &Transaction, prevouts: &HashMap<u32, &TxOut>) -> Self {
         let total_in: u64 = prevouts.values().map(|prevout| prevout.value).sum();
         let total_out: u64 = tx.output.iter().map(|vout| vout.value).sum();
-        let fee = total_in - total_out;
+        let fee = total_in.checked_sub(total_out).unwrap_or_else(|| {
+            warn!(
+                "inconsistent prevouts for {}: inputs {} sat < outputs {} sat",
+                tx.txid(),
+                total_in,
+                total_out
+            );
+            0
+        });
         let vsize = tx.get_weight() / 4;
 
         TxFeeInfo {
@@ -67,3 +96,259 @@ pub fn make_fee_histogram(mut entries: Vec<&TxFeeInfo>) -> Vec<(f32, u32)> {
     }
     histogram
 }
+
+// Whether the fee pays for the default minimum relay feerate (-minrelaytxfee)
+pub fn meets_min_relay_feerate(fee: u64, weight: usize) -> bool {
+    let vsize = (weight + 3) / 4;
+    fee as f32 >= vsize as f32 * MIN_RELAY_FEERATE
+}
+
+// Estimate the feerate needed to confirm within `conf_target` blocks from a fee histogram, as
+// the feerate of the transactions that would fill these blocks (assuming no new transactions)
+pub fn estimate_feerate_from_histogram(histogram: &[(f32, u32)], conf_target: u16) -> f32 {
+    let target_vsize = conf_target as u64 * BLOCK_VSIZE;
+    let mut vsize = 0;
+    for (feerate, bin_size) in histogram {
+        vsize += *bin_size as u64;
+        if vsize >= target_vsize {
+            return feerate.max(MIN_RELAY_FEERATE);
+        }
+    }
+    MIN_RELAY_FEERATE
+}
+
+#[derive(Serialize)]
+pub struct ConfirmationEta {
+    pub confirmed: bool,
+    pub effective_feerate: Option<f32>, // in sat/vbyte, of unconfirmed transactions
+    pub blocks: u32,                    // 1 when projected to be in the next block
+    pub minutes: u32,
+}
+
+impl ConfirmationEta {
+    pub fn confirmed() -> Self {
+        ConfirmationEta {
+            confirmed: true,
+            effective_feerate: None,
+            blocks: 0,
+            minutes: 0,
+        }
+    }
+
+    // The projected block a transaction paying `feerate` would be mined in, from the vsize of the
+    // transactions paying more in a fee histogram (assuming no new transactions). Transactions in
+    // the same histogram bin are not counted as ahead of it.
+    pub fn from_histogram(histogram: &[(f32, u32)], feerate: f32) -> Self {
+        let vsize_ahead: u64 = histogram
+            .iter()
+            .take_while(|(bin_feerate, _)| *bin_feerate > feerate)
+            .map(|(_, bin_size)| *bin_size as u64)
+            .sum();
+        let blocks = (vsize_ahead / BLOCK_VSIZE) as u32 + 1;
+        ConfirmationEta {
+            confirmed: false,
+            effective_feerate: Some(feerate),
+            blocks,
+            minutes: blocks * BLOCK_INTERVAL_MINUTES,
+        }
+    }
+}
+
+// Get the lowest feerate of the transactions that would make it into the next block (picking them
+// by feerate, ignoring dependencies) and the vsize left over for later blocks
+pub fn next_block_cutoff(mut entries: Vec<&TxFeeInfo>) -> (f32, u32) {
+    entries.sort_unstable_by(|e1, e2| e2.fee_per_vbyte.partial_cmp(&e1.fee_per_vbyte).unwrap());
+    let total_vsize: u64 = entries.iter().map(|e| e.vsize as u64).sum();
+    let mut vsize = 0;
+    let mut cutoff = MIN_RELAY_FEERATE;
+    for e in entries {
+        if vsize + e.vsize as u64 > BLOCK_VSIZE {
+            return (cutoff, (total_vsize - vsize) as u32);
+        }
+        vsize += e.vsize as u64;
+        cutoff = e.fee_per_vbyte.max(MIN_RELAY_FEERATE);
+    }
+    // everything fits, any transaction paying the minimum relay fee would too
+    (MIN_RELAY_FEERATE, 0)
+}
+
+// Score the congestion from 0 (the next block has room to spare) to 100, from the vsize that
+// doesn't fit in the next block and the trend of the next block's feerate (as a ratio to an
+// earlier cutoff), so that the same backlog scores higher while fees are rising
+pub fn congestion_score(excess_vsize: u32, fee_trend: f32) -> u8 {
+    let backlog = excess_vsize as f32 / BLOCK_VSIZE as f32 / CONGESTED_BACKLOG_BLOCKS;
+    (backlog * fee_trend.max(0.5).min(2.0) * 100.0).min(100.0) as u8
+}
+
+// Total vsize per fixed feerate bucket, as (bucket lower bound, vsize) in ascending order.
+// Empty buckets are kept, so that the layout is the same regardless of the mempool contents.
+pub fn make_feerate_histogram(entries: Vec<&TxFeeInfo>) -> Vec<(f32, u32)> {
+    let mut histogram: Vec<(f32, u32)> = FEERATE_BUCKETS.iter().map(|b| (*b, 0)).collect();
+    for e in entries {
+        let bucket = FEERATE_BUCKETS
+            .iter()
+            .rposition(|b| *b <= e.fee_per_vbyte)
+            .unwrap_or(0);
+        histogram[bucket].1 += e.vsize;
+    }
+    histogram
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_feerate_from_histogram() {
+        // (feerate, vsize of the transactions paying it) by descending feerate
+        let histogram = [
+            (50.0, 600_000),
+            (20.0, 600_000),
+            (10.0, 1_000_000),
+            (5.0, 300_000),
+        ];
+        assert_eq!(estimate_feerate_from_histogram(&histogram, 1), 20.0);
+        assert_eq!(estimate_feerate_from_histogram(&histogram, 2), 10.0);
+        // exactly filling the blocks
+        let filled = [(30.0, 1_000_000), (15.0, 1_000_000)];
+        assert_eq!(estimate_feerate_from_histogram(&filled, 1), 30.0);
+        assert_eq!(estimate_feerate_from_histogram(&filled, 2), 15.0);
+        // the blocks wouldn't be filled, or only by transactions below the minimum relay feerate
+        assert_eq!(
+            estimate_feerate_from_histogram(&histogram, 3),
+            MIN_RELAY_FEERATE
+        );
+        assert_eq!(estimate_feerate_from_histogram(&[], 1), MIN_RELAY_FEERATE);
+        let cheap = [(0.01, 2_000_000)];
+        assert_eq!(
+            estimate_feerate_from_histogram(&cheap, 1),
+            MIN_RELAY_FEERATE
+        );
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    #[test]
+    fn test_fee_info_underflow() {
+        use bitcoin::{OutPoint, Script, TxIn};
+
+        let txout = |value| TxOut {
+            value,
+            script_pubkey: Script::new(),
+        };
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            }],
+            output: vec![txout(10_000)],
+        };
+        let vsize = (tx.get_weight() / 4) as u32;
+
+        let prevout = txout(12_000);
+        let prevouts: HashMap<u32, &TxOut> = vec![(0, &prevout)].into_iter().collect();
+        let info = TxFeeInfo::new(&tx, &prevouts);
+        assert_eq!((info.fee, info.vsize), (2_000, vsize));
+
+        // prevouts worth less than the outputs (an index inconsistency) don't underflow
+        let prevout = txout(9_000);
+        let prevouts: HashMap<u32, &TxOut> = vec![(0, &prevout)].into_iter().collect();
+        let info = TxFeeInfo::new(&tx, &prevouts);
+        assert_eq!((info.fee, info.fee_per_vbyte), (0, 0.0));
+    }
+
+    #[test]
+    fn test_confirmation_eta() {
+        // (feerate, vsize of the transactions paying it) by descending feerate
+        let histogram = [
+            (50.0, 600_000),
+            (20.0, 600_000),
+            (10.0, 1_000_000),
+            (5.0, 300_000),
+        ];
+        let eta = |feerate| {
+            let eta = ConfirmationEta::from_histogram(&histogram, feerate);
+            assert!(!eta.confirmed);
+            assert_eq!(eta.effective_feerate, Some(feerate));
+            assert_eq!(eta.minutes, eta.blocks * BLOCK_INTERVAL_MINUTES);
+            eta.blocks
+        };
+        assert_eq!(eta(100.0), 1);
+        // the transactions of its own bin aren't ahead of it
+        assert_eq!(eta(50.0), 1);
+        assert_eq!(eta(20.0), 1);
+        assert_eq!(eta(15.0), 2);
+        assert_eq!(eta(10.0), 2);
+        assert_eq!(eta(5.0), 3);
+        assert_eq!(eta(1.0), 3);
+        assert_eq!(ConfirmationEta::from_histogram(&[], 1.0).blocks, 1);
+
+        let confirmed = ConfirmationEta::confirmed();
+        assert!(confirmed.confirmed);
+        assert_eq!((confirmed.blocks, confirmed.minutes), (0, 0));
+    }
+
+    #[test]
+    fn test_meets_min_relay_feerate() {
+        // 561 weight units are 141 vbytes, rounded up
+        let min_fee = (141.0 * MIN_RELAY_FEERATE).ceil() as u64;
+        assert!(meets_min_relay_feerate(min_fee, 561));
+        assert!(meets_min_relay_feerate(min_fee, 564));
+        assert!(!meets_min_relay_feerate(min_fee - 1, 561));
+        assert!(!meets_min_relay_feerate(min_fee, 800));
+        assert!(meets_min_relay_feerate(min_fee * 10, 800));
+    }
+
+    fn fee_info(vsize: u32, fee_per_vbyte: f32) -> TxFeeInfo {
+        TxFeeInfo {
+            fee: (vsize as f32 * fee_per_vbyte) as u64,
+            vsize,
+            fee_per_vbyte,
+        }
+    }
+
+    #[test]
+    fn test_next_block_cutoff() {
+        let entries = vec![
+            fee_info(300_000, 10.0),
+            fee_info(400_000, 30.0),
+            fee_info(200_000, 5.0),
+            fee_info(500_000, 20.0),
+        ];
+        // the 30 and 20 sat/vbyte transactions fill 900k of the next block, the 300k vbytes
+        // paying 10 sat/vbyte don't fit anymore
+        assert_eq!(next_block_cutoff(entries.iter().collect()), (20.0, 500_000));
+
+        let fits = vec![fee_info(400_000, 30.0), fee_info(600_000, 20.0)];
+        assert_eq!(
+            next_block_cutoff(fits.iter().collect()),
+            (MIN_RELAY_FEERATE, 0)
+        );
+        assert_eq!(next_block_cutoff(vec![]), (MIN_RELAY_FEERATE, 0));
+
+        // never below the minimum relay feerate
+        let cheap = vec![fee_info(1_000_000, 0.01), fee_info(100_000, 0.01)];
+        assert_eq!(
+            next_block_cutoff(cheap.iter().collect()),
+            (MIN_RELAY_FEERATE, 100_000)
+        );
+    }
+
+    #[test]
+    fn test_congestion_score() {
+        assert_eq!(congestion_score(0, 1.0), 0);
+        assert_eq!(congestion_score(0, 2.0), 0);
+        // half of the saturating backlog
+        assert_eq!(congestion_score(3_000_000, 1.0), 50);
+        // scored higher while the fees are rising, lower while they are falling, within bounds
+        assert_eq!(congestion_score(3_000_000, 2.0), 100);
+        assert_eq!(congestion_score(3_000_000, 10.0), 100);
+        assert_eq!(congestion_score(3_000_000, 0.5), 25);
+        assert_eq!(congestion_score(3_000_000, 0.0), 25);
+        assert_eq!(congestion_score(12_000_000, 1.0), 100);
+        assert_eq!(congestion_score(std::u32::MAX, 2.0), 100);
+    }
+}
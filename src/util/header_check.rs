@@ -0,0 +1,225 @@
+use crate::chain::BlockHeader;
+#[cfg(not(feature = "liquid"))]
+use crate::chain::Network;
+use crate::util::HeaderList;
+
+use bitcoin::util::hash::BitcoinHash;
+#[cfg(not(feature = "liquid"))]
+use bitcoin::util::uint::Uint256;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+
+use std::collections::VecDeque;
+use std::fmt;
+
+// The block time must be after the median time of the previous 11 blocks
+const MEDIAN_TIME_SPAN: usize = 11;
+
+// Verifies the new headers from bitcoind before they are indexed, so that a misbehaving daemon
+// can't make the index follow a bogus chain silently: each header must extend the previous one,
+// have a proof of work meeting its target (Bitcoin only, liquid blocks are signed) and a time
+// after the median time past. The difficulty adjustments are not checked, they differ by network.
+#[derive(Default)]
+pub struct HeaderChecker {
+    #[cfg(not(feature = "liquid"))]
+    pow_limit: Option<Uint256>, // the easiest allowed target, unknown on custom chains
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HeaderCheckError {
+    Disconnected(Sha256dHash), // doesn't extend the previous header (or the indexed chain)
+    TargetAboveLimit(Sha256dHash),
+    InvalidPow(Sha256dHash),
+    TimeTooOld(Sha256dHash), // not after the median time past
+}
+
+impl HeaderCheckError {
+    // Used as the label of the failure metrics
+    pub fn reason(&self) -> &'static str {
+        match self {
+            HeaderCheckError::Disconnected(_) => "disconnected",
+            HeaderCheckError::TargetAboveLimit(_) => "target_above_limit",
+            HeaderCheckError::InvalidPow(_) => "invalid_pow",
+            HeaderCheckError::TimeTooOld(_) => "time_too_old",
+        }
+    }
+}
+
+impl fmt::Display for HeaderCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderCheckError::Disconnected(hash) => {
+                write!(f, "header {} doesn't extend the chain", hash)
+            }
+            HeaderCheckError::TargetAboveLimit(hash) => {
+                write!(f, "header {} has a target above the network's limit", hash)
+            }
+            HeaderCheckError::InvalidPow(hash) => {
+                write!(f, "header {} doesn't meet its proof of work target", hash)
+            }
+            HeaderCheckError::TimeTooOld(hash) => {
+                write!(f, "header {} isn't after the median time past", hash)
+            }
+        }
+    }
+}
+
+impl HeaderChecker {
+    // `custom_chain` skips the target limit of `network`, for the chains run with --genesis-hash
+    #[cfg(not(feature = "liquid"))]
+    pub fn new(network: Network, custom_chain: bool) -> Self {
+        let pow_limit = match network {
+            _ if custom_chain => None,
+            // 0x207fffff in compact form
+            Network::Regtest => Some(Uint256([0, 0, 0, 0x7fff_ff00_0000_0000])),
+            // 0x1d00ffff in compact form
+            Network::Bitcoin | Network::Testnet => Some(Uint256([0, 0, 0, 0xffff_0000])),
+        };
+        HeaderChecker { pow_limit }
+    }
+
+    // `new_headers` are in chain order (the tip last), extending `headers` or from the genesis
+    pub fn check(
+        &self,
+        headers: &HeaderList,
+        new_headers: &[BlockHeader],
+    ) -> Result<(), HeaderCheckError> {
+        let first = match new_headers.first() {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        let height = if first.prev_blockhash == Sha256dHash::default() {
+            0
+        } else {
+            headers
+                .header_by_blockhash(&first.prev_blockhash)
+                .ok_or_else(|| HeaderCheckError::Disconnected(first.bitcoin_hash()))?
+                .height()
+                + 1
+        };
+        // the times of the blocks before the new ones, on the indexed chain up to their parent
+        let mut times: VecDeque<u32> = (height.saturating_sub(MEDIAN_TIME_SPAN)..height)
+            .map(|height| headers.header_by_height(height).unwrap().header().time)
+            .collect();
+
+        let mut prev_blockhash = first.prev_blockhash;
+        for header in new_headers {
+            let hash = header.bitcoin_hash();
+            if header.prev_blockhash != prev_blockhash {
+                return Err(HeaderCheckError::Disconnected(hash));
+            }
+            #[cfg(not(feature = "liquid"))]
+            self.check_pow(header, hash)?;
+            if !times.is_empty() && header.time <= median(&times) {
+                return Err(HeaderCheckError::TimeTooOld(hash));
+            }
+            times.push_back(header.time);
+            if times.len() > MEDIAN_TIME_SPAN {
+                times.pop_front();
+            }
+            prev_blockhash = hash;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn check_pow(&self, header: &BlockHeader, hash: Sha256dHash) -> Result<(), HeaderCheckError> {
+        let target = header.target();
+        if self.pow_limit.map_or(false, |limit| target > limit) {
+            return Err(HeaderCheckError::TargetAboveLimit(hash));
+        }
+        header
+            .validate_pow(&target)
+            .map_err(|_| HeaderCheckError::InvalidPow(hash))
+    }
+}
+
+fn median(times: &VecDeque<u32>) -> u32 {
+    let mut sorted: Vec<u32> = times.iter().cloned().collect();
+    sorted.sort();
+    sorted[sorted.len() / 2]
+}
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network as BNetwork;
+
+    // Mine a regtest header on top of `prev`, whose target is met by one hash in two
+    fn mine(prev: &BlockHeader, time: u32) -> BlockHeader {
+        let mut header = BlockHeader {
+            prev_blockhash: prev.bitcoin_hash(),
+            time,
+            nonce: 0,
+            ..*prev
+        };
+        while header.validate_pow(&header.target()).is_err() {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    #[test]
+    fn test_header_check() {
+        let checker = HeaderChecker::new(Network::Regtest, false);
+        let genesis = genesis_block(BNetwork::Regtest).header;
+        let mut chain = vec![genesis];
+        for i in 1..15 {
+            let header = mine(chain.last().unwrap(), genesis.time + i * 600);
+            chain.push(header);
+        }
+        let empty = HeaderList::empty();
+        assert_eq!(checker.check(&empty, &chain), Ok(()));
+        assert_eq!(checker.check(&empty, &[]), Ok(()));
+
+        // the median time past is taken from the indexed headers
+        let mut indexed = HeaderList::empty();
+        let entries = indexed.order(chain[..12].to_vec());
+        indexed.apply(entries);
+        assert_eq!(checker.check(&indexed, &chain[12..]), Ok(()));
+        let median_time = chain[6].time;
+        let too_old = mine(&chain[11], median_time);
+        assert_eq!(
+            checker.check(&indexed, &[too_old]),
+            Err(HeaderCheckError::TimeTooOld(too_old.bitcoin_hash()))
+        );
+        let after_median = mine(&chain[11], median_time + 1);
+        assert_eq!(checker.check(&indexed, &[after_median]), Ok(()));
+
+        // unknown parent and gaps
+        assert_eq!(
+            checker.check(&indexed, &chain[13..]),
+            Err(HeaderCheckError::Disconnected(chain[13].bitcoin_hash()))
+        );
+        let gap = [chain[12], chain[14]];
+        assert_eq!(
+            checker.check(&indexed, &gap),
+            Err(HeaderCheckError::Disconnected(chain[14].bitcoin_hash()))
+        );
+
+        // proof of work
+        let mut invalid = chain[12];
+        while invalid.validate_pow(&invalid.target()).is_ok() {
+            invalid.nonce += 1;
+        }
+        assert_eq!(
+            checker.check(&indexed, &[invalid]),
+            Err(HeaderCheckError::InvalidPow(invalid.bitcoin_hash()))
+        );
+        let easy = BlockHeader {
+            bits: 0x2100ffff,
+            ..chain[12]
+        };
+        assert_eq!(
+            checker.check(&indexed, &[easy]),
+            Err(HeaderCheckError::TargetAboveLimit(easy.bitcoin_hash()))
+        );
+        let mainnet = HeaderChecker::new(Network::Bitcoin, false);
+        assert_eq!(
+            mainnet.check(&indexed, &chain[12..]),
+            Err(HeaderCheckError::TargetAboveLimit(chain[12].bitcoin_hash()))
+        );
+        let custom = HeaderChecker::new(Network::Bitcoin, true);
+        assert_eq!(custom.check(&indexed, &chain[12..]), Ok(()));
+    }
+}
@@ -0,0 +1,173 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+use stderrlog::StdErrLog;
+
+use crate::errors::*;
+
+// The logging level, with overrides for modules (and their submodules)
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogLevels {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl LogLevels {
+    // Map the number of -v flags to a level, like stderrlog does
+    pub fn from_verbosity(verbosity: u64) -> Self {
+        let default = match verbosity {
+            0 => LevelFilter::Error,
+            1 => LevelFilter::Warn,
+            2 => LevelFilter::Info,
+            3 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+        LogLevels {
+            default,
+            modules: vec![],
+        }
+    }
+
+    // Get the level of a log target, from the most specific module override
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target == module
+                    || (target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, LevelFilter::max)
+    }
+}
+
+// Parse levels formatted as "<level>[,<module>=<level>...]" (e.g. "info,electrs::rest=trace")
+impl FromStr for LogLevels {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let parse_level = |level: &str| {
+            level
+                .parse::<LevelFilter>()
+                .ok()
+                .chain_err(|| format!("invalid log level {:?}", level))
+        };
+        let mut default = None;
+        let mut modules = vec![];
+        for directive in value.split(',').map(str::trim) {
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next().unwrap();
+            match parts.next() {
+                Some(level) => modules.push((name.to_string(), parse_level(level)?)),
+                None if default.is_none() => default = Some(parse_level(name)?),
+                None => bail!("duplicate default log level {:?}", name),
+            }
+        }
+        Ok(LogLevels {
+            default: default.chain_err(|| {
+                format!(
+                    "missing default log level in {:?}, expected <level>[,<module>=<level>...]",
+                    value
+                )
+            })?,
+            modules,
+        })
+    }
+}
+
+impl fmt::Display for LogLevels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.default.to_string().to_lowercase())?;
+        for (module, level) in &self.modules {
+            write!(f, ",{}={}", module, level.to_string().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+// Logs to stderr, with levels that can be changed at runtime (via /admin/log-level)
+#[derive(Debug)]
+pub struct LogFilter {
+    inner: StdErrLog, // set to the most verbose level, filtered by the levels below
+    levels: RwLock<LogLevels>,
+}
+
+impl LogFilter {
+    // Install the global logger, which lives until the process exits
+    pub fn init(inner: StdErrLog, levels: LogLevels) -> &'static LogFilter {
+        let max_level = levels.max_level();
+        let logger: &'static LogFilter = Box::leak(Box::new(LogFilter {
+            inner,
+            levels: RwLock::new(levels),
+        }));
+        log::set_logger(logger).expect("logging initialization failed");
+        log::set_max_level(max_level);
+        logger
+    }
+
+    pub fn levels(&self) -> LogLevels {
+        self.levels.read().unwrap().clone()
+    }
+
+    pub fn set_levels(&self, levels: LogLevels) {
+        log::set_max_level(levels.max_level());
+        *self.levels.write().unwrap() = levels;
+    }
+}
+
+impl Log for LogFilter {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.levels.read().unwrap().level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_levels() {
+        let levels: LogLevels = "info,electrs::new_index=debug,electrs::new_index::mempool=trace"
+            .parse()
+            .unwrap();
+        assert_eq!(levels.level("electrs::rest"), LevelFilter::Info);
+        assert_eq!(levels.level("electrs::new_index"), LevelFilter::Debug);
+        assert_eq!(
+            levels.level("electrs::new_index::schema"),
+            LevelFilter::Debug
+        );
+        assert_eq!(
+            levels.level("electrs::new_index::mempool"),
+            LevelFilter::Trace
+        );
+        assert_eq!(levels.level("electrs::new_index_other"), LevelFilter::Info);
+        assert_eq!(levels.max_level(), LevelFilter::Trace);
+        assert_eq!(
+            levels.to_string(),
+            "info,electrs::new_index=debug,electrs::new_index::mempool=trace"
+        );
+
+        assert!("electrs=debug".parse::<LogLevels>().is_err());
+        assert!("info,warn".parse::<LogLevels>().is_err());
+        assert!("verbose".parse::<LogLevels>().is_err());
+    }
+}
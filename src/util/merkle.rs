@@ -1,7 +1,10 @@
+use bitcoin::consensus::encode::{serialize, VarInt};
 use bitcoin_hashes::{sha256d::Hash as Sha256dHash, Hash};
 
+use crate::chain::BlockHeader;
 use crate::errors::*;
 use crate::new_index::ChainQuery;
+use crate::util::Bytes;
 
 pub fn get_tx_merkle_proof(
     chain: &ChainQuery,
@@ -19,6 +22,102 @@ pub fn get_tx_merkle_proof(
     Ok((branch, pos))
 }
 
+// Serialized BIP37 merkleblock (the block header and a partial merkle tree) proving that the
+// transaction is included in the block, as returned by bitcoind's `gettxoutproof`
+pub fn get_tx_merkleblock(
+    chain: &ChainQuery,
+    tx_hash: &Sha256dHash,
+    block_hash: &Sha256dHash,
+) -> Result<Bytes> {
+    let header = chain
+        .get_block_with_meta(block_hash)
+        .chain_err(|| format!("missing block #{}", block_hash))?
+        .header_entry
+        .header()
+        .clone();
+    let txids = chain
+        .get_block_txids(&block_hash)
+        .chain_err(|| format!("missing block txids for #{}", block_hash))?;
+    let pos = txids
+        .iter()
+        .position(|txid| txid == tx_hash)
+        .chain_err(|| format!("missing txid {}", tx_hash))?;
+    Ok(build_merkleblock(&header, &txids, pos))
+}
+
+fn build_merkleblock(header: &BlockHeader, txids: &[Sha256dHash], pos: usize) -> Bytes {
+    let mut tree = PartialMerkleTree {
+        txids,
+        matched: pos,
+        bits: vec![],
+        hashes: vec![],
+    };
+    let mut height = 0;
+    while tree.width(height) > 1 {
+        height += 1;
+    }
+    tree.traverse_and_build(height, 0);
+
+    let mut flags = vec![0u8; (tree.bits.len() + 7) / 8];
+    for (i, bit) in tree.bits.iter().enumerate() {
+        flags[i / 8] |= (*bit as u8) << (i % 8);
+    }
+
+    let mut merkleblock = serialize(header);
+    merkleblock.extend(serialize(&(txids.len() as u32)));
+    merkleblock.extend(serialize(&VarInt(tree.hashes.len() as u64)));
+    for hash in &tree.hashes {
+        merkleblock.extend_from_slice(&hash[..]);
+    }
+    merkleblock.extend(serialize(&VarInt(flags.len() as u64)));
+    merkleblock.extend(flags);
+    merkleblock
+}
+
+// Depth-first partial merkle tree with a single matched transaction (see BIP37)
+struct PartialMerkleTree<'a> {
+    txids: &'a [Sha256dHash],
+    matched: usize,
+    bits: Vec<bool>,
+    hashes: Vec<Sha256dHash>,
+}
+
+impl<'a> PartialMerkleTree<'a> {
+    // Number of nodes at the given height, where the leaves are at height 0
+    fn width(&self, height: usize) -> usize {
+        (self.txids.len() + (1 << height) - 1) >> height
+    }
+
+    fn calc_hash(&self, height: usize, pos: usize) -> Sha256dHash {
+        if height == 0 {
+            return self.txids[pos];
+        }
+        let left = self.calc_hash(height - 1, pos * 2);
+        // the last node of an odd-width level is paired with itself
+        let right = if pos * 2 + 1 < self.width(height - 1) {
+            self.calc_hash(height - 1, pos * 2 + 1)
+        } else {
+            left
+        };
+        merklize(left, right)
+    }
+
+    fn traverse_and_build(&mut self, height: usize, pos: usize) {
+        // whether the subtree below this node contains the matched transaction
+        let parent_of_match = self.matched >> height == pos;
+        self.bits.push(parent_of_match);
+        if height == 0 || !parent_of_match {
+            let hash = self.calc_hash(height, pos);
+            self.hashes.push(hash);
+        } else {
+            self.traverse_and_build(height - 1, pos * 2);
+            if pos * 2 + 1 < self.width(height - 1) {
+                self.traverse_and_build(height - 1, pos * 2 + 1);
+            }
+        }
+    }
+}
+
 pub fn get_header_merkle_proof(
     chain: &ChainQuery,
     height: usize,
@@ -97,3 +196,144 @@ fn create_merkle_branch_and_root(
     }
     (merkle, hashes[0])
 }
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::encode::deserialize;
+    use bitcoin::network::constants::Network;
+    use bitcoin_hashes::hex::FromHex;
+
+    use super::*;
+
+    // Check the partial merkle tree of a merkleblock, returning its root and matched txids
+    fn verify_merkleblock(merkleblock: &[u8]) -> (Sha256dHash, Vec<(usize, Sha256dHash)>) {
+        let count = deserialize::<u32>(&merkleblock[80..84]).unwrap() as usize;
+        let hash_count = merkleblock[84] as usize;
+        let hashes: Vec<Sha256dHash> = merkleblock[85..85 + 32 * hash_count]
+            .chunks(32)
+            .map(|hash| Sha256dHash::from_slice(hash).unwrap())
+            .collect();
+        let flags = &merkleblock[86 + 32 * hash_count..];
+        assert_eq!(flags.len(), merkleblock[85 + 32 * hash_count] as usize);
+
+        struct Walk<'a> {
+            count: usize,
+            hashes: &'a [Sha256dHash],
+            flags: &'a [u8],
+            bit: usize,
+            matches: Vec<(usize, Sha256dHash)>,
+        }
+        impl<'a> Walk<'a> {
+            fn width(&self, height: usize) -> usize {
+                (self.count + (1 << height) - 1) >> height
+            }
+            fn node(&mut self, height: usize, pos: usize) -> Sha256dHash {
+                let bit = (self.flags[self.bit / 8] >> (self.bit % 8)) & 1 == 1;
+                self.bit += 1;
+                if height == 0 || !bit {
+                    let (hash, rest) = self.hashes.split_first().unwrap();
+                    self.hashes = rest;
+                    if bit {
+                        self.matches.push((pos, *hash));
+                    }
+                    return *hash;
+                }
+                let left = self.node(height - 1, pos * 2);
+                let right = if pos * 2 + 1 < self.width(height - 1) {
+                    self.node(height - 1, pos * 2 + 1)
+                } else {
+                    left
+                };
+                merklize(left, right)
+            }
+        }
+        let mut walk = Walk {
+            count,
+            hashes: &hashes,
+            flags,
+            bit: 0,
+            matches: vec![],
+        };
+        let mut height = 0;
+        while walk.width(height) > 1 {
+            height += 1;
+        }
+        let root = walk.node(height, 0);
+        assert!(walk.hashes.is_empty());
+        (root, walk.matches)
+    }
+
+    #[test]
+    fn test_merkleblock_single_tx() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let txid = genesis.txdata[0].txid();
+        let merkleblock = build_merkleblock(&genesis.header, &[txid], 0);
+        let expected = [
+            "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd",
+            "7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c",
+            "01000000",
+            "01",
+            "3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a",
+            "0101",
+        ]
+        .concat();
+        assert_eq!(hex::encode(&merkleblock), expected);
+    }
+
+    #[test]
+    fn test_merkleblock() {
+        // the result of bitcoind's `gettxoutproof` for the 4th of the 9 transactions of block
+        // 0000000000013b8ab2cd513b0261a14096412195a72a0c4827d229dcc7e0f7af
+        let proof = Vec::<u8>::from_hex(concat!(
+            "0100000090f0a9f110702f808219ebea1173056042a714bad51b916cb680000000000000",
+            "5275289558f51c9966699404ae2294730c3c9f9bda53523ce50e9b95e558da2fdb261b4d",
+            "4c86041b1ab1bf930900000005fac7708a6e81b2a986dea60db2663840ed141130848162",
+            "eb1bd1dee54f309a1b2ee1e12587e497ada70d9bd10d31e83f0a924825b96cb8d04e8936",
+            "d793fb60db7ad8b910d0c7ba2369bc7f18bb53d80e1869ba2c32274996cebe1ae264bc0e",
+            "2289189ff0316cdc10511da71da757e553cada9f3b5b1434f3923673adb57d83caac392c",
+            "38af156d6fc30b55fad4112df2b95531e68114e9ad10011e72f7b7cfdb025700",
+        ))
+        .unwrap();
+        let header: BlockHeader = deserialize(&proof[..80]).unwrap();
+        let txid = Sha256dHash::from_hex(
+            "220ebc64e21abece964927322cba69180ed853bb187fbc6923bac7d010b9d87a",
+        )
+        .unwrap();
+        assert_eq!(
+            verify_merkleblock(&proof),
+            (header.merkle_root, vec![(3, txid)])
+        );
+
+        // the same tree shape for the other transactions of a block of the same size
+        let mut txids: Vec<Sha256dHash> = (0..9u8).map(|i| Sha256dHash::hash(&[i])).collect();
+        txids[3] = txid;
+        let merkleblock = build_merkleblock(&header, &txids, 3);
+        assert_eq!(merkleblock[..85], proof[..85]);
+        assert_eq!(
+            merkleblock[merkleblock.len() - 3..],
+            proof[proof.len() - 3..]
+        );
+        assert_eq!(merkleblock.len(), proof.len());
+        let root = create_merkle_branch_and_root(txids.clone(), 0).1;
+        assert_eq!(verify_merkleblock(&merkleblock), (root, vec![(3, txid)]));
+
+        // and every other position, in blocks of other sizes
+        for count in 1..20 {
+            for pos in 0..count {
+                let txids = &txids
+                    .iter()
+                    .cycle()
+                    .take(count)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let merkleblock = build_merkleblock(&header, txids, pos);
+                let root = create_merkle_branch_and_root(txids.to_vec(), 0).1;
+                assert_eq!(
+                    verify_merkleblock(&merkleblock),
+                    (root, vec![(pos, txids[pos])])
+                );
+            }
+        }
+    }
+}
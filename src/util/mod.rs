@@ -1,20 +1,70 @@
+mod amount;
 mod block;
+mod cache_policy;
+mod debug_timing;
+mod header_check;
+mod log_filter;
 mod merkle;
+mod muhash;
+mod notify_buffer;
+mod route_limits;
 mod script;
+mod tenants;
 mod transaction;
 
 pub mod fees;
 
+#[cfg(not(feature = "liquid"))]
+pub mod bip158;
+#[cfg(not(feature = "liquid"))]
+pub mod coinselect;
+#[cfg(not(feature = "liquid"))]
+pub mod descriptor;
+
+#[cfg(feature = "liquid")]
+mod confidential;
 #[cfg(feature = "liquid")]
 mod elements;
 
-pub use self::block::{BlockHeaderMeta, BlockId, BlockMeta, BlockStatus, HeaderEntry, HeaderList};
-pub use self::merkle::{get_header_merkle_proof, get_id_from_pos, get_tx_merkle_proof};
-pub use self::script::{get_innerscripts, get_script_asm, script_to_address};
-pub use self::transaction::{has_prevout, is_coinbase, is_spendable, TransactionStatus};
+pub use self::amount::{Amount, FeeRate};
+pub use self::block::{
+    BlockHeaderMeta, BlockId, BlockMeta, BlockStatus, BlockSummary, HeaderEntry, HeaderList,
+};
+pub use self::cache_policy::{parse_cache_ttl, CacheClass, CachePolicy};
+pub use self::debug_timing::{DebugTiming, Layer};
+pub use self::header_check::{HeaderCheckError, HeaderChecker};
+pub use self::log_filter::{LogFilter, LogLevels};
+pub use self::merkle::{
+    get_header_merkle_proof, get_id_from_pos, get_tx_merkle_proof, get_tx_merkleblock,
+};
+pub use self::muhash::MuHash;
+pub use self::notify_buffer::NotifyBuffer;
+pub use self::route_limits::{
+    parse_client_limit, parse_disabled_route, parse_route_limit, ClientLimit, ClientLimiter,
+    RouteLimit, RouteLimitError, RouteLimiter,
+};
+#[cfg(not(feature = "liquid"))]
+pub use self::script::address_to_script;
+pub use self::script::{
+    get_innerscripts, get_script_asm, get_script_type, is_v1_p2tr, script_to_address, AddressStyle,
+};
+pub use self::tenants::{authenticate, parse_tenant, Tenant};
+pub use self::transaction::{
+    has_prevout, is_coinbase, is_spendable, is_standard_version, parse_outpoint, signals_rbf,
+    TransactionStatus,
+};
+
+#[cfg(not(feature = "liquid"))]
+pub use self::block::{AdoptionStats, BlockStats};
+#[cfg(not(feature = "liquid"))]
+pub use self::transaction::dummy_txin;
 
 #[cfg(feature = "liquid")]
-pub use self::elements::{BlockProofValue, IssuanceValue, PegOutRequest};
+pub use self::confidential::{parse_blinder, parse_blinding_key, OutputBlinders};
+#[cfg(feature = "liquid")]
+pub use self::elements::{
+    month_index, month_name, parse_month, BlockProofValue, IssuanceValue, PegOutRequest, PegStats,
+};
 
 use std::collections::HashMap;
 use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
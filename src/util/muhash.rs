@@ -0,0 +1,224 @@
+use crypto::chacha20::ChaCha20;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+
+// An implementation of MuHash3072, the rolling UTXO set hash used by bitcoind
+// (see src/crypto/muhash.cpp). Elements are mapped into the multiplicative group
+// modulo the prime 2^3072 - 1103717, so that the hash of a set can be updated
+// incrementally and independently of the order in which elements were added/removed.
+
+const LIMBS: usize = 48;
+const BYTE_SIZE: usize = LIMBS * 8;
+const MAX_PRIME_DIFF: u64 = 1103717;
+
+#[derive(Clone, Copy)]
+pub struct Num3072([u64; LIMBS]);
+
+impl Num3072 {
+    pub fn one() -> Self {
+        let mut limbs = [0u64; LIMBS];
+        limbs[0] = 1;
+        Num3072(limbs)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        assert_eq!(bytes.len(), BYTE_SIZE, "invalid Num3072 length");
+        let mut limbs = [0u64; LIMBS];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = array_ref![bytes, i * 8, 8]
+                .iter()
+                .rev()
+                .fold(0u64, |acc, b| (acc << 8) | *b as u64);
+        }
+        Num3072(limbs)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(BYTE_SIZE);
+        for limb in self.0.iter() {
+            for i in 0..8 {
+                bytes.push((limb >> (i * 8)) as u8);
+            }
+        }
+        bytes
+    }
+
+    // Map an arbitrary element into the group
+    fn from_element(data: &[u8]) -> Self {
+        let mut hash = [0u8; 32];
+        let mut sha2 = Sha256::new();
+        sha2.input(data);
+        sha2.result(&mut hash);
+
+        let zeros = [0u8; BYTE_SIZE];
+        let mut keystream = [0u8; BYTE_SIZE];
+        ChaCha20::new(&hash, &[0u8; 8]).process(&zeros, &mut keystream);
+        Num3072::from_bytes(&keystream)
+    }
+
+    pub fn mul(&self, other: &Num3072) -> Num3072 {
+        let mut wide = [0u64; LIMBS * 2];
+        for i in 0..LIMBS {
+            let mut carry: u128 = 0;
+            for j in 0..LIMBS {
+                let t = (self.0[i] as u128) * (other.0[j] as u128) + wide[i + j] as u128 + carry;
+                wide[i + j] = t as u64;
+                carry = t >> 64;
+            }
+            wide[i + LIMBS] = carry as u64;
+        }
+
+        // fold the upper half back in, using 2^3072 = MAX_PRIME_DIFF (mod p)
+        let mut limbs = [0u64; LIMBS];
+        let mut carry: u128 = 0;
+        for i in 0..LIMBS {
+            let t = (wide[i + LIMBS] as u128) * (MAX_PRIME_DIFF as u128) + wide[i] as u128 + carry;
+            limbs[i] = t as u64;
+            carry = t >> 64;
+        }
+
+        let mut result = Num3072(limbs);
+        let mut carry = carry as u64;
+        while carry > 0 {
+            carry = result.add_small(carry * MAX_PRIME_DIFF) as u64;
+        }
+        result.reduce();
+        result
+    }
+
+    // Computes the modular inverse as self^(p-2)
+    pub fn inverse(&self) -> Num3072 {
+        let mut exp = [u64::max_value(); LIMBS];
+        exp[0] -= MAX_PRIME_DIFF + 1;
+
+        let mut result = Num3072::one();
+        for limb in exp.iter().rev() {
+            for bit in (0..64).rev() {
+                result = result.mul(&result);
+                if (limb >> bit) & 1 == 1 {
+                    result = result.mul(self);
+                }
+            }
+        }
+        result
+    }
+
+    // Adds a small value, returning whether it overflowed past 2^3072
+    fn add_small(&mut self, value: u64) -> bool {
+        let mut carry = value;
+        for limb in self.0.iter_mut() {
+            let (sum, overflow) = limb.overflowing_add(carry);
+            *limb = sum;
+            if !overflow {
+                return false;
+            }
+            carry = 1;
+        }
+        true
+    }
+
+    // Subtract the modulus if needed, so that the number is fully reduced
+    fn reduce(&mut self) {
+        let mut reduced = *self;
+        if reduced.add_small(MAX_PRIME_DIFF) {
+            *self = reduced;
+        }
+    }
+}
+
+// A MuHash3072 accumulator, kept as a numerator/denominator pair to defer the
+// (expensive) modular inversion until the hash is finalized.
+#[derive(Clone, Copy)]
+pub struct MuHash {
+    numerator: Num3072,
+    denominator: Num3072,
+}
+
+impl MuHash {
+    pub fn new() -> Self {
+        MuHash {
+            numerator: Num3072::one(),
+            denominator: Num3072::one(),
+        }
+    }
+
+    pub fn insert(&mut self, data: &[u8]) {
+        self.numerator = self.numerator.mul(&Num3072::from_element(data));
+    }
+
+    pub fn remove(&mut self, data: &[u8]) {
+        self.denominator = self.denominator.mul(&Num3072::from_element(data));
+    }
+
+    // Merge the elements added/removed by another accumulator into this one
+    pub fn combine(&mut self, other: &MuHash) {
+        self.numerator = self.numerator.mul(&other.numerator);
+        self.denominator = self.denominator.mul(&other.denominator);
+    }
+
+    // Returns the hash in the byte order used by bitcoind's `gettxoutsetinfo muhash`
+    pub fn finalize(&self) -> [u8; 32] {
+        let value = self.numerator.mul(&self.denominator.inverse());
+        let mut hash = [0u8; 32];
+        let mut sha2 = Sha256::new();
+        sha2.input(&value.to_bytes());
+        sha2.result(&mut hash);
+        hash.reverse();
+        hash
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.numerator.to_bytes(), self.denominator.to_bytes()].concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        assert_eq!(bytes.len(), BYTE_SIZE * 2, "invalid MuHash length");
+        MuHash {
+            numerator: Num3072::from_bytes(&bytes[..BYTE_SIZE]),
+            denominator: Num3072::from_bytes(&bytes[BYTE_SIZE..]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MuHash;
+    use hex;
+
+    fn from_int(i: u8) -> Vec<u8> {
+        let mut data = vec![0u8; 32];
+        data[0] = i;
+        data
+    }
+
+    #[test]
+    fn test_muhash_vector() {
+        // test vector from bitcoind's muhash_tests.cpp
+        let mut acc = MuHash::new();
+        acc.insert(&from_int(0));
+        acc.insert(&from_int(1));
+        acc.remove(&from_int(2));
+        assert_eq!(
+            hex::encode(acc.finalize()),
+            "10d312b100cbd32ada024a6646e40d3482fcff103668d2625f10002a607d5863"
+        );
+    }
+
+    #[test]
+    fn test_muhash_order_independent() {
+        let mut acc1 = MuHash::new();
+        acc1.insert(&from_int(1));
+        acc1.insert(&from_int(2));
+        acc1.remove(&from_int(1));
+
+        let mut acc2 = MuHash::new();
+        acc2.insert(&from_int(2));
+
+        let mut acc3 = MuHash::from_bytes(&acc1.to_bytes());
+        acc3.combine(&MuHash::new());
+
+        assert_eq!(acc1.finalize(), acc2.finalize());
+        assert_eq!(acc1.finalize(), acc3.finalize());
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+// The changes of each subscription, buffered until `window` elapsed since the first one and then
+// flushed together, so that a subscription gets at most one (aggregated) notification per window
+pub struct NotifyBuffer<K: Ord, V> {
+    window: Duration,
+    pending: BTreeMap<K, V>,
+    since: Option<Instant>, // when the first pending change was buffered
+}
+
+impl<K: Ord, V> NotifyBuffer<K, V> {
+    pub fn new(window: Duration) -> Self {
+        NotifyBuffer {
+            window,
+            pending: BTreeMap::new(),
+            since: None,
+        }
+    }
+
+    // Buffer a change, merged into the pending one of the subscription (if any)
+    pub fn push<F>(&mut self, key: K, change: V, now: Instant, merge: F)
+    where
+        F: FnOnce(&mut V, V),
+    {
+        match self.pending.get_mut(&key) {
+            Some(pending) => merge(pending, change),
+            None => {
+                self.pending.insert(key, change);
+            }
+        }
+        self.since.get_or_insert(now);
+    }
+
+    // Drop the pending change of a subscription, e.g. once it was unsubscribed or reverted
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let pending = self.pending.remove(key);
+        if self.pending.is_empty() {
+            self.since = None;
+        }
+        pending
+    }
+
+    // The pending changes, once the window elapsed
+    pub fn flush(&mut self, now: Instant) -> Vec<(K, V)> {
+        match self.since {
+            Some(since) if now.duration_since(since) >= self.window => {
+                self.since = None;
+                std::mem::replace(&mut self.pending, BTreeMap::new())
+                    .into_iter()
+                    .collect()
+            }
+            _ => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NotifyBuffer;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_notify_buffer() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let sum = |pending: &mut u32, change: u32| *pending += change;
+
+        let mut buffer = NotifyBuffer::new(Duration::from_secs(10));
+        assert!(buffer.flush(at(0)).is_empty());
+        buffer.push("a", 1, at(1), sum);
+        buffer.push("b", 2, at(5), sum);
+        buffer.push("a", 3, at(8), sum);
+        // the window starts with the first buffered change
+        assert!(buffer.flush(at(10)).is_empty());
+        assert_eq!(buffer.flush(at(11)), vec![("a", 4), ("b", 2)]);
+        assert!(buffer.flush(at(30)).is_empty());
+
+        // a new window starts with the next change
+        buffer.push("b", 1, at(30), sum);
+        assert!(buffer.flush(at(35)).is_empty());
+        assert_eq!(buffer.remove(&"b"), Some(1));
+        buffer.push("a", 1, at(36), sum);
+        assert!(buffer.flush(at(45)).is_empty());
+        assert_eq!(buffer.flush(at(46)), vec![("a", 1)]);
+
+        // without a window, the changes are flushed right away
+        let mut buffer = NotifyBuffer::new(Duration::from_secs(0));
+        buffer.push("a", 1, at(0), sum);
+        buffer.push("a", 1, at(0), sum);
+        assert_eq!(buffer.flush(at(0)), vec![("a", 2)]);
+    }
+}
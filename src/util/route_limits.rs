@@ -0,0 +1,479 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+const MAX_RATE_COUNTERS: usize = 100_000; // expired counters are pruned beyond this
+const EVICTED_RATE_COUNTERS: usize = MAX_RATE_COUNTERS / 10; // the oldest, if none expired
+
+// A limit on the requests to the paths matching a pattern, where `:name` segments match any
+// single segment (and can be limited like query parameters), `*` matches any single segment and
+// a trailing `*` matches any number of remaining segments (including none).
+#[derive(Debug, Clone)]
+pub struct RouteLimit {
+    pattern: Vec<String>,
+    kind: RouteLimitKind,
+}
+
+#[derive(Debug, Clone)]
+enum RouteLimitKind {
+    // maximum value of a numeric query parameter or `:name` segment
+    MaxParam(String, u64),
+    // maximum number of requests per period, counted separately for every `:name` segments value
+    Rate(u32, Duration),
+    // not served at all
+    Disabled,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RouteLimitError {
+    ParamTooLarge(String, u64),
+    RateLimited(Duration), // until the current period ends
+    Disabled,
+}
+
+impl RouteLimit {
+    fn matches<'a>(&self, path: &[&'a str]) -> Option<Vec<(&str, &'a str)>> {
+        match_pattern(&self.pattern, path)
+    }
+}
+
+// A token bucket limit on the requests of each client (by IP address) to the paths matching a
+// pattern: bursts of up to `count` requests, refilled at `count` requests per `period`
+#[derive(Debug, Clone)]
+pub struct ClientLimit {
+    pattern: Vec<String>,
+    count: u32,
+    period: Duration,
+}
+
+impl ClientLimit {
+    // The tokens after `elapsed`, starting from `tokens`
+    fn refill(&self, tokens: f64, elapsed: Duration) -> f64 {
+        let refilled = tokens + as_secs(elapsed) * f64::from(self.count) / as_secs(self.period);
+        refilled.min(f64::from(self.count))
+    }
+
+    // The time until the missing tokens are refilled
+    fn refill_time(&self, missing: f64) -> Duration {
+        let secs = missing * as_secs(self.period) / f64::from(self.count);
+        Duration::from_millis((secs * 1000.0).ceil() as u64)
+    }
+}
+
+fn as_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+// Match the path segments, returning the values of the named segments
+fn match_pattern<'a, 'p>(
+    pattern: &'p [String],
+    path: &[&'a str],
+) -> Option<Vec<(&'p str, &'a str)>> {
+    let mut named = vec![];
+    for (i, segment) in pattern.iter().enumerate() {
+        if segment == "*" && i == pattern.len() - 1 {
+            return Some(named);
+        }
+        let value = path.get(i)?;
+        if segment.starts_with(':') {
+            named.push((&segment[1..], *value));
+        } else if segment != "*" && segment != value {
+            return None;
+        }
+    }
+    if path.len() == pattern.len() {
+        Some(named)
+    } else {
+        None
+    }
+}
+
+// Parse a route limit, formatted as "<pattern>?<param>=<max>" to limit a parameter's value or
+// as "<pattern>@<count>/<seconds>" to limit the rate of requests
+pub fn parse_route_limit(value: &str) -> Result<RouteLimit> {
+    let invalid = || {
+        format!(
+            "invalid route limit {:?}, expected <pattern>?<param>=<max> or <pattern>@<count>/<seconds>",
+            value
+        )
+    };
+    let (pattern, kind) = if let Some(pos) = value.rfind('@') {
+        let mut rate = value[pos + 1..].splitn(2, '/');
+        let count = rate.next().unwrap().parse().chain_err(invalid)?;
+        let secs = rate.next().chain_err(invalid)?.parse().chain_err(invalid)?;
+        if count == 0 || secs == 0 {
+            bail!(invalid());
+        }
+        (
+            &value[..pos],
+            RouteLimitKind::Rate(count, Duration::from_secs(secs)),
+        )
+    } else if let Some(pos) = value.rfind('?') {
+        let mut param = value[pos + 1..].splitn(2, '=');
+        let name = param.next().unwrap().to_string();
+        let max = param
+            .next()
+            .chain_err(invalid)?
+            .parse()
+            .chain_err(invalid)?;
+        (&value[..pos], RouteLimitKind::MaxParam(name, max))
+    } else {
+        bail!(invalid());
+    };
+    parse_pattern(pattern, kind).chain_err(invalid)
+}
+
+// Parse a pattern of paths that are not served (e.g. "/blocks/stats/*")
+pub fn parse_disabled_route(value: &str) -> Result<RouteLimit> {
+    parse_pattern(value, RouteLimitKind::Disabled).chain_err(|| {
+        format!(
+            "invalid disabled route {:?}, expected a path pattern",
+            value
+        )
+    })
+}
+
+// Parse a per-client limit, formatted as "<pattern>@<count>/<seconds>" (e.g. "/*@50/10")
+pub fn parse_client_limit(value: &str) -> Result<ClientLimit> {
+    let invalid = || {
+        format!(
+            "invalid client limit {:?}, expected <pattern>@<count>/<seconds>",
+            value
+        )
+    };
+    match parse_route_limit(value).chain_err(invalid)? {
+        RouteLimit {
+            pattern,
+            kind: RouteLimitKind::Rate(count, period),
+        } => Ok(ClientLimit {
+            pattern,
+            count,
+            period,
+        }),
+        _ => bail!(invalid()),
+    }
+}
+
+fn parse_pattern(pattern: &str, kind: RouteLimitKind) -> Result<RouteLimit> {
+    if !pattern.starts_with('/') {
+        bail!("path patterns must start with /");
+    }
+    Ok(RouteLimit {
+        pattern: pattern.split('/').skip(1).map(str::to_string).collect(),
+        kind,
+    })
+}
+
+// Enforces the route limits configured by the operator, before the requests are handled
+pub struct RouteLimiter {
+    limits: Vec<RouteLimit>,
+    counters: Mutex<HashMap<(usize, Vec<String>), (Instant, u32)>>, // => (period start, count)
+}
+
+impl RouteLimiter {
+    pub fn new(limits: Vec<RouteLimit>) -> Self {
+        RouteLimiter {
+            limits,
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Get the first limit exceeded by the request, if any (counting it against the rate limits)
+    pub fn check(
+        &self,
+        path: &[&str],
+        query_params: &HashMap<String, String>,
+    ) -> Option<RouteLimitError> {
+        for (index, limit) in self.limits.iter().enumerate() {
+            let named = match limit.matches(path) {
+                Some(named) => named,
+                None => continue,
+            };
+            match limit.kind {
+                RouteLimitKind::MaxParam(ref name, max) => {
+                    let value = query_params.get(name).map(String::as_str).or_else(|| {
+                        named
+                            .iter()
+                            .find(|(segment, _)| *segment == name.as_str())
+                            .map(|(_, value)| *value)
+                    });
+                    // invalid values are left for the handler to reject
+                    if let Some(value) = value.and_then(|value| value.parse::<u64>().ok()) {
+                        if value > max {
+                            return Some(RouteLimitError::ParamTooLarge(name.clone(), max));
+                        }
+                    }
+                }
+                RouteLimitKind::Disabled => return Some(RouteLimitError::Disabled),
+                RouteLimitKind::Rate(count, period) => {
+                    let key = (index, named.iter().map(|(_, v)| v.to_string()).collect());
+                    if let Some(retry_after) = self.count_request(key, count, period) {
+                        return Some(RouteLimitError::RateLimited(retry_after));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn count_request(
+        &self,
+        key: (usize, Vec<String>),
+        count: u32,
+        period: Duration,
+    ) -> Option<Duration> {
+        let now = Instant::now();
+        let mut counters = self.counters.lock().unwrap();
+        if counters.len() >= MAX_RATE_COUNTERS {
+            let limits = &self.limits;
+            counters.retain(|(index, _), (start, _)| match limits[*index].kind {
+                RouteLimitKind::Rate(_, period) => now.duration_since(*start) < period,
+                RouteLimitKind::MaxParam(..) | RouteLimitKind::Disabled => false,
+            });
+            // the counters of (e.g. per address) limits can be created by anyone, so the oldest
+            // periods are forgotten rather than growing without bounds
+            if counters.len() >= MAX_RATE_COUNTERS {
+                evict_oldest(&mut counters, EVICTED_RATE_COUNTERS, |(start, _)| *start);
+            }
+        }
+        let counter = counters.entry(key).or_insert((now, 0));
+        let elapsed = now.duration_since(counter.0);
+        if elapsed >= period {
+            *counter = (now, 0);
+        } else if counter.1 >= count {
+            return Some(period - elapsed);
+        }
+        counter.1 += 1;
+        None
+    }
+}
+
+// Remove the `count` entries with the oldest instants
+fn evict_oldest<K, V, F>(map: &mut HashMap<K, V>, count: usize, instant: F)
+where
+    K: Clone + Eq + Hash,
+    F: Fn(&V) -> Instant,
+{
+    let mut entries: Vec<(Instant, K)> = map
+        .iter()
+        .map(|(key, value)| (instant(value), key.clone()))
+        .collect();
+    entries.sort_unstable_by_key(|(instant, _)| *instant);
+    for (_, key) in entries.into_iter().take(count) {
+        map.remove(&key);
+    }
+}
+
+// The clients are told apart by their IPv4 address, or by the /64 prefix of their IPv6 address
+// (the subnet usually assigned to a single host, which can pick any address within it)
+fn client_key(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V4(_) => client,
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if segments[..5] == [0; 5] && segments[5] == 0xffff {
+                let octets = ip.octets();
+                return IpAddr::V4(Ipv4Addr::new(
+                    octets[12], octets[13], octets[14], octets[15],
+                ));
+            }
+            IpAddr::V6(Ipv6Addr::new(
+                segments[0],
+                segments[1],
+                segments[2],
+                segments[3],
+                0,
+                0,
+                0,
+                0,
+            ))
+        }
+    }
+}
+
+// Enforces the client limits configured by the operator, with a token bucket per limit and client
+pub struct ClientLimiter {
+    limits: Vec<ClientLimit>,
+    buckets: Mutex<HashMap<(usize, IpAddr), (Instant, f64)>>, // => (last refill, tokens)
+}
+
+impl ClientLimiter {
+    pub fn new(limits: Vec<ClientLimit>) -> Self {
+        ClientLimiter {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Take a token from the buckets of all the limits matching the path, or get the time until
+    // the request can be retried (without taking any)
+    pub fn check(&self, client: IpAddr, path: &[&str]) -> Option<Duration> {
+        let matching: Vec<usize> = (0..self.limits.len())
+            .filter(|index| match_pattern(&self.limits[*index].pattern, path).is_some())
+            .collect();
+        if matching.is_empty() {
+            return None;
+        }
+        let client = client_key(client);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_RATE_COUNTERS {
+            // full buckets are the same as new ones
+            let limits = &self.limits;
+            buckets.retain(|(index, _), (refilled_at, tokens)| {
+                let limit = &limits[*index];
+                limit.refill(*tokens, now.duration_since(*refilled_at)) < f64::from(limit.count)
+            });
+            // otherwise the least recently used are forgotten, as for the route limits
+            if buckets.len() >= MAX_RATE_COUNTERS {
+                evict_oldest(&mut buckets, EVICTED_RATE_COUNTERS, |(refilled_at, _)| {
+                    *refilled_at
+                });
+            }
+        }
+        let mut retry_after = None;
+        for index in &matching {
+            let limit = &self.limits[*index];
+            let bucket = buckets
+                .entry((*index, client))
+                .or_insert((now, f64::from(limit.count)));
+            *bucket = (now, limit.refill(bucket.1, now.duration_since(bucket.0)));
+            if bucket.1 < 1.0 {
+                let wait = limit.refill_time(1.0 - bucket.1);
+                retry_after = Some(retry_after.map_or(wait, |other: Duration| other.max(wait)));
+            }
+        }
+        if retry_after.is_none() {
+            for index in matching {
+                buckets.get_mut(&(index, client)).unwrap().1 -= 1.0;
+            }
+        }
+        retry_after
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_limits() {
+        let limiter = RouteLimiter::new(vec![
+            parse_route_limit("/outpoint/*/trace?hops=5").unwrap(),
+            parse_route_limit("/blocks/stats/*/:count?count=50").unwrap(),
+            parse_route_limit("/address/:address/txs/chain/*@2/60").unwrap(),
+            parse_disabled_route("/blocks/:start_height").unwrap(),
+        ]);
+        let check = |path: &str, query: &str| {
+            let path: Vec<&str> = path.split('/').skip(1).collect();
+            let query_params = query
+                .split('&')
+                .filter(|param| !param.is_empty())
+                .map(|param| {
+                    let mut parts = param.splitn(2, '=');
+                    let name = parts.next().unwrap().to_string();
+                    (name, parts.next().unwrap_or("").to_string())
+                })
+                .collect();
+            limiter.check(&path, &query_params)
+        };
+
+        assert_eq!(check("/outpoint/abcd:0/trace", "hops=5"), None);
+        assert_eq!(
+            check("/outpoint/abcd:0/trace", "hops=6"),
+            Some(RouteLimitError::ParamTooLarge("hops".to_string(), 5))
+        );
+        assert_eq!(check("/outpoint/abcd:0", "hops=6"), None);
+        assert_eq!(
+            check("/blocks/stats/100/51", ""),
+            Some(RouteLimitError::ParamTooLarge("count".to_string(), 50))
+        );
+
+        // the trailing wildcard matches the first page too, counted per address
+        assert_eq!(check("/address/a/txs/chain", ""), None);
+        assert_eq!(check("/address/a/txs/chain/abcd", ""), None);
+        match check("/address/a/txs/chain/efgh", "") {
+            Some(RouteLimitError::RateLimited(_)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(check("/address/b/txs/chain", ""), None);
+
+        assert_eq!(check("/blocks/100", ""), Some(RouteLimitError::Disabled));
+        assert_eq!(check("/blocks/tip/height", ""), None);
+
+        assert!(parse_route_limit("/address/:address").is_err());
+        assert!(parse_route_limit("address/*@10/60").is_err());
+        assert!(parse_route_limit("/address/*@10/0").is_err());
+        assert!(parse_route_limit("/outpoint/*/trace?hops").is_err());
+        assert!(parse_disabled_route("blocks/:start_height").is_err());
+    }
+
+    #[test]
+    fn test_route_limit_counters_bounded() {
+        let limiter =
+            RouteLimiter::new(vec![parse_route_limit("/address/:address@1/3600").unwrap()]);
+        for i in 0..MAX_RATE_COUNTERS + 1 {
+            limiter.check(&["address", i.to_string().as_str()], &HashMap::new());
+        }
+        let counters = limiter.counters.lock().unwrap().len();
+        assert!(counters <= MAX_RATE_COUNTERS && counters > MAX_RATE_COUNTERS / 2);
+        // the most recent counters are kept
+        let last = MAX_RATE_COUNTERS.to_string();
+        assert!(limiter
+            .check(&["address", last.as_str()], &HashMap::new())
+            .is_some());
+    }
+
+    #[test]
+    fn test_client_limits() {
+        let limiter = ClientLimiter::new(vec![
+            parse_client_limit("/*@3/3600").unwrap(),
+            parse_client_limit("/address/*@1/3600").unwrap(),
+        ]);
+        let check = |client: &str, path: &str| {
+            let path: Vec<&str> = path.split('/').skip(1).collect();
+            limiter.check(client.parse().unwrap(), &path)
+        };
+
+        // limited requests don't take tokens from the other buckets
+        assert_eq!(check("10.0.0.1", "/address/a"), None);
+        let retry_after = check("10.0.0.1", "/address/b").unwrap();
+        assert!(
+            retry_after > Duration::from_secs(3500) && retry_after <= Duration::from_secs(3600)
+        );
+        assert_eq!(check("10.0.0.1", "/blocks/tip/height"), None);
+        assert_eq!(check("10.0.0.1", "/blocks/tip/hash"), None);
+        assert!(check("10.0.0.1", "/blocks/tip/hash").is_some());
+
+        // counted per client, by /64 prefix for IPv6 (and as IPv4 for the mapped addresses)
+        assert_eq!(check("10.0.0.2", "/address/a"), None);
+        assert!(check("::ffff:10.0.0.2", "/address/b").is_some());
+        assert_eq!(check("2001:db8:0:1::1", "/address/a"), None);
+        assert!(check("2001:db8:0:1:ffff::2", "/address/a").is_some());
+        assert_eq!(check("2001:db8:0:2::1", "/address/a"), None);
+        assert_eq!(check("::1", "/blocks/tip/height"), None);
+
+        assert!(parse_client_limit("/tx/*/trace?hops=5").is_err());
+        assert!(parse_client_limit("/*@0/10").is_err());
+        assert!(parse_client_limit("*@1/10").is_err());
+    }
+
+    #[test]
+    fn test_client_buckets_bounded() {
+        let limiter = ClientLimiter::new(vec![parse_client_limit("/*@1/3600").unwrap()]);
+        let client = |i: usize| IpAddr::V4(Ipv4Addr::from(i as u32));
+        for i in 0..MAX_RATE_COUNTERS + 1 {
+            assert_eq!(limiter.check(client(i), &["blocks"]), None);
+        }
+        let buckets = limiter.buckets.lock().unwrap().len();
+        assert!(buckets <= MAX_RATE_COUNTERS && buckets > MAX_RATE_COUNTERS / 2);
+        // the most recent buckets are kept
+        assert!(limiter
+            .check(client(MAX_RATE_COUNTERS), &["blocks"])
+            .is_some());
+    }
+}
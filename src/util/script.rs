@@ -1,13 +1,15 @@
 use bitcoin::blockdata::script::{Instruction::PushBytes, Script};
 #[cfg(not(feature = "liquid"))]
 use {
-    bitcoin::network::constants::Network as BNetwork,
-    bitcoin_bech32::constants::Network as B32Network,
-    bitcoin_bech32::{self, u5},
+    bech32::{u5, Bech32, FromBase32, ToBase32},
+    bitcoin::blockdata::opcodes::all::{OP_CHECKSIG, OP_DUP, OP_EQUAL, OP_EQUALVERIFY, OP_HASH160},
+    bitcoin::blockdata::script::Builder,
+    bitcoin::util::base58,
     bitcoin_hashes::{hash160::Hash as Hash160, Hash},
+    std::str::FromStr,
 };
 
-use crate::chain::{address, Network};
+use crate::chain::AddressParams;
 use crate::chain::{TxIn, TxOut};
 
 pub struct InnerScripts {
@@ -15,46 +17,142 @@ pub struct InnerScripts {
     pub witness_script: Option<Script>,
 }
 
-pub fn script_to_address(script: &Script, network: &Network) -> Option<String> {
+// How addresses are rendered (Bitcoin only)
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct AddressStyle {
+    pub bech32_uppercase: bool, // more compact in QR codes
+    pub p2pk_as_p2pkh: bool,    // render p2pk outputs as the p2pkh address of their pubkey
+}
+
+#[allow(unused_variables)] // `style` is unused in liquid mode
+pub fn script_to_address(
+    script: &Script,
+    params: &'static AddressParams,
+    style: &AddressStyle,
+) -> Option<String> {
     // rust-elements provides an Address::from_script() utility that's not yet
     // available in rust-bitcoin, but should be soon
     #[cfg(feature = "liquid")]
-    return address::Address::from_script(script, None, network.address_params())
+    return crate::chain::address::Address::from_script(script, None, params)
         .map(|a| a.to_string());
 
     #[cfg(not(feature = "liquid"))]
     {
-        let payload = if script.is_p2pkh() {
-            address::Payload::PubkeyHash(Hash160::from_slice(&script[3..23]).ok()?)
+        let base58 = |prefix: u8, hash: &[u8]| {
+            let mut data = vec![prefix];
+            data.extend_from_slice(hash);
+            base58::check_encode_slice(&data)
+        };
+
+        if script.is_p2pkh() {
+            Some(base58(params.p2pkh_prefix, &script[3..23]))
+        } else if script.is_p2pk() && style.p2pk_as_p2pkh {
+            let pubkey = &script[1..script.len() - 1];
+            Some(base58(
+                params.p2pkh_prefix,
+                &Hash160::hash(pubkey).into_inner(),
+            ))
         } else if script.is_p2sh() {
-            address::Payload::ScriptHash(Hash160::from_slice(&script[2..22]).ok()?)
+            Some(base58(params.p2sh_prefix, &script[2..22]))
         } else if script.is_v0_p2wpkh() || script.is_v0_p2wsh() {
-            let program = if script.is_v0_p2wpkh() {
-                script[2..22].to_vec()
+            let mut data = vec![u5::try_from_u8(0).expect("0<32")];
+            data.extend(script[2..].to_base32());
+            let address = Bech32::new(params.bech32_hrp.clone(), data)
+                .ok()?
+                .to_string();
+
+            Some(if style.bech32_uppercase {
+                address.to_uppercase()
             } else {
-                script[2..34].to_vec()
-            };
-
-            address::Payload::WitnessProgram(
-                bitcoin_bech32::WitnessProgram::new(
-                    u5::try_from_u8(0).expect("0<32"),
-                    program,
-                    B32Network::from(network),
-                )
-                .unwrap(),
-            )
+                address
+            })
         } else {
+            None
+        }
+    }
+}
+
+// The output script of an address encoded with `params`, or None if it isn't a valid address
+// for them (Bitcoin only, liquid addresses are parsed by elements::Address)
+#[cfg(not(feature = "liquid"))]
+pub fn address_to_script(address: &str, params: &AddressParams) -> Option<Script> {
+    if let Ok(data) = base58::from_check(address) {
+        if data.len() != 21 {
             return None;
+        }
+        let hash = &data[1..];
+        return if data[0] == params.p2pkh_prefix {
+            Some(
+                Builder::new()
+                    .push_opcode(OP_DUP)
+                    .push_opcode(OP_HASH160)
+                    .push_slice(hash)
+                    .push_opcode(OP_EQUALVERIFY)
+                    .push_opcode(OP_CHECKSIG)
+                    .into_script(),
+            )
+        } else if data[0] == params.p2sh_prefix {
+            Some(
+                Builder::new()
+                    .push_opcode(OP_HASH160)
+                    .push_slice(hash)
+                    .push_opcode(OP_EQUAL)
+                    .into_script(),
+            )
+        } else {
+            None
         };
+    }
 
-        Some(
-            address::Address {
-                payload,
-                network: BNetwork::from(network),
-            }
-            .to_string(),
-        )
+    let bech32 = Bech32::from_str(address).ok()?;
+    if bech32.hrp() != params.bech32_hrp || bech32.data().is_empty() {
+        return None;
+    }
+    let version = bech32.data()[0].to_u8();
+    let program = Vec::<u8>::from_base32(&bech32.data()[1..]).ok()?;
+    let valid = match version {
+        0 => program.len() == 20 || program.len() == 32,
+        1..=16 => (2..=40).contains(&program.len()),
+        _ => false,
+    };
+    if !valid {
+        return None;
     }
+    Some(
+        Builder::new()
+            .push_int(i64::from(version))
+            .push_slice(&program)
+            .into_script(),
+    )
+}
+
+// Classify the script into one of the standard output types
+pub fn get_script_type(script: &Script) -> &'static str {
+    if script.is_empty() {
+        "empty"
+    } else if script.is_op_return() {
+        "op_return"
+    } else if script.is_p2pk() {
+        "p2pk"
+    } else if script.is_p2pkh() {
+        "p2pkh"
+    } else if script.is_p2sh() {
+        "p2sh"
+    } else if script.is_v0_p2wpkh() {
+        "v0_p2wpkh"
+    } else if script.is_v0_p2wsh() {
+        "v0_p2wsh"
+    } else if script.is_provably_unspendable() {
+        "provably_unspendable"
+    } else {
+        "unknown"
+    }
+}
+
+// Segwit v1 outputs with a 32 bytes program
+pub fn is_v1_p2tr(script: &Script) -> bool {
+    let bytes = script.as_bytes();
+    bytes.len() == 34 && bytes[0] == 0x51 && bytes[1] == 0x20
 }
 
 pub fn get_script_asm(script: &Script) -> String {
@@ -94,3 +192,62 @@ pub fn get_innerscripts(txin: &TxIn, prevout: &TxOut) -> InnerScripts {
         witness_script,
     }
 }
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use super::*;
+    use crate::chain::{address::Address, Network};
+
+    #[test]
+    fn test_address_params() {
+        let mainnet: &'static AddressParams =
+            Box::leak(Box::new(Network::Bitcoin.address_params()));
+        let style = AddressStyle::default();
+        for addr in &[
+            "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2",
+            "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy",
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+            "bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3",
+        ] {
+            let script = Address::from_str(addr).unwrap().script_pubkey();
+            assert_eq!(address_to_script(addr, mainnet), Some(script.clone()));
+            assert_eq!(
+                script_to_address(&script, mainnet, &style)
+                    .as_ref()
+                    .map(String::as_str),
+                Some(*addr)
+            );
+        }
+
+        let params: &'static AddressParams =
+            Box::leak(Box::new("p2pkh=48, p2sh=50, hrp=LTC".parse().unwrap()));
+        assert_eq!(
+            *params,
+            AddressParams {
+                p2pkh_prefix: 48,
+                p2sh_prefix: 50,
+                bech32_hrp: "ltc".to_string(),
+            }
+        );
+        for addr in &[
+            "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2",
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+        ] {
+            let script = address_to_script(addr, mainnet).unwrap();
+            let custom_addr = script_to_address(&script, params, &style).unwrap();
+            assert!(custom_addr.starts_with(if script.is_p2pkh() { "L" } else { "ltc1q" }));
+            assert_eq!(address_to_script(&custom_addr, params), Some(script));
+            assert_eq!(address_to_script(addr, params), None);
+            assert_eq!(address_to_script(&custom_addr, mainnet), None);
+        }
+
+        assert!("p2pkh=48,p2sh=50".parse::<AddressParams>().is_err());
+        assert!("p2pkh=256,p2sh=50,hrp=ltc"
+            .parse::<AddressParams>()
+            .is_err());
+        assert!("p2pkh=48,p2sh=50,hrp=".parse::<AddressParams>().is_err());
+        assert!("p2pkh=48,p2sh=50,hrp=ltc,magic=1"
+            .parse::<AddressParams>()
+            .is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use std::fmt;
+
+use crate::errors::*;
+
+pub const DEFAULT_TENANT_WEBHOOKS: usize = 100;
+pub const DEFAULT_TENANT_WATCHED_SCRIPTS: usize = 10_000;
+const MAX_TENANT_NAME_LENGTH: usize = 64;
+
+// A client application of a shared server, identified by its API key. Once tenants are configured,
+// the webhooks and WebSocket watches require a key: each tenant only sees its own webhooks, and
+// its registrations are counted against its own quotas (on top of the server-wide limits).
+#[derive(Clone, PartialEq)]
+pub struct Tenant {
+    pub name: String, // stored along with the webhooks, so that the keys can be rotated
+    pub api_key: String,
+    pub max_webhooks: usize,
+    pub max_watched_scripts: usize, // by all the WebSocket connections of the tenant
+}
+
+// without the API key, as the config is printed on startup
+impl fmt::Debug for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}@{}/{}",
+            self.name, self.max_webhooks, self.max_watched_scripts
+        )
+    }
+}
+
+// Parse a tenant, formatted as "<name>:<api key>[@<max webhooks>/<max watched scripts>]"
+pub fn parse_tenant(value: &str) -> Result<Tenant> {
+    let invalid = || {
+        format!(
+            "invalid tenant {:?}, expected <name>:<api key>[@<max webhooks>/<max watched scripts>]",
+            value
+        )
+    };
+    let mut parts = value.splitn(2, '@');
+    let (name, api_key) = match parts.next().unwrap().splitn(2, ':').collect::<Vec<_>>()[..] {
+        [name, api_key] if !name.is_empty() && !api_key.is_empty() => (name, api_key),
+        _ => bail!(invalid()),
+    };
+    ensure!(
+        name.len() <= MAX_TENANT_NAME_LENGTH
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+        "invalid tenant name {:?}",
+        name
+    );
+    let (max_webhooks, max_watched_scripts) = match parts.next() {
+        Some(quotas) => match quotas.splitn(2, '/').collect::<Vec<_>>()[..] {
+            [webhooks, scripts] => (
+                webhooks.parse().chain_err(invalid)?,
+                scripts.parse().chain_err(invalid)?,
+            ),
+            _ => bail!(invalid()),
+        },
+        None => (DEFAULT_TENANT_WEBHOOKS, DEFAULT_TENANT_WATCHED_SCRIPTS),
+    };
+    Ok(Tenant {
+        name: name.to_string(),
+        api_key: api_key.to_string(),
+        max_webhooks,
+        max_watched_scripts,
+    })
+}
+
+// The tenant identified by the API key. Without any tenant configured, the clients share a
+// single namespace and don't need a key (None).
+pub fn authenticate<'a>(
+    tenants: &'a [Tenant],
+    api_key: Option<&str>,
+) -> Result<Option<&'a Tenant>> {
+    if tenants.is_empty() {
+        return Ok(None);
+    }
+    let api_key = api_key.chain_err(|| "missing API key")?;
+    tenants
+        .iter()
+        .find(|tenant| constant_time_eq(tenant.api_key.as_bytes(), api_key.as_bytes()))
+        .map(Some)
+        .chain_err(|| "invalid API key")
+}
+
+// Compare the keys without leaking how much of their prefix matches
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{authenticate, parse_tenant, DEFAULT_TENANT_WEBHOOKS};
+
+    #[test]
+    fn test_tenants() {
+        let alice = parse_tenant("alice:k1").unwrap();
+        assert_eq!(
+            (alice.name.as_str(), alice.api_key.as_str()),
+            ("alice", "k1")
+        );
+        assert_eq!(alice.max_webhooks, DEFAULT_TENANT_WEBHOOKS);
+        // the keys may contain colons
+        let bob = parse_tenant("bob:k:2@5/100").unwrap();
+        assert_eq!(bob.api_key, "k:2");
+        assert_eq!((bob.max_webhooks, bob.max_watched_scripts), (5, 100));
+        for invalid in &[
+            "alice",
+            "alice:",
+            ":k1",
+            "a b:k1",
+            "alice:k1@5",
+            "alice:k1@a/1",
+        ] {
+            assert!(parse_tenant(invalid).is_err(), "{}", invalid);
+        }
+
+        let tenants = vec![alice, bob];
+        assert_eq!(
+            authenticate(&tenants, Some("k:2")).unwrap(),
+            Some(&tenants[1])
+        );
+        assert!(authenticate(&tenants, Some("k1x")).is_err());
+        assert!(authenticate(&tenants, None).is_err());
+        // without tenants, the key is ignored
+        assert_eq!(authenticate(&[], Some("k1")).unwrap(), None);
+        assert_eq!(authenticate(&[], None).unwrap(), None);
+    }
+}
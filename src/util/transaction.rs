@@ -1,10 +1,22 @@
+use bitcoin_hashes::hex::FromHex;
 #[cfg(feature = "liquid")]
 use bitcoin_hashes::hex::ToHex;
 use bitcoin_hashes::sha256d::Hash as Sha256dHash;
 
-use crate::chain::{TxIn, TxOut};
+use crate::chain::{OutPoint, Transaction, TxIn, TxOut};
+use crate::errors::*;
 use crate::util::BlockId;
 
+// sizes used for weight estimation, assuming the worst case for DER signatures
+#[cfg(not(feature = "liquid"))]
+const DUMMY_SIG_LEN: usize = 72;
+#[cfg(not(feature = "liquid"))]
+const DUMMY_PUBKEY_LEN: usize = 33;
+
+// transactions with other versions are not relayed by bitcoind (nor elementsd)
+const MIN_STANDARD_VERSION: u32 = 1;
+const MAX_STANDARD_VERSION: u32 = 2;
+
 #[cfg(feature = "liquid")]
 const REGTEST_INITIAL_ISSUANCE_PREVOUT: &str =
     "50cdc410c9d0d61eeacc531f52d2c70af741da33af127c364e52ac1ee7c030a5";
@@ -15,6 +27,9 @@ pub struct TransactionStatus {
     pub block_height: Option<usize>,
     pub block_hash: Option<Sha256dHash>,
     pub block_time: Option<u32>,
+    // position of the transaction within the block, only included in /tx/:txid/status
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_index: Option<usize>,
 }
 
 impl From<Option<BlockId>> for TransactionStatus {
@@ -25,17 +40,31 @@ impl From<Option<BlockId>> for TransactionStatus {
                 block_height: Some(b.height as usize),
                 block_hash: Some(b.hash),
                 block_time: Some(b.time),
+                block_index: None,
             },
             None => TransactionStatus {
                 confirmed: false,
                 block_height: None,
                 block_hash: None,
                 block_time: None,
+                block_index: None,
             },
         }
     }
 }
 
+// Parse an outpoint formatted as `txid:vout`
+pub fn parse_outpoint(outpoint: &str) -> Result<OutPoint> {
+    let mut parts = outpoint.splitn(2, ':');
+    let txid = Sha256dHash::from_hex(parts.next().unwrap()).chain_err(|| "invalid txid")?;
+    let vout = parts
+        .next()
+        .chain_err(|| "invalid outpoint, expected txid:vout")?
+        .parse::<u32>()
+        .chain_err(|| "invalid vout")?;
+    Ok(OutPoint { txid, vout })
+}
+
 pub fn is_coinbase(txin: &TxIn) -> bool {
     #[cfg(not(feature = "liquid"))]
     return txin.previous_output.is_null();
@@ -52,9 +81,98 @@ pub fn has_prevout(txin: &TxIn) -> bool {
         && txin.previous_output.txid.to_hex() != REGTEST_INITIAL_ISSUANCE_PREVOUT;
 }
 
+// Build an input with a dummy (maximum-size) signature for the given spent output type,
+// so that the weight of unsigned transactions can be estimated
+#[cfg(not(feature = "liquid"))]
+pub fn dummy_txin(input_type: &str) -> Option<TxIn> {
+    let push = |len: usize| {
+        let mut data = vec![len as u8];
+        data.resize(len + 1, 0);
+        data
+    };
+    let (script_sig, witness) = match input_type {
+        "p2pk" => (push(DUMMY_SIG_LEN), vec![]),
+        "p2pkh" => (
+            [push(DUMMY_SIG_LEN), push(DUMMY_PUBKEY_LEN)].concat(),
+            vec![],
+        ),
+        "v0_p2wpkh" => (
+            vec![],
+            vec![vec![0; DUMMY_SIG_LEN], vec![0; DUMMY_PUBKEY_LEN]],
+        ),
+        // the redeem script is a push of the 22 bytes v0_p2wpkh program
+        "p2sh-p2wpkh" => (
+            push(22),
+            vec![vec![0; DUMMY_SIG_LEN], vec![0; DUMMY_PUBKEY_LEN]],
+        ),
+        // key path spend with a 64 bytes schnorr signature
+        "v1_p2tr" => (vec![], vec![vec![0; 64]]),
+        _ => return None,
+    };
+    Some(TxIn {
+        previous_output: OutPoint::default(),
+        script_sig: script_sig.into(),
+        sequence: 0xffff_ffff,
+        witness,
+    })
+}
+
+// BIP125 opt-in: any input with a sequence number below 0xfffffffe
+pub fn signals_rbf(tx: &Transaction) -> bool {
+    tx.input.iter().any(|txin| txin.sequence < 0xffff_fffe)
+}
+
+pub fn is_standard_version(tx: &Transaction) -> bool {
+    tx.version >= MIN_STANDARD_VERSION && tx.version <= MAX_STANDARD_VERSION
+}
+
 pub fn is_spendable(txout: &TxOut) -> bool {
     #[cfg(not(feature = "liquid"))]
     return !txout.script_pubkey.is_provably_unspendable();
     #[cfg(feature = "liquid")]
     return !txout.is_fee() && !txout.script_pubkey.is_provably_unspendable();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_outpoint() {
+        let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        let outpoint = parse_outpoint(&format!("{}:1", txid)).unwrap();
+        assert_eq!(outpoint.txid.to_string(), txid);
+        assert_eq!(outpoint.vout, 1);
+
+        assert!(parse_outpoint(txid).is_err());
+        assert!(parse_outpoint(&format!("{}:", txid)).is_err());
+        assert!(parse_outpoint(&format!("{}:-1", txid)).is_err());
+        assert!(parse_outpoint(&format!("{}:4294967296", txid)).is_err());
+        assert!(parse_outpoint(&format!("{}:1", &txid[1..])).is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "liquid"))]
+    fn test_signals_rbf() {
+        let tx = |sequences: Vec<u32>| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: sequences
+                .into_iter()
+                .map(|sequence| TxIn {
+                    previous_output: OutPoint::default(),
+                    script_sig: vec![].into(),
+                    sequence,
+                    witness: vec![],
+                })
+                .collect(),
+            output: vec![],
+        };
+        assert!(!signals_rbf(&tx(vec![])));
+        assert!(!signals_rbf(&tx(vec![0xffff_ffff])));
+        // 0xfffffffe enables the locktime without signaling
+        assert!(!signals_rbf(&tx(vec![0xffff_fffe, 0xffff_ffff])));
+        assert!(signals_rbf(&tx(vec![0xffff_fffd])));
+        assert!(signals_rbf(&tx(vec![0xffff_ffff, 0])));
+    }
+}
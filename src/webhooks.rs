@@ -0,0 +1,748 @@
+use bitcoin_hashes::hex::ToHex;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+use crate::chain::OutPoint;
+use crate::errors::*;
+use crate::new_index::{ChainQuery, DBFlush, DBRow, DB};
+use crate::util::{spawn_thread, Amount, Channel, FullHash, HeaderEntry, Tenant};
+use crate::ws::received_until;
+
+// Webhooks are the persistent counterpart of the WebSocket watches: a JSON payload is POSTed to
+// the registered callback URL once an address/scripthash received at least `min_value` satoshis
+// (in total) or an outpoint is worth at least `min_value`, counting only outputs with at least
+// `confirmations` confirmations. The registrations are kept in their own database, and
+// re-evaluated whenever the indexer moves the tip, so a reorg dropping the target below the
+// threshold simply delays the notification. Failed deliveries are retried with an exponential
+// backoff, across restarts, until the callback responds with a 2xx status or MAX_ATTEMPTS.
+// With tenants configured, each webhook belongs to the tenant that registered it, and is only
+// visible to it.
+
+pub const MAX_WEBHOOKS: usize = 1000;
+pub const MAX_CONFIRMATIONS: usize = 1000;
+const MAX_URL_LENGTH: usize = 2048;
+const MAX_LABEL_LENGTH: usize = 256;
+const MAX_ATTEMPTS: u32 = 10;
+const RETRY_DELAY: u64 = 10; // seconds before the first retry, doubled after each failed attempt
+const MAX_RETRY_DELAY: u64 = 3600;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RESPONSE_LINE: u64 = 1024;
+// how often the pending deliveries are retried, between the tip notifications
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const WEBHOOK_PREFIX: u8 = b'W';
+const NEXT_ID_KEY: &[u8] = b"N";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum WebhookTarget {
+    Scripthash(FullHash),
+    Outpoint(OutPoint),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum WebhookStatus {
+    Watching,
+    // the times are in seconds since the epoch, to be resumed after a restart
+    Delivering {
+        payload: String,
+        attempts: u32,
+        next_attempt: u64,
+        last_error: Option<String>,
+    },
+    Delivered {
+        at: u64,
+    },
+    Failed {
+        attempts: u32,
+        error: String,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: u64,
+    pub target: WebhookTarget,
+    pub min_value: Amount,
+    pub confirmations: usize,
+    pub url: String,
+    pub label: Option<String>,
+    pub tenant: Option<String>, // the name of the tenant that registered it
+    pub created_at: u64,
+    pub status: WebhookStatus,
+}
+
+impl Webhook {
+    pub fn to_json(&self) -> Value {
+        let target = match self.target {
+            WebhookTarget::Scripthash(ref scripthash) => {
+                json!({ "scripthash": scripthash[..].to_hex() })
+            }
+            WebhookTarget::Outpoint(ref outpoint) => {
+                json!({ "outpoint": format!("{}:{}", outpoint.txid, outpoint.vout) })
+            }
+        };
+        let status = match self.status {
+            WebhookStatus::Watching => json!({ "state": "watching" }),
+            WebhookStatus::Delivering {
+                attempts,
+                next_attempt,
+                ref last_error,
+                ..
+            } => json!({
+                "state": "delivering",
+                "attempts": attempts,
+                "next_attempt": next_attempt,
+                "last_error": last_error,
+            }),
+            WebhookStatus::Delivered { at } => json!({ "state": "delivered", "delivered_at": at }),
+            WebhookStatus::Failed {
+                attempts,
+                ref error,
+            } => json!({ "state": "failed", "attempts": attempts, "last_error": error }),
+        };
+        json!({
+            "id": self.id,
+            "target": target,
+            "min_value": self.min_value,
+            "confirmations": self.confirmations,
+            "url": self.url,
+            "label": self.label,
+            "tenant": self.tenant,
+            "created_at": self.created_at,
+            "status": status,
+        })
+    }
+
+    fn belongs_to(&self, tenant: Option<&Tenant>) -> bool {
+        self.tenant.as_ref().map(String::as_str) == tenant.map(|tenant| tenant.name.as_str())
+    }
+
+    fn key(id: u64) -> Vec<u8> {
+        let mut key = vec![WEBHOOK_PREFIX];
+        key.extend_from_slice(&id.to_be_bytes());
+        key
+    }
+
+    fn to_row(&self) -> DBRow {
+        DBRow {
+            key: Webhook::key(self.id),
+            value: bincode::serialize(self).unwrap(),
+        }
+    }
+
+    fn from_row(row: DBRow) -> Self {
+        bincode::deserialize(&row.value).expect("failed to parse webhook")
+    }
+}
+
+pub struct Webhooks {
+    db: DB,
+    state: Mutex<State>,
+}
+
+struct State {
+    webhooks: BTreeMap<u64, Webhook>,
+    next_id: u64,
+}
+
+impl Webhooks {
+    pub fn open(path: &Path) -> Webhooks {
+        let db = DB::open_webhooks(path, &[]);
+        let webhooks: BTreeMap<u64, Webhook> = db
+            .iter_scan(&[WEBHOOK_PREFIX])
+            .map(Webhook::from_row)
+            .map(|webhook| (webhook.id, webhook))
+            .collect();
+        // never reused, so that a stale id can't refer to a newer registration
+        let next_id = db.get(NEXT_ID_KEY).map_or(1, |value| {
+            bincode::deserialize(&value).expect("failed to parse the next webhook id")
+        });
+        info!("loaded {} webhooks from {:?}", webhooks.len(), path);
+        Webhooks {
+            db,
+            state: Mutex::new(State { webhooks, next_id }),
+        }
+    }
+
+    pub fn register(
+        &self,
+        target: WebhookTarget,
+        min_value: Amount,
+        confirmations: usize,
+        url: String,
+        label: Option<String>,
+        tenant: Option<&Tenant>,
+    ) -> Result<Webhook> {
+        ensure!(
+            confirmations >= 1 && confirmations <= MAX_CONFIRMATIONS,
+            "confirmations must be between 1 and {}",
+            MAX_CONFIRMATIONS
+        );
+        ensure!(url.len() <= MAX_URL_LENGTH, "url too long");
+        parse_callback_url(&url)?;
+        ensure!(
+            label.as_ref().map_or(true, |l| l.len() <= MAX_LABEL_LENGTH),
+            "label too long"
+        );
+        let mut state = self.state.lock().unwrap();
+        ensure!(
+            state.webhooks.len() < MAX_WEBHOOKS,
+            "too many webhooks (max {})",
+            MAX_WEBHOOKS
+        );
+        if let Some(tenant) = tenant {
+            let count = state
+                .webhooks
+                .values()
+                .filter(|webhook| webhook.belongs_to(Some(tenant)))
+                .count();
+            ensure!(
+                count < tenant.max_webhooks,
+                "too many webhooks for the tenant (max {})",
+                tenant.max_webhooks
+            );
+        }
+        let webhook = Webhook {
+            id: state.next_id,
+            target,
+            min_value,
+            confirmations,
+            url,
+            label,
+            tenant: tenant.map(|tenant| tenant.name.clone()),
+            created_at: unix_time(),
+            status: WebhookStatus::Watching,
+        };
+        state.next_id += 1;
+        let next_id = DBRow {
+            key: NEXT_ID_KEY.to_vec(),
+            value: bincode::serialize(&state.next_id).unwrap(),
+        };
+        self.db
+            .write(vec![webhook.to_row(), next_id], DBFlush::Enable);
+        state.webhooks.insert(webhook.id, webhook.clone());
+        Ok(webhook)
+    }
+
+    // The webhooks of other tenants are never returned (nor removed), as if they didn't exist
+    pub fn get(&self, id: u64, tenant: Option<&Tenant>) -> Option<Webhook> {
+        let state = self.state.lock().unwrap();
+        state
+            .webhooks
+            .get(&id)
+            .filter(|webhook| webhook.belongs_to(tenant))
+            .cloned()
+    }
+
+    pub fn list(&self, tenant: Option<&Tenant>) -> Vec<Webhook> {
+        self.state
+            .lock()
+            .unwrap()
+            .webhooks
+            .values()
+            .filter(|webhook| webhook.belongs_to(tenant))
+            .cloned()
+            .collect()
+    }
+
+    pub fn remove(&self, id: u64, tenant: Option<&Tenant>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let owned = state
+            .webhooks
+            .get(&id)
+            .map_or(false, |webhook| webhook.belongs_to(tenant));
+        if owned {
+            state.webhooks.remove(&id);
+            self.db.delete(vec![Webhook::key(id)]);
+        }
+        owned
+    }
+
+    // Schedule the delivery of the watching webhooks whose target reached the threshold, as of
+    // the given tip, given the amount received by a target with `confirmations` (if any)
+    fn evaluate<F>(&self, tip: &HeaderEntry, received: F)
+    where
+        F: Fn(&WebhookTarget, usize) -> Option<Amount>,
+    {
+        let now = unix_time();
+        let mut state = self.state.lock().unwrap();
+        let mut rows = vec![];
+        for webhook in state.webhooks.values_mut() {
+            if webhook.status != WebhookStatus::Watching {
+                continue;
+            }
+            let received = match received(&webhook.target, webhook.confirmations) {
+                Some(received) if received >= webhook.min_value => received,
+                _ => continue,
+            };
+            let payload = json!({
+                "event": "webhook",
+                "id": webhook.id,
+                "label": webhook.label,
+                "received": received,
+                "confirmations": webhook.confirmations,
+                "tip_height": tip.height(),
+                "tip_hash": tip.hash().to_hex(),
+            });
+            webhook.status = WebhookStatus::Delivering {
+                payload: payload.to_string(),
+                attempts: 0,
+                next_attempt: now,
+                last_error: None,
+            };
+            rows.push(webhook.to_row());
+        }
+        if !rows.is_empty() {
+            debug!("{} webhooks reached their threshold", rows.len());
+            self.db.write(rows, DBFlush::Enable);
+        }
+    }
+
+    // The deliveries to attempt by `now`, as (id, url, payload)
+    fn due(&self, now: u64) -> Vec<(u64, String, String)> {
+        let state = self.state.lock().unwrap();
+        state
+            .webhooks
+            .values()
+            .filter_map(|webhook| match webhook.status {
+                WebhookStatus::Delivering {
+                    ref payload,
+                    next_attempt,
+                    ..
+                } if next_attempt <= now => {
+                    Some((webhook.id, webhook.url.clone(), payload.clone()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Record the outcome of a delivery attempt, scheduling the next one if it failed
+    fn record_attempt(&self, id: u64, result: Result<()>, now: u64) {
+        let mut state = self.state.lock().unwrap();
+        // unless it was removed meanwhile
+        let webhook = match state.webhooks.get_mut(&id) {
+            Some(webhook) => webhook,
+            None => return,
+        };
+        let (payload, attempts) = match webhook.status {
+            WebhookStatus::Delivering {
+                ref payload,
+                attempts,
+                ..
+            } => (payload.clone(), attempts + 1),
+            _ => return,
+        };
+        webhook.status = match result {
+            Ok(()) => WebhookStatus::Delivered { at: now },
+            Err(e) if attempts >= MAX_ATTEMPTS => {
+                warn!("webhook {} failed after {} attempts: {}", id, attempts, e);
+                WebhookStatus::Failed {
+                    attempts,
+                    error: e.to_string(),
+                }
+            }
+            Err(e) => {
+                let delay = (RETRY_DELAY << (attempts - 1).min(31)).min(MAX_RETRY_DELAY);
+                debug!("webhook {} failed, retrying in {}s: {}", id, delay, e);
+                WebhookStatus::Delivering {
+                    payload,
+                    attempts,
+                    next_attempt: now + delay,
+                    last_error: Some(e.to_string()),
+                }
+            }
+        };
+        self.db.write(vec![webhook.to_row()], DBFlush::Enable);
+    }
+
+    pub fn start(webhooks: Arc<Webhooks>, chain: Arc<ChainQuery>) -> WebhookNotifier {
+        let notification = Channel::new();
+        let sender = notification.sender();
+        let thread = spawn_thread("webhooks", move || {
+            let mut tip: Option<HeaderEntry> = None;
+            loop {
+                match notification.receiver().recv_timeout(POLL_INTERVAL) {
+                    Ok(Notification::Periodic) => {
+                        let best = chain.best_header();
+                        if tip.as_ref() != Some(&best) {
+                            webhooks.evaluate(&best, |target, confirmations| {
+                                target_received(&chain, target, confirmations, best.height())
+                            });
+                            tip = Some(best);
+                        }
+                    }
+                    Ok(Notification::Exit) | Err(RecvTimeoutError::Disconnected) => break,
+                    Err(RecvTimeoutError::Timeout) => (),
+                }
+                for (id, url, payload) in webhooks.due(unix_time()) {
+                    // the pending deliveries are resumed on restart, rather than delaying it
+                    if let Ok(Notification::Exit) = notification.receiver().try_recv() {
+                        return;
+                    }
+                    let result = post_json(&url, &payload);
+                    webhooks.record_attempt(id, result, unix_time());
+                }
+            }
+        });
+        WebhookNotifier {
+            notification: sender,
+            thread: Some(thread),
+        }
+    }
+}
+
+enum Notification {
+    Periodic,
+    Exit,
+}
+
+// Evaluates the webhooks on new tips and delivers them, until dropped
+pub struct WebhookNotifier {
+    notification: Sender<Notification>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl WebhookNotifier {
+    pub fn notify(&self) {
+        self.notification.send(Notification::Periodic).unwrap();
+    }
+}
+
+impl Drop for WebhookNotifier {
+    fn drop(&mut self) {
+        trace!("stop delivering webhooks");
+        self.notification.send(Notification::Exit).unwrap();
+        self.thread.take().map(|t| t.join().unwrap());
+        trace!("webhooks are stopped");
+    }
+}
+
+// The amount received by the target in blocks with at least `confirmations`, re-read on every
+// new tip (bounded by MAX_WEBHOOKS)
+fn target_received(
+    chain: &ChainQuery,
+    target: &WebhookTarget,
+    confirmations: usize,
+    tip_height: usize,
+) -> Option<Amount> {
+    // the highest block with enough confirmations
+    let max_height = (tip_height + 1).checked_sub(confirmations)?;
+    match target {
+        WebhookTarget::Scripthash(scripthash) => {
+            Some(received_until(&chain.fundings(&scripthash[..]), max_height))
+        }
+        WebhookTarget::Outpoint(outpoint) => {
+            let blockid = chain.tx_confirming_block(&outpoint.txid)?;
+            if blockid.height > max_height {
+                return None;
+            }
+            Some(Amount::from_sat(chain.lookup_txo(outpoint)?.value))
+        }
+    }
+}
+
+fn parse_callback_url(url: &str) -> Result<Url> {
+    let url = Url::parse(url).chain_err(|| "invalid url")?;
+    // the callbacks are expected on the operator's network, e.g. behind a TLS-terminating proxy
+    ensure!(
+        url.scheme() == "http",
+        "only http:// callbacks are supported"
+    );
+    ensure!(url.host_str().is_some(), "missing url host");
+    Ok(url)
+}
+
+// A minimal HTTP/1.1 client, enough for the callbacks to be acknowledged
+fn post_json(url: &str, payload: &str) -> Result<()> {
+    let url = parse_callback_url(url)?;
+    let host = url.host_str().unwrap();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addr = (host, port)
+        .to_socket_addrs()
+        .chain_err(|| format!("failed to resolve {}", host))?
+        .next()
+        .chain_err(|| format!("no address for {}", host))?;
+    let mut stream = TcpStream::connect_timeout(&addr, DELIVERY_TIMEOUT)
+        .chain_err(|| format!("failed to connect to {}", addr))?;
+    stream.set_read_timeout(Some(DELIVERY_TIMEOUT)).unwrap();
+    stream.set_write_timeout(Some(DELIVERY_TIMEOUT)).unwrap();
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host_header,
+        payload.len(),
+        payload
+    );
+    stream
+        .write_all(request.as_bytes())
+        .chain_err(|| "failed to send the payload")?;
+    let mut status_line = String::new();
+    BufReader::new(stream.take(MAX_RESPONSE_LINE))
+        .read_line(&mut status_line)
+        .chain_err(|| "failed to read the response")?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .chain_err(|| format!("invalid response {:?}", status_line.trim_end()))?;
+    ensure!(
+        (200..300).contains(&status),
+        "callback responded with status {}",
+        status
+    );
+    Ok(())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        post_json, Webhook, WebhookStatus, WebhookTarget, Webhooks, MAX_ATTEMPTS, RETRY_DELAY,
+    };
+    use crate::chain::OutPoint;
+    use crate::errors::*;
+    use crate::util::{parse_tenant, Amount, HeaderList};
+    use bitcoin::BlockHeader;
+    use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tempfile;
+
+    fn delivering(webhook: &Webhook) -> (u32, u64) {
+        match webhook.status {
+            WebhookStatus::Delivering {
+                attempts,
+                next_attempt,
+                ..
+            } => (attempts, next_attempt),
+            ref status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn test_webhooks_lifecycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("webhooks");
+        let tip = HeaderList::empty()
+            .order(vec![BlockHeader {
+                version: 1,
+                prev_blockhash: Sha256dHash::default(),
+                merkle_root: Sha256dHash::default(),
+                time: 0,
+                bits: 0,
+                nonce: 0,
+            }])
+            .remove(0);
+        let url = "http://127.0.0.1:1/callback".to_string();
+        let scripthash = WebhookTarget::Scripthash([1; 32]);
+        let outpoint = WebhookTarget::Outpoint(OutPoint::null());
+
+        let webhooks = Webhooks::open(&path);
+        let register = |target: &WebhookTarget, min_value| {
+            webhooks.register(
+                target.clone(),
+                Amount::from_sat(min_value),
+                6,
+                url.clone(),
+                None,
+                None,
+            )
+        };
+        assert_eq!(register(&scripthash, 1000).unwrap().id, 1);
+        assert_eq!(register(&outpoint, 0).unwrap().id, 2);
+        assert_eq!(register(&outpoint, 0).unwrap().id, 3);
+        assert!(webhooks.remove(3, None));
+        assert!(!webhooks.remove(3, None));
+        assert!(webhooks
+            .register(
+                outpoint.clone(),
+                Amount::from_sat(0),
+                0,
+                url.clone(),
+                None,
+                None
+            )
+            .is_err());
+        assert!(webhooks
+            .register(
+                outpoint.clone(),
+                Amount::from_sat(0),
+                1,
+                "https://a/".into(),
+                None,
+                None
+            )
+            .is_err());
+        drop(webhooks);
+
+        // the registrations survive restarts, and their ids aren't reused
+        let webhooks = Webhooks::open(&path);
+        let ids: Vec<u64> = webhooks.list(None).iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        let webhook = webhooks
+            .register(
+                outpoint.clone(),
+                Amount::from_sat(0),
+                1,
+                url.clone(),
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(webhook.id, 4);
+        webhooks.remove(4, None);
+
+        // below the threshold, or without enough confirmations
+        webhooks.evaluate(&tip, |target, _| match target {
+            WebhookTarget::Scripthash(_) => Some(Amount::from_sat(999)),
+            WebhookTarget::Outpoint(_) => None,
+        });
+        assert!(webhooks.due(u64::max_value()).is_empty());
+
+        webhooks.evaluate(&tip, |target, confirmations| {
+            assert_eq!(confirmations, 6);
+            match target {
+                WebhookTarget::Scripthash(_) => Some(Amount::from_sat(1000)),
+                WebhookTarget::Outpoint(_) => None,
+            }
+        });
+        let due = webhooks.due(u64::max_value());
+        assert_eq!(due.len(), 1);
+        let (id, _, ref payload) = due[0];
+        assert_eq!(id, 1);
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["received"], 1000);
+        assert_eq!(payload["tip_height"], 0);
+
+        // retried with an exponential backoff, across restarts
+        webhooks.record_attempt(1, Err("refused".into()), 100);
+        assert_eq!(
+            delivering(&webhooks.get(1, None).unwrap()),
+            (1, 100 + RETRY_DELAY)
+        );
+        assert!(webhooks.due(100 + RETRY_DELAY - 1).is_empty());
+        webhooks.record_attempt(1, Err("refused".into()), 200);
+        assert_eq!(
+            delivering(&webhooks.get(1, None).unwrap()),
+            (2, 200 + 2 * RETRY_DELAY)
+        );
+        drop(webhooks);
+        let webhooks = Webhooks::open(&path);
+        assert_eq!(
+            delivering(&webhooks.get(1, None).unwrap()),
+            (2, 200 + 2 * RETRY_DELAY)
+        );
+        webhooks.record_attempt(1, Ok(()), 300);
+        assert_eq!(
+            webhooks.get(1, None).unwrap().status,
+            WebhookStatus::Delivered { at: 300 }
+        );
+        // one-shot, even if the target is re-evaluated
+        webhooks.evaluate(&tip, |_, _| Some(Amount::from_sat(1000)));
+        assert!(webhooks.due(u64::max_value()).is_empty());
+
+        // given up after MAX_ATTEMPTS
+        webhooks.evaluate(&tip, |_, _| Some(Amount::from_sat(0)));
+        for attempt in 0..MAX_ATTEMPTS {
+            assert_eq!(webhooks.due(u64::max_value()).len(), 1);
+            webhooks.record_attempt(2, Err("refused".into()), u64::from(attempt));
+        }
+        match webhooks.get(2, None).unwrap().status {
+            WebhookStatus::Failed { attempts, .. } => assert_eq!(attempts, MAX_ATTEMPTS),
+            ref status => panic!("unexpected status {:?}", status),
+        }
+        assert!(webhooks.due(u64::max_value()).is_empty());
+    }
+
+    #[test]
+    fn test_webhooks_tenants() {
+        let dir = tempfile::tempdir().unwrap();
+        let webhooks = Webhooks::open(&dir.path().join("webhooks"));
+        let alice = parse_tenant("alice:k1@2/10").unwrap();
+        let bob = parse_tenant("bob:k2").unwrap();
+        let register = |tenant| {
+            webhooks.register(
+                WebhookTarget::Outpoint(OutPoint::null()),
+                Amount::from_sat(0),
+                1,
+                "http://127.0.0.1:1/".to_string(),
+                None,
+                tenant,
+            )
+        };
+        assert_eq!(register(Some(&alice)).unwrap().id, 1);
+        assert_eq!(register(Some(&alice)).unwrap().id, 2);
+        // counted against the tenant's own quota
+        assert!(register(Some(&alice)).is_err());
+        let webhook = register(Some(&bob)).unwrap();
+        assert_eq!(webhook.tenant, Some("bob".to_string()));
+        assert_eq!(register(None).unwrap().id, 4);
+
+        // each tenant only sees (and removes) its own webhooks
+        let ids = |tenant| -> Vec<u64> { webhooks.list(tenant).iter().map(|w| w.id).collect() };
+        assert_eq!(ids(Some(&alice)), vec![1, 2]);
+        assert_eq!(ids(Some(&bob)), vec![3]);
+        assert_eq!(ids(None), vec![4]);
+        assert!(webhooks.get(1, Some(&bob)).is_none());
+        assert!(webhooks.get(3, None).is_none());
+        assert!(!webhooks.remove(1, Some(&bob)));
+        assert!(webhooks.remove(1, Some(&alice)));
+        assert!(register(Some(&alice)).is_ok());
+    }
+
+    #[test]
+    fn test_post_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook?key=1", listener.local_addr().unwrap());
+        let respond = |status: &'static str| {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"{}") {
+                let len = stream.read(&mut buf).unwrap();
+                assert!(len > 0);
+                request.extend_from_slice(&buf[..len]);
+            }
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+            String::from_utf8(request).unwrap()
+        };
+
+        let client = {
+            let url = url.clone();
+            thread::spawn(move || -> Result<()> { post_json(&url, "{}") })
+        };
+        let request = respond("204 No Content");
+        assert!(request.starts_with("POST /hook?key=1 HTTP/1.1\r\n"));
+        assert!(request.ends_with("\r\n\r\n{}"));
+        assert!(client.join().unwrap().is_ok());
+
+        let client = thread::spawn(move || post_json(&url, "{}"));
+        respond("500 Internal Server Error");
+        assert!(client.join().unwrap().is_err());
+    }
+}
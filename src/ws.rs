@@ -0,0 +1,1647 @@
+use base64;
+use bitcoin_hashes::hex::FromHex;
+use bitcoin_hashes::sha256d::Hash as Sha256dHash;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use error_chain::ChainedError;
+use hex;
+use serde_json::{from_str, Value};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use url::form_urlencoded;
+
+#[cfg(not(feature = "liquid"))]
+use crate::chain::Network;
+use crate::chain::{AddressParams, OutPoint, Transaction, TxOut};
+use crate::config::Config;
+use crate::errors::*;
+use crate::metrics::{MetricOpts, Metrics};
+use crate::new_index::{compute_script_hash, ChainQuery, Query};
+use crate::util::{
+    authenticate, full_hash, has_prevout, spawn_thread, BlockId, Channel, FullHash, HeaderEntry,
+    NotifyBuffer, SyncChannel, Tenant,
+};
+#[cfg(not(feature = "liquid"))]
+use crate::util::{descriptor::XpubDescriptor, parse_outpoint, Amount};
+#[cfg(not(feature = "liquid"))]
+use {bitcoin::network::constants::Network as BNetwork, secp256k1::Secp256k1};
+
+// A minimal WebSocket (RFC 6455) server, pushing new blocks, new mempool transactions and
+// scripthash activity to subscribed clients. Requests are JSON text messages, e.g.:
+//      {"subscribe": "blocks"}
+//      {"subscribe": "mempool"}
+//      {"subscribe": "scripthash", "scripthash": "<hex>"}
+//      {"subscribe": "address", "address": "<address>"}
+// (and the same with "unsubscribe").
+//
+// Watches are one-shot notifications, fired once an address/scripthash received at least
+// `min_value` satoshis (in total) or an outpoint is worth at least `min_value`, counting only
+// outputs with at least `confirmations` confirmations (default 1):
+//      {"subscribe": "watch", "address": "<address>", "min_value": 100000, "confirmations": 6}
+//      {"subscribe": "watch", "outpoint": "<txid>:<vout>", "min_value": 0, "confirmations": 3}
+// The reply includes the watch id, used to cancel it with {"unsubscribe": "watch", "id": <id>}.
+//
+// Registered descriptors (as accepted by /xpub) are watched as a whole, deriving new scripts
+// ahead of the last used one as the wallet gets used, so that `--xpub-gap-limit` unused scripts
+// are always watched (for each chain). Their activity is reported in a single event per update:
+//      {"subscribe": "descriptor", "descriptor": "wpkh(xpub.../0/*)"}
+// The reply includes the descriptor id, used to cancel it with
+// {"unsubscribe": "descriptor", "id": <id>}.
+//
+// With `--notify-debounce`, the scripthash and descriptor events are buffered and then sent as
+// a single event per subscription, listing the transactions that changed over the window.
+//
+// With `--tenant`, the connections authenticate with the API key of their tenant, sent in the
+// X-API-Key header of the handshake or as its `api_key` query parameter (e.g. `/?api_key=<key>`,
+// for browsers), and the scripts they watch are counted against the tenant's quota.
+
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_FRAME_SIZE: u64 = 64 * 1024; // client messages are small
+const MAX_HANDSHAKE_SIZE: u64 = 8 * 1024; // the HTTP upgrade request, including its headers
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_CONNECTIONS: usize = 100;
+// updates spanning more blocks or new mempool transactions than this re-check every subscription
+const MAX_UPDATE_BLOCKS: usize = 10;
+const MAX_UPDATE_MEMPOOL_TXS: usize = 10_000;
+const MAX_TRACKED_MEMPOOL_TXS: usize = 100_000;
+const MAX_SUBSCRIPTIONS: usize = 1000; // scripthashes (and watches) per connection
+#[cfg(not(feature = "liquid"))]
+const MAX_WATCH_CONFIRMATIONS: usize = 1000;
+#[cfg(not(feature = "liquid"))]
+const MAX_DESCRIPTORS: usize = 10; // per connection
+#[cfg(not(feature = "liquid"))]
+const MAX_DESCRIPTOR_SCRIPTS: usize = 5000; // derived per descriptor
+const MAX_WATCHED_SCRIPTS: usize = 100_000; // subscribed and derived, by all the connections
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+type History = HashMap<Sha256dHash, Option<usize>>; // txid -> height
+
+// Changes since the previous periodic update, shared between all connections
+struct Update {
+    block: Option<Value>,
+    mempool_txids: Vec<Sha256dHash>,
+    tip_changed: bool,
+    // the scripthashes whose history changed, or None if unknown (e.g. following a reorg)
+    touched: Option<HashSet<FullHash>>,
+}
+
+impl Update {
+    fn touches(&self, scripthash: &FullHash) -> bool {
+        self.touched
+            .as_ref()
+            .map_or(true, |touched| touched.contains(scripthash))
+    }
+}
+
+// The notifier's view as of the previous update
+#[derive(Default)]
+struct UpdateState {
+    tip: Option<HeaderEntry>,
+    mempool: HashSet<Sha256dHash>,
+    // the scripthashes of (up to MAX_TRACKED_MEMPOOL_TXS of) the mempool transactions, to know
+    // the ones touched by their eviction
+    mempool_scripthashes: HashMap<Sha256dHash, Vec<FullHash>>,
+}
+
+// The subscriptions whose events are buffered
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Subscription {
+    Scripthash(FullHash),
+    #[cfg(not(feature = "liquid"))]
+    Descriptor(usize),
+}
+
+enum Message {
+    Request(String),
+    Ping(Vec<u8>),
+    Update(Arc<Update>),
+    Done,
+}
+
+pub enum Notification {
+    Periodic,
+    Exit,
+}
+
+// Re-evaluated whenever the tip changes, so a reorg dropping the target below the threshold
+// simply delays the notification. The confirmed fundings of scripthash targets are only read again
+// when new blocks touch them.
+#[cfg(not(feature = "liquid"))]
+struct Watch {
+    id: usize,
+    target: WatchTarget,
+    min_value: Amount,
+    confirmations: usize,
+    checked: bool, // evaluated at least once
+}
+
+#[cfg(not(feature = "liquid"))]
+enum WatchTarget {
+    Scripthash(FullHash, Vec<(usize, Amount)>), // with its fundings, as (height, value)
+    Outpoint(OutPoint),
+}
+
+#[cfg(not(feature = "liquid"))]
+struct DescriptorWatch {
+    id: usize,
+    desc: XpubDescriptor,
+    scripts: Vec<Vec<FullHash>>, // the derived scripthashes, by chain and index
+    derived: HashMap<FullHash, DerivedScript>, // indexed once derived, for the touched lookups
+}
+
+#[cfg(not(feature = "liquid"))]
+struct DerivedScript {
+    chain: usize,
+    index: usize,
+    history: History,
+}
+
+#[cfg(not(feature = "liquid"))]
+impl DescriptorWatch {
+    fn new(id: usize, desc: XpubDescriptor) -> Self {
+        DescriptorWatch {
+            id,
+            scripts: vec![vec![]; desc.chains()],
+            desc,
+            derived: HashMap::new(),
+        }
+    }
+
+    fn derived(&self) -> usize {
+        self.derived.len()
+    }
+
+    // The index following each chain's derived scripts
+    fn next_indexes(&self) -> Vec<usize> {
+        self.scripts.iter().map(Vec::len).collect()
+    }
+
+    fn tx_count(&self) -> usize {
+        self.derived
+            .values()
+            .map(|script| script.history.len())
+            .sum()
+    }
+
+    // Derive the scripts of each chain until `gap_limit` of them follow the last used one,
+    // returning how many were derived
+    fn derive_ahead<H>(
+        &mut self,
+        gap_limit: usize,
+        budget: &mut ScriptBudget,
+        history: H,
+    ) -> Result<usize>
+    where
+        H: Fn(&FullHash) -> History,
+    {
+        let secp = Secp256k1::verification_only();
+        let mut derived = 0;
+        for chain in 0..self.scripts.len() {
+            let scripts = &self.scripts[chain];
+            let derived_scripts = &self.derived;
+            let mut used = scripts
+                .iter()
+                .rposition(|scripthash| !derived_scripts[scripthash].history.is_empty())
+                .map_or(0, |index| index + 1);
+            while self.scripts[chain].len() < used + gap_limit {
+                ensure!(
+                    self.derived() < MAX_DESCRIPTOR_SCRIPTS,
+                    "too many scripts to derive (max {})",
+                    MAX_DESCRIPTOR_SCRIPTS
+                );
+                let index = self.scripts[chain].len();
+                let script = self.desc.derive(&secp, chain, index as u32)?;
+                budget.reserve(1)?;
+                let scripthash = compute_script_hash(&script);
+                let history = history(&scripthash);
+                if !history.is_empty() {
+                    used = index + 1;
+                }
+                self.scripts[chain].push(scripthash);
+                self.derived.insert(
+                    scripthash,
+                    DerivedScript {
+                        chain,
+                        index,
+                        history,
+                    },
+                );
+                derived += 1;
+            }
+        }
+        Ok(derived)
+    }
+
+    // The activity of the derived scripts touched by the update, deriving more of them as they
+    // get used
+    fn refresh<H>(
+        &mut self,
+        update: &Update,
+        gap_limit: usize,
+        budget: &mut ScriptBudget,
+        history: H,
+    ) -> Option<Value>
+    where
+        H: Fn(&FullHash) -> History,
+    {
+        let mut touched: Vec<FullHash> = match update.touched {
+            Some(ref touched) if touched.len() < self.derived.len() => touched
+                .iter()
+                .filter(|scripthash| self.derived.contains_key(*scripthash))
+                .cloned()
+                .collect(),
+            _ => self
+                .derived
+                .keys()
+                .filter(|scripthash| update.touches(scripthash))
+                .cloned()
+                .collect(),
+        };
+        touched.sort_by_key(|scripthash| {
+            let script = &self.derived[scripthash];
+            (script.chain, script.index)
+        });
+
+        let mut changed = vec![];
+        for scripthash in touched {
+            let new_history = history(&scripthash);
+            let script = self.derived.get_mut(&scripthash).unwrap();
+            for (txid, height) in &new_history {
+                if script.history.get(txid) != Some(height) {
+                    changed.push(json!({
+                        "txid": txid,
+                        "block_height": height,
+                        "scripthash": hex::encode(scripthash),
+                        "chain": script.chain,
+                        "index": script.index,
+                    }));
+                }
+            }
+            script.history = new_history;
+        }
+        let derived = match self.derive_ahead(gap_limit, budget, history) {
+            Ok(derived) => derived,
+            Err(e) => {
+                // keep watching the scripts derived so far
+                warn!("descriptor {}: {}", self.id, e);
+                0
+            }
+        };
+        if changed.is_empty() && derived == 0 {
+            return None;
+        }
+        Some(json!({
+            "event": "descriptor",
+            "id": self.id,
+            "txs": changed,
+            "derived": derived,
+            "next_index": self.next_indexes(),
+        }))
+    }
+}
+
+// The scripts watched by a connection (its scripthash subscriptions and the scripts derived for
+// its descriptors), counted against the MAX_WATCHED_SCRIPTS shared by all the connections, and
+// against the quota shared by the connections of its tenant (if any)
+struct ScriptBudget {
+    total: Arc<AtomicUsize>,
+    tenant: Option<(Arc<AtomicUsize>, usize)>, // watched by the tenant's connections, and its quota
+    reserved: usize,
+}
+
+impl ScriptBudget {
+    fn new(total: Arc<AtomicUsize>) -> Self {
+        ScriptBudget {
+            total,
+            tenant: None,
+            reserved: 0,
+        }
+    }
+
+    // Once the connection authenticated, before it reserved anything
+    fn set_tenant(&mut self, watched: Arc<AtomicUsize>, quota: usize) {
+        assert_eq!(self.reserved, 0);
+        self.tenant = Some((watched, quota));
+    }
+
+    fn reserve(&mut self, count: usize) -> Result<()> {
+        ensure!(
+            try_add(&self.total, count, MAX_WATCHED_SCRIPTS),
+            "too many scripts watched by the server (max {})",
+            MAX_WATCHED_SCRIPTS
+        );
+        if let Some((ref watched, quota)) = self.tenant {
+            if !try_add(watched, count, quota) {
+                self.total.fetch_sub(count, Ordering::SeqCst);
+                bail!("too many scripts watched by the tenant (max {})", quota);
+            }
+        }
+        self.reserved += count;
+        Ok(())
+    }
+
+    fn release(&mut self, count: usize) {
+        let count = count.min(self.reserved);
+        self.total.fetch_sub(count, Ordering::SeqCst);
+        if let Some((ref watched, _)) = self.tenant {
+            watched.fetch_sub(count, Ordering::SeqCst);
+        }
+        self.reserved -= count;
+    }
+}
+
+impl Drop for ScriptBudget {
+    fn drop(&mut self) {
+        let reserved = self.reserved;
+        self.release(reserved);
+    }
+}
+
+// Add `count` to the counter, unless it would exceed `max`
+fn try_add(counter: &AtomicUsize, count: usize, max: usize) -> bool {
+    let mut current = counter.load(Ordering::SeqCst);
+    loop {
+        if current + count > max {
+            return false;
+        }
+        match counter.compare_exchange(current, current + count, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(_) => return true,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+fn script_history(query: &Query, scripthash: &FullHash) -> History {
+    query
+        .history_txids(&scripthash[..])
+        .into_iter()
+        .map(|(txid, blockid)| (txid, blockid.map(|b| b.height)))
+        .collect()
+}
+
+struct Connection {
+    query: Arc<Query>,
+    #[cfg(not(feature = "liquid"))]
+    network: Network,
+    address_params: &'static AddressParams,
+    blocks: bool,
+    mempool: bool,
+    scripthashes: HashMap<FullHash, History>,
+    budget: ScriptBudget,
+    pending: NotifyBuffer<Subscription, Value>,
+    #[cfg(not(feature = "liquid"))]
+    watches: Vec<Watch>,
+    #[cfg(not(feature = "liquid"))]
+    next_watch_id: usize,
+    #[cfg(not(feature = "liquid"))]
+    descriptors: Vec<DescriptorWatch>,
+    #[cfg(not(feature = "liquid"))]
+    gap_limit: usize,
+    tenants: Arc<TenantsWatched>,
+    stream: TcpStream,
+    addr: SocketAddr,
+    chan: SyncChannel<Message>,
+}
+
+// The configured tenants, along with the number of scripts watched by the connections of each
+type TenantsWatched = Vec<(Tenant, Arc<AtomicUsize>)>;
+
+impl Connection {
+    fn new(
+        query: Arc<Query>,
+        config: &Config,
+        watched: Arc<AtomicUsize>,
+        tenants: Arc<TenantsWatched>,
+        stream: TcpStream,
+        addr: SocketAddr,
+    ) -> Self {
+        Connection {
+            query,
+            #[cfg(not(feature = "liquid"))]
+            network: config.network_type,
+            address_params: config.address_params,
+            blocks: false,
+            mempool: false,
+            scripthashes: HashMap::new(),
+            budget: ScriptBudget::new(watched),
+            pending: NotifyBuffer::new(config.notify_debounce),
+            #[cfg(not(feature = "liquid"))]
+            watches: vec![],
+            #[cfg(not(feature = "liquid"))]
+            next_watch_id: 0,
+            #[cfg(not(feature = "liquid"))]
+            descriptors: vec![],
+            #[cfg(not(feature = "liquid"))]
+            gap_limit: config.xpub_gap_limit,
+            tenants,
+            stream,
+            addr,
+            chan: SyncChannel::new(10),
+        }
+    }
+
+    fn parse_scripthash(&self, cmd: &Value) -> Result<FullHash> {
+        if let Some(scripthash) = cmd.get("scripthash").and_then(Value::as_str) {
+            let scripthash = Vec::<u8>::from_hex(scripthash).chain_err(|| "non-hex scripthash")?;
+            ensure!(scripthash.len() == 32, "invalid scripthash length");
+            Ok(full_hash(&scripthash))
+        } else if let Some(address) = cmd.get("address").and_then(Value::as_str) {
+            let script = address_to_script(address, self.address_params)?;
+            Ok(compute_script_hash(&script))
+        } else {
+            bail!("missing scripthash or address")
+        }
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn add_watch(&mut self, cmd: &Value) -> Result<Value> {
+        ensure!(self.watches.len() < MAX_SUBSCRIPTIONS, "too many watches");
+        let target = match cmd.get("outpoint").and_then(Value::as_str) {
+            Some(outpoint) => WatchTarget::Outpoint(parse_outpoint(outpoint)?),
+            None => {
+                let scripthash = self.parse_scripthash(cmd)?;
+                let fundings = self.query.chain().fundings(&scripthash[..]);
+                WatchTarget::Scripthash(scripthash, fundings)
+            }
+        };
+        let min_value = cmd
+            .get("min_value")
+            .and_then(Value::as_u64)
+            .chain_err(|| "missing min_value")?;
+        let confirmations = match cmd.get("confirmations") {
+            Some(confirmations) => confirmations
+                .as_u64()
+                .chain_err(|| "invalid confirmations")? as usize,
+            None => 1,
+        };
+        ensure!(
+            confirmations >= 1 && confirmations <= MAX_WATCH_CONFIRMATIONS,
+            "confirmations must be between 1 and {}",
+            MAX_WATCH_CONFIRMATIONS
+        );
+        self.next_watch_id += 1;
+        let id = self.next_watch_id;
+        self.watches.push(Watch {
+            id,
+            target,
+            min_value: Amount::from_sat(min_value),
+            confirmations,
+            checked: false,
+        });
+        Ok(json!({ "subscribed": "watch", "id": id }))
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn remove_watch(&mut self, cmd: &Value) -> Result<Value> {
+        let id = cmd
+            .get("id")
+            .and_then(Value::as_u64)
+            .chain_err(|| "missing watch id")? as usize;
+        let count = self.watches.len();
+        self.watches.retain(|watch| watch.id != id);
+        ensure!(self.watches.len() < count, "unknown watch {}", id);
+        Ok(json!({ "unsubscribed": "watch", "id": id }))
+    }
+
+    // The amount received by the watch target, if it reached the threshold
+    #[cfg(not(feature = "liquid"))]
+    fn watch_received(&self, watch: &Watch, tip_height: usize) -> Option<Amount> {
+        // the highest block with enough confirmations
+        let max_height = (tip_height + 1).checked_sub(watch.confirmations)?;
+        let received = match watch.target {
+            WatchTarget::Scripthash(_, ref fundings) => received_until(fundings, max_height),
+            WatchTarget::Outpoint(ref outpoint) => {
+                let chain = self.query.chain();
+                let blockid = chain.tx_confirming_block(&outpoint.txid)?;
+                if blockid.height > max_height {
+                    return None;
+                }
+                Amount::from_sat(chain.lookup_txo(outpoint)?.value)
+            }
+        };
+        Some(received).filter(|received| *received >= watch.min_value)
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn check_watches(&mut self, update: &Update) -> Vec<Value> {
+        let tip_height = self.query.chain().best_height();
+        let mut events = vec![];
+        for mut watch in self.watches.split_off(0) {
+            if watch.checked && !update.tip_changed {
+                self.watches.push(watch);
+                continue;
+            }
+            if let WatchTarget::Scripthash(ref scripthash, ref mut fundings) = watch.target {
+                if watch.checked && update.touches(scripthash) {
+                    *fundings = self.query.chain().fundings(&scripthash[..]);
+                }
+            }
+            watch.checked = true;
+            match self.watch_received(&watch, tip_height) {
+                Some(received) => events.push(json!({
+                    "event": "watch",
+                    "id": watch.id,
+                    "received": received,
+                    "confirmations": watch.confirmations,
+                    "tip_height": tip_height,
+                })),
+                None => self.watches.push(watch),
+            }
+        }
+        events
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn add_descriptor(&mut self, cmd: &Value) -> Result<Value> {
+        ensure!(
+            self.descriptors.len() < MAX_DESCRIPTORS,
+            "too many descriptors"
+        );
+        let desc = cmd
+            .get("descriptor")
+            .and_then(Value::as_str)
+            .chain_err(|| "missing descriptor")?;
+        let desc = XpubDescriptor::parse(desc, BNetwork::from(&self.network))?;
+        self.next_watch_id += 1;
+        let mut watch = DescriptorWatch::new(self.next_watch_id, desc);
+        let query = &self.query;
+        let derived = watch.derive_ahead(self.gap_limit, &mut self.budget, |scripthash| {
+            script_history(query, scripthash)
+        });
+        if let Err(e) = derived {
+            self.budget.release(watch.derived());
+            return Err(e);
+        }
+        let reply = json!({
+            "subscribed": "descriptor",
+            "id": watch.id,
+            "derived": watch.derived(),
+            "next_index": watch.next_indexes(),
+            "tx_count": watch.tx_count(),
+        });
+        self.descriptors.push(watch);
+        Ok(reply)
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn remove_descriptor(&mut self, cmd: &Value) -> Result<Value> {
+        let id = cmd
+            .get("id")
+            .and_then(Value::as_u64)
+            .chain_err(|| "missing descriptor id")? as usize;
+        let position = self
+            .descriptors
+            .iter()
+            .position(|watch| watch.id == id)
+            .chain_err(|| format!("unknown descriptor {}", id))?;
+        let watch = self.descriptors.remove(position);
+        self.budget.release(watch.derived());
+        self.pending.remove(&Subscription::Descriptor(id));
+        Ok(json!({ "unsubscribed": "descriptor", "id": id }))
+    }
+
+    // The activity of each descriptor's scripts, including those derived as it got used
+    #[cfg(not(feature = "liquid"))]
+    fn check_descriptors(&mut self, update: &Update) -> Vec<Value> {
+        let query = &self.query;
+        let budget = &mut self.budget;
+        let gap_limit = self.gap_limit;
+        self.descriptors
+            .iter_mut()
+            .filter_map(|watch| {
+                watch.refresh(update, gap_limit, budget, |scripthash| {
+                    script_history(query, scripthash)
+                })
+            })
+            .collect()
+    }
+
+    fn handle_request(&mut self, line: &str) -> Result<Value> {
+        let cmd: Value = from_str(line).chain_err(|| "invalid JSON format")?;
+        let (subscribe, topic) = match (cmd.get("subscribe"), cmd.get("unsubscribe")) {
+            (Some(Value::String(topic)), None) => (true, topic.as_str()),
+            (None, Some(Value::String(topic))) => (false, topic.as_str()),
+            _ => bail!("invalid request: {}", cmd),
+        };
+        match topic {
+            "blocks" => self.blocks = subscribe,
+            "mempool" => self.mempool = subscribe,
+            "scripthash" | "address" => {
+                let scripthash = self.parse_scripthash(&cmd)?;
+                if !subscribe {
+                    if self.scripthashes.remove(&scripthash).is_some() {
+                        self.budget.release(1);
+                        self.pending.remove(&Subscription::Scripthash(scripthash));
+                    }
+                } else if !self.scripthashes.contains_key(&scripthash) {
+                    ensure!(
+                        self.scripthashes.len() < MAX_SUBSCRIPTIONS,
+                        "too many subscriptions"
+                    );
+                    self.budget.reserve(1)?;
+                    let history = script_history(&self.query, &scripthash);
+                    self.scripthashes.insert(scripthash, history);
+                }
+            }
+            #[cfg(not(feature = "liquid"))]
+            "watch" if subscribe => return self.add_watch(&cmd),
+            #[cfg(not(feature = "liquid"))]
+            "watch" => return self.remove_watch(&cmd),
+            #[cfg(not(feature = "liquid"))]
+            "descriptor" if subscribe => return self.add_descriptor(&cmd),
+            #[cfg(not(feature = "liquid"))]
+            "descriptor" => return self.remove_descriptor(&cmd),
+            _ => bail!("unknown topic {}", topic),
+        }
+        let status = if subscribe {
+            "subscribed"
+        } else {
+            "unsubscribed"
+        };
+        Ok(json!({ status: topic }))
+    }
+
+    fn handle_update(&mut self, update: &Update) -> Vec<Value> {
+        let now = Instant::now();
+        let mut events = vec![];
+        if self.blocks {
+            events.extend(update.block.clone());
+        }
+        if self.mempool && !update.mempool_txids.is_empty() {
+            events.push(json!({"event": "mempool", "txids": update.mempool_txids}));
+        }
+        let scripthashes: Vec<FullHash> = self
+            .scripthashes
+            .keys()
+            .filter(|scripthash| update.touches(scripthash))
+            .cloned()
+            .collect();
+        for scripthash in scripthashes {
+            let history = script_history(&self.query, &scripthash);
+            let old_history = self.scripthashes.insert(scripthash, history).unwrap();
+            let history = &self.scripthashes[&scripthash];
+            // new transactions, and transactions whose confirmation status changed
+            let changed: Vec<Value> = history
+                .iter()
+                .filter(|(txid, height)| old_history.get(*txid) != Some(*height))
+                .map(|(txid, height)| json!({"txid": txid, "block_height": height}))
+                .collect();
+            if !changed.is_empty() {
+                let event = json!({
+                    "event": "scripthash",
+                    "scripthash": hex::encode(scripthash),
+                    "txs": changed,
+                });
+                self.pending.push(
+                    Subscription::Scripthash(scripthash),
+                    event,
+                    now,
+                    merge_events,
+                );
+            }
+        }
+        #[cfg(not(feature = "liquid"))]
+        {
+            events.extend(self.check_watches(update));
+            for event in self.check_descriptors(update) {
+                let id = event["id"].as_u64().unwrap() as usize;
+                self.pending
+                    .push(Subscription::Descriptor(id), event, now, merge_events);
+            }
+        }
+        events.extend(self.pending.flush(now).into_iter().map(|(_, event)| event));
+        events
+    }
+
+    fn send_values(&mut self, values: &[Value]) -> Result<()> {
+        for value in values {
+            write_frame(&mut self.stream, OPCODE_TEXT, value.to_string().as_bytes())
+                .chain_err(|| format!("failed to send {}", value))?;
+        }
+        Ok(())
+    }
+
+    fn handle_replies(&mut self) -> Result<()> {
+        loop {
+            let msg = self.chan.receiver().recv().chain_err(|| "channel closed")?;
+            match msg {
+                Message::Request(line) => {
+                    let reply = self
+                        .handle_request(&line)
+                        .unwrap_or_else(|e| json!({ "error": e.to_string() }));
+                    self.send_values(&[reply])?
+                }
+                Message::Ping(payload) => write_frame(&mut self.stream, OPCODE_PONG, &payload)
+                    .chain_err(|| "failed to send pong")?,
+                Message::Update(update) => {
+                    let values = self.handle_update(&update);
+                    self.send_values(&values)?
+                }
+                Message::Done => {
+                    let _ = write_frame(&mut self.stream, OPCODE_CLOSE, &[]);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn handle_requests(mut reader: BufReader<TcpStream>, tx: SyncSender<Message>) -> Result<()> {
+        let result = Connection::read_messages(&mut reader, &tx);
+        let _ = tx.send(Message::Done);
+        result
+    }
+
+    fn read_messages(reader: &mut BufReader<TcpStream>, tx: &SyncSender<Message>) -> Result<()> {
+        loop {
+            let (opcode, payload) = read_frame(reader)?;
+            let msg = match opcode {
+                OPCODE_TEXT => Message::Request(
+                    String::from_utf8(payload).chain_err(|| "invalid UTF8 message")?,
+                ),
+                OPCODE_PING => Message::Ping(payload),
+                OPCODE_PONG => continue,
+                OPCODE_CLOSE => return Ok(()),
+                _ => bail!("unsupported opcode {}", opcode),
+            };
+            tx.send(msg).chain_err(|| "channel closed")?;
+        }
+    }
+
+    fn run(mut self) {
+        let mut reader =
+            BufReader::new(self.stream.try_clone().expect("failed to clone TcpStream"));
+        // don't let idle or slow clients hold a connection slot before upgrading
+        let tenants: Vec<Tenant> = self.tenants.iter().map(|(t, _)| t.clone()).collect();
+        let handshake = self
+            .stream
+            .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+            .chain_err(|| "failed to set handshake timeout")
+            .and_then(|_| handshake(&mut reader, &mut self.stream, &tenants))
+            .and_then(|tenant| {
+                self.stream
+                    .set_read_timeout(None)
+                    .chain_err(|| "failed to clear handshake timeout")
+                    .map(|_| tenant)
+            });
+        match handshake {
+            Ok(Some(tenant)) => {
+                let (_, watched) = self
+                    .tenants
+                    .iter()
+                    .find(|(t, _)| t.name == tenant.name)
+                    .unwrap();
+                debug!("[{}] authenticated as tenant {}", self.addr, tenant.name);
+                self.budget
+                    .set_tenant(Arc::clone(watched), tenant.max_watched_scripts);
+            }
+            Ok(None) => (),
+            Err(e) => {
+                warn!("[{}] handshake failed: {}", self.addr, e);
+                let _ = self.stream.shutdown(Shutdown::Both);
+                return;
+            }
+        }
+        let tx = self.chan.sender();
+        let child = spawn_thread("ws-reader", || Connection::handle_requests(reader, tx));
+        if let Err(e) = self.handle_replies() {
+            error!(
+                "[{}] connection handling failed: {}",
+                self.addr,
+                e.display_chain().to_string()
+            );
+        }
+        debug!("[{}] shutting down connection", self.addr);
+        let _ = self.stream.shutdown(Shutdown::Both);
+        if let Err(err) = child.join().expect("receiver panicked") {
+            debug!("[{}] receiver failed: {}", self.addr, err);
+        }
+    }
+}
+
+// Merge a subscription's event into its buffered one: the transactions are those that changed
+// over the window (with their latest status), and the scripts derived meanwhile add up
+fn merge_events(pending: &mut Value, event: Value) {
+    if let (Some(txs), Some(new_txs)) = (pending["txs"].as_array_mut(), event["txs"].as_array()) {
+        for tx in new_txs {
+            let same = |old: &&mut Value| {
+                old["txid"] == tx["txid"] && old["scripthash"] == tx["scripthash"]
+            };
+            match txs.iter_mut().find(same) {
+                Some(old) => *old = tx.clone(),
+                None => txs.push(tx.clone()),
+            }
+        }
+    }
+    if let (Some(derived), Some(new_derived)) =
+        (pending["derived"].as_u64(), event["derived"].as_u64())
+    {
+        pending["derived"] = json!(derived + new_derived);
+    }
+    if let Some(next_index) = event.get("next_index") {
+        pending["next_index"] = next_index.clone();
+    }
+}
+
+fn address_to_script(address: &str, params: &AddressParams) -> Result<bitcoin::Script> {
+    #[cfg(not(feature = "liquid"))]
+    return crate::util::address_to_script(address, params)
+        .chain_err(|| "invalid address or address on invalid network");
+
+    #[cfg(feature = "liquid")]
+    return {
+        use crate::chain::address::Address;
+        use std::str::FromStr;
+
+        let address = Address::from_str(address).chain_err(|| "invalid address")?;
+        ensure!(address.params == params, "address on invalid network");
+        Ok(address.script_pubkey())
+    };
+}
+
+// The value received in blocks up to (and including) `max_height`
+#[cfg(not(feature = "liquid"))]
+pub fn received_until(fundings: &[(usize, Amount)], max_height: usize) -> Amount {
+    fundings
+        .iter()
+        .filter(|(height, _)| *height <= max_height)
+        .map(|(_, value)| *value)
+        .sum()
+}
+
+// Upgrade the connection, once it authenticated as one of the `tenants` (if any are configured)
+fn handshake<R: BufRead, W: Write>(
+    reader: &mut R,
+    stream: &mut W,
+    tenants: &[Tenant],
+) -> Result<Option<Tenant>> {
+    let mut key = None;
+    let mut api_key = None;
+    let mut request_line = true;
+    let mut reader = reader.take(MAX_HANDSHAKE_SIZE);
+    loop {
+        let mut line = String::new();
+        reader
+            .read_line(&mut line)
+            .chain_err(|| "failed to read handshake")?;
+        ensure!(
+            line.ends_with('\n'),
+            "handshake truncated or too large (max {} bytes)",
+            MAX_HANDSHAKE_SIZE
+        );
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if request_line {
+            request_line = false;
+            api_key = query_api_key(line);
+            continue;
+        }
+        let mut parts = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.trim().to_string());
+            } else if name.trim().eq_ignore_ascii_case("x-api-key") {
+                api_key = Some(value.trim().to_string());
+            }
+        }
+    }
+    let key = key.chain_err(|| "missing Sec-WebSocket-Key")?;
+    let tenant = match authenticate(tenants, api_key.as_ref().map(String::as_str)) {
+        Ok(tenant) => tenant.cloned(),
+        Err(e) => {
+            let _ = stream.write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n");
+            return Err(e);
+        }
+    };
+
+    let mut sha1 = Sha1::new();
+    sha1.input_str(&key);
+    sha1.input_str(WS_GUID);
+    let mut hash = [0u8; 20];
+    sha1.result(&mut hash);
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        base64::encode(&hash)
+    );
+    stream
+        .write_all(response.as_bytes())
+        .chain_err(|| "failed to send handshake")?;
+    Ok(tenant)
+}
+
+// The `api_key` query parameter of the handshake's request line (e.g. "GET /?api_key=<key> ...")
+fn query_api_key(request_line: &str) -> Option<String> {
+    let target = request_line.split_whitespace().nth(1)?;
+    let query = target.splitn(2, '?').nth(1)?;
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == "api_key")
+        .map(|(_, value)| value.into_owned())
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    reader
+        .read_exact(&mut header)
+        .chain_err(|| "failed to read frame")?;
+    let opcode = header[0] & 0x0F;
+    ensure!(header[0] & 0x80 != 0, "fragmented frames are not supported");
+    ensure!(header[1] & 0x80 != 0, "client frames must be masked");
+
+    let len = match header[1] & 0x7F {
+        126 => {
+            let mut buf = [0u8; 2];
+            reader.read_exact(&mut buf).chain_err(|| "failed to read")?;
+            u16::from_be_bytes(buf) as u64
+        }
+        127 => {
+            let mut buf = [0u8; 8];
+            reader.read_exact(&mut buf).chain_err(|| "failed to read")?;
+            u64::from_be_bytes(buf)
+        }
+        len => len as u64,
+    };
+    ensure!(len <= MAX_FRAME_SIZE, "frame too large ({} bytes)", len);
+
+    let mut mask = [0u8; 4];
+    reader
+        .read_exact(&mut mask)
+        .chain_err(|| "failed to read")?;
+    let mut payload = vec![0u8; len as usize];
+    reader
+        .read_exact(&mut payload)
+        .chain_err(|| "failed to read")?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok((opcode, payload))
+}
+
+fn write_frame<W: Write>(stream: &mut W, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= 0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)
+}
+
+pub struct WsServer {
+    notification: Sender<Notification>,
+    server: Option<thread::JoinHandle<()>>,
+}
+
+impl WsServer {
+    fn start_notifier(
+        notification: Channel<Notification>,
+        senders: Arc<Mutex<Vec<SyncSender<Message>>>>,
+        acceptor: Sender<Option<(TcpStream, SocketAddr)>>,
+        query: Arc<Query>,
+    ) {
+        spawn_thread("ws-notification", move || {
+            let mut state = UpdateState::default();
+            for msg in notification.receiver().iter() {
+                match msg {
+                    Notification::Periodic => {
+                        let update = get_update(&query, &mut state);
+                        let update = Arc::new(update);
+                        let mut senders = senders.lock().unwrap();
+                        for sender in senders.split_off(0) {
+                            if let Err(TrySendError::Disconnected(_)) =
+                                sender.try_send(Message::Update(update.clone()))
+                            {
+                                continue;
+                            }
+                            senders.push(sender);
+                        }
+                    }
+                    Notification::Exit => acceptor.send(None).unwrap(),
+                }
+            }
+        });
+    }
+
+    fn start_acceptor(addr: SocketAddr) -> Channel<Option<(TcpStream, SocketAddr)>> {
+        let chan = Channel::new();
+        let acceptor = chan.sender();
+        spawn_thread("ws-acceptor", move || {
+            let listener = TcpListener::bind(addr).expect(&format!("bind({}) failed", addr));
+            info!("WebSocket server running on {}", addr);
+            loop {
+                let (stream, addr) = listener.accept().expect("accept failed");
+                stream
+                    .set_nonblocking(false)
+                    .expect("failed to set connection as blocking");
+                acceptor.send(Some((stream, addr))).expect("send failed");
+            }
+        });
+        chan
+    }
+
+    pub fn start(addr: SocketAddr, config: &Config, query: Arc<Query>, metrics: &Metrics) -> Self {
+        let connections = metrics.gauge(MetricOpts::new(
+            "ws_connections",
+            "# of WebSocket connections",
+        ));
+        let config = Arc::new(config.clone());
+        let active = Arc::new(AtomicUsize::new(0));
+        let watched = Arc::new(AtomicUsize::new(0));
+        let tenants: Arc<TenantsWatched> = Arc::new(
+            config
+                .tenants
+                .iter()
+                .map(|tenant| (tenant.clone(), Arc::new(AtomicUsize::new(0))))
+                .collect(),
+        );
+        let notification = Channel::new();
+        WsServer {
+            notification: notification.sender(),
+            server: Some(spawn_thread("ws", move || {
+                let senders = Arc::new(Mutex::new(Vec::<SyncSender<Message>>::new()));
+                let acceptor = WsServer::start_acceptor(addr);
+                WsServer::start_notifier(
+                    notification,
+                    senders.clone(),
+                    acceptor.sender(),
+                    query.clone(),
+                );
+                let mut children = vec![];
+                while let Some((stream, addr)) = acceptor.receiver().recv().unwrap() {
+                    if active.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+                        warn!("too many WebSocket connections, rejecting {}", addr);
+                        let _ = stream.shutdown(Shutdown::Both);
+                        continue;
+                    }
+                    active.fetch_add(1, Ordering::SeqCst);
+                    let active = active.clone();
+                    let watched = watched.clone();
+                    let tenants = tenants.clone();
+                    let config = config.clone();
+                    let query = query.clone();
+                    let senders = senders.clone();
+                    let connections = connections.clone();
+                    children.push(spawn_thread("ws-peer", move || {
+                        info!("[{}] connected WebSocket peer", addr);
+                        connections.inc();
+                        let conn = Connection::new(query, &config, watched, tenants, stream, addr);
+                        senders.lock().unwrap().push(conn.chan.sender());
+                        conn.run();
+                        connections.dec();
+                        active.fetch_sub(1, Ordering::SeqCst);
+                        info!("[{}] disconnected WebSocket peer", addr);
+                    }));
+                }
+                trace!(
+                    "closing {} WebSocket connections",
+                    senders.lock().unwrap().len()
+                );
+                for sender in senders.lock().unwrap().iter() {
+                    let _ = sender.send(Message::Done);
+                }
+                for child in children {
+                    let _ = child.join();
+                }
+                trace!("WebSocket connections are closed");
+            })),
+        }
+    }
+
+    pub fn notify(&self) {
+        self.notification.send(Notification::Periodic).unwrap();
+    }
+}
+
+fn get_update(query: &Query, state: &mut UpdateState) -> Update {
+    let chain = query.chain();
+    let tip = chain.best_header();
+    let tip_changed = state.tip.as_ref() != Some(&tip);
+    let (block, mut touched) = if tip_changed {
+        let summary = chain.get_block_summary(tip.hash());
+        let block = json!({
+            "event": "block",
+            "block": BlockId::from(&tip),
+            "summary": summary,
+        });
+        // the blocks connected on top of the previous tip, unless it was reorged out
+        let touched = match state.tip {
+            Some(ref last)
+                if tip.height() > last.height()
+                    && tip.height() - last.height() <= MAX_UPDATE_BLOCKS
+                    && chain.hash_by_height(last.height()) == Some(*last.hash()) =>
+            {
+                blocks_scripthashes(chain, last.height() + 1..=tip.height())
+            }
+            _ => None,
+        };
+        state.tip = Some(tip);
+        (Some(block), touched)
+    } else {
+        (None, Some(HashSet::new()))
+    };
+
+    let mempool: HashSet<Sha256dHash> = query.mempool().txids().into_iter().cloned().collect();
+    let mempool_txids: Vec<Sha256dHash> = mempool.difference(&state.mempool).cloned().collect();
+    for txid in state.mempool.difference(&mempool) {
+        match state.mempool_scripthashes.remove(txid) {
+            Some(scripthashes) => {
+                if let Some(ref mut touched) = touched {
+                    touched.extend(scripthashes);
+                }
+            }
+            // confirmed transactions are covered by the blocks' scripthashes
+            None if chain.tx_confirming_block(txid).is_some() => (),
+            None => touched = None,
+        }
+    }
+    if touched.is_some() && mempool_txids.len() > MAX_UPDATE_MEMPOOL_TXS {
+        touched = None;
+    }
+    if let Some(ref mut touched) = touched {
+        for (txid, scripthashes) in mempool_scripthashes(query, &mempool_txids) {
+            touched.extend(scripthashes.iter().cloned());
+            if state.mempool_scripthashes.len() < MAX_TRACKED_MEMPOOL_TXS {
+                state.mempool_scripthashes.insert(txid, scripthashes);
+            }
+        }
+    }
+    state.mempool = mempool;
+
+    Update {
+        block,
+        mempool_txids,
+        tip_changed,
+        touched,
+    }
+}
+
+// The scripthashes funded or spent by the transactions
+fn txs_scripthashes<'a>(
+    txs: &'a [Transaction],
+    prevouts: &'a HashMap<OutPoint, TxOut>,
+) -> impl Iterator<Item = FullHash> + 'a {
+    let funded = txs.iter().flat_map(|tx| tx.output.iter());
+    let spent = txs
+        .iter()
+        .flat_map(|tx| tx.input.iter())
+        .filter_map(move |txin| prevouts.get(&txin.previous_output));
+    funded
+        .chain(spent)
+        .map(|txo| compute_script_hash(&txo.script_pubkey))
+}
+
+fn spent_outpoints(txs: &[Transaction]) -> BTreeSet<OutPoint> {
+    txs.iter()
+        .flat_map(|tx| tx.input.iter())
+        .filter(|txin| has_prevout(txin))
+        .map(|txin| txin.previous_output)
+        .collect()
+}
+
+// The scripthashes touched by the best-chain blocks at `heights`, or None if they can't be read
+fn blocks_scripthashes(
+    chain: &ChainQuery,
+    heights: RangeInclusive<usize>,
+) -> Option<HashSet<FullHash>> {
+    let mut touched = HashSet::new();
+    for height in heights {
+        let blockhash = chain.hash_by_height(height)?;
+        let txs = chain
+            .lookup_txns(&chain.get_block_txids(&blockhash)?)
+            .ok()?;
+        let prevouts = chain.lookup_avail_txos(&spent_outpoints(&txs));
+        touched.extend(txs_scripthashes(&txs, &prevouts));
+    }
+    Some(touched)
+}
+
+// The scripthashes touched by each of the new mempool transactions. The mempool is only locked
+// to read the transactions and their unconfirmed prevouts, not while reading the confirmed ones.
+fn mempool_scripthashes(query: &Query, txids: &[Sha256dHash]) -> Vec<(Sha256dHash, Vec<FullHash>)> {
+    let (txs, outpoints, mut prevouts) = {
+        let mempool = query.mempool();
+        let txs: Vec<Transaction> = txids
+            .iter()
+            .filter_map(|txid| mempool.lookup_txn(txid))
+            .collect();
+        let outpoints = spent_outpoints(&txs);
+        let unconfirmed = mempool.lookup_unconfirmed_txos(&outpoints);
+        (txs, outpoints, unconfirmed)
+    };
+    let confirmed: BTreeSet<OutPoint> = outpoints
+        .into_iter()
+        .filter(|outpoint| !prevouts.contains_key(outpoint))
+        .collect();
+    prevouts.extend(query.chain().lookup_avail_txos(&confirmed));
+    txs.iter()
+        .map(|tx| {
+            let tx_scripthashes = txs_scripthashes(std::slice::from_ref(tx), &prevouts);
+            (tx.txid(), tx_scripthashes.collect())
+        })
+        .collect()
+}
+
+impl Drop for WsServer {
+    fn drop(&mut self) {
+        trace!("stop accepting new WebSocket connections");
+        self.notification.send(Notification::Exit).unwrap();
+        self.server.take().map(|t| t.join().unwrap());
+        trace!("WebSocket server is stopped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        handshake, merge_events, read_frame, write_frame, ScriptBudget, Update, MAX_FRAME_SIZE,
+        MAX_WATCHED_SCRIPTS, OPCODE_PING, OPCODE_TEXT,
+    };
+    use crate::util::parse_tenant;
+    use std::collections::HashSet;
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    #[cfg(not(feature = "liquid"))]
+    use {
+        super::{received_until, txs_scripthashes, DescriptorWatch, History},
+        crate::chain::{OutPoint, Transaction, TxIn, TxOut},
+        crate::new_index::compute_script_hash,
+        crate::util::{descriptor::XpubDescriptor, Amount, FullHash},
+        bitcoin::network::constants::Network as BNetwork,
+        bitcoin::Script,
+        bitcoin_hashes::sha256d::Hash as Sha256dHash,
+        bitcoin_hashes::Hash,
+        std::collections::HashMap,
+    };
+
+    // Mask the payload with the given key, as clients do
+    fn client_frame(opcode: u8, len_bytes: &[u8], mask: [u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x80 | opcode];
+        frame.extend_from_slice(len_bytes);
+        frame[1] |= 0x80;
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn test_handshake() {
+        // the example of RFC 6455, section 1.3
+        let request = "GET /chat HTTP/1.1\r\n\
+                       Host: server.example.com\r\n\
+                       Upgrade: websocket\r\n\
+                       Connection: Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n";
+        let mut response = vec![];
+        handshake(&mut Cursor::new(request), &mut response, &[]).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kK+C5xOo/zzBGo=\r\n"));
+
+        let missing_key = "GET /chat HTTP/1.1\r\nUpgrade: websocket\r\n\r\n";
+        assert!(handshake(&mut Cursor::new(missing_key), &mut vec![], &[]).is_err());
+
+        // truncated, and endless headers
+        let truncated = "GET /chat HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==";
+        assert!(handshake(&mut Cursor::new(truncated), &mut vec![], &[]).is_err());
+        let endless = "X-Header: value\r\n".repeat(1000);
+        assert!(handshake(&mut Cursor::new(endless), &mut vec![], &[]).is_err());
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(10_000));
+        assert!(handshake(&mut Cursor::new(long_line), &mut vec![], &[]).is_err());
+    }
+
+    #[test]
+    fn test_handshake_tenants() {
+        let tenants = vec![
+            parse_tenant("alice:k1").unwrap(),
+            parse_tenant("bob:k/2").unwrap(),
+        ];
+        let request = |target: &str, headers: &str| {
+            format!(
+                "GET {} HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{}\r\n",
+                target, headers
+            )
+        };
+        let tenant = |request: String| {
+            handshake(&mut Cursor::new(request), &mut vec![], &tenants)
+                .map(|tenant| tenant.unwrap().name)
+        };
+        assert_eq!(tenant(request("/", "X-API-Key: k1\r\n")).unwrap(), "alice");
+        // as a query parameter, for browsers
+        assert_eq!(tenant(request("/?api_key=k%2F2", "")).unwrap(), "bob");
+        assert_eq!(tenant(request("/?a=1&api_key=k1", "")).unwrap(), "alice");
+
+        // rejected without a valid key
+        let mut response = vec![];
+        let missing = request("/", "");
+        assert!(handshake(&mut Cursor::new(missing), &mut response, &tenants).is_err());
+        assert!(response.starts_with(b"HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(tenant(request("/?api_key=k3", "")).is_err());
+        assert!(tenant(request("/", "X-API-Key: k\r\n")).is_err());
+    }
+
+    #[test]
+    fn test_read_frame() {
+        // the masked "Hello" of RFC 6455, section 5.7
+        let frame = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (opcode, payload) = read_frame(&mut Cursor::new(&frame[..])).unwrap();
+        assert_eq!(opcode, OPCODE_TEXT);
+        assert_eq!(payload, b"Hello");
+        assert_eq!(
+            client_frame(OPCODE_TEXT, &[5], [0x37, 0xfa, 0x21, 0x3d], b"Hello"),
+            frame.to_vec()
+        );
+
+        // 16-bit and 64-bit extended payload lengths
+        let payload: Vec<u8> = (0..300u32).map(|i| i as u8).collect();
+        let mask = [1, 2, 3, 4];
+        for len_bytes in &[
+            vec![126, 0x01, 0x2c],
+            vec![127, 0, 0, 0, 0, 0, 0, 0x01, 0x2c],
+        ] {
+            let frame = client_frame(OPCODE_PING, len_bytes, mask, &payload);
+            let (opcode, read) = read_frame(&mut Cursor::new(frame)).unwrap();
+            assert_eq!(opcode, OPCODE_PING);
+            assert_eq!(read, payload);
+        }
+
+        // consecutive frames are read one by one
+        let mut frames = client_frame(OPCODE_TEXT, &[2], mask, b"{}");
+        frames.extend(client_frame(OPCODE_TEXT, &[1], mask, b"1"));
+        let mut reader = Cursor::new(frames);
+        assert_eq!(read_frame(&mut reader).unwrap().1, b"{}");
+        assert_eq!(read_frame(&mut reader).unwrap().1, b"1");
+        assert!(read_frame(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_read_invalid_frame() {
+        // unmasked
+        let frame = [0x81, 0x05, b'H', b'e', b'l', b'l', b'o'];
+        assert!(read_frame(&mut Cursor::new(&frame[..])).is_err());
+        // fragmented
+        let mut frame = client_frame(OPCODE_TEXT, &[5], [1, 2, 3, 4], b"Hello");
+        frame[0] &= 0x7F;
+        assert!(read_frame(&mut Cursor::new(frame)).is_err());
+        // too large, rejected before reading the payload
+        let len = (MAX_FRAME_SIZE + 1).to_be_bytes();
+        let mut len_bytes = vec![127];
+        len_bytes.extend_from_slice(&len);
+        let frame = client_frame(OPCODE_TEXT, &len_bytes, [1, 2, 3, 4], b"");
+        assert!(read_frame(&mut Cursor::new(frame)).is_err());
+        // truncated payload
+        let mut frame = client_frame(OPCODE_TEXT, &[5], [1, 2, 3, 4], b"Hello");
+        frame.pop();
+        assert!(read_frame(&mut Cursor::new(frame)).is_err());
+    }
+
+    #[test]
+    fn test_write_frame() {
+        let mut frame = vec![];
+        write_frame(&mut frame, OPCODE_TEXT, b"Hello").unwrap();
+        assert_eq!(frame, vec![0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+
+        for &(len, ref header) in &[
+            (125, vec![0x81, 125]),
+            (126, vec![0x81, 126, 0x00, 0x7e]),
+            (0xFFFF, vec![0x81, 126, 0xff, 0xff]),
+            (0x10000, vec![0x81, 127, 0, 0, 0, 0, 0, 0x01, 0, 0]),
+        ] {
+            let mut frame = vec![];
+            write_frame(&mut frame, OPCODE_TEXT, &vec![0x2a; len]).unwrap();
+            assert_eq!(&frame[..header.len()], &header[..]);
+            assert_eq!(frame.len(), header.len() + len);
+        }
+    }
+
+    #[test]
+    fn test_update_touches() {
+        let update = |touched| Update {
+            block: None,
+            mempool_txids: vec![],
+            tip_changed: true,
+            touched,
+        };
+        let (a, b) = ([1u8; 32], [2u8; 32]);
+        assert!(update(None).touches(&a));
+        let touched: HashSet<_> = vec![a].into_iter().collect();
+        assert!(update(Some(touched.clone())).touches(&a));
+        assert!(!update(Some(touched)).touches(&b));
+    }
+
+    #[test]
+    fn test_merge_events() {
+        // a transaction seen in the mempool and then confirmed within the window is listed once
+        let mut pending = json!({
+            "event": "scripthash",
+            "txs": [{"txid": "aa", "block_height": null}, {"txid": "bb", "block_height": null}],
+        });
+        merge_events(
+            &mut pending,
+            json!({
+                "event": "scripthash",
+                "txs": [{"txid": "aa", "block_height": 100}, {"txid": "cc", "block_height": null}],
+            }),
+        );
+        assert_eq!(
+            pending["txs"],
+            json!([
+                {"txid": "aa", "block_height": 100},
+                {"txid": "bb", "block_height": null},
+                {"txid": "cc", "block_height": null},
+            ])
+        );
+
+        // the descriptors' transactions are told apart by script, and their derivations add up
+        let tx = |scripthash: &str, height: u32| json!({"txid": "aa", "scripthash": scripthash, "block_height": height});
+        let mut pending = json!({
+            "event": "descriptor",
+            "id": 1,
+            "txs": [tx("01", 100)],
+            "derived": 2,
+            "next_index": [5],
+        });
+        merge_events(
+            &mut pending,
+            json!({
+                "event": "descriptor",
+                "id": 1,
+                "txs": [tx("02", 101)],
+                "derived": 1,
+                "next_index": [6],
+            }),
+        );
+        assert_eq!(pending["txs"], json!([tx("01", 100), tx("02", 101)]));
+        assert_eq!(pending["derived"], 3);
+        assert_eq!(pending["next_index"], json!([6]));
+    }
+
+    #[test]
+    fn test_script_budget() {
+        let total = Arc::new(AtomicUsize::new(0));
+        let mut a = ScriptBudget::new(total.clone());
+        let mut b = ScriptBudget::new(total.clone());
+        a.reserve(MAX_WATCHED_SCRIPTS - 1).unwrap();
+        // the limit is shared by all the connections
+        b.reserve(1).unwrap();
+        assert!(b.reserve(1).is_err());
+        assert!(a.reserve(1).is_err());
+        assert_eq!(total.load(Ordering::SeqCst), MAX_WATCHED_SCRIPTS);
+
+        a.release(10);
+        b.reserve(10).unwrap();
+        // only the reserved scripts are released
+        b.release(100);
+        assert_eq!(total.load(Ordering::SeqCst), MAX_WATCHED_SCRIPTS - 10);
+        drop(a);
+        assert_eq!(total.load(Ordering::SeqCst), 0);
+        drop(b);
+
+        // and by the connections of a tenant, within its quota
+        let tenant = Arc::new(AtomicUsize::new(0));
+        let mut a = ScriptBudget::new(total.clone());
+        let mut b = ScriptBudget::new(total.clone());
+        a.set_tenant(tenant.clone(), 10);
+        b.set_tenant(tenant.clone(), 10);
+        a.reserve(6).unwrap();
+        assert!(b.reserve(5).is_err());
+        // the scripts rejected by the tenant's quota aren't counted by the server
+        assert_eq!(total.load(Ordering::SeqCst), 6);
+        b.reserve(4).unwrap();
+        drop(a);
+        assert_eq!(tenant.load(Ordering::SeqCst), 4);
+        assert_eq!(total.load(Ordering::SeqCst), 4);
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    #[test]
+    fn test_descriptor_watch() {
+        let xpub = "xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnw";
+        let desc =
+            XpubDescriptor::parse(&format!("wpkh({}/1/*)", xpub), BNetwork::Bitcoin).unwrap();
+        let total = Arc::new(AtomicUsize::new(0));
+        let mut budget = ScriptBudget::new(total.clone());
+        let mut watch = DescriptorWatch::new(1, desc);
+        let no_history = |_: &FullHash| History::new();
+        assert_eq!(watch.derive_ahead(3, &mut budget, no_history).unwrap(), 3);
+        assert_eq!(watch.next_indexes(), vec![3]);
+        assert_eq!(total.load(Ordering::SeqCst), 3);
+
+        // the derived scripts are only re-read when touched
+        let update = |touched: Vec<FullHash>| Update {
+            block: None,
+            mempool_txids: vec![],
+            tip_changed: false,
+            touched: Some(touched.into_iter().collect()),
+        };
+        let untouched = |_: &FullHash| -> History { panic!("untouched script re-read") };
+        assert!(watch
+            .refresh(&update(vec![]), 3, &mut budget, untouched)
+            .is_none());
+
+        // using the last script derives 3 more of them
+        let used = watch.scripts[0][2];
+        let txid = Sha256dHash::hash(b"tx");
+        let history = |scripthash: &FullHash| -> History {
+            let mut history = History::new();
+            if *scripthash == used {
+                history.insert(txid, Some(100));
+            }
+            history
+        };
+        let event = watch
+            .refresh(&update(vec![used, [0u8; 32]]), 3, &mut budget, history)
+            .unwrap();
+        assert_eq!(event["derived"], 3);
+        assert_eq!(event["next_index"], json!([6]));
+        assert_eq!(event["txs"][0]["index"], 2);
+        assert_eq!(event["txs"][0]["block_height"], 100);
+        assert_eq!(watch.tx_count(), 1);
+        assert_eq!(total.load(Ordering::SeqCst), 6);
+
+        // unchanged histories raise no event
+        assert!(watch
+            .refresh(&update(vec![used]), 3, &mut budget, history)
+            .is_none());
+        drop(budget);
+        assert_eq!(total.load(Ordering::SeqCst), 0);
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    #[test]
+    fn test_txs_scripthashes() {
+        let txo = |script: u8| TxOut {
+            value: 1000,
+            script_pubkey: Script::from(vec![script]),
+        };
+        let prevout = OutPoint {
+            txid: Default::default(),
+            vout: 0,
+        };
+        let unknown = OutPoint {
+            txid: Default::default(),
+            vout: 1,
+        };
+        let txin = |previous_output| TxIn {
+            previous_output,
+            script_sig: Script::new(),
+            sequence: 0xffff_ffff,
+            witness: vec![],
+        };
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![txin(prevout), txin(unknown)],
+            output: vec![txo(0x51), txo(0x52)],
+        };
+        let mut prevouts = HashMap::new();
+        prevouts.insert(prevout, txo(0x53));
+
+        let touched: HashSet<_> = txs_scripthashes(&[tx], &prevouts).collect();
+        let expected: HashSet<_> = vec![0x51, 0x52, 0x53]
+            .into_iter()
+            .map(|script| compute_script_hash(&Script::from(vec![script])))
+            .collect();
+        assert_eq!(touched, expected);
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    #[test]
+    fn test_received_until() {
+        let fundings = vec![
+            (10, Amount::from_sat(1000)),
+            (12, Amount::from_sat(500)),
+            (12, Amount::from_sat(250)),
+        ];
+        assert_eq!(received_until(&fundings, 9), Amount::from_sat(0));
+        assert_eq!(received_until(&fundings, 11), Amount::from_sat(1000));
+        assert_eq!(received_until(&fundings, 12), Amount::from_sat(1750));
+        assert_eq!(received_until(&[], 100), Amount::from_sat(0));
+    }
+}
@@ -0,0 +1,158 @@
+use bitcoin::consensus::encode::deserialize;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use zmq;
+
+use crate::chain::Transaction;
+use crate::errors::*;
+use crate::util::spawn_thread;
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+const RECV_TIMEOUT_MS: i32 = 1000; // how long it takes to notice that the listener was dropped
+
+// Updates announced by bitcoind since they were last taken. Notifications can be dropped by ZMQ
+// (e.g. when the high water mark is reached), so the mempool is still synced periodically.
+#[derive(Default)]
+pub struct ZmqUpdates {
+    pub txs: Vec<Transaction>, // in announcement order, so that parents come before children
+    pub new_block: bool,
+}
+
+// Subscribes to bitcoind's `rawtx` and `rawblock` ZMQ notifications (-zmqpubrawtx/-zmqpubrawblock)
+pub struct ZmqListener {
+    updates: Arc<Mutex<ZmqUpdates>>,
+    wakeup: chan::Receiver<()>,
+    stop: Option<chan::Sender<()>>, // dropped to stop the listening thread
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ZmqListener {
+    pub fn start(rawtx: Option<&str>, rawblock: Option<&str>) -> Result<ZmqListener> {
+        let context = zmq::Context::new();
+        let socket = context
+            .socket(zmq::SUB)
+            .chain_err(|| "failed to create ZMQ socket")?;
+        for (topic, endpoint) in &[("rawtx", rawtx), ("rawblock", rawblock)] {
+            if let Some(endpoint) = endpoint {
+                socket
+                    .connect(endpoint)
+                    .chain_err(|| format!("failed to connect to ZMQ endpoint {}", endpoint))?;
+                socket
+                    .set_subscribe(topic.as_bytes())
+                    .chain_err(|| format!("failed to subscribe to ZMQ {}", topic))?;
+                info!("listening to ZMQ {} notifications from {}", topic, endpoint);
+            }
+        }
+        socket
+            .set_rcvtimeo(RECV_TIMEOUT_MS)
+            .chain_err(|| "failed to set ZMQ receive timeout")?;
+
+        let updates = Arc::new(Mutex::new(ZmqUpdates::default()));
+        let (wakeup_sender, wakeup) = chan::sync(1);
+        let (stop_sender, stop) = chan::sync::<()>(0);
+        let thread_updates = Arc::clone(&updates);
+        let thread = spawn_thread("zmq", move || {
+            let _context = context; // must outlive the socket
+            let mut retry_delay = Duration::from_millis(100);
+            loop {
+                let mut stopped = false;
+                chan_select! {
+                    default => {},
+                    stop.recv() => stopped = true,
+                }
+                if stopped {
+                    break;
+                }
+                // multipart messages are (topic, body, sequence number)
+                let parts = match socket.recv_multipart(0) {
+                    Ok(parts) => parts,
+                    Err(zmq::Error::EAGAIN) => continue, // no notification before the timeout
+                    Err(err) => {
+                        // backing off, as persistent errors would otherwise spin this thread
+                        warn!(
+                            "failed to receive ZMQ notification: {}, retrying in {:?}",
+                            err, retry_delay
+                        );
+                        let timeout = chan::after(retry_delay);
+                        chan_select! {
+                            stop.recv() => {},
+                            timeout.recv() => {},
+                        }
+                        retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                        continue;
+                    }
+                };
+                retry_delay = Duration::from_millis(100);
+                if parts.len() < 2 {
+                    continue;
+                }
+                match &parts[0][..] {
+                    b"rawtx" => match deserialize::<Transaction>(&parts[1]) {
+                        Ok(tx) => thread_updates.lock().unwrap().txs.push(tx),
+                        Err(err) => warn!("failed to parse ZMQ rawtx: {}", err),
+                    },
+                    b"rawblock" => thread_updates.lock().unwrap().new_block = true,
+                    _ => continue,
+                }
+                // coalesce wakeups, a pending one already covers this update
+                chan_select! {
+                    default => {},
+                    wakeup_sender.send(()) => {},
+                }
+            }
+        });
+
+        Ok(ZmqListener {
+            updates,
+            wakeup,
+            stop: Some(stop_sender),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn take_updates(&self) -> ZmqUpdates {
+        mem::replace(&mut *self.updates.lock().unwrap(), ZmqUpdates::default())
+    }
+
+    // Receives a message whenever there are new updates to take
+    pub fn wakeup(&self) -> &chan::Receiver<()> {
+        &self.wakeup
+    }
+}
+
+impl Drop for ZmqListener {
+    fn drop(&mut self) {
+        trace!("stop listening to ZMQ notifications");
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+        trace!("ZMQ listener is stopped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ZmqListener;
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_stop() {
+        // bitcoind not being there yet isn't an error, the connection is retried in the background
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let endpoint = format!("tcp://127.0.0.1:{}", port);
+        let listener = ZmqListener::start(Some(&endpoint), Some(&endpoint)).unwrap();
+        assert!(listener.take_updates().txs.is_empty());
+
+        let start = Instant::now();
+        drop(listener);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}